@@ -0,0 +1,329 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use server::analysis::{InterpreterResponse, Privileges, Statement, ValueType};
+use server::auth::AuthorizationLevel;
+use server::error::ServerError;
+use server::storage::{StorageKey, StorageValue};
+
+use crate::decode;
+use crate::http::{self, HttpResponse, MAX_BUFFER_SIZE};
+use crate::blocking::{DEFAULT_MAX_IDLE_CONNECTIONS, DEFAULT_TIMEOUT};
+
+
+/// A client for use with tokio, with the same methods as the blocking client.
+///
+/// The client can be shared between tasks. Each request borrows an open connection from the
+/// pool, or opens a new one if none are free, and gives it back once the response is read.
+pub struct AsyncClient {
+    /// The address of the server, like `127.0.0.1:7878`
+    address: String,
+    /// Headers sent with every request, like the username
+    headers: Vec<(String, String)>,
+    /// How long to wait for the server
+    timeout: Duration,
+    /// How many open connections to keep
+    max_idle_connections: usize,
+    /// Open connections waiting for the next request
+    idle: Mutex<Vec<TcpStream>>,
+}
+
+
+impl AsyncClient {
+    /// Create a client for the server at an address
+    pub fn new(address: &str) -> AsyncClient {
+        AsyncClient {
+            address: address.to_string(),
+            headers: vec![],
+            timeout: DEFAULT_TIMEOUT,
+            max_idle_connections: DEFAULT_MAX_IDLE_CONNECTIONS,
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    /// Send a username with every request
+    pub fn with_username(self, username: &str) -> AsyncClient {
+        self.with_header("Username", username)
+    }
+
+    /// Log in with a username and password on every request
+    pub fn with_password(self, username: &str, password: &str) -> AsyncClient {
+        self.with_header("Authorization", &http::basic_authorization(username, password))
+    }
+
+    /// Log in with an API key on every request
+    pub fn with_api_key(self, api_key: &str) -> AsyncClient {
+        self.with_header("Authorization", &http::bearer_authorization(api_key))
+    }
+
+    /// Use a capability token on every request, see `cap_issue`
+    pub fn with_capability(self, token: &str) -> AsyncClient {
+        self.with_header("Capability", token)
+    }
+
+    /// Send a header with every request
+    pub fn with_header(mut self, name: &str, value: &str) -> AsyncClient {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set how long to wait for the server
+    pub fn with_timeout(mut self, timeout: Duration) -> AsyncClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many open connections to keep for later requests
+    pub fn with_max_idle_connections(mut self, max_idle_connections: usize) -> AsyncClient {
+        self.max_idle_connections = max_idle_connections;
+        self
+    }
+
+    /// Run a query written in the query language
+    pub async fn query(&self, query: &str) -> Result<InterpreterResponse, ServerError> {
+        self.send(&http::query_body(query)).await
+    }
+
+    /// Run statements, which keeps the case of keys and strings
+    pub async fn execute(&self, statements: Vec<Statement>) -> Result<InterpreterResponse, ServerError> {
+        self.send(&http::statements_body(&statements)).await
+    }
+
+    /// Send a request, trying again on a new connection if a pooled one was already closed
+    async fn send(&self, body: &str) -> Result<InterpreterResponse, ServerError> {
+        let request = http::encode_request(&self.address, &self.headers, body);
+        loop {
+            let pooled = self.idle.lock().await.pop();
+            let reused = pooled.is_some();
+            let mut stream = match pooled {
+                Some(stream) => stream,
+                None => self.connect().await?,
+            };
+            let response = match timeout(self.timeout, exchange(&mut stream, &request)).await {
+                Ok(result) => result?,
+                Err(_) => return Err(ServerError::NetworkError("Timed out waiting for the server.".to_string())),
+            };
+            let response = match response {
+                Some(response) => response,
+                // The server closed the idle connection before it saw the request
+                None if reused => continue,
+                None => return Err(ServerError::NetworkError("Connection closed by the server.".to_string())),
+            };
+            if response.keep_alive {
+                let mut idle = self.idle.lock().await;
+                if idle.len() < self.max_idle_connections {
+                    idle.push(stream);
+                }
+            }
+            return http::decode_response(&response);
+        }
+    }
+
+    /// Open a new connection to the server
+    async fn connect(&self) -> Result<TcpStream, ServerError> {
+        let stream = match timeout(self.timeout, TcpStream::connect(&self.address)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(error)) => return Err(ServerError::NetworkError(format!("Could not connect: {}", error))),
+            Err(_) => return Err(ServerError::NetworkError("Timed out connecting to the server.".to_string())),
+        };
+        if stream.set_nodelay(true).is_err() {
+            return Err(ServerError::NetworkError("Could not set up the connection.".to_string()));
+        }
+        Ok(stream)
+    }
+
+    /// Run a single statement
+    async fn run(&self, statement: Statement) -> Result<InterpreterResponse, ServerError> {
+        self.execute(vec![statement]).await
+    }
+
+    /// Get a value
+    pub async fn get(&self, key: &str) -> Result<StorageValue, ServerError> {
+        self.run(Statement::Get(key.to_string())).await.and_then(decode::value)
+    }
+
+    /// Set a value that never expires
+    pub async fn set<V: Into<StorageValue>>(&self, key: &str, value: V) -> Result<(), ServerError> {
+        self.run(Statement::Set(key.to_string(), value.into(), None)).await.and_then(decode::unit)
+    }
+
+    /// Set a value that expires after some seconds
+    pub async fn set_with_ttl<V: Into<StorageValue>>(&self, key: &str, value: V, ttl: u64) -> Result<(), ServerError> {
+        self.run(Statement::Set(key.to_string(), value.into(), Some(ttl))).await.and_then(decode::unit)
+    }
+
+    /// Delete a value
+    pub async fn delete(&self, key: &str) -> Result<(), ServerError> {
+        self.run(Statement::Delete(key.to_string())).await.and_then(decode::unit)
+    }
+
+    /// See if a key exists
+    pub async fn exists(&self, key: &str) -> Result<bool, ServerError> {
+        self.run(Statement::Exists(key.to_string())).await.and_then(decode::flag)
+    }
+
+    /// Get the seconds a key has left, None if it never expires
+    pub async fn ttl(&self, key: &str) -> Result<Option<u64>, ServerError> {
+        self.run(Statement::GetLifetime(key.to_string())).await.and_then(decode::expiration)
+    }
+
+    /// Change the seconds a key has left, None to keep it forever
+    pub async fn expire(&self, key: &str, ttl: Option<u64>) -> Result<(), ServerError> {
+        self.run(Statement::UpdateLifetime(key.to_string(), ttl)).await.and_then(decode::unit)
+    }
+
+    /// Get the type of a value
+    pub async fn value_type(&self, key: &str) -> Result<ValueType, ServerError> {
+        self.run(Statement::ValueType(key.to_string())).await.and_then(decode::value_type)
+    }
+
+    /// List every key matching a glob pattern
+    pub async fn keys(&self, pattern: &str) -> Result<Vec<StorageKey>, ServerError> {
+        self.run(Statement::Keys(pattern.to_string())).await.and_then(decode::keys)
+    }
+
+    /// Get a value from a vector
+    pub async fn vget(&self, key: &str, index: usize) -> Result<StorageValue, ServerError> {
+        self.run(Statement::VectorGet(key.to_string(), index)).await.and_then(decode::value)
+    }
+
+    /// Set a value in a vector
+    pub async fn vset<V: Into<StorageValue>>(&self, key: &str, index: usize, value: V) -> Result<(), ServerError> {
+        self.run(Statement::VectorSet(key.to_string(), index, value.into())).await.and_then(decode::unit)
+    }
+
+    /// Push a value to the end of a vector
+    pub async fn vpush<V: Into<StorageValue>>(&self, key: &str, value: V) -> Result<(), ServerError> {
+        self.run(Statement::VectorAppend(key.to_string(), value.into())).await.and_then(decode::unit)
+    }
+
+    /// Pop the value at the end of a vector
+    pub async fn vpop(&self, key: &str) -> Result<StorageValue, ServerError> {
+        self.run(Statement::VectorPop(key.to_string())).await.and_then(decode::value)
+    }
+
+    /// Get the length of a vector
+    pub async fn vlen(&self, key: &str) -> Result<usize, ServerError> {
+        self.run(Statement::VectorLength(key.to_string())).await.and_then(decode::size)
+    }
+
+    /// Get a value from a map
+    pub async fn mget<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<StorageValue, ServerError> {
+        self.run(Statement::MapGet(key.to_string(), field.into())).await.and_then(decode::value)
+    }
+
+    /// Set a value in a map
+    pub async fn mset<F: Into<StorageValue>, V: Into<StorageValue>>(
+        &self, key: &str, field: F, value: V
+    ) -> Result<(), ServerError> {
+        self.run(Statement::MapSet(key.to_string(), field.into(), value.into())).await.and_then(decode::unit)
+    }
+
+    /// Delete a value from a map
+    pub async fn mdel<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<(), ServerError> {
+        self.run(Statement::MapDelete(key.to_string(), field.into())).await.and_then(decode::unit)
+    }
+
+    /// See if a map has a value for a field
+    pub async fn mex<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<bool, ServerError> {
+        self.run(Statement::MapExists(key.to_string(), field.into())).await.and_then(decode::flag)
+    }
+
+    /// Get the number of values in a map
+    pub async fn mlen(&self, key: &str) -> Result<usize, ServerError> {
+        self.run(Statement::MapLength(key.to_string())).await.and_then(decode::size)
+    }
+
+    /// Add a user, with a password if they log in with one. Needs an admin.
+    pub async fn user_add(&self, username: &str, level: AuthorizationLevel, password: Option<&str>) -> Result<(), ServerError> {
+        let statement = Statement::UserAdd(username.to_string(), level, password.map(str::to_string));
+        self.run(statement).await.and_then(decode::unit)
+    }
+
+    /// Remove a user. Needs an admin.
+    pub async fn user_del(&self, username: &str) -> Result<(), ServerError> {
+        self.run(Statement::UserDelete(username.to_string())).await.and_then(decode::unit)
+    }
+
+    /// Change a user's password. Needs an admin.
+    pub async fn user_passwd(&self, username: &str, password: &str) -> Result<(), ServerError> {
+        self.run(Statement::UserPassword(username.to_string(), password.to_string())).await.and_then(decode::unit)
+    }
+
+    /// List every user and their level, sorted by username. Needs an admin.
+    pub async fn user_list(&self) -> Result<Vec<(String, AuthorizationLevel)>, ServerError> {
+        self.run(Statement::UserList).await.and_then(decode::users)
+    }
+
+    /// Create a token giving some privileges for some seconds, limited to keys starting with any of
+    /// the prefixes if there are some. Needs an admin and a server with a capability secret.
+    pub async fn cap_issue(&self, privileges: Privileges, lifetime: u64, prefixes: &[&str]) -> Result<String, ServerError> {
+        let prefixes = prefixes.iter().map(|prefix| prefix.to_string()).collect();
+        self.run(Statement::IssueCapability(privileges, lifetime, prefixes)).await.and_then(decode::string)
+    }
+}
+
+/// Write a request and read the response. Gives None if the connection closed before any of it came back.
+async fn exchange(stream: &mut TcpStream, request: &[u8]) -> Result<Option<HttpResponse>, ServerError> {
+    if stream.write_all(request).await.is_err() || stream.flush().await.is_err() {
+        // Only a pooled connection the server already closed should fail here, so try again
+        return Ok(None);
+    }
+    let mut buffer = vec![];
+    loop {
+        let mut temp_buffer = [0; MAX_BUFFER_SIZE];
+        match stream.read(&mut temp_buffer).await {
+            Ok(0) if buffer.is_empty() => return Ok(None),
+            Ok(0) => return Err(ServerError::NetworkError("Connection closed in the middle of a response.".to_string())),
+            Ok(read) => buffer.extend(&temp_buffer[..read]),
+            Err(error) if buffer.is_empty() && http::closed_by_server(&error) => return Ok(None),
+            Err(error) => return Err(ServerError::NetworkError(format!("Problem reading response: {}", error))),
+        }
+        if let Some(response) = http::parse_response(&buffer)? {
+            return Ok(Some(response));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    use super::*;
+    use server::analysis::Interpreter;
+    use server::auth::MockAuthenticator;
+    use server::io::tcp::TcpStreamHandler;
+    use server::single_threaded::SingleThreadedServer;
+    use server::storage::hashmap_storage::HashMapStorage;
+
+    #[tokio::test]
+    async fn test_typed_methods() {
+        let handler = TcpStreamHandler::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let address = handler.local_addr().to_string();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+        let client = AsyncClient::new(&address).with_username("write");
+
+        client.set("Name", "Ada Lovelace").await.unwrap();
+        assert_eq!(client.get("Name").await.unwrap(), StorageValue::from("Ada Lovelace"));
+        assert!(client.exists("Name").await.unwrap());
+        client.expire("Name", Some(60)).await.unwrap();
+        assert!(matches!(client.ttl("Name").await, Ok(Some(ttl)) if ttl <= 60));
+        client.delete("Name").await.unwrap();
+        assert!(!client.exists("Name").await.unwrap());
+        assert_eq!(client.idle.lock().await.len(), 1);
+
+        let client = AsyncClient::new(&address).with_username("read");
+        assert!(matches!(client.set("a", 1).await, Err(ServerError::AuthorizationError(_))));
+        AsyncClient::new(&address).with_username("admin").query("shutdown;").await.unwrap();
+        server.join().unwrap();
+    }
+}
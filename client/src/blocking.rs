@@ -0,0 +1,371 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use server::analysis::{InterpreterResponse, Privileges, Statement, ValueType};
+use server::auth::AuthorizationLevel;
+use server::error::ServerError;
+use server::storage::{StorageKey, StorageValue};
+
+use crate::decode;
+use crate::http::{self, HttpResponse, MAX_BUFFER_SIZE};
+
+
+/// How long to wait for the server when not told otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many open connections to keep for later requests when not told otherwise
+pub const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 8;
+
+
+/// A client that blocks the calling thread until the server responds.
+///
+/// The client can be shared between threads. Each request borrows an open connection from the
+/// pool, or opens a new one if none are free, and gives it back once the response is read.
+pub struct Client {
+    /// The address of the server, like `127.0.0.1:7878`
+    address: String,
+    /// Headers sent with every request, like the username
+    headers: Vec<(String, String)>,
+    /// How long to wait for the server
+    timeout: Duration,
+    /// How many open connections to keep
+    max_idle_connections: usize,
+    /// Open connections waiting for the next request
+    idle: Mutex<Vec<TcpStream>>,
+}
+
+
+impl Client {
+    /// Create a client for the server at an address
+    pub fn new(address: &str) -> Client {
+        Client {
+            address: address.to_string(),
+            headers: vec![],
+            timeout: DEFAULT_TIMEOUT,
+            max_idle_connections: DEFAULT_MAX_IDLE_CONNECTIONS,
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    /// Send a username with every request
+    pub fn with_username(self, username: &str) -> Client {
+        self.with_header("Username", username)
+    }
+
+    /// Log in with a username and password on every request
+    pub fn with_password(self, username: &str, password: &str) -> Client {
+        self.with_header("Authorization", &http::basic_authorization(username, password))
+    }
+
+    /// Log in with an API key on every request
+    pub fn with_api_key(self, api_key: &str) -> Client {
+        self.with_header("Authorization", &http::bearer_authorization(api_key))
+    }
+
+    /// Use a capability token on every request, see `cap_issue`
+    pub fn with_capability(self, token: &str) -> Client {
+        self.with_header("Capability", token)
+    }
+
+    /// Send a header with every request
+    pub fn with_header(mut self, name: &str, value: &str) -> Client {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set how long to wait for the server
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how many open connections to keep for later requests
+    pub fn with_max_idle_connections(mut self, max_idle_connections: usize) -> Client {
+        self.max_idle_connections = max_idle_connections;
+        self
+    }
+
+    /// Run a query written in the query language
+    pub fn query(&self, query: &str) -> Result<InterpreterResponse, ServerError> {
+        self.send(&http::query_body(query))
+    }
+
+    /// Run statements, which keeps the case of keys and strings
+    pub fn execute(&self, statements: Vec<Statement>) -> Result<InterpreterResponse, ServerError> {
+        self.send(&http::statements_body(&statements))
+    }
+
+    /// Send a request, trying again on a new connection if a pooled one was already closed
+    fn send(&self, body: &str) -> Result<InterpreterResponse, ServerError> {
+        let request = http::encode_request(&self.address, &self.headers, body);
+        loop {
+            let pooled = self.idle.lock().unwrap().pop();
+            let reused = pooled.is_some();
+            let mut stream = match pooled {
+                Some(stream) => stream,
+                None => self.connect()?,
+            };
+            let response = match exchange(&mut stream, &request)? {
+                Some(response) => response,
+                // The server closed the idle connection before it saw the request
+                None if reused => continue,
+                None => return Err(ServerError::NetworkError("Connection closed by the server.".to_string())),
+            };
+            if response.keep_alive {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < self.max_idle_connections {
+                    idle.push(stream);
+                }
+            }
+            return http::decode_response(&response);
+        }
+    }
+
+    /// Open a new connection to the server
+    fn connect(&self) -> Result<TcpStream, ServerError> {
+        let stream = match TcpStream::connect(&self.address) {
+            Ok(stream) => stream,
+            Err(error) => return Err(ServerError::NetworkError(format!("Could not connect: {}", error))),
+        };
+        if stream.set_read_timeout(Some(self.timeout)).is_err() || stream.set_nodelay(true).is_err() {
+            return Err(ServerError::NetworkError("Could not set up the connection.".to_string()));
+        }
+        Ok(stream)
+    }
+
+    /// Run a single statement
+    fn run(&self, statement: Statement) -> Result<InterpreterResponse, ServerError> {
+        self.execute(vec![statement])
+    }
+
+    /// Get a value
+    pub fn get(&self, key: &str) -> Result<StorageValue, ServerError> {
+        self.run(Statement::Get(key.to_string())).and_then(decode::value)
+    }
+
+    /// Set a value that never expires
+    pub fn set<V: Into<StorageValue>>(&self, key: &str, value: V) -> Result<(), ServerError> {
+        self.run(Statement::Set(key.to_string(), value.into(), None)).and_then(decode::unit)
+    }
+
+    /// Set a value that expires after some seconds
+    pub fn set_with_ttl<V: Into<StorageValue>>(&self, key: &str, value: V, ttl: u64) -> Result<(), ServerError> {
+        self.run(Statement::Set(key.to_string(), value.into(), Some(ttl))).and_then(decode::unit)
+    }
+
+    /// Delete a value
+    pub fn delete(&self, key: &str) -> Result<(), ServerError> {
+        self.run(Statement::Delete(key.to_string())).and_then(decode::unit)
+    }
+
+    /// See if a key exists
+    pub fn exists(&self, key: &str) -> Result<bool, ServerError> {
+        self.run(Statement::Exists(key.to_string())).and_then(decode::flag)
+    }
+
+    /// Get the seconds a key has left, None if it never expires
+    pub fn ttl(&self, key: &str) -> Result<Option<u64>, ServerError> {
+        self.run(Statement::GetLifetime(key.to_string())).and_then(decode::expiration)
+    }
+
+    /// Change the seconds a key has left, None to keep it forever
+    pub fn expire(&self, key: &str, ttl: Option<u64>) -> Result<(), ServerError> {
+        self.run(Statement::UpdateLifetime(key.to_string(), ttl)).and_then(decode::unit)
+    }
+
+    /// Get the type of a value
+    pub fn value_type(&self, key: &str) -> Result<ValueType, ServerError> {
+        self.run(Statement::ValueType(key.to_string())).and_then(decode::value_type)
+    }
+
+    /// List every key matching a glob pattern
+    pub fn keys(&self, pattern: &str) -> Result<Vec<StorageKey>, ServerError> {
+        self.run(Statement::Keys(pattern.to_string())).and_then(decode::keys)
+    }
+
+    /// Get a value from a vector
+    pub fn vget(&self, key: &str, index: usize) -> Result<StorageValue, ServerError> {
+        self.run(Statement::VectorGet(key.to_string(), index)).and_then(decode::value)
+    }
+
+    /// Set a value in a vector
+    pub fn vset<V: Into<StorageValue>>(&self, key: &str, index: usize, value: V) -> Result<(), ServerError> {
+        self.run(Statement::VectorSet(key.to_string(), index, value.into())).and_then(decode::unit)
+    }
+
+    /// Push a value to the end of a vector
+    pub fn vpush<V: Into<StorageValue>>(&self, key: &str, value: V) -> Result<(), ServerError> {
+        self.run(Statement::VectorAppend(key.to_string(), value.into())).and_then(decode::unit)
+    }
+
+    /// Pop the value at the end of a vector
+    pub fn vpop(&self, key: &str) -> Result<StorageValue, ServerError> {
+        self.run(Statement::VectorPop(key.to_string())).and_then(decode::value)
+    }
+
+    /// Get the length of a vector
+    pub fn vlen(&self, key: &str) -> Result<usize, ServerError> {
+        self.run(Statement::VectorLength(key.to_string())).and_then(decode::size)
+    }
+
+    /// Get a value from a map
+    pub fn mget<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<StorageValue, ServerError> {
+        self.run(Statement::MapGet(key.to_string(), field.into())).and_then(decode::value)
+    }
+
+    /// Set a value in a map
+    pub fn mset<F: Into<StorageValue>, V: Into<StorageValue>>(
+        &self, key: &str, field: F, value: V
+    ) -> Result<(), ServerError> {
+        self.run(Statement::MapSet(key.to_string(), field.into(), value.into())).and_then(decode::unit)
+    }
+
+    /// Delete a value from a map
+    pub fn mdel<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<(), ServerError> {
+        self.run(Statement::MapDelete(key.to_string(), field.into())).and_then(decode::unit)
+    }
+
+    /// See if a map has a value for a field
+    pub fn mex<F: Into<StorageValue>>(&self, key: &str, field: F) -> Result<bool, ServerError> {
+        self.run(Statement::MapExists(key.to_string(), field.into())).and_then(decode::flag)
+    }
+
+    /// Get the number of values in a map
+    pub fn mlen(&self, key: &str) -> Result<usize, ServerError> {
+        self.run(Statement::MapLength(key.to_string())).and_then(decode::size)
+    }
+
+    /// Add a user, with a password if they log in with one. Needs an admin.
+    pub fn user_add(&self, username: &str, level: AuthorizationLevel, password: Option<&str>) -> Result<(), ServerError> {
+        let statement = Statement::UserAdd(username.to_string(), level, password.map(str::to_string));
+        self.run(statement).and_then(decode::unit)
+    }
+
+    /// Remove a user. Needs an admin.
+    pub fn user_del(&self, username: &str) -> Result<(), ServerError> {
+        self.run(Statement::UserDelete(username.to_string())).and_then(decode::unit)
+    }
+
+    /// Change a user's password. Needs an admin.
+    pub fn user_passwd(&self, username: &str, password: &str) -> Result<(), ServerError> {
+        self.run(Statement::UserPassword(username.to_string(), password.to_string())).and_then(decode::unit)
+    }
+
+    /// List every user and their level, sorted by username. Needs an admin.
+    pub fn user_list(&self) -> Result<Vec<(String, AuthorizationLevel)>, ServerError> {
+        self.run(Statement::UserList).and_then(decode::users)
+    }
+
+    /// Create a token giving some privileges for some seconds, limited to keys starting with any of
+    /// the prefixes if there are some. Needs an admin and a server with a capability secret.
+    pub fn cap_issue(&self, privileges: Privileges, lifetime: u64, prefixes: &[&str]) -> Result<String, ServerError> {
+        let prefixes = prefixes.iter().map(|prefix| prefix.to_string()).collect();
+        self.run(Statement::IssueCapability(privileges, lifetime, prefixes)).and_then(decode::string)
+    }
+}
+
+
+/// Write a request and read the response. Gives None if the connection closed before any of it came back.
+fn exchange(stream: &mut TcpStream, request: &[u8]) -> Result<Option<HttpResponse>, ServerError> {
+    if stream.write_all(request).is_err() || stream.flush().is_err() {
+        // Only a pooled connection the server already closed should fail here, so try again
+        return Ok(None);
+    }
+    let mut buffer = vec![];
+    loop {
+        let mut temp_buffer = [0; MAX_BUFFER_SIZE];
+        match stream.read(&mut temp_buffer) {
+            Ok(0) if buffer.is_empty() => return Ok(None),
+            Ok(0) => return Err(ServerError::NetworkError("Connection closed in the middle of a response.".to_string())),
+            Ok(read) => buffer.extend(&temp_buffer[..read]),
+            Err(error) if buffer.is_empty() && http::closed_by_server(&error) => return Ok(None),
+            Err(error) => return Err(ServerError::NetworkError(format!("Problem reading response: {}", error))),
+        }
+        if let Some(response) = http::parse_response(&buffer)? {
+            return Ok(Some(response));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use server::analysis::Interpreter;
+    use server::auth::MockAuthenticator;
+    use server::io::tcp::TcpStreamHandler;
+    use server::single_threaded::SingleThreadedServer;
+    use server::storage::hashmap_storage::HashMapStorage;
+    use server::storage::{CollectionType, KeyType, StorageMap, StorageVector};
+
+    #[test]
+    fn test_typed_methods() {
+        let handler = TcpStreamHandler::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let address = handler.local_addr().to_string();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+        let client = Arc::new(Client::new(&address).with_username("admin").with_max_idle_connections(2));
+
+        client.set("Name", "Ada Lovelace").unwrap();
+        assert_eq!(client.get("Name").unwrap(), StorageValue::from("Ada Lovelace"));
+        client.set_with_ttl("session", 5, 60).unwrap();
+        assert!(matches!(client.ttl("session"), Ok(Some(ttl)) if ttl <= 60));
+        assert!(matches!(client.get("missing"), Err(ServerError::KeyError(_))));
+        assert!(matches!(client.query("get name;"), Err(ServerError::KeyError(_))));
+
+        let threads: Vec<_> = (0..4).map(|index| {
+            let client = Arc::clone(&client);
+            thread::spawn(move || client.set(&format!("key{}", index), index as i64).unwrap())
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(client.keys("key*").unwrap().len(), 4);
+        assert!(client.idle.lock().unwrap().len() <= 2);
+
+        client.set("v", StorageValue::Vector(StorageVector::new(CollectionType::Int))).unwrap();
+        client.set("m", StorageValue::Map(StorageMap::new(KeyType::String, CollectionType::String))).unwrap();
+        client.vpush("v", 1).unwrap();
+        client.vpush("v", 2).unwrap();
+        assert_eq!(client.vlen("v").unwrap(), 2);
+        assert_eq!(client.vpop("v").unwrap(), StorageValue::Int(2));
+        client.mset("m", "Field", "Value").unwrap();
+        assert_eq!(client.mget("m", "Field").unwrap(), StorageValue::from("Value"));
+        assert!(!client.mex("m", "field").unwrap());
+        client.query("shutdown;").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_reset_connections_are_retried() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let respond = |stream: &mut TcpStream, connection: &str| {
+                assert!(stream.read(&mut [0; MAX_BUFFER_SIZE]).unwrap() > 0);
+                let response = format!(
+                    "HTTP/1.1 200 Ok\r\nConnection: {}\r\nContent-Length: 13\r\n\r\n{{\"Version\":1}}", connection
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            };
+            let (mut pooled, _) = listener.accept().unwrap();
+            respond(&mut pooled, "keep-alive");
+            // Closing with the next request unread resets the connection
+            pooled.peek(&mut [0]).unwrap();
+            drop(pooled);
+            let (mut fresh, _) = listener.accept().unwrap();
+            respond(&mut fresh, "close");
+        });
+        let client = Client::new(&address);
+        client.set("a", 1).unwrap();
+        client.set("a", 2).unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use std::path::PathBuf;
+use std::process;
+
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+use client::{Client, InterpreterResponse};
+use client::display::format_response;
+use server::analysis::ResponseMode;
+
+/// The server to connect to when not told otherwise
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+/// The file in the home directory that keeps queries between sessions
+const HISTORY_FILE: &str = ".store_cli_history";
+/// The prompt for the first line of a query
+const PROMPT: &str = "store> ";
+/// The prompt for the following lines of a query that hasn't ended with `;` yet
+const CONTINUATION_PROMPT: &str = "   ..> ";
+/// How to run the client
+const USAGE: &str = "Usage: store-cli [--address <host:port>] [--user <username> [--password <password>]] [--api-key <key>] [--capability <token>] [--file <script>]
+
+Options:
+  -a, --address <host:port>  The server to connect to (default 127.0.0.1:7878)
+  -u, --user <username>      The username to send with every query
+  -p, --password <password>  Log in as the user with this password
+  -k, --api-key <key>        Log in with an API key instead of a username
+  -c, --capability <token>   Use a capability token made by cap_issue instead of logging in
+  -f, --file <script>        Run the queries in a file instead of reading them interactively
+  -h, --help                 Show this message";
+
+/// What the command line asked for
+struct Options {
+    address: String,
+    user: Option<String>,
+    password: Option<String>,
+    api_key: Option<String>,
+    capability: Option<String>,
+    script: Option<String>,
+}
+
+/// Read the options from the command line arguments
+fn parse_options(mut args: impl Iterator<Item=String>) -> Result<Options, String> {
+    let mut options = Options {
+        address: DEFAULT_ADDRESS.to_string(), user: None, password: None, api_key: None, capability: None, script: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("Missing a value for {}.", name));
+        match arg.as_str() {
+            "-a" | "--address" => options.address = value(&arg)?,
+            "-u" | "--user" => options.user = Some(value(&arg)?),
+            "-p" | "--password" => options.password = Some(value(&arg)?),
+            "-k" | "--api-key" => options.api_key = Some(value(&arg)?),
+            "-c" | "--capability" => options.capability = Some(value(&arg)?),
+            "-f" | "--file" => options.script = Some(value(&arg)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            other => return Err(format!("Unknown argument {}.", other)),
+        }
+    }
+    if options.password.is_some() && options.user.is_none() {
+        return Err("--password needs --user.".to_string());
+    }
+    Ok(options)
+}
+
+/// Check whether a query has ended, that is its last character is a `;` outside of any string
+fn is_complete(query: &str) -> bool {
+    let mut in_string = false;
+    let mut escaped = false;
+    for character in query.chars() {
+        match (in_string, escaped, character) {
+            (true, true, _) => escaped = false,
+            (true, false, '\\') => escaped = true,
+            (_, _, '"') => in_string = !in_string,
+            _ => (),
+        }
+    }
+    !in_string && query.trim_end().ends_with(';')
+}
+
+/// Send a query and print the response. Returns whether every statement succeeded.
+fn run_query(client: &Client, query: &str) -> bool {
+    // The tokenizer doesn't expect anything after the last statement, not even a new line
+    let response = match client.query(query.trim()) {
+        Ok(InterpreterResponse::Results(mut results)) if results.len() == 1 => results.pop().unwrap(),
+        response => response,
+    };
+    match response {
+        Ok(response) => {
+            println!("{}", format_response(&response));
+            match response {
+                InterpreterResponse::Results(results) => results.iter().all(Result::is_ok),
+                _ => true,
+            }
+        },
+        Err(error) => {
+            eprintln!("(error) {}", error);
+            false
+        },
+    }
+}
+
+/// Run every query in a script, carrying on past errors. Returns whether they all succeeded.
+fn run_script(client: &Client, path: &str) -> bool {
+    let script = match std::fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(error) => {
+            eprintln!("Could not read {}: {}", path, error);
+            return false;
+        },
+    };
+    let mut succeeded = true;
+    let mut query = String::new();
+    for line in script.lines() {
+        query.push_str(line);
+        query.push('\n');
+        if is_complete(&query) {
+            succeeded &= run_query(client, &query);
+            query.clear();
+        }
+    }
+    if !query.trim().is_empty() {
+        eprintln!("(error) The script ended in the middle of a query, missing a ';'.");
+        succeeded = false;
+    }
+    succeeded
+}
+
+/// Read queries from the terminal until the user quits
+fn run_interactive(client: &Client, address: &str) {
+    let history = std::env::var("HOME").ok().map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    let mut editor = Editor::<()>::new();
+    if let Some(history) = &history {
+        // There is no history the first time
+        let _ = editor.load_history(history);
+    }
+    println!("Connected to {}. End queries with ';', type 'exit' or press Ctrl-D to quit.", address);
+
+    let mut query = String::new();
+    loop {
+        let prompt = match query.is_empty() {
+            true => PROMPT,
+            false => CONTINUATION_PROMPT,
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                query.clear();
+                continue;
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                eprintln!("Could not read input: {}", error);
+                break;
+            },
+        };
+        if query.is_empty() && matches!(line.trim(), "exit" | "quit") {
+            break;
+        }
+        if query.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        query.push_str(&line);
+        query.push('\n');
+        if is_complete(&query) {
+            editor.add_history_entry(query.trim_end());
+            run_query(client, &query);
+            query.clear();
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            eprintln!("Could not save history: {}", error);
+        }
+    }
+}
+
+fn main() {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        },
+    };
+    // Show the result of every statement, not just the last one
+    let mut client = Client::new(&options.address).with_header(ResponseMode::HEADER, "all");
+    client = match (&options.user, &options.password) {
+        (Some(user), Some(password)) => client.with_password(user, password),
+        (Some(user), None) => client.with_username(user),
+        _ => client,
+    };
+    if let Some(api_key) = &options.api_key {
+        client = client.with_api_key(api_key);
+    }
+    if let Some(token) = &options.capability {
+        client = client.with_capability(token);
+    }
+
+    match &options.script {
+        Some(script) => if !run_script(&client, script) {
+            process::exit(1);
+        },
+        None => run_interactive(&client, &options.address),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        assert!(is_complete("get a;\n"));
+        assert!(is_complete("set a\n  \"x\";  "));
+        assert!(!is_complete("set a \"x;\"\n"));
+        assert!(!is_complete("set a \"\\\";\n"));
+        assert!(is_complete("set a \"\\\";\";"));
+        assert!(!is_complete("get a"));
+    }
+}
@@ -0,0 +1,100 @@
+use server::analysis::{InterpreterResponse, ValueType};
+use server::auth::AuthorizationLevel;
+use server::error::ServerError;
+use server::storage::{StorageKey, StorageValue};
+
+
+/// Create an error for a response that doesn't fit the request
+fn make_unexpected_error(response: InterpreterResponse) -> ServerError {
+    ServerError::InternalError(format!("Unexpected response {:?}.", response))
+}
+
+
+/// Accept any response to a request that only reports success
+pub fn unit(_response: InterpreterResponse) -> Result<(), ServerError> {
+    Ok(())
+}
+
+
+/// Get the value out of a response
+pub fn value(response: InterpreterResponse) -> Result<StorageValue, ServerError> {
+    match response {
+        InterpreterResponse::Value(value) => Ok(value),
+        InterpreterResponse::Null => Ok(StorageValue::Null),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get the answer out of a yes or no response
+pub fn flag(response: InterpreterResponse) -> Result<bool, ServerError> {
+    match response {
+        InterpreterResponse::Bool(flag) => Ok(flag),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get the size out of a response
+pub fn size(response: InterpreterResponse) -> Result<usize, ServerError> {
+    match response {
+        InterpreterResponse::Size(size) => Ok(size),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get the seconds left to live out of a response, None if the key never expires
+pub fn expiration(response: InterpreterResponse) -> Result<Option<u64>, ServerError> {
+    match response {
+        InterpreterResponse::Expiration(expiration) => Ok(expiration),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get the keys out of a response
+pub fn keys(response: InterpreterResponse) -> Result<Vec<StorageKey>, ServerError> {
+    match response {
+        InterpreterResponse::Keys(keys) => Ok(keys),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get the type out of a response
+pub fn value_type(response: InterpreterResponse) -> Result<ValueType, ServerError> {
+    match response {
+        InterpreterResponse::ValueType(value_type) => Ok(value_type),
+        response => Err(make_unexpected_error(response)),
+    }
+}
+
+
+/// Get each user and their level out of a response, sorted by username
+pub fn users(response: InterpreterResponse) -> Result<Vec<(String, AuthorizationLevel)>, ServerError> {
+    let map = match response {
+        InterpreterResponse::Value(StorageValue::Map(map)) => map,
+        response => return Err(make_unexpected_error(response)),
+    };
+    let mut users = vec![];
+    for (username, level) in map.iter() {
+        match (username, level) {
+            (StorageValue::String(username), StorageValue::String(level)) => {
+                users.push((username.clone(), AuthorizationLevel::from_name(level)?));
+            },
+            _ => return Err(ServerError::InternalError("Unexpected entry in the list of users.".to_string())),
+        }
+    }
+    users.sort_by(|left, right| left.0.cmp(&right.0));
+    Ok(users)
+}
+
+
+/// Get a string value out of a response
+pub fn string(response: InterpreterResponse) -> Result<String, ServerError> {
+    match response {
+        InterpreterResponse::Value(StorageValue::String(value)) => Ok(value),
+        response => Err(make_unexpected_error(response)),
+    }
+}
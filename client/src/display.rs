@@ -0,0 +1,197 @@
+use server::analysis::{InterpreterResponse, ValueType};
+use server::storage::{CollectionType, KeyType, StorageMap, StorageValue, StorageVector};
+
+
+/// Write a response the way a person would want to read it
+pub fn format_response(response: &InterpreterResponse) -> String {
+    match response {
+        InterpreterResponse::Value(value) => format_value(value),
+        InterpreterResponse::Message(message) => message.clone(),
+        InterpreterResponse::Size(size) => size.to_string(),
+        InterpreterResponse::Expiration(None) => "never expires".to_string(),
+        InterpreterResponse::Expiration(Some(seconds)) => format!("expires in {}", format_duration(*seconds)),
+        InterpreterResponse::Key(key) => format!("{:?}", key),
+        InterpreterResponse::Keys(keys) => format_keys(keys),
+        InterpreterResponse::Scan(cursor, keys) => format!("cursor {}\n{}", cursor, format_keys(keys)),
+        InterpreterResponse::Version(version) => format!("version {}", version),
+        InterpreterResponse::Results(results) => {
+            let lines: Vec<String> = results.iter().map(|result| match result {
+                Ok(response) => format_response(response),
+                Err(error) => format!("(error) {}", error),
+            }).collect();
+            format_list(&lines, "(no results)")
+        },
+        InterpreterResponse::Bool(flag) => flag.to_string(),
+        InterpreterResponse::ValueType(value_type) => format_value_type(value_type),
+        InterpreterResponse::ShuttingDown => "shutting down".to_string(),
+        InterpreterResponse::Null => "OK".to_string(),
+    }
+}
+
+
+/// Write a value, with vectors as numbered lists and maps as tables
+pub fn format_value(value: &StorageValue) -> String {
+    match value {
+        StorageValue::Vector(vector) => format_vector(vector),
+        StorageValue::Map(map) => format_map(map),
+        scalar => format_scalar(scalar),
+    }
+}
+
+
+/// Write a value that fits on one line
+fn format_scalar(value: &StorageValue) -> String {
+    match value {
+        StorageValue::Null => "(null)".to_string(),
+        StorageValue::Bool(flag) => flag.to_string(),
+        StorageValue::String(string) => format!("{:?}", string),
+        StorageValue::Int(int) => int.to_string(),
+        StorageValue::Float(float) => float.to_string(),
+        StorageValue::Vector(vector) => format!("(vector of {})", vector.len()),
+        StorageValue::Map(map) => format!("(map of {})", map.len()),
+    }
+}
+
+
+/// Write a vector as a numbered list
+fn format_vector(vector: &StorageVector) -> String {
+    let lines: Vec<String> = vector.iter().map(format_scalar).collect();
+    format_list(&lines, "(empty vector)")
+}
+
+
+/// Write a map as a table of fields and values, sorted by field
+fn format_map(map: &StorageMap) -> String {
+    if map.len() == 0 {
+        return "(empty map)".to_string();
+    }
+    let mut rows: Vec<(String, String)> = map.iter()
+        .map(|(field, value)| (format_scalar(field), format_scalar(value)))
+        .collect();
+    rows.sort();
+    let field_width = rows.iter().map(|(field, _)| field.chars().count()).max().unwrap_or(0).max("field".len());
+    let value_width = rows.iter().map(|(_, value)| value.chars().count()).max().unwrap_or(0).max("value".len());
+
+    let mut table = vec![
+        format!("{:<field_width$} | {:<value_width$}", "field", "value"),
+        format!("{}-+-{}", "-".repeat(field_width), "-".repeat(value_width)),
+    ];
+    for (field, value) in rows {
+        table.push(format!("{:<field_width$} | {:<value_width$}", field, value));
+    }
+    table.iter().map(|row| row.trim_end()).collect::<Vec<_>>().join("\n")
+}
+
+
+/// Write keys as a numbered list
+fn format_keys(keys: &[String]) -> String {
+    let lines: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+    format_list(&lines, "(no keys)")
+}
+
+
+/// Number each entry, lining up entries that span several lines under the first
+fn format_list(entries: &[String], empty: &str) -> String {
+    if entries.is_empty() {
+        return empty.to_string();
+    }
+    let number_width = entries.len().to_string().len();
+    let mut lines = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let mut entry_lines = entry.lines();
+        let first = entry_lines.next().unwrap_or("");
+        lines.push(format!("{:>number_width$}) {}", index + 1, first));
+        for line in entry_lines {
+            lines.push(format!("{:number_width$}  {}", "", line));
+        }
+    }
+    lines.join("\n")
+}
+
+
+/// Write a number of seconds like `1d 2h 3m 4s`, leaving out the parts that are zero
+pub fn format_duration(seconds: u64) -> String {
+    if seconds == 0 {
+        return "0s".to_string();
+    }
+    let parts = [
+        (seconds / 86400, "d"),
+        (seconds % 86400 / 3600, "h"),
+        (seconds % 3600 / 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    parts.iter()
+        .filter(|(amount, _)| *amount > 0)
+        .map(|(amount, unit)| format!("{}{}", amount, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+
+/// Write the type of a value
+fn format_value_type(value_type: &ValueType) -> String {
+    match value_type {
+        ValueType::Null => "null".to_string(),
+        ValueType::Bool => "bool".to_string(),
+        ValueType::Int => "int".to_string(),
+        ValueType::Float => "float".to_string(),
+        ValueType::String => "string".to_string(),
+        ValueType::Vector(collection_type) => format!("vector of {}", format_collection_type(collection_type)),
+        ValueType::Map(key_type, collection_type) => {
+            let key_type = match key_type {
+                KeyType::String => "string",
+                KeyType::Int => "int",
+            };
+            format!("map of {} to {}", key_type, format_collection_type(collection_type))
+        },
+    }
+}
+
+
+/// Write the type of the values in a collection
+fn format_collection_type(collection_type: &CollectionType) -> &'static str {
+    match collection_type {
+        CollectionType::Bool => "bool",
+        CollectionType::String => "string",
+        CollectionType::Int => "int",
+        CollectionType::Float => "float",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::error::ServerError;
+
+    #[test]
+    fn test_format_collections() {
+        let mut map = StorageMap::new(KeyType::String, CollectionType::Int);
+        map.set(StorageValue::from("beta"), StorageValue::Int(20)).unwrap();
+        map.set(StorageValue::from("a"), StorageValue::Int(1)).unwrap();
+        assert_eq!(
+            format_value(&StorageValue::Map(map)),
+            "field  | value\n-------+------\n\"a\"    | 1\n\"beta\" | 20"
+        );
+
+        let mut vector = StorageVector::new(CollectionType::String);
+        vector.push(StorageValue::from("x")).unwrap();
+        assert_eq!(format_value(&StorageValue::Vector(vector)), "1) \"x\"");
+        assert_eq!(format_value(&StorageValue::Vector(StorageVector::new(CollectionType::Int))), "(empty vector)");
+    }
+
+    #[test]
+    fn test_format_responses() {
+        assert_eq!(format_duration(3725), "1h 2m 5s");
+        assert_eq!(format_duration(86400), "1d");
+        assert_eq!(format_response(&InterpreterResponse::Expiration(Some(90))), "expires in 1m 30s");
+        assert_eq!(format_response(&InterpreterResponse::Expiration(None)), "never expires");
+
+        let response = InterpreterResponse::Results(vec![
+            Ok(InterpreterResponse::Null),
+            Ok(InterpreterResponse::Keys(vec!["a".to_string(), "b".to_string()])),
+            Err(ServerError::KeyError("No key c".to_string())),
+        ]);
+        assert_eq!(format_response(&response), "1) OK\n2) 1) \"a\"\n   2) \"b\"\n3) (error) KeyError: No key c");
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::{self, ErrorKind};
+
+use httparse::{self, Response, Status};
+use serde_json::json;
+
+use server::analysis::{InterpreterResponse, Statement};
+use server::error::ServerError;
+
+
+/// How much to read from a connection at a time
+pub const MAX_BUFFER_SIZE: usize = 1024;
+const MAX_NUMBER_OF_HEADERS: usize = 16;
+
+
+/// A response read off a connection
+pub struct HttpResponse {
+    /// The status code
+    pub status: u16,
+    /// The JSON body, or the error message
+    pub body: String,
+    /// Whether the server will keep the connection open for another request
+    pub keep_alive: bool,
+}
+
+
+/// The body of a request running some statements
+pub fn statements_body(statements: &[Statement]) -> String {
+    json!({"statements": statements}).to_string()
+}
+
+
+/// The body of a request running a query in the query language
+pub fn query_body(query: &str) -> String {
+    json!({"query": query}).to_string()
+}
+
+
+/// The `Authorization` header value for a username and password
+pub fn basic_authorization(username: &str, password: &str) -> String {
+    format!("Basic {}", base64::encode(format!("{}:{}", username, password)))
+}
+
+
+/// The `Authorization` header value for an API key
+pub fn bearer_authorization(api_key: &str) -> String {
+    format!("Bearer {}", api_key)
+}
+
+
+/// Write a request with a body, along with any extra headers like the username
+pub fn encode_request(host: &str, headers: &[(String, String)], body: &str) -> Vec<u8> {
+    let mut request = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        host, body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    request.into_bytes()
+}
+
+
+/// Check if a read failed because the server closed the connection, rather than timing out.
+///
+/// A server closing an idle connection it hasn't read from resets it instead of shutting it down.
+pub fn closed_by_server(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+    )
+}
+
+
+/// Parse the response at the start of the buffer. Gives None until the whole response has arrived.
+pub fn parse_response(buffer: &[u8]) -> Result<Option<HttpResponse>, ServerError> {
+    let mut headers_list = [httparse::EMPTY_HEADER; MAX_NUMBER_OF_HEADERS];
+    let mut response = Response::new(&mut headers_list);
+    let body_start = match response.parse(buffer) {
+        Ok(Status::Complete(size)) => size,
+        Ok(Status::Partial) => return Ok(None),
+        Err(_) => return Err(ServerError::NetworkError("Malformed response.".to_string())),
+    };
+    let mut body_length = 0;
+    let mut keep_alive = response.version == Some(1);
+    for header in response.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("Content-Length") {
+            body_length = match value.trim().parse() {
+                Ok(length) => length,
+                Err(_) => return Err(ServerError::NetworkError("Malformed response.".to_string())),
+            };
+        } else if header.name.eq_ignore_ascii_case("Connection") {
+            keep_alive = value.trim().eq_ignore_ascii_case("keep-alive");
+        }
+    }
+    if buffer.len() < body_start + body_length {
+        return Ok(None);
+    }
+    Ok(Some(HttpResponse {
+        status: response.code.unwrap_or(500),
+        body: String::from_utf8_lossy(&buffer[body_start..body_start + body_length]).to_string(),
+        keep_alive,
+    }))
+}
+
+
+/// Turn the error message the server sent, like `KeyError: No key found`, back into the error
+fn decode_error(body: &str) -> ServerError {
+    let (kind, message) = match body.split_once(": ") {
+        Some((kind, message)) => (kind, message.to_string()),
+        None => return ServerError::InternalError(body.to_string()),
+    };
+    match kind {
+        "KeyError" => ServerError::KeyError(message),
+        "NetworkError" => ServerError::NetworkError(message),
+        "WriteError" => ServerError::WriteError(message),
+        "TokenizationError" => ServerError::TokenizationError(message),
+        "ParseError" => ServerError::ParseError(message),
+        "IndexError" => ServerError::IndexError(message),
+        "TypeError" => ServerError::TypeError(message),
+        "AuthorizationError" => ServerError::AuthorizationError(message),
+        "AuthenticationError" => ServerError::AuthenticationError(message),
+        "RequestError" => ServerError::RequestError(message),
+        "InternalError" => ServerError::InternalError(message),
+        "ConflictError" => ServerError::ConflictError(message),
+        "ConfigError" => ServerError::ConfigError(message),
+        "TooLargeError" => ServerError::TooLargeError(message),
+        "TimeoutError" => ServerError::TimeoutError(message),
+        "UnavailableError" => ServerError::UnavailableError(message),
+        _ => ServerError::InternalError(body.to_string()),
+    }
+}
+
+
+/// Decode the body of a response into the server's response or error
+pub fn decode_response(response: &HttpResponse) -> Result<InterpreterResponse, ServerError> {
+    if response.status != 200 {
+        return Err(decode_error(&response.body));
+    }
+    let decoded = match response.body.trim_start().starts_with('[') {
+        true => serde_json::from_str(&response.body).map(InterpreterResponse::Results),
+        false => serde_json::from_str(&response.body),
+    };
+    match decoded {
+        Ok(decoded) => Ok(decoded),
+        Err(error) => Err(ServerError::NetworkError(format!("Could not decode response: {}", error))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::storage::StorageValue;
+
+    #[test]
+    fn test_decode_responses() {
+        let buffer = b"HTTP/1.1 200 Ok\r\nConnection: close\r\nContent-Length: 19\r\n\r\n{\"Value\":{\"Int\":5}}";
+        assert!(parse_response(&buffer[..40]).unwrap().is_none());
+        let response = parse_response(buffer).unwrap().unwrap();
+        assert!(!response.keep_alive);
+        assert!(matches!(decode_response(&response), Ok(InterpreterResponse::Value(StorageValue::Int(5)))));
+
+        let buffer = b"HTTP/1.1 422 Unprocessible Entity\r\nContent-Length: 21\r\n\r\nKeyError: No key a: b";
+        let response = parse_response(buffer).unwrap().unwrap();
+        assert!(response.keep_alive);
+        assert!(matches!(decode_response(&response), Err(ServerError::KeyError(message)) if message == "No key a: b"));
+    }
+}
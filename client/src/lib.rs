@@ -0,0 +1,33 @@
+//! # Client
+//!
+//! Typed clients for talking to the key-value database server over HTTP.
+//!
+//! Requests are sent as serialized statements, so keys and strings keep their case, and
+//! responses are decoded straight into the server's own types. Connections are kept open and
+//! reused between requests.
+//!
+//! ```no_run
+//! use client::Client;
+//!
+//! let client = Client::new("127.0.0.1:7878").with_username("write");
+//! client.set_with_ttl("session", "abc", 60).unwrap();
+//! let value = client.get("session").unwrap();
+//! ```
+#![warn(missing_docs)]
+
+/// Writing requests and reading responses
+mod http;
+/// Turning responses into the types the typed methods return
+mod decode;
+/// Client that blocks the calling thread
+pub mod blocking;
+/// Client for use with tokio
+pub mod asynchronous;
+/// Showing responses in a form people can read
+pub mod display;
+
+pub use asynchronous::AsyncClient;
+pub use blocking::Client;
+pub use server::analysis::{InterpreterResponse, Statement};
+pub use server::error::ServerError;
+pub use server::storage::StorageValue;
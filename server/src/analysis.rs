@@ -8,6 +8,8 @@ pub mod tokenizer;
 pub mod statements;
 /// Executes statements
 pub mod interpreter;
+/// Matching keys against glob patterns
+pub mod pattern;
 
 pub use tokenizer::Tokenizer;
 pub use tokens::{AnnotatedToken, Token};
@@ -1,10 +1,18 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
 use crate::analysis::Statement;
-use crate::auth::AuthorizationLevel;
+use crate::analysis::pattern::glob_match;
+use crate::auth::{AccessRules, AuthorizationLevel, CapabilitySigner, SharedUsers};
 use crate::error::ServerError;
+use crate::persistence::command_log::{self, CommandLog};
+use crate::persistence::snapshot;
 use crate::storage::{
     CollectionType,
     KeyType,
@@ -16,8 +24,12 @@ use crate::storage::{
     StorageVector,
 };
 
+/// How many keys a scan visits when no count is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
 /// Defines the different privilege levels that can be attached to a request.
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Privileges {
     /// Admins can do anything
     Admin,
@@ -29,6 +41,28 @@ pub enum Privileges {
     Unauthorized,
 }
 
+impl Privileges {
+    /// Get the authorization level these privileges give, if any
+    pub fn level(&self) -> Option<AuthorizationLevel> {
+        match self {
+            Privileges::Admin => Some(AuthorizationLevel::Admin),
+            Privileges::Write => Some(AuthorizationLevel::Write),
+            Privileges::Read => Some(AuthorizationLevel::Read),
+            Privileges::Unauthorized => None,
+        }
+    }
+}
+
+impl From<AuthorizationLevel> for Privileges {
+    fn from(level: AuthorizationLevel) -> Privileges {
+        match level {
+            AuthorizationLevel::Admin => Privileges::Admin,
+            AuthorizationLevel::Write => Privileges::Write,
+            AuthorizationLevel::Read => Privileges::Read,
+        }
+    }
+}
+
 /// A request to the interpreter
 #[derive(Clone, Debug)]
 pub struct InterpreterRequest {
@@ -36,6 +70,34 @@ pub struct InterpreterRequest {
     pub statements: Vec<Statement>,
     /// Privileges available to this request
     pub authorization: AuthorizationLevel,
+    /// Rules limiting the keys and commands this request can use, if any
+    pub access_rules: Option<Arc<AccessRules>>,
+    /// Which responses to send back
+    pub response_mode: ResponseMode,
+}
+
+/// Which responses a request with several statements gets back.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResponseMode {
+    /// Only the response to the last statement
+    #[default]
+    Last,
+    /// The result of every statement, in order
+    All,
+}
+
+impl ResponseMode {
+    /// The header clients use to pick a response mode
+    pub const HEADER: &'static str = "Response-Mode";
+
+    /// Get the response mode asked for in the request headers, defaulting to the last response.
+    pub fn from_headers(headers: &HashMap<String, String>) -> Result<ResponseMode, ServerError> {
+        match headers.get(ResponseMode::HEADER).map(|mode| mode.to_lowercase()).as_deref() {
+            None | Some("last") => Ok(ResponseMode::Last),
+            Some("all") => Ok(ResponseMode::All),
+            Some(mode) => Err(ServerError::RequestError(format!("Unknown response mode {}.", mode))),
+        }
+    }
 }
 
 /// Output type for asking for values.
@@ -70,6 +132,14 @@ pub enum InterpreterResponse {
     Expiration(Option<u64>),
     /// Get a key
     Key(StorageKey),
+    /// Get a list of keys
+    Keys(Vec<StorageKey>),
+    /// A page of keys from a scan and the cursor to continue from
+    Scan(u64, Vec<StorageKey>),
+    /// The version of a key
+    Version(u64),
+    /// The result of each statement in a request
+    Results(Vec<Result<InterpreterResponse, ServerError>>),
     /// Get a boolean value
     Bool(bool),
     /// Value types
@@ -80,53 +150,377 @@ pub enum InterpreterResponse {
     Null,
 }
 
+impl InterpreterResponse {
+    /// Check if the server was told to shut down.
+    pub fn is_shutting_down(&self) -> bool {
+        match self {
+            InterpreterResponse::ShuttingDown => true,
+            InterpreterResponse::Results(results) => {
+                matches!(results.last(), Some(Ok(InterpreterResponse::ShuttingDown)))
+            },
+            _ => false,
+        }
+    }
+}
+
+
+/// Gathers the results of the statements in a request into a single response.
+///
+/// Outside of a transaction, in `All` mode, a failed statement doesn't stop the rest of the
+/// request. Inside one, the first error fails the whole request so it can be rolled back.
+pub struct ResponseCollector {
+    /// Which responses to keep
+    mode: ResponseMode,
+    /// The result of each statement run so far
+    results: Vec<Result<InterpreterResponse, ServerError>>,
+    /// Whether the statements are inside a transaction
+    in_transaction: bool,
+    /// Set once an error has stopped the request
+    failed: bool,
+}
+
+impl ResponseCollector {
+    /// Create a collector for the response mode
+    pub fn new(mode: ResponseMode) -> ResponseCollector {
+        ResponseCollector { mode, results: vec![], in_transaction: false, failed: false }
+    }
+
+    /// Record the result of a statement and check if the rest of the request should run.
+    pub fn add(&mut self, statement: &Statement, result: Result<InterpreterResponse, ServerError>) -> bool {
+        match (statement, &result) {
+            (Statement::Begin, Ok(_)) => self.in_transaction = true,
+            (Statement::Commit, Ok(_)) => self.in_transaction = false,
+            _ => (),
+        }
+        let keep_going = match &result {
+            Ok(response) => !response.is_shutting_down(),
+            Err(_) => self.mode == ResponseMode::All && !self.in_transaction,
+        };
+        self.failed = result.is_err() && !keep_going;
+        self.results.push(result);
+        keep_going
+    }
+
+    /// Get the response to the whole request
+    pub fn finish(mut self) -> Result<InterpreterResponse, ServerError> {
+        if self.failed || self.mode == ResponseMode::Last {
+            return self.results.pop().unwrap_or(Ok(InterpreterResponse::Null));
+        }
+        Ok(InterpreterResponse::Results(self.results))
+    }
+}
+
+
 /// An interpreter backed by some storage 
 pub struct Interpreter<S: Storage + Send> {
     /// The underlying storage to communicate with
     pub storage: S,
+    /// Log of every statement that changed the storage, if persistence is on
+    command_log: Option<CommandLog>,
+    /// Where snapshots are saved, if anywhere
+    snapshot_path: Option<PathBuf>,
+    /// Set while a snapshot is being written in the background
+    background_save: Arc<AtomicBool>,
+    /// The transaction in progress, if any
+    transaction: Option<Transaction>,
+    /// The last version given to a changed key
+    version_clock: u64,
+    /// The users that user management statements change, if they can be changed
+    users: Option<SharedUsers>,
+    /// Signs capability tokens, if they can be issued
+    capability_signer: Option<CapabilitySigner>,
+}
+
+
+/// Changes made inside a `begin ... commit` block, kept so they can be undone.
+#[derive(Default)]
+struct Transaction {
+    /// The value of each key before the transaction first changed it
+    undo_log: Vec<(StorageKey, Option<StorageElement>)>,
+    /// The keys already saved in the undo log
+    saved_keys: HashSet<StorageKey>,
+    /// Statements to write to the command log once the transaction commits, with the version
+    /// clock once each had run
+    pending_log: Vec<(Statement, u64)>,
+    /// The version clock when the transaction began
+    start_version: u64,
 }
 
 impl<S: Storage + Send> Interpreter<S> {
     /// Create a new interpreter for the storage
     pub fn new(storage: S) -> Interpreter<S> {
-        Interpreter{storage}
+        Interpreter{
+            storage,
+            command_log: None,
+            snapshot_path: None,
+            background_save: Arc::new(AtomicBool::new(false)),
+            transaction: None,
+            version_clock: 0,
+            users: None,
+            capability_signer: None,
+        }
+    }
+
+    /// Allow snapshots to be saved to the given location.
+    pub fn set_snapshot_path(&mut self, path: PathBuf) {
+        self.snapshot_path = Some(path);
+    }
+
+    /// Let user management statements change these users.
+    pub fn set_users(&mut self, users: SharedUsers) {
+        self.users = Some(users);
+    }
+
+    /// Let capability tokens be issued, signed by this signer.
+    pub fn set_capability_signer(&mut self, signer: CapabilitySigner) {
+        self.capability_signer = Some(signer);
+    }
+
+    /// Record every statement that changes the storage from now on.
+    pub fn set_command_log(&mut self, command_log: CommandLog) {
+        self.command_log = Some(command_log);
+    }
+
+    /// Never hand out a version at or below this one, e.g. because it was used before a restart.
+    pub fn advance_version_clock(&mut self, version: u64) {
+        self.version_clock = self.version_clock.max(version);
+    }
+
+    /// Rebuild the storage by running every statement saved in a command log after the offset.
+    /// 
+    /// Replayed keys get back the versions they had before, and the version clock carries on
+    /// from the highest version the log records. This should be run before a command log is
+    /// attached, otherwise the replayed statements would be written out a second time.
+    pub fn replay_command_log(&mut self, path: &Path, offset: u64) -> Result<usize, ServerError> {
+        let replay = command_log::read_statements(path, offset)?;
+        let count = replay.statements.len();
+        let stored_version = self.storage.elements()?
+            .iter()
+            .map(|element| element.version)
+            .max()
+            .unwrap_or(0);
+        self.advance_version_clock(stored_version);
+        for logged in replay.statements {
+            // Logs from before versions were saved have 0, so those keys just get the next one
+            self.advance_version_clock(logged.version.saturating_sub(1));
+            if let Err(err) = self.process_statement(logged.statement, None) {
+                println!("Error replaying command log: {:?}", err);
+            }
+            self.advance_version_clock(logged.version);
+        }
+        self.advance_version_clock(replay.version_clock);
+        Ok(count)
     }
 
     /// Interpret a request
     pub fn interpret(&mut self, request: InterpreterRequest) -> Result<InterpreterResponse, ServerError> {
-        let InterpreterRequest{statements, authorization} = request;
-        self.process_statements(statements, authorization)
+        let InterpreterRequest{statements, authorization, access_rules, response_mode} = request;
+        self.process_statements(statements, authorization, access_rules.as_deref(), response_mode)
+    }
+
+    /// Start a transaction, so later changes can be rolled back until it is committed.
+    pub fn begin_transaction(&mut self) -> Result<InterpreterResponse, ServerError> {
+        if self.transaction.is_some() {
+            return Err(ServerError::RequestError("Transactions can't be nested.".to_string()));
+        }
+        self.transaction = Some(Transaction { start_version: self.version_clock, ..Transaction::default() });
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Keep every change made in the current transaction.
+    pub fn commit_transaction(&mut self) -> Result<InterpreterResponse, ServerError> {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return Err(ServerError::RequestError("No transaction to commit.".to_string())),
+        };
+        if let Some(log) = &mut self.command_log {
+            for (statement, version) in transaction.pending_log {
+                log.append(&statement, version)?;
+            }
+        }
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Undo every change made in the current transaction, if there is one.
+    ///
+    /// The versions the transaction used are still recorded, so they aren't handed out again
+    /// after a restart.
+    pub fn rollback_transaction(&mut self) -> Result<(), ServerError> {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return Ok(()),
+        };
+        for (key, previous) in transaction.undo_log.into_iter().rev() {
+            match previous {
+                Some(element) => self.storage.set(&key, element)?,
+                None => {
+                    self.storage.delete(&key)?;
+                },
+            }
+        }
+        if let Some(log) = &mut self.command_log {
+            if self.version_clock > transaction.start_version {
+                log.append_version(self.version_clock)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the current value of the key a statement is about to change, if in a transaction.
+    fn save_previous_value(&mut self, statement: &Statement) -> Result<(), ServerError> {
+        let transaction = match &mut self.transaction {
+            Some(transaction) if statement.is_write() => transaction,
+            _ => return Ok(()),
+        };
+        if let Some(key) = statement.key() {
+            if transaction.saved_keys.insert(key.clone()) {
+                let previous = self.storage.get_if_exists(key)?;
+                transaction.undo_log.push((key.clone(), previous));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a statement to the command log, or hold on to it until the transaction commits.
+    fn log_statement(&mut self, statement: Statement) -> Result<(), ServerError> {
+        if let Some(transaction) = &mut self.transaction {
+            transaction.pending_log.push((statement, self.version_clock));
+        } else if let Some(log) = &mut self.command_log {
+            log.append(&statement, self.version_clock)?;
+        }
+        Ok(())
+    }
+
+    /// Write a delete to the command log for each key the storage evicted to make room, so
+    /// evicted keys don't come back when the log is replayed.
+    ///
+    /// An eviction stands whether or not the transaction it happened in commits, so it is written
+    /// straight away and the transaction forgets the key: a rollback won't bring it back, and
+    /// earlier changes to it aren't written out at commit.
+    fn log_evictions(&mut self, keys: Vec<StorageKey>) -> Result<(), ServerError> {
+        for key in keys {
+            if let Some(transaction) = &mut self.transaction {
+                transaction.undo_log.retain(|(saved_key, _)| *saved_key != key);
+                transaction.saved_keys.remove(&key);
+                transaction.pending_log.retain(|(statement, _)| statement.key() != Some(&key));
+            }
+            if let Some(log) = &mut self.command_log {
+                log.append(&Statement::Delete(key), self.version_clock)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Interpret a request made up only of statements that read from the storage.
+    /// 
+    /// Since the storage isn't changed, several of these can run at the same time.
+    pub fn interpret_read(&self, request: InterpreterRequest) -> Result<InterpreterResponse, ServerError> {
+        let InterpreterRequest{statements, authorization, access_rules, response_mode} = request;
+        validate_authorization(&statements, authorization, access_rules.as_deref())?;
+        let mut responses = ResponseCollector::new(response_mode);
+        for statement in statements {
+            let response = self.process_read_statement(statement.clone(), access_rules.as_deref());
+            if !responses.add(&statement, response) {
+                break;
+            }
+        }
+        responses.finish()
     }
 
     /// Validate the statements in a request and run them.
     fn process_statements(
-        &mut self, statements: Vec<Statement>, authorization: AuthorizationLevel
+        &mut self,
+        statements: Vec<Statement>,
+        authorization: AuthorizationLevel,
+        access_rules: Option<&AccessRules>,
+        response_mode: ResponseMode,
     ) -> Result<InterpreterResponse, ServerError> {
-        validate_authorization(&statements, authorization)?;
-        let mut final_response: Result<InterpreterResponse, ServerError> = Ok(InterpreterResponse::Null);
+        validate_authorization(&statements, authorization, access_rules)?;
+        validate_transactions(&statements)?;
+        // A shard set keeps a transaction open between requests, so only one begun here has to end
+        let in_outer_transaction = self.transaction.is_some();
+        let mut responses = ResponseCollector::new(response_mode);
         for statement in statements {
-            final_response = self.process_statement(statement);
-            if let Ok(InterpreterResponse::ShuttingDown) = final_response {
+            let response = self.process_logged_statement(&statement, access_rules);
+            if !responses.add(&statement, response) {
                 break;
             }
-            if let Err(_) = final_response {
-                break;
+        }
+        let final_response = responses.finish();
+        if final_response.is_err() {
+            self.rollback_transaction()?;
+        }
+        debug_assert!(
+            in_outer_transaction || self.transaction.is_none(), "Request finished inside its own transaction."
+        );
+        if let Some(log) = &mut self.command_log {
+            match &final_response {
+                Ok(response) if response.is_shutting_down() => log.sync()?,
+                _ => log.sync_if_due()?,
             }
         }
         final_response
     }
 
-    /// Process a single statement.
+    /// Process a single statement from a request, writing it to the command log if it succeeds.
+    fn process_logged_statement(
+        &mut self, statement: &Statement, access_rules: Option<&AccessRules>
+    ) -> Result<InterpreterResponse, ServerError> {
+        let logged_statement = match (&self.command_log, statement.is_write()) {
+            (Some(_), true) => Some(statement.for_command_log()),
+            _ => None,
+        };
+        if may_use_memory(statement) {
+            let evicted = self.storage.enforce_memory_limit()?;
+            self.log_evictions(evicted)?;
+        }
+        self.save_previous_value(statement)?;
+        let response = self.process_statement(statement.clone(), access_rules)?;
+        if let Some(statement) = logged_statement {
+            self.log_statement(statement)?;
+        }
+        Ok(response)
+    }
+
+    /// Process a single statement, giving a new version to the key it changes.
     fn process_statement(
-        &mut self, statement: Statement
+        &mut self, statement: Statement, access_rules: Option<&AccessRules>
+    ) -> Result<InterpreterResponse, ServerError> {
+        let written_key = match statement.is_write() {
+            true => statement.key().cloned(),
+            false => None,
+        };
+        let response = self.execute_statement(statement, access_rules)?;
+        match (written_key, response) {
+            // Nothing was changed, so the version stays the same
+            (_, InterpreterResponse::Bool(false)) => Ok(InterpreterResponse::Bool(false)),
+            (Some(key), response) => self.bump_version(&key, response),
+            (None, response) => Ok(response),
+        }
+    }
+
+    /// Give a key that was just changed the next version, unless it no longer exists.
+    fn bump_version(
+        &mut self, key: &StorageKey, response: InterpreterResponse
+    ) -> Result<InterpreterResponse, ServerError> {
+        let element = match self.storage.get_mut(key) {
+            Ok(element) => element,
+            Err(_) => return Ok(response),
+        };
+        self.version_clock += 1;
+        element.version = self.version_clock;
+        match response {
+            InterpreterResponse::Version(_) => Ok(InterpreterResponse::Version(self.version_clock)),
+            response => Ok(response),
+        }
+    }
+
+    /// Run a single statement.
+    fn execute_statement(
+        &mut self, statement: Statement, access_rules: Option<&AccessRules>
     ) -> Result<InterpreterResponse, ServerError> {
         match statement {
             Statement::Shutdown => return Ok(InterpreterResponse::ShuttingDown),
-            Statement::Null => return Ok(InterpreterResponse::Null),
-            Statement::Get(key) => return self.get(&key),
-            Statement::Exists(key) => return self.exists(&key),
-            Statement::GetIfExists(key) => return self.get_if_exists(&key),
-            Statement::GetLifetime(key) => return self.get_lifetime(&key),
             Statement::ExpireKeys => return self.expire_keys(),
             Statement::Delete(key) => return self.delete(&key),
             Statement::Set(key, value, lifetime) => return self.set(&key, value, lifetime),
@@ -135,22 +529,158 @@ impl<S: Storage + Send> Interpreter<S> {
             },
             Statement::Update(key, value, lifetime) => return self.update(&key, value, lifetime),
             Statement::UpdateLifetime(key, lifetime) => return self.update_expiration(&key, lifetime),
-            Statement::VectorGet(key, index) => return self.vector_get(&key, index),
-            Statement::VectorLength(key) => return self.vector_length(&key),
             Statement::VectorAppend(key, value) => return self.vector_append(&key, value),
+            Statement::VectorPrepend(key, value) => return self.vector_prepend(&key, value),
             Statement::VectorPop(key) => return self.vector_pop(&key),
             Statement::VectorSet(key, index, value) => return self.vector_set(&key, index, value),
-            Statement::MapGet(key, element_key) => return self.map_get(&key, &element_key),
-            Statement::MapExists(key, element_key) => return self.map_exists(&key, &element_key),
-            Statement::MapLength(key) => return self.map_length(&key),
             Statement::MapDelete(key, element_key) => return self.map_delete(&key, &element_key),
             Statement::MapSet(key, element_key, value) => {
                 return self.map_set(&key, element_key, value)
             },
-            Statement::ValueType(key) => return self.value_type(&key),
+            Statement::CompareAndSet(key, version, value, lifetime) => {
+                return self.compare_and_set(&key, version, value, lifetime)
+            },
+            Statement::Save => return self.save(),
+            Statement::BackgroundSave => return self.background_save(),
+            Statement::Begin => return self.begin_transaction(),
+            Statement::Commit => return self.commit_transaction(),
+            Statement::UserAdd(username, level, password_hash) => {
+                self.user_add(&username, level, password_hash.as_deref())
+            },
+            Statement::UserDelete(username) => self.user_delete(&username),
+            Statement::UserPassword(username, password) => self.user_password(&username, &password),
+            statement => return self.process_read_statement(statement, access_rules),
         }
     }
 
+    /// Process a single statement that only reads from the storage.
+    fn process_read_statement(
+        &self, statement: Statement, access_rules: Option<&AccessRules>
+    ) -> Result<InterpreterResponse, ServerError> {
+        match statement {
+            Statement::Null => Ok(InterpreterResponse::Null),
+            Statement::Get(key) => self.get(&key),
+            Statement::Exists(key) => self.exists(&key),
+            Statement::GetIfExists(key) => self.get_if_exists(&key),
+            Statement::GetLifetime(key) => self.get_lifetime(&key),
+            Statement::VectorGet(key, index) => self.vector_get(&key, index),
+            Statement::VectorLength(key) => self.vector_length(&key),
+            Statement::MapGet(key, element_key) => self.map_get(&key, &element_key),
+            Statement::MapExists(key, element_key) => self.map_exists(&key, &element_key),
+            Statement::MapLength(key) => self.map_length(&key),
+            Statement::ValueType(key) => self.value_type(&key),
+            Statement::Range(start, end, limit) => self.range(&start, &end, limit),
+            Statement::Prefix(prefix, limit) => self.prefix(&prefix, limit),
+            Statement::Keys(pattern) => self.keys(&pattern),
+            Statement::Scan(cursor, pattern, count) => self.scan(cursor, pattern, count),
+            Statement::Watch(key, version) => self.watch(&key, version),
+            Statement::UserList => self.user_list(),
+            Statement::IssueCapability(privileges, lifetime, prefixes) => {
+                self.issue_capability(privileges, lifetime, prefixes, access_rules)
+            },
+            statement => Err(
+                ServerError::InternalError(format!("Statement {:?} is not read only.", statement))
+            ),
+        }
+    }
+
+    /// Get the snapshot location, as long as no background save is running.
+    fn get_snapshot_path(&self) -> Result<PathBuf, ServerError> {
+        let path = match &self.snapshot_path {
+            Some(path) => path.clone(),
+            None => return Err(ServerError::WriteError("Snapshots are not enabled.".to_string())),
+        };
+        if self.background_save.load(Ordering::SeqCst) {
+            return Err(ServerError::WriteError("A background save is already running.".to_string()));
+        }
+        Ok(path)
+    }
+
+    /// How far into the command log the storage currently reaches
+    fn command_log_offset(&self) -> u64 {
+        match &self.command_log {
+            Some(log) => log.offset(),
+            None => 0,
+        }
+    }
+
+    /// Save a snapshot of the storage and wait for it to finish
+    fn save(&mut self) -> Result<InterpreterResponse, ServerError> {
+        let path = self.get_snapshot_path()?;
+        let elements = self.storage.elements()?;
+        snapshot::write_snapshot(&path, elements, self.command_log_offset(), self.version_clock)?;
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Copy the storage and write the snapshot from another thread
+    fn background_save(&mut self) -> Result<InterpreterResponse, ServerError> {
+        let path = self.get_snapshot_path()?;
+        let elements = self.storage.elements()?;
+        let command_log_offset = self.command_log_offset();
+        let version_clock = self.version_clock;
+        let background_save = Arc::clone(&self.background_save);
+        background_save.store(true, Ordering::SeqCst);
+        thread::spawn(move || {
+            if let Err(err) = snapshot::write_snapshot(&path, elements, command_log_offset, version_clock) {
+                println!("Error in background save: {:?}", err);
+            }
+            background_save.store(false, Ordering::SeqCst);
+        });
+        Ok(InterpreterResponse::Message("Background save started".to_string()))
+    }
+
+    /// Get the users, if user management is turned on
+    fn get_users(&self) -> Result<&SharedUsers, ServerError> {
+        match &self.users {
+            Some(users) => Ok(users),
+            None => Err(ServerError::RequestError("User management needs the users authenticator.".to_string())),
+        }
+    }
+
+    /// Add a user, with the hash of their password, see `Statement::hash_password`
+    fn user_add(
+        &mut self, username: &str, level: AuthorizationLevel, password_hash: Option<&str>
+    ) -> Result<InterpreterResponse, ServerError> {
+        self.get_users()?.write().unwrap().add(username, level, password_hash)?;
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Remove a user
+    fn user_delete(&mut self, username: &str) -> Result<InterpreterResponse, ServerError> {
+        self.get_users()?.write().unwrap().delete(username)?;
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Change a user's password to one already hashed
+    fn user_password(&mut self, username: &str, password_hash: &str) -> Result<InterpreterResponse, ServerError> {
+        self.get_users()?.write().unwrap().set_password(username, password_hash)?;
+        Ok(InterpreterResponse::Message("Ok".to_string()))
+    }
+
+    /// Get a map from each username to their level
+    fn user_list(&self) -> Result<InterpreterResponse, ServerError> {
+        let mut map = StorageMap::new(KeyType::String, CollectionType::String);
+        for user in self.get_users()?.read().unwrap().list() {
+            map.set(StorageValue::from(user.username.as_str()), StorageValue::from(user.level.name()))?;
+        }
+        Ok(InterpreterResponse::Value(StorageValue::Map(map)))
+    }
+
+    /// Create a capability token, reaching no further than the rules of the user asking for it
+    fn issue_capability(
+        &self, privileges: Privileges, lifetime: u64, prefixes: Vec<String>, access_rules: Option<&AccessRules>
+    ) -> Result<InterpreterResponse, ServerError> {
+        let signer = match &self.capability_signer {
+            Some(signer) => signer,
+            None => return Err(ServerError::RequestError("Capability tokens need auth.capability_secret to be set.".to_string())),
+        };
+        if let Some(access_rules) = access_rules {
+            access_rules.check_capability(privileges, &prefixes)?;
+        }
+        let token = signer.issue(privileges, lifetime, prefixes)?;
+        Ok(InterpreterResponse::Value(StorageValue::String(token)))
+    }
+
     /// Get the value of an item
     fn get(&self, key: &StorageKey) -> Result<InterpreterResponse, ServerError> {
         let result = self.storage.get(key)?;
@@ -176,6 +706,78 @@ impl<S: Storage + Send> Interpreter<S> {
         Ok(InterpreterResponse::ValueType(result))
     }
 
+    /// List keys in order from start up to but not including end
+    fn range(
+        &self, start: &str, end: &str, limit: Option<usize>
+    ) -> Result<InterpreterResponse, ServerError> {
+        let keys = self.storage.range_keys(start, end, limit)?;
+        Ok(InterpreterResponse::Keys(keys))
+    }
+
+    /// List keys beginning with the prefix in order
+    fn prefix(&self, prefix: &str, limit: Option<usize>) -> Result<InterpreterResponse, ServerError> {
+        let keys = self.storage.prefix_keys(prefix, limit)?;
+        Ok(InterpreterResponse::Keys(keys))
+    }
+
+    /// List every key matching the pattern
+    fn keys(&self, pattern: &str) -> Result<InterpreterResponse, ServerError> {
+        let (_, keys) = self.storage.scan(0, usize::MAX)?;
+        let keys = keys.into_iter().filter(|key| glob_match(pattern, key)).collect();
+        Ok(InterpreterResponse::Keys(keys))
+    }
+
+    /// Visit a page of keys, keeping the ones matching the pattern if there is one
+    fn scan(
+        &self, cursor: u64, pattern: Option<String>, count: Option<usize>
+    ) -> Result<InterpreterResponse, ServerError> {
+        // Statements built without the parser can still ask for nothing, which would never move the cursor
+        let count = match count {
+            Some(0) => return Err(ServerError::RequestError("Expected a count above 0.".to_string())),
+            count => count.unwrap_or(DEFAULT_SCAN_COUNT),
+        };
+        let (next_cursor, keys) = self.storage.scan(cursor, count)?;
+        let keys = match pattern {
+            Some(pattern) => keys.into_iter().filter(|key| glob_match(&pattern, key)).collect(),
+            None => keys,
+        };
+        Ok(InterpreterResponse::Scan(next_cursor, keys))
+    }
+
+    /// Get the version of a key, or 0 if it doesn't exist
+    fn version(&self, key: &StorageKey) -> Result<u64, ServerError> {
+        match self.storage.get_if_exists(key)? {
+            Some(element) => Ok(element.version),
+            None => Ok(0),
+        }
+    }
+
+    /// Make sure a key is at the expected version
+    fn check_version(&self, key: &StorageKey, expected_version: u64) -> Result<u64, ServerError> {
+        let version = self.version(key)?;
+        if version != expected_version {
+            return Err(ServerError::ConflictError(
+                format!("Key {} is at version {}, not {}.", key, version, expected_version)
+            ));
+        }
+        Ok(version)
+    }
+
+    /// Get the version of a key, failing if a version is given and the key has moved on from it.
+    ///
+    /// This only checks the key as the statement runs. A request like
+    /// `begin; watch k 3; set k 1; commit;` is what makes the write depend on it, since the
+    /// failed check rolls back the whole transaction.
+    fn watch(
+        &self, key: &StorageKey, expected_version: Option<u64>
+    ) -> Result<InterpreterResponse, ServerError> {
+        let version = match expected_version {
+            Some(expected_version) => self.check_version(key, expected_version)?,
+            None => self.version(key)?,
+        };
+        Ok(InterpreterResponse::Version(version))
+    }
+
     /// See if a key exists in the storage container
     fn exists(&self, key: &StorageKey) -> Result<InterpreterResponse, ServerError> {
         let result = self.storage.contains_key(key)?;
@@ -229,9 +831,18 @@ impl<S: Storage + Send> Interpreter<S> {
             None => None,
             Some(time) => Some(SystemTime::now() + Duration::from_secs(time)),
         };
-        let element = StorageElement{key: key.to_string(), value, expiration};
+        let element = StorageElement{key: key.to_string(), value, expiration, version: 0};
         self.storage.set(key, element)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        // The new version is filled in once the change is made
+        Ok(InterpreterResponse::Version(0))
+    }
+
+    /// Set the value for a key only if it is still at the expected version
+    fn compare_and_set(
+        &mut self, key: &StorageKey, expected_version: u64, value: StorageValue, expiration: Option<u64>
+    ) -> Result<InterpreterResponse, ServerError> {
+        self.check_version(key, expected_version)?;
+        self.set(key, value, expiration)
     }
 
     /// Set the value for a key only if it doesn't already exist
@@ -242,7 +853,7 @@ impl<S: Storage + Send> Interpreter<S> {
             None => None,
             Some(time) => Some(SystemTime::now() + Duration::from_secs(time)),
         };
-        let element = StorageElement{key: key.to_string(), value, expiration};
+        let element = StorageElement{key: key.to_string(), value, expiration, version: 0};
         let result = self.storage.set_if_not_exists(key, element)?;
         Ok(InterpreterResponse::Bool(result))
     }
@@ -255,9 +866,9 @@ impl<S: Storage + Send> Interpreter<S> {
             None => None,
             Some(time) => Some(SystemTime::now() + Duration::from_secs(time)),
         };
-        let element = StorageElement{key: key.to_string(), value, expiration};
+        let element = StorageElement{key: key.to_string(), value, expiration, version: 0};
         self.storage.update(key, element)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        Ok(InterpreterResponse::Version(0))
     }
 
     /// Update the expiration time of a key that already exists
@@ -269,11 +880,11 @@ impl<S: Storage + Send> Interpreter<S> {
             Some(time) => Some(SystemTime::now() + Duration::from_secs(time)),
         };
         self.storage.update_expiration(key, expiration)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        Ok(InterpreterResponse::Version(0))
     }
 
     /// Get an element if it is expected to be a vector
-    fn get_vector_element(&mut self, key: &StorageKey) -> Result<StorageVector, ServerError> {
+    fn get_vector_element(&self, key: &StorageKey) -> Result<StorageVector, ServerError> {
         let element = self.storage.get(key)?;
         if let StorageValue::Vector(vector) = element.value {
             Ok(vector)
@@ -283,7 +894,7 @@ impl<S: Storage + Send> Interpreter<S> {
     }
 
     /// Get an element if it is expected to be a map.
-    fn get_map_element(&mut self, key: &StorageKey) -> Result<StorageMap, ServerError> {
+    fn get_map_element(&self, key: &StorageKey) -> Result<StorageMap, ServerError> {
         let element = self.storage.get(key)?;
         if let StorageValue::Map(map) = element.value {
             Ok(map)
@@ -314,7 +925,7 @@ impl<S: Storage + Send> Interpreter<S> {
 
     /// Get a single value from a vector
     fn vector_get(
-        &mut self, key: &StorageKey, index: usize
+        &self, key: &StorageKey, index: usize
     ) -> Result<InterpreterResponse, ServerError> {
         let vector = self.get_vector_element(key)?;
         let value = vector.get(index)?;
@@ -323,7 +934,7 @@ impl<S: Storage + Send> Interpreter<S> {
     
     /// Get the length of a vector
     fn vector_length(
-        &mut self, key: &StorageKey
+        &self, key: &StorageKey
     ) -> Result<InterpreterResponse, ServerError> {
         let vector = self.get_vector_element(key)?;
         Ok(InterpreterResponse::Size(vector.len()))
@@ -335,7 +946,16 @@ impl<S: Storage + Send> Interpreter<S> {
     ) -> Result<InterpreterResponse, ServerError> {
         let vector = self.get_vector_element_mut(key)?;
         vector.push(value)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        Ok(InterpreterResponse::Version(0))
+    }
+
+    /// Push a value to the front of a vector
+    fn vector_prepend(
+        &mut self, key: &StorageKey, value: StorageValue
+    ) -> Result<InterpreterResponse, ServerError> {
+        let vector = self.get_vector_element_mut(key)?;
+        vector.push_front(value)?;
+        Ok(InterpreterResponse::Version(0))
     }
 
     /// Pop a value from the back of a vector
@@ -357,12 +977,12 @@ impl<S: Storage + Send> Interpreter<S> {
     ) -> Result<InterpreterResponse, ServerError> {
         let vector = self.get_vector_element_mut(key)?;
         vector.set(index, value)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        Ok(InterpreterResponse::Version(0))
     }
 
     /// Get a single element of a map
     fn map_get(
-        &mut self, key: &StorageKey, map_key: &StorageValue
+        &self, key: &StorageKey, map_key: &StorageValue
     ) -> Result<InterpreterResponse, ServerError> {
         let map = self.get_map_element(key)?;
         let value = map.get(map_key)?;
@@ -371,7 +991,7 @@ impl<S: Storage + Send> Interpreter<S> {
 
     /// Get the number of elements in a map
     fn map_length(
-        &mut self, key: &StorageKey
+        &self, key: &StorageKey
     ) -> Result<InterpreterResponse, ServerError> {
         let map = self.get_map_element(key)?;
         Ok(InterpreterResponse::Size(map.len()))
@@ -379,7 +999,7 @@ impl<S: Storage + Send> Interpreter<S> {
 
     /// See if an element exists in a map
     fn map_exists(
-        &mut self, key: &StorageKey, map_key: &StorageValue
+        &self, key: &StorageKey, map_key: &StorageValue
     ) -> Result<InterpreterResponse, ServerError> {
         let map = self.get_map_element(key)?;
         let result = map.contains_key(map_key)?;
@@ -392,7 +1012,7 @@ impl<S: Storage + Send> Interpreter<S> {
     ) -> Result<InterpreterResponse, ServerError> {
         let map = self.get_map_element_mut(key)?;
         map.set(map_key, value)?;
-        Ok(InterpreterResponse::Message("Ok".to_string()))
+        Ok(InterpreterResponse::Version(0))
     }
 
     /// Delete an element in a map
@@ -406,18 +1026,61 @@ impl<S: Storage + Send> Interpreter<S> {
 }
 
 
-/// Validate that a statement is available at the given authorization level.
-fn validate_authorization(
-    statements: &Vec<Statement>, authorization: AuthorizationLevel
+/// Check if a statement could add to the memory used by the storage.
+/// 
+/// Statements that only remove data are always allowed to run, even when the storage is full.
+fn may_use_memory(statement: &Statement) -> bool {
+    match statement {
+        Statement::Delete(..) | Statement::VectorPop(..) | Statement::MapDelete(..) => false,
+        statement => statement.is_write(),
+    }
+}
+
+
+/// Check that every `begin` in a request is closed by a `commit` without nesting.
+pub fn validate_transactions(statements: &[Statement]) -> Result<(), ServerError> {
+    let mut in_transaction = false;
+    for statement in statements {
+        match statement {
+            Statement::Begin if in_transaction => {
+                return Err(ServerError::RequestError("Transactions can't be nested.".to_string()))
+            },
+            Statement::Commit if !in_transaction => {
+                return Err(ServerError::RequestError("Found commit without begin.".to_string()))
+            },
+            statement if in_transaction && statement.is_user_management() => {
+                return Err(ServerError::RequestError("Users can't be managed inside a transaction.".to_string()))
+            },
+            // A snapshot would keep writes that may still be rolled back
+            Statement::Save | Statement::BackgroundSave if in_transaction => {
+                return Err(ServerError::RequestError("Snapshots can't be taken inside a transaction.".to_string()))
+            },
+            Statement::Shutdown if in_transaction => {
+                return Err(ServerError::RequestError("The server can't be shut down inside a transaction.".to_string()))
+            },
+            Statement::Begin => in_transaction = true,
+            Statement::Commit => in_transaction = false,
+            _ => (),
+        }
+    }
+    if in_transaction {
+        Err(ServerError::RequestError("Transaction was not committed.".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Check that every statement in a request is allowed at the authorization level, and by the
+/// rules limiting which keys and commands the user can use if there are any.
+pub fn validate_authorization(
+    statements: &Vec<Statement>, authorization: AuthorizationLevel, access_rules: Option<&AccessRules>
 ) -> Result<(), ServerError> {
     let mut is_authorized = true;
     for statement in statements.iter() {
         is_authorized = match statement {
-            Statement::Shutdown => authorization == AuthorizationLevel::Admin,
-            Statement::Delete(..) | Statement::Set(..) | Statement::SetIfNotExists(..) |
-            Statement::VectorSet(..) | Statement::VectorAppend(..) | Statement::VectorPop(..) |
-            Statement::MapSet(..) | Statement::MapDelete(..) | Statement::Update(..) |
-            Statement::UpdateLifetime(..) => (authorization == AuthorizationLevel::Admin) |
+            statement if statement.is_admin() => authorization == AuthorizationLevel::Admin,
+            statement if statement.is_write() => (authorization == AuthorizationLevel::Admin) |
                 (authorization == AuthorizationLevel::Write),
             _ => true,
         };
@@ -426,9 +1089,183 @@ fn validate_authorization(
         }
     }
 
-    if is_authorized {
-        Ok(())
-    } else {
-        Err(ServerError::AuthorizationError("User is not authorized to perform this query.".to_string()))
+    if !is_authorized {
+        return Err(ServerError::AuthorizationError("User is not authorized to perform this query.".to_string()));
+    }
+    if let Some(access_rules) = access_rules {
+        for statement in statements.iter() {
+            access_rules.check(statement)?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    fn run<S: Storage + Send>(
+        interpreter: &mut Interpreter<S>, statements: Vec<Statement>, authorization: AuthorizationLevel
+    ) -> Result<InterpreterResponse, ServerError> {
+        interpreter.interpret(InterpreterRequest { statements, authorization, access_rules: None, response_mode: ResponseMode::Last })
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::Begin,
+            Statement::Set("x".to_string(), StorageValue::Int(2), None),
+            Statement::Set("y".to_string(), StorageValue::Int(3), None),
+            Statement::VectorAppend("x".to_string(), StorageValue::Int(4)),
+            Statement::Commit,
+        ];
+        let result = run(&mut interpreter, statements, AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::TypeError(_))));
+        assert!(matches!(interpreter.storage.get("x").unwrap().value, StorageValue::Int(1)));
+        assert!(!interpreter.storage.contains_key("y").unwrap());
+
+        let statements = vec![
+            Statement::Begin,
+            Statement::Set("y".to_string(), StorageValue::Int(5), None),
+            Statement::Commit,
+        ];
+        run(&mut interpreter, statements, AuthorizationLevel::Write).unwrap();
+        assert!(matches!(interpreter.storage.get("y").unwrap().value, StorageValue::Int(5)));
+        let result = run(&mut interpreter, vec![Statement::Begin], AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::RequestError(_))));
+    }
+
+    #[test]
+    fn test_transactions_reject_save_and_shutdown() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        for statement in [Statement::Save, Statement::BackgroundSave, Statement::Shutdown] {
+            let statements = vec![
+                Statement::Begin,
+                Statement::Set("x".to_string(), StorageValue::Int(1), None),
+                statement,
+                Statement::Commit,
+            ];
+            let result = run(&mut interpreter, statements, AuthorizationLevel::Admin);
+            assert!(matches!(result, Err(ServerError::RequestError(_))));
+            assert!(!interpreter.storage.contains_key("x").unwrap());
+            assert!(interpreter.transaction.is_none());
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        let watch = |key: &str| vec![Statement::Watch(key.to_string(), None)];
+        let result = run(&mut interpreter, watch("x"), AuthorizationLevel::Read);
+        assert!(matches!(result, Ok(InterpreterResponse::Version(0))));
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::Set("y".to_string(), StorageValue::Int(1), None),
+            Statement::CompareAndSet("x".to_string(), 1, StorageValue::Int(2), None),
+        ];
+        let result = run(&mut interpreter, statements, AuthorizationLevel::Write);
+        assert!(matches!(result, Ok(InterpreterResponse::Version(3))));
+
+        let statement = Statement::CompareAndSet("x".to_string(), 1, StorageValue::Int(3), None);
+        let result = run(&mut interpreter, vec![statement], AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::ConflictError(_))));
+        let statements = vec![
+            Statement::Watch("x".to_string(), Some(3)),
+            Statement::Watch("y".to_string(), Some(2)),
+            Statement::Begin,
+            Statement::Set("x".to_string(), StorageValue::Int(4), None),
+            Statement::Watch("y".to_string(), Some(3)),
+            Statement::Commit,
+        ];
+        let result = run(&mut interpreter, statements, AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::ConflictError(_))));
+        assert!(matches!(interpreter.storage.get("x").unwrap().value, StorageValue::Int(2)));
+        assert_eq!(interpreter.storage.get("x").unwrap().version, 3);
+
+        // Plain writes give back the new version too
+        let statement = Statement::Update("y".to_string(), StorageValue::Int(2), None);
+        let result = run(&mut interpreter, vec![statement], AuthorizationLevel::Write);
+        let version = interpreter.storage.get("y").unwrap().version;
+        assert!(version > 3);
+        assert!(matches!(result, Ok(InterpreterResponse::Version(v)) if v == version));
+    }
+
+    #[test]
+    fn test_watch_in_transaction() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        let set = |value| Statement::Set("x".to_string(), StorageValue::Int(value), None);
+        let guarded = |version, value| vec![
+            Statement::Begin, Statement::Watch("x".to_string(), Some(version)), set(value), Statement::Commit,
+        ];
+        let version = match run(&mut interpreter, vec![set(1)], AuthorizationLevel::Write) {
+            Ok(InterpreterResponse::Version(version)) => version,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        run(&mut interpreter, guarded(version, 2), AuthorizationLevel::Write).unwrap();
+        assert!(matches!(interpreter.storage.get("x").unwrap().value, StorageValue::Int(2)));
+
+        // The key moved on since the version was read, so nothing in the transaction is kept
+        let result = run(&mut interpreter, guarded(version, 3), AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::ConflictError(_))));
+        assert!(matches!(interpreter.storage.get("x").unwrap().value, StorageValue::Int(2)));
+        assert!(interpreter.transaction.is_none());
+    }
+
+    #[test]
+    fn test_capabilities_stay_within_access_rules() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        interpreter.set_capability_signer(CapabilitySigner::new(b"a secret of enough bytes").unwrap());
+        let rules = Arc::new(AccessRules { write: vec!["team_a:*".to_string()], ..AccessRules::default() });
+        let mut issue = |prefixes: Vec<&str>| {
+            let prefixes = prefixes.into_iter().map(|prefix| prefix.to_string()).collect();
+            interpreter.interpret(InterpreterRequest {
+                statements: vec![Statement::IssueCapability(Privileges::Admin, 86400, prefixes)],
+                authorization: AuthorizationLevel::Admin,
+                access_rules: Some(Arc::clone(&rules)),
+                response_mode: ResponseMode::Last,
+            })
+        };
+        assert!(matches!(issue(vec![]), Err(ServerError::AuthorizationError(_))));
+        assert!(matches!(issue(vec!["team_b:"]), Err(ServerError::AuthorizationError(_))));
+        assert!(matches!(issue(vec!["team_a:jobs:"]), Ok(InterpreterResponse::Value(StorageValue::String(_)))));
+    }
+
+    #[test]
+    fn test_all_responses() {
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::Get("missing".to_string()),
+            Statement::Get("x".to_string()),
+        ];
+        let request = InterpreterRequest {
+            statements, authorization: AuthorizationLevel::Write, access_rules: None, response_mode: ResponseMode::All
+        };
+        let results = match interpreter.interpret(request) {
+            Ok(InterpreterResponse::Results(results)) => results,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        assert_eq!(results.len(), 3);
+        assert!(matches!(results[0], Ok(InterpreterResponse::Version(1))));
+        assert!(matches!(results[1], Err(ServerError::KeyError(_))));
+        assert!(matches!(results[2], Ok(InterpreterResponse::Value(StorageValue::Int(1)))));
+
+        let statements = vec![
+            Statement::Set("y".to_string(), StorageValue::Int(1), None),
+            Statement::Begin,
+            Statement::Set("x".to_string(), StorageValue::Int(2), None),
+            Statement::Get("missing".to_string()),
+            Statement::Commit,
+        ];
+        let request = InterpreterRequest {
+            statements, authorization: AuthorizationLevel::Write, access_rules: None, response_mode: ResponseMode::All
+        };
+        assert!(matches!(interpreter.interpret(request), Err(ServerError::KeyError(_))));
+        assert!(matches!(interpreter.storage.get("x").unwrap().value, StorageValue::Int(1)));
+        assert!(interpreter.storage.contains_key("y").unwrap());
     }
 }
@@ -1,6 +1,7 @@
 use std::iter::Iterator;
 
-use crate::analysis::{AnnotatedToken, Statement, Token, Tokenizer};
+use crate::analysis::{AnnotatedToken, Privileges, Statement, Token, Tokenizer};
+use crate::auth::AuthorizationLevel;
 use crate::error::ServerError;
 use crate::storage::{CollectionType, KeyType, StorageKey, StorageValue, StorageVector, StorageMap};
 
@@ -77,26 +78,42 @@ impl Parser {
         }
         let AnnotatedToken{token, position, lexeme,} = self.advance();
         let statement = match token {
+            Token::BackgroundSave => self.background_save(),
+            Token::Begin => self.begin(),
+            Token::Commit => self.commit(),
+            Token::CompareAndSet => self.compare_and_set(),
             Token::Delete => self.delete(),
             Token::Exists => self.exists(),
             Token::Get => self.get(),
+            Token::Keys => self.keys(),
             Token::GetOrNone => self.get_or_none(),
+            Token::IssueCapability => self.issue_capability(),
             Token::MapDelete => self.map_delete(),
             Token::MapExists => self.map_exists(),
             Token::MapGet => self.map_get(),
             Token::MapLength => self.map_length(),
             Token::MapSet => self.map_set(),
+            Token::Prefix => self.prefix(),
+            Token::Range => self.range(),
+            Token::Save => self.save(),
+            Token::Scan => self.scan(),
             Token::Set => self.set(),
             Token::SetIfNotExists => self.set_if_not_exists(),
             Token::SetLifetime => self.set_lifetime(),
             Token::Shutdown => self.shutdown(),
             Token::Update => self.update(),
+            Token::UserAdd => self.user_add(),
+            Token::UserDelete => self.user_delete(),
+            Token::UserList => self.user_list(),
+            Token::UserPassword => self.user_password(),
             Token::ValueType => self.value_type(),
             Token::VectorAppend => self.vector_append(),
+            Token::VectorPrepend => self.vector_prepend(),
             Token::VectorGet => self.vector_get(),
             Token::VectorLength => self.vector_length(),
             Token::VectorPop => self.vector_pop(),
             Token::VectorSet => self.vector_set(),
+            Token::Watch => self.watch(),
             _ => return Err(
                 ServerError::ParseError(
                     format!(
@@ -145,6 +162,26 @@ impl Parser {
         Ok(f(&map_name, key))
     }   
 
+    fn background_save(&mut self) -> Result<Statement, ServerError> {
+        Ok(Statement::BackgroundSave)
+    }
+
+    fn begin(&mut self) -> Result<Statement, ServerError> {
+        Ok(Statement::Begin)
+    }
+
+    fn commit(&mut self) -> Result<Statement, ServerError> {
+        Ok(Statement::Commit)
+    }
+
+    fn compare_and_set(&mut self) -> Result<Statement, ServerError> {
+        let name = self.get_name_from_next_token()?;
+        let version = self.get_index_from_next_token()? as u64;
+        let value = self.get_value_from_next_token()?;
+        let lifetime = self.get_lifetime_from_next_token()?;
+        Ok(Statement::CompareAndSet(name, version, value, lifetime))
+    }
+
     fn delete(&mut self) -> Result<Statement, ServerError> {
         self.process_identifier_statement(|x| Statement::Delete(x.clone()))
     }
@@ -162,6 +199,11 @@ impl Parser {
             |x| Statement::GetIfExists(x.clone()))   
     }
 
+    fn keys(&mut self) -> Result<Statement, ServerError> {
+        let pattern = self.get_key_bound_from_next_token()?;
+        Ok(Statement::Keys(pattern))
+    }
+
     fn map_delete(&mut self) -> Result<Statement, ServerError> {
         self.process_map_identifier_statement(
             |x, y| Statement::MapDelete(x.clone(), y)
@@ -193,6 +235,50 @@ impl Parser {
         Ok(Statement::MapSet(map_name, key, value))
     }
 
+    fn prefix(&mut self) -> Result<Statement, ServerError> {
+        let prefix = self.get_key_bound_from_next_token()?;
+        let limit = self.get_limit_from_next_tokens()?;
+        Ok(Statement::Prefix(prefix, limit))
+    }
+
+    fn range(&mut self) -> Result<Statement, ServerError> {
+        let start = self.get_key_bound_from_next_token()?;
+        let end = self.get_key_bound_from_next_token()?;
+        let limit = self.get_limit_from_next_tokens()?;
+        Ok(Statement::Range(start, end, limit))
+    }
+
+    fn save(&mut self) -> Result<Statement, ServerError> {
+        Ok(Statement::Save)
+    }
+
+    fn scan(&mut self) -> Result<Statement, ServerError> {
+        let cursor = self.get_index_from_next_token()? as u64;
+        let mut pattern = None;
+        let mut count = None;
+        while !self.is_at_statement_end() {
+            let token = self.advance().clone();
+            match token.token {
+                Token::Match if pattern.is_none() => {
+                    pattern = Some(self.get_key_bound_from_next_token()?);
+                },
+                Token::Count if count.is_none() => {
+                    count = Some(self.get_index_from_next_token()?);
+                    // The cursor would never move, so a client scanning until it gets 0 back would never stop
+                    if count == Some(0) {
+                        return Err(ServerError::ParseError("Expected a count above 0.".to_string()));
+                    }
+                },
+                _ => return Err(
+                    ServerError::ParseError(
+                        format!("Expected match or count. Got {} at {}", token.lexeme, token.position)
+                    )
+                ),
+            }
+        }
+        Ok(Statement::Scan(cursor, pattern, count))
+    }
+
     fn set(&mut self) ->Result<Statement, ServerError> {
         let name = self.get_name_from_next_token()?;
         let value = self.get_value_from_next_token()?;
@@ -225,6 +311,44 @@ impl Parser {
         Ok(Statement::Update(name, value, lifetime))
     }
 
+    fn issue_capability(&mut self) -> Result<Statement, ServerError> {
+        let privileges = Privileges::from(self.get_authorization_level_from_next_token()?);
+        let lifetime = match self.get_lifetime_from_next_token()? {
+            Some(lifetime) => lifetime,
+            None => return Err(ServerError::ParseError("Expected how many seconds the capability lasts.".to_string())),
+        };
+        let mut prefixes = vec![];
+        while !self.is_at_statement_end() {
+            prefixes.push(self.get_key_bound_from_next_token()?);
+        }
+        Ok(Statement::IssueCapability(privileges, lifetime, prefixes))
+    }
+
+    fn user_add(&mut self) -> Result<Statement, ServerError> {
+        let username = self.get_key_bound_from_next_token()?;
+        let level = self.get_authorization_level_from_next_token()?;
+        let password = match self.is_at_statement_end() {
+            true => None,
+            false => Some(self.get_string_from_next_token()?),
+        };
+        Ok(Statement::UserAdd(username, level, password))
+    }
+
+    fn user_delete(&mut self) -> Result<Statement, ServerError> {
+        let username = self.get_key_bound_from_next_token()?;
+        Ok(Statement::UserDelete(username))
+    }
+
+    fn user_list(&mut self) -> Result<Statement, ServerError> {
+        Ok(Statement::UserList)
+    }
+
+    fn user_password(&mut self) -> Result<Statement, ServerError> {
+        let username = self.get_key_bound_from_next_token()?;
+        let password = self.get_string_from_next_token()?;
+        Ok(Statement::UserPassword(username, password))
+    }
+
     fn value_type(&mut self) -> Result<Statement, ServerError> {
         self.process_identifier_statement(
             |x| Statement::ValueType(x.clone())
@@ -237,6 +361,12 @@ impl Parser {
         Ok(Statement::VectorAppend(name, value))
     }
 
+    fn vector_prepend(&mut self) -> Result<Statement, ServerError> {
+        let name = self.get_name_from_next_token()?;
+        let value = self.get_scalar_value_from_next_token()?;
+        Ok(Statement::VectorPrepend(name, value))
+    }
+
     fn vector_get(&mut self) -> Result<Statement, ServerError> {
         let name = self.get_name_from_next_token()?;
         let index = self.get_index_from_next_token()?;
@@ -261,6 +391,15 @@ impl Parser {
         Ok(Statement::VectorSet(name, index, value))
     }
 
+    fn watch(&mut self) -> Result<Statement, ServerError> {
+        let name = self.get_name_from_next_token()?;
+        let version = match self.is_at_statement_end() {
+            true => None,
+            false => Some(self.get_index_from_next_token()? as u64),
+        };
+        Ok(Statement::Watch(name, version))
+    }
+
     /// Remove any successive semicolons at the current position
     /// 
     /// Semicolons can optionally appear at the end of a statement or to separate statements
@@ -407,6 +546,71 @@ impl Parser {
         }
     }
 
+    /// Get a key or part of a key, written either as a string or as an identifier
+    fn get_key_bound_from_next_token(&mut self) -> Result<StorageKey, ServerError> {
+        if self.is_at_end() {
+            return Err(ServerError::ParseError("Expected a key instead of the end of the query.".to_string()));
+        }
+        let token = self.advance();
+        match &token.token {
+            Token::StringValue(value) => Ok(*value.clone()),
+            Token::Identifier(identifier) => Ok(*identifier.clone()),
+            _ => Err(
+                ServerError::ParseError(
+                    format!("Expected a string or identifier. Got {} at {}", token.lexeme, token.position)
+                )
+            ),
+        }
+    }
+
+    /// Get a string literal
+    fn get_string_from_next_token(&mut self) -> Result<String, ServerError> {
+        if self.is_at_end() {
+            return Err(ServerError::ParseError("Expected a string instead of the end of the query.".to_string()));
+        }
+        let token = self.advance();
+        match &token.token {
+            Token::StringValue(value) => Ok(*value.clone()),
+            _ => Err(
+                ServerError::ParseError(
+                    format!("Expected a string. Got {} at {}", token.lexeme, token.position)
+                )
+            ),
+        }
+    }
+
+    /// Get an authorization level: admin, write or read
+    fn get_authorization_level_from_next_token(&mut self) -> Result<AuthorizationLevel, ServerError> {
+        if self.is_at_end() {
+            return Err(ServerError::ParseError("Expected admin, write or read instead of the end of the query.".to_string()));
+        }
+        let token = self.advance();
+        let level = match &token.token {
+            Token::Identifier(identifier) => AuthorizationLevel::from_name(identifier).ok(),
+            _ => None,
+        };
+        match level {
+            Some(level) => Ok(level),
+            None => Err(
+                ServerError::ParseError(
+                    format!("Expected admin, write or read. Got {} at {}", token.lexeme, token.position)
+                )
+            ),
+        }
+    }
+
+    /// Get an optional limit clause of the form `limit N`
+    fn get_limit_from_next_tokens(&mut self) -> Result<Option<usize>, ServerError> {
+        if self.is_at_statement_end() || self.view().token != Token::Limit {
+            return Ok(None);
+        }
+        self.advance();
+        if self.is_at_end() {
+            return Err(ServerError::ParseError("Expected a number after limit.".to_string()));
+        }
+        self.get_index_from_next_token().map(Some)
+    }
+
     fn get_value_from_next_token(&mut self) -> Result<StorageValue, ServerError> {
         if self.is_at_statement_end() {
             return Ok(StorageValue::Null);
@@ -529,3 +733,24 @@ fn is_collection_or_key_type(token: &Token) -> bool {
         _ => false
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Tokenizer;
+
+    fn parse(query: &str) -> Result<Vec<Statement>, ServerError> {
+        let tokens = Tokenizer::new(query).tokenize()?;
+        Parser::new(tokens).parse()
+    }
+
+    #[test]
+    fn test_parse_scan() {
+        assert_eq!(
+            parse("scan 5 match \"user:*\" count 2;").unwrap(),
+            vec![Statement::Scan(5, Some("user:*".to_string()), Some(2))]
+        );
+        assert!(matches!(parse("scan 5 count 0;"), Err(ServerError::ParseError(_))));
+    }
+}
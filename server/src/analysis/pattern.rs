@@ -0,0 +1,118 @@
+/// Check if a key matches a glob-style pattern.
+///
+/// `*` matches any number of characters, `?` matches exactly one, `[abc]` matches one of a set
+/// of characters, `[a-z]` a range and `[^abc]` anything not in the set. A backslash matches the
+/// next character literally.
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let mut pattern_index = 0;
+    let mut key_index = 0;
+    // Where to resume after the last star: the pattern after it and the key it has swallowed up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while key_index < key.len() {
+        if pattern_index < pattern.len() {
+            if pattern[pattern_index] == '*' {
+                pattern_index += 1;
+                backtrack = Some((pattern_index, key_index));
+                continue;
+            }
+            if let Some(next_index) = match_char(&pattern, pattern_index, key[key_index]) {
+                pattern_index = next_index;
+                key_index += 1;
+                continue;
+            }
+        }
+        match backtrack {
+            Some((star_pattern_index, star_key_index)) => {
+                pattern_index = star_pattern_index;
+                key_index = star_key_index + 1;
+                backtrack = Some((star_pattern_index, key_index));
+            },
+            None => return false,
+        }
+    }
+    pattern[pattern_index..].iter().all(|c| *c == '*')
+}
+
+
+/// Match one character against the pattern element at the index and get the index after it.
+fn match_char(pattern: &[char], index: usize, c: char) -> Option<usize> {
+    match pattern[index] {
+        '?' => Some(index + 1),
+        '\\' if index + 1 < pattern.len() => {
+            if pattern[index + 1] == c { Some(index + 2) } else { None }
+        },
+        '[' => match match_class(pattern, index, c) {
+            Some((true, next_index)) => Some(next_index),
+            Some((false, _)) => None,
+            // An unclosed bracket is just a bracket
+            None => if c == '[' { Some(index + 1) } else { None },
+        },
+        other => if other == c { Some(index + 1) } else { None },
+    }
+}
+
+
+/// Match one character against a bracketed class starting at the index.
+///
+/// Gives whether it matched and the index after the closing bracket, or None if it is unclosed.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut index = start + 1;
+    let negate = index < pattern.len() && pattern[index] == '^';
+    if negate {
+        index += 1;
+    }
+    let mut matched = false;
+    while index < pattern.len() {
+        if pattern[index] == ']' {
+            return Some((matched != negate, index + 1));
+        }
+        if pattern[index] == '\\' && index + 1 < pattern.len() {
+            index += 1;
+        }
+        let low = pattern[index];
+        if index + 2 < pattern.len() && pattern[index + 1] == '-' && pattern[index + 2] != ']' {
+            let high = pattern[index + 2];
+            matched |= low <= c && c <= high;
+            index += 3;
+        } else {
+            matched |= low == c;
+            index += 1;
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_and_wildcards() {
+        assert!(glob_match("user:1", "user:1"));
+        assert!(!glob_match("user:1", "user:12"));
+        assert!(glob_match("user:*", "user:12"));
+        assert!(glob_match("user:*", "user:"));
+        assert!(!glob_match("user:*", "users"));
+        assert!(glob_match("*:*:name", "user:1:name"));
+        assert!(!glob_match("*:*:name", "user:1:email"));
+        assert!(glob_match("user:?", "user:1"));
+        assert!(!glob_match("user:?", "user:12"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(glob_match("key[12]", "key1"));
+        assert!(!glob_match("key[12]", "key3"));
+        assert!(glob_match("key[a-c]", "keyb"));
+        assert!(!glob_match("key[^a-c]", "keyb"));
+        assert!(glob_match("key[^a-c]", "keyd"));
+        assert!(glob_match("key\\*", "key*"));
+        assert!(!glob_match("key\\*", "key1"));
+        assert!(glob_match("key[", "key["));
+    }
+}
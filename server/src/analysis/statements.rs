@@ -1,13 +1,31 @@
 use std::fmt::Debug;
 
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::interpreter::Privileges;
+use crate::auth::AuthorizationLevel;
+use crate::auth::credentials::hash_new_password;
+use crate::error::ServerError;
 use crate::storage::{StorageKey, StorageValue};
 
 /// Lifetime in seconds of a 
 type Lifetime = u64;
 
 
-/// Statement
+/// The keys a statement can touch
 #[derive(Clone, Debug, PartialEq)]
+pub enum KeyScope<'a> {
+    /// No keys at all
+    None,
+    /// Exactly one key
+    Key(&'a str),
+    /// Any key starting with a prefix, where an empty prefix is every key
+    Prefix(String),
+}
+
+
+/// Statement
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum Statement {
     /// Get a value
     Get(StorageKey),
@@ -33,6 +51,8 @@ pub enum Statement {
     VectorSet(StorageKey, usize, StorageValue),
     /// Push a value to a vector
     VectorAppend(StorageKey, StorageValue),
+    /// Push a value to the front of a vector
+    VectorPrepend(StorageKey, StorageValue),
     /// Pop a value from a vector
     VectorPop(StorageKey),
     /// Get the length of a vector
@@ -47,12 +67,224 @@ pub enum Statement {
     MapLength(StorageKey),
     /// See if an element exists in a map
     MapExists(StorageKey, StorageValue),
+    /// List keys from a start (inclusive) to an end (exclusive) in order
+    Range(StorageKey, StorageKey, Option<usize>),
+    /// List keys beginning with a prefix in order
+    Prefix(StorageKey, Option<usize>),
+    /// List every key matching a glob pattern
+    Keys(String),
+    /// Visit keys from a cursor, optionally matching a pattern and with a count to visit
+    Scan(u64, Option<String>, Option<usize>),
     /// Get the type of some value
     ValueType(StorageKey),
     /// Try to expire keys according to the storage object's policy
     ExpireKeys,
     /// Shut the server down
     Shutdown,
+    /// Save a snapshot of the database and wait for it to finish
+    Save,
+    /// Save a snapshot of the database in the background
+    BackgroundSave,
+    /// Start a transaction
+    Begin,
+    /// Keep the changes made since the transaction started
+    Commit,
+    /// Get the version of a key, failing if it isn't the expected one. Nothing is remembered
+    /// between requests, so to guard writes with it, send it inside the same `begin … commit`.
+    Watch(StorageKey, Option<u64>),
+    /// Set a value only if the key is still at the expected version
+    CompareAndSet(StorageKey, u64, StorageValue, Option<Lifetime>),
+    /// Add a user with a level and, if they log in with one, a password. Clients send the
+    /// password itself, which is swapped for its hash before the statement runs, see `hash_password`.
+    UserAdd(String, AuthorizationLevel, Option<String>),
+    /// Remove a user
+    UserDelete(String),
+    /// Change a user's password, hashed before the statement runs like the one for `UserAdd`
+    UserPassword(String, String),
+    /// List every user and their level
+    UserList,
+    /// Create a capability token with some privileges, lasting some seconds, for keys with some prefixes
+    IssueCapability(Privileges, Lifetime, Vec<String>),
     /// Null statement
     Null,
 }
+
+
+impl Statement {
+    /// Check if a statement only reads from the storage, so it can run alongside other reads.
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Statement::Get(..) | Statement::Exists(..) | Statement::GetIfExists(..) |
+            Statement::GetLifetime(..) | Statement::VectorGet(..) | Statement::VectorLength(..) |
+            Statement::MapGet(..) | Statement::MapExists(..) | Statement::MapLength(..) |
+            Statement::ValueType(..) | Statement::Range(..) | Statement::Prefix(..) |
+            Statement::Keys(..) | Statement::Scan(..) | Statement::Watch(..) | Statement::UserList |
+            Statement::IssueCapability(..) | Statement::Null
+        )
+    }
+
+    /// Check if a statement manages the users rather than the storage.
+    pub fn is_user_management(&self) -> bool {
+        matches!(
+            self,
+            Statement::UserAdd(..) | Statement::UserDelete(..) | Statement::UserPassword(..) | Statement::UserList
+        )
+    }
+
+    /// Check if only admins can run a statement.
+    pub fn is_admin(&self) -> bool {
+        match self {
+            Statement::Shutdown | Statement::Save | Statement::BackgroundSave | Statement::IssueCapability(..) => true,
+            statement => statement.is_user_management(),
+        }
+    }
+
+    /// Get the key a statement works on, if it works on exactly one.
+    pub fn key(&self) -> Option<&StorageKey> {
+        match self {
+            Statement::Get(key) | Statement::Set(key, ..) | Statement::Update(key, ..) |
+            Statement::Exists(key) | Statement::Delete(key) | Statement::GetLifetime(key) |
+            Statement::UpdateLifetime(key, ..) | Statement::GetIfExists(key) |
+            Statement::SetIfNotExists(key, ..) | Statement::VectorGet(key, ..) |
+            Statement::VectorSet(key, ..) | Statement::VectorAppend(key, ..) |
+            Statement::VectorPrepend(key, ..) | Statement::VectorPop(key) | Statement::VectorLength(key) | Statement::MapGet(key, ..) |
+            Statement::MapSet(key, ..) | Statement::MapDelete(key, ..) | Statement::MapLength(key) |
+            Statement::MapExists(key, ..) | Statement::ValueType(key) | Statement::Watch(key, ..) |
+            Statement::CompareAndSet(key, ..) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Get the keys a statement can touch.
+    ///
+    /// Listings only give a prefix every key they can return starts with, which can be wider than
+    /// what they really return: a range is limited to what its ends have in common.
+    pub fn key_scope(&self) -> KeyScope<'_> {
+        if let Some(key) = self.key() {
+            return KeyScope::Key(key);
+        }
+        match self {
+            Statement::Prefix(prefix, _) => KeyScope::Prefix(prefix.clone()),
+            Statement::Range(start, end, _) => {
+                let common: String = start.chars().zip(end.chars())
+                    .take_while(|(left, right)| left == right)
+                    .map(|(c, _)| c)
+                    .collect();
+                KeyScope::Prefix(common)
+            },
+            Statement::Keys(pattern) | Statement::Scan(_, Some(pattern), _) => pattern_scope(pattern),
+            Statement::Scan(_, None, _) => KeyScope::Prefix(String::new()),
+            _ => KeyScope::None,
+        }
+    }
+
+    /// Get the name of the command a statement runs, which is its keyword where it has one.
+    pub fn command_name(&self) -> &'static str {
+        match self {
+            Statement::Get(..) => "get",
+            Statement::Set(..) => "set",
+            Statement::Update(..) => "upd",
+            Statement::Exists(..) => "ex",
+            Statement::Delete(..) => "del",
+            Statement::GetLifetime(..) => "lt",
+            Statement::UpdateLifetime(..) => "set_lt",
+            Statement::GetIfExists(..) => "try_get",
+            Statement::SetIfNotExists(..) => "try_set",
+            Statement::VectorGet(..) => "vget",
+            Statement::VectorSet(..) => "vset",
+            Statement::VectorAppend(..) => "vpush",
+            Statement::VectorPrepend(..) => "vpushf",
+            Statement::VectorPop(..) => "vpop",
+            Statement::VectorLength(..) => "vlen",
+            Statement::MapGet(..) => "mget",
+            Statement::MapSet(..) => "mset",
+            Statement::MapDelete(..) => "mdel",
+            Statement::MapLength(..) => "mlen",
+            Statement::MapExists(..) => "mex",
+            Statement::Range(..) => "range",
+            Statement::Prefix(..) => "prefix",
+            Statement::Keys(..) => "keys",
+            Statement::Scan(..) => "scan",
+            Statement::ValueType(..) => "type",
+            Statement::ExpireKeys => "expire",
+            Statement::Shutdown => "shutdown",
+            Statement::Save => "save",
+            Statement::BackgroundSave => "bgsave",
+            Statement::Begin => "begin",
+            Statement::Commit => "commit",
+            Statement::Watch(..) => "watch",
+            Statement::CompareAndSet(..) => "cas",
+            Statement::UserAdd(..) => "user_add",
+            Statement::UserDelete(..) => "user_del",
+            Statement::UserPassword(..) => "user_passwd",
+            Statement::UserList => "user_list",
+            Statement::IssueCapability(..) => "cap_issue",
+            Statement::Null => "null",
+        }
+    }
+
+    /// Check if a statement modifies the contents of the storage.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Statement::Delete(..) | Statement::Set(..) | Statement::SetIfNotExists(..) |
+            Statement::VectorSet(..) | Statement::VectorAppend(..) | Statement::VectorPrepend(..) |
+            Statement::VectorPop(..) | Statement::MapSet(..) | Statement::MapDelete(..) | Statement::Update(..) |
+            Statement::UpdateLifetime(..) | Statement::CompareAndSet(..)
+        )
+    }
+
+    /// Get the statement to write to the command log for this one.
+    ///
+    /// A compare and set already passed its check, so it is replayed as a plain set.
+    pub fn for_command_log(&self) -> Statement {
+        match self {
+            Statement::CompareAndSet(key, _, value, lifetime) => {
+                Statement::Set(key.clone(), value.clone(), *lifetime)
+            },
+            statement => statement.clone(),
+        }
+    }
+
+    /// Swap any password a client sent for its salted hash, so interpreters only ever see hashes.
+    ///
+    /// Hashing is slow on purpose, so this runs before a request reaches an interpreter rather
+    /// than while one is locked, where it would hold up every other request needing it.
+    pub fn hash_password(self, iterations: u32) -> Result<Statement, ServerError> {
+        match self {
+            Statement::UserAdd(username, level, Some(password)) => {
+                Ok(Statement::UserAdd(username, level, Some(hash_new_password(&password, iterations)?)))
+            },
+            Statement::UserPassword(username, password) => {
+                Ok(Statement::UserPassword(username, hash_new_password(&password, iterations)?))
+            },
+            statement => Ok(statement),
+        }
+    }
+
+    /// Get a copy of the statement that is safe to log, with any password hidden.
+    pub fn redacted(&self) -> Statement {
+        match self {
+            Statement::UserAdd(username, level, Some(_)) => {
+                Statement::UserAdd(username.clone(), *level, Some(REDACTED.to_string()))
+            },
+            Statement::UserPassword(username, _) => Statement::UserPassword(username.clone(), REDACTED.to_string()),
+            statement => statement.clone(),
+        }
+    }
+}
+
+
+/// What passwords are replaced with in logs
+const REDACTED: &str = "[redacted]";
+
+
+/// Get the keys a glob pattern can match: the pattern itself if it has no special characters,
+/// else everything starting with the part before the first one.
+fn pattern_scope(pattern: &str) -> KeyScope<'_> {
+    match pattern.find(['*', '?', '[', '\\']) {
+        Some(index) => KeyScope::Prefix(pattern[..index].to_string()),
+        None => KeyScope::Key(pattern),
+    }
+}
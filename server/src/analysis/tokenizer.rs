@@ -12,6 +12,15 @@ fn is_identifier_char(c: char) -> bool {
     c.is_alphanumeric() | (c == '_')
 }
 
+/// Lowercase a character, leaving it alone if its lowercase form is more than one character
+fn lowercase_char(c: char) -> char {
+    let mut lowercase = c.to_lowercase();
+    match (lowercase.next(), lowercase.next()) {
+        (Some(lowercase), None) => lowercase,
+        _ => c,
+    }
+}
+
 /// See if a character is valid to directly append to the end of a literal value
 fn is_valid_literal_end_char(c: char) -> bool {
     c.is_whitespace() | ";:,]}".contains(c)
@@ -22,6 +31,8 @@ fn is_valid_literal_end_char(c: char) -> bool {
 /// No complicated logic is implemented.
 pub struct Tokenizer {
     command: Vec<char>,
+    /// The command as it was written, so string literals keep their case
+    original: Vec<char>,
     current_index: usize,
     token_start_index: usize,
     error_detected: bool,
@@ -31,10 +42,11 @@ pub struct Tokenizer {
 impl Tokenizer {
     /// Build a new tokenizer
     pub fn new(command: &str) -> Tokenizer {
-        let command = command.to_lowercase();
-        let command = Vec::from_iter(command.chars());
+        let original = Vec::from_iter(command.chars());
+        let command = original.iter().map(|c| lowercase_char(*c)).collect();
         Tokenizer {
             command,
+            original,
             current_index: 0,
             token_start_index: 0,
             error_detected: false,
@@ -188,7 +200,7 @@ impl Tokenizer {
                     ),
                 }
             } else {
-                char_vec.push(next_char);
+                char_vec.push(self.original[self.current_index - 1]);
             }
         }
         if !is_valid_literal_end_char(self.view()) {
@@ -342,6 +354,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_tokenizer_keeps_string_case() {
+        let mut tokenizer = Tokenizer::new("SET Key \"Hello World\";");
+        let tokens: Vec<Token> = tokenizer.tokenize().unwrap().into_iter().map(|token| token.token).collect();
+        assert_eq!(tokens, vec![
+            Token::Set,
+            Token::Identifier(Box::new("key".to_string())),
+            Token::StringValue(Box::new("Hello World".to_string())),
+            Token::Semicolon,
+        ]);
+    }
+
     
     #[test]
     fn test_tokenizer_query_with_float() {
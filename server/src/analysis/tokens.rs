@@ -21,6 +21,7 @@ pub fn get_word_to_token_map() -> HashMap<String, Token> {
         ("vget".to_string(), Token::VectorGet),
         ("vpop".to_string(), Token::VectorPop),
         ("vpush".to_string(), Token::VectorAppend),
+        ("vpushf".to_string(), Token::VectorPrepend),
         ("vlen".to_string(), Token::VectorLength),
         // Map operations
         ("mex".to_string(), Token::MapExists),
@@ -28,6 +29,15 @@ pub fn get_word_to_token_map() -> HashMap<String, Token> {
         ("mset".to_string(), Token::MapSet),
         ("mdel".to_string(), Token::MapDelete),
         ("mlen".to_string(), Token::MapLength),
+        // Ordered key listing
+        ("range".to_string(), Token::Range),
+        ("prefix".to_string(), Token::Prefix),
+        ("limit".to_string(), Token::Limit),
+        // Key discovery
+        ("keys".to_string(), Token::Keys),
+        ("scan".to_string(), Token::Scan),
+        ("match".to_string(), Token::Match),
+        ("count".to_string(), Token::Count),
         // Type keywords
         ("int".to_string(), Token::IntType),
         ("float".to_string(), Token::FloatType),
@@ -35,8 +45,23 @@ pub fn get_word_to_token_map() -> HashMap<String, Token> {
         ("bool".to_string(), Token::BoolType),
         ("vec".to_string(), Token::VectorType),
         ("map".to_string(), Token::MapType),
+        // Transactions
+        ("begin".to_string(), Token::Begin),
+        ("commit".to_string(), Token::Commit),
+        // Optimistic concurrency
+        ("watch".to_string(), Token::Watch),
+        ("cas".to_string(), Token::CompareAndSet),
         // Admin functions
         ("shutdown".to_string(), Token::Shutdown),
+        ("save".to_string(), Token::Save),
+        ("bgsave".to_string(), Token::BackgroundSave),
+        // User management
+        ("user_add".to_string(), Token::UserAdd),
+        ("user_del".to_string(), Token::UserDelete),
+        ("user_passwd".to_string(), Token::UserPassword),
+        ("user_list".to_string(), Token::UserList),
+        // Capability tokens
+        ("cap_issue".to_string(), Token::IssueCapability),
     ])
 }
 
@@ -72,8 +97,30 @@ pub enum Token {
     GetOrNone,
     /// Set only if it doesn't exist
     SetIfNotExists,
+    /// Start a transaction
+    Begin,
+    /// Commit a transaction
+    Commit,
+    /// Check the version of a key
+    Watch,
+    /// Set a value only if its version matches
+    CompareAndSet,
     /// Shut down the server
     Shutdown,
+    /// Save a snapshot
+    Save,
+    /// Save a snapshot in the background
+    BackgroundSave,
+    /// Add a user
+    UserAdd,
+    /// Remove a user
+    UserDelete,
+    /// Change a user's password
+    UserPassword,
+    /// List the users
+    UserList,
+    /// Create a capability token
+    IssueCapability,
     /// Null value
     None,
     /// Beginning of a list
@@ -102,6 +149,8 @@ pub enum Token {
     VectorGet,
     /// Vector appent
     VectorAppend,
+    /// Vector push to the front
+    VectorPrepend,
     /// Map element delete
     MapDelete,
     /// Vector pop
@@ -112,6 +161,20 @@ pub enum Token {
     MapLength,
     /// Check if a key is in a map
     MapExists,
+    /// List keys in a range
+    Range,
+    /// List keys with a prefix
+    Prefix,
+    /// Limit the number of results
+    Limit,
+    /// List keys matching a pattern
+    Keys,
+    /// Visit keys a few at a time
+    Scan,
+    /// Pattern to match keys against
+    Match,
+    /// How many keys to visit at once
+    Count,
     /// What kind of object something is 
     ValueType,
     /// Integer type
@@ -1,42 +1,74 @@
 use std::time::Duration;
-use std::net::{IpAddr, Ipv4Addr};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::collections::HashMap;
 
 use tokio::{self, time};
 use tokio::sync::mpsc::{self, Sender, Receiver};
 
-use server::auth::{AuthenticationService, MockAuthenticator, AuthorizationLevel, AuthenticationResult};
+use server::audit::{self, AuditLog, SharedAuditLog};
+use server::auth::{AccessRules, AuthenticationService, AuthorizationLevel, AuthenticationResult};
+use server::auth::credentials::DEFAULT_ITERATIONS;
+use server::config::ServerConfig;
 use server::error::ServerError;
+use server::io::stream::StreamQuery;
 use server::io::tcp_async::{TcpStreamHandler, StreamRequest, TcpStreamSender};
-use server::storage::hashmap_storage::HashMapStorage;
-use server::analysis::{Interpreter, InterpreterRequest, InterpreterResponse, Parser, Statement, Tokenizer};
-
-
-const CHANNEL_QUEUE_SIZE: usize = 128;
+use server::io::unix_async::UnixStreamHandler;
+use server::persistence;
+use server::storage::{Storage, StorageBackend};
+use server::storage::btree_storage::BTreeMapStorage;
+use server::analysis::{
+    Interpreter, InterpreterRequest, InterpreterResponse, ResponseMode, Statement,
+};
 
 
 type ResponseSender = Sender<Result<InterpreterResponse, ServerError>>;
 type ExecuteRequest = (InterpreterRequest, Option<ResponseSender>);
 type ExecuteSender = Sender<ExecuteRequest>;
 type ExecuteReceiver = Receiver<ExecuteRequest>;
-type AnalysisRequest = (String, AuthorizationLevel, ResponseSender);
+type AnalysisRequest = (StreamQuery, AuthorizationLevel, Option<Arc<AccessRules>>, ResponseMode, ResponseSender);
 type AnalysisSender = Sender<AnalysisRequest>;
 type AnalysisReceiver = Receiver<AnalysisRequest>;
+type SharedAuthenticator = Arc<Mutex<Box<dyn AuthenticationService + Send>>>;
+
+/// Authenticate a request on a blocking thread, since checking a password hash takes long enough
+/// to hold up every other task on an async worker.
+async fn authenticate(authenticator: SharedAuthenticator, headers: HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
+    let authentication = tokio::task::spawn_blocking(move || {
+        let mut authenticator = authenticator.lock().unwrap();
+        authenticator.authenticate(&headers)
+    });
+    match authentication.await {
+        Ok(result) => result,
+        Err(_) => Err(ServerError::InternalError("Authentication service error".to_string())),
+    }
+}
 
 
-fn authenticate(authenticator: Arc<Mutex<MockAuthenticator>>, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
-    let mut authenticator = authenticator.lock().unwrap();
-    authenticator.authenticate(&headers)
+/// The ways clients can connect
+enum Listener {
+    Tcp(TcpStreamHandler),
+    Unix(UnixStreamHandler),
 }
 
+impl Listener {
+    async fn receive_request(&mut self) -> StreamRequest {
+        match self {
+            Listener::Tcp(handler) => handler.receive_request().await,
+            Listener::Unix(handler) => handler.receive_request().await,
+        }
+    }
+}
 
-async fn listen_for_requests(analysis_sender: AnalysisSender) {
-    let authenticator = Arc::new(Mutex::new(MockAuthenticator));
-    let mut stream_handler = TcpStreamHandler::new(IpAddr::V4(Ipv4Addr::new(127, 0,0,1)), 7878).await;
+async fn listen_for_requests(
+    analysis_sender: AnalysisSender,
+    mut stream_handler: Listener,
+    authenticator: SharedAuthenticator,
+    request_timeout: Duration,
+    audit_log: Option<SharedAuditLog>,
+) {
     loop {
         let request = stream_handler.receive_request().await;
-        let StreamRequest {request, headers, sender} = request;
+        let StreamRequest {request, headers, peer, sender} = request;
 
         let request = match request {
             Ok(request) => request,
@@ -45,21 +77,40 @@ async fn listen_for_requests(analysis_sender: AnalysisSender) {
                 continue;
             }
         };
+        // The audit log needs the statements, so parse them here rather than in the analyzer
+        let (request, statements) = match audit_log {
+            Some(_) => match request.into_statements() {
+                Ok(statements) => (Ok(StreamQuery::Statements(statements.clone())), statements),
+                Err(err) => (Err(err), vec![]),
+            },
+            None => (Ok(request), vec![]),
+        };
+        let finish = |response: Result<InterpreterResponse, ServerError>, username: Option<String>| {
+            audit::record(&audit_log, username, peer.clone(), &statements, &response);
+            response
+        };
 
-        let authentication_result = authenticate(Arc::clone(&authenticator), &headers);
-        let (username, authorization)= match authentication_result {
-            Ok(AuthenticationResult::Authenticated(username, level)) => (username, level),
+        let authentication_result = authenticate(Arc::clone(&authenticator), headers.clone()).await;
+        let (username, authorization, access_rules)= match authentication_result {
+            Ok(AuthenticationResult::Authenticated(username, level, access_rules)) => (username, level, access_rules),
             Ok(AuthenticationResult::Unauthenticated) => {
                 let err = Err(ServerError::AuthenticationError("Authentication failed.".to_string()));
-                send_response_to_client(sender, err).await;
+                send_response_to_client(sender, finish(err, None)).await;
                 continue;
             },
             Err(error) => {
                 let err = Err(error);
-                send_response_to_client(sender, err).await;
+                send_response_to_client(sender, finish(err, None)).await;
                 continue;
             },
         };
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                send_response_to_client(sender, finish(Err(err), Some(username))).await;
+                continue;
+            }
+        };
 
         let authorization = match authorization {
             None => {
@@ -67,21 +118,33 @@ async fn listen_for_requests(analysis_sender: AnalysisSender) {
                     format!("User {} not authorized to access this resource.", username)
                 );
                 let err = Err(error);
-                send_response_to_client(sender, err).await;
+                send_response_to_client(sender, finish(err, Some(username))).await;
                 continue;
             },
             Some(auth) => auth,
         };
+        let response_mode = match ResponseMode::from_headers(&headers) {
+            Ok(response_mode) => response_mode,
+            Err(err) => {
+                send_response_to_client(sender, finish(Err(err), Some(username))).await;
+                continue;
+            },
+        };
         let (job_sender,  mut job_receiver) = mpsc::channel(1);
-        let analysis_request = (request, authorization, job_sender);
+        let analysis_request = (request, authorization, access_rules, response_mode, job_sender);
         if let Err(err) = analysis_sender.send(analysis_request).await {
             println!("Error sending job to analyzer. {:?}", err);
-            send_response_to_client(sender, Err(ServerError::InternalError("Error sending job to analyzer.".to_string()))).await;
+            let err = Err(ServerError::InternalError("Error sending job to analyzer.".to_string()));
+            send_response_to_client(sender, finish(err, Some(username))).await;
             continue;
 
         }
-        let response = job_receiver.recv().await.unwrap();
-        send_response_to_client(sender, response).await;
+        let response = match time::timeout(request_timeout, job_receiver.recv()).await {
+            Ok(Some(response)) => response,
+            Ok(None) => Err(ServerError::InternalError("Internal error found.".to_string())),
+            Err(_) => Err(ServerError::InternalError("Command timed out.".to_string())),
+        };
+        send_response_to_client(sender, finish(response, Some(username))).await;
 
 
         println!("Listening for requests");
@@ -102,33 +165,29 @@ async fn send_response(sender: ResponseSender, response: Result<InterpreterRespo
     }
 }
 
-fn process_analyze_request(request: String, authorization: AuthorizationLevel) -> Result<InterpreterRequest, ServerError> {
-    let mut tokenizer = Tokenizer::new(&request);
-    let tokens = tokenizer.tokenize();
-    let tokens = match tokens {
-        Ok(tokens) => tokens,
-        Err(err) => {
-            return Err(err);
-        }
-    };
-    let mut parser = Parser::new(tokens);
-    let statements = parser.parse();
+fn process_analyze_request(
+    request: StreamQuery,
+    authorization: AuthorizationLevel,
+    access_rules: Option<Arc<AccessRules>>,
+    response_mode: ResponseMode,
+) -> Result<InterpreterRequest, ServerError> {
+    let statements = request.into_runnable_statements(DEFAULT_ITERATIONS);
     let statements = match statements {
         Ok(statements) => statements,
         Err(err) => {
             return Err(err);
         }
     };
-    Ok(InterpreterRequest { statements, authorization })
+    Ok(InterpreterRequest { statements, authorization, access_rules, response_mode })
 }
 
 
 async fn analyze_request(mut analyze_receiver: AnalysisReceiver, execute_sender: ExecuteSender) {
     loop {
-        let (request, authorization, sender) = analyze_receiver.recv().await.unwrap();
+        let (request, authorization, access_rules, response_mode, sender) = analyze_receiver.recv().await.unwrap();
         let exec_sender = execute_sender.clone();
         tokio::spawn(async move {
-            let result = process_analyze_request(request, authorization);
+            let result = process_analyze_request(request, authorization, access_rules, response_mode);
             match result {
                 Ok(result) => {
                     let sender_clone = sender.clone();
@@ -157,25 +216,59 @@ fn check_flag(flag: &Arc<Mutex<bool>>) -> bool {
     *flag.lock().unwrap()
 }
 
-async fn execute_requests(mut receiver: ExecuteReceiver, shutdown_flag: Arc<Mutex<bool>>) {
-    let storage = HashMapStorage::new();
-    let mut interpreter = Interpreter::new(storage);
+async fn send_execute_response(
+    sender: Option<ResponseSender>, response: Result<InterpreterResponse, ServerError>
+) {
+    if let Some(sender) = sender {
+        match sender.send(response).await {
+            Ok(_) => (),
+            Err(err) => {
+                println!("Error sending response from executor: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Run a request on a blocking thread, since waiting for the interpreter's lock would hold up
+/// every other task on an async worker.
+async fn interpret<S: Storage + Send + Sync + 'static>(
+    interpreter: Arc<RwLock<Interpreter<S>>>, request: InterpreterRequest
+) -> Result<InterpreterResponse, ServerError> {
+    let response = tokio::task::spawn_blocking(move || {
+        match request.statements.iter().all(Statement::is_read) {
+            true => interpreter.read().unwrap().interpret_read(request),
+            false => interpreter.write().unwrap().interpret(request),
+        }
+    });
+    match response.await {
+        Ok(response) => response,
+        Err(_) => Err(ServerError::InternalError("Interpreter error".to_string())),
+    }
+}
+
+async fn execute_requests<S: Storage + Send + Sync + 'static>(
+    mut receiver: ExecuteReceiver,
+    shutdown_flag: Arc<Mutex<bool>>,
+    interpreter: Interpreter<S>,
+) {
+    let interpreter = Arc::new(RwLock::new(interpreter));
     loop {
         let (request, sender) = receiver.recv().await.unwrap();
-        let response = interpreter.interpret(request);
-        let shutting_down = if let Ok(InterpreterResponse::ShuttingDown) = &response {
-            true
-        } else {
-            false
-        };
-        if let Some(sender) = sender {
-            match sender.send(response).await {
-                Ok(_) => (),
-                Err(err) => {
-                    println!("Error sending response from executor: {:?}", err);
-                }
-            }
+        if request.statements.iter().all(Statement::is_read) {
+            // Reads share the interpreter, so they don't have to wait for each other
+            let interpreter = Arc::clone(&interpreter);
+            tokio::spawn(async move {
+                let response = interpret(interpreter, request).await;
+                send_execute_response(sender, response).await;
+            });
+            continue;
         }
+        let response = interpret(Arc::clone(&interpreter), request).await;
+        let shutting_down = match &response {
+            Ok(response) => response.is_shutting_down(),
+            Err(_) => false,
+        };
+        send_execute_response(sender, response).await;
         if shutting_down {
             println!("Received shutdown signal!");
             reset_flag(&shutdown_flag, true);
@@ -183,37 +276,72 @@ async fn execute_requests(mut receiver: ExecuteReceiver, shutdown_flag: Arc<Mute
     }
 }
 
-async fn expire_old_keys(execute_sender: ExecuteSender) {
+async fn expire_old_keys(execute_sender: ExecuteSender, interval: Duration, calls: usize) {
     loop {
-        time::sleep(Duration::from_millis(100)).await;
-        let request = InterpreterRequest {
-            statements: vec![Statement::ExpireKeys], authorization: AuthorizationLevel::Admin
-        };
-        let sender = None;
-        execute_sender.send((request, sender)).await.unwrap();
+        time::sleep(interval).await;
+        for _ in 0..calls {
+            let request = InterpreterRequest {
+                statements: vec![Statement::ExpireKeys],
+                authorization: AuthorizationLevel::Admin,
+                access_rules: None,
+                response_mode: ResponseMode::Last,
+            };
+            let sender = None;
+            execute_sender.send((request, sender)).await.unwrap();
+        }
     }
 }
 
 
-async fn serve() {
+async fn serve<S: Storage + Send + Sync + 'static>(config: ServerConfig, storage: S) {
+    let users = config.load_users().unwrap();
+    let authenticator = Arc::new(Mutex::new(config.new_authenticator(users.clone()).unwrap()));
     let shutdown_flag = Arc::new(Mutex::new(false));
-    let (execute_sender, execute_receiver) = mpsc::channel(CHANNEL_QUEUE_SIZE);
-    let (analysis_sender, analysis_receiver) = mpsc::channel(CHANNEL_QUEUE_SIZE);
+    let (execute_sender, execute_receiver) = mpsc::channel(config.queue_size);
+    let (analysis_sender, analysis_receiver) = mpsc::channel(config.queue_size);
 
+    let mut interpreter = persistence::restore_interpreter(storage, &config.persistence()).unwrap();
+    if let Some(users) = users {
+        interpreter.set_users(users);
+    }
+    if let Some(signer) = config.capability_signer().unwrap() {
+        interpreter.set_capability_signer(signer);
+    }
+    let audit_log = config.audit().map(|audit| AuditLog::open(audit).unwrap().shared());
     let shutdown_copy = Arc::clone(&shutdown_flag);
     tokio::spawn(async move {
-        execute_requests(execute_receiver, shutdown_copy).await;
+        execute_requests(execute_receiver, shutdown_copy, interpreter).await;
     });
 
     let execute_sender_analyze = execute_sender.clone();
     tokio::spawn(async move {
         analyze_request(analysis_receiver, execute_sender_analyze).await;
     });
+    let (interval, calls) = (config.expiration_interval, config.expiration_calls);
     tokio::spawn(async move {
-        expire_old_keys(execute_sender).await;
+        expire_old_keys(execute_sender, interval, calls).await;
     });
+    if let Some(unix_socket) = config.unix_socket() {
+        let unix_handler = UnixStreamHandler::with_config(unix_socket).await.unwrap();
+        let unix_analysis_sender = analysis_sender.clone();
+        let unix_authenticator = Arc::clone(&authenticator);
+        let request_timeout = config.request_timeout;
+        let unix_audit_log = audit_log.clone();
+        tokio::spawn(async move {
+            listen_for_requests(
+                unix_analysis_sender, Listener::Unix(unix_handler), unix_authenticator, request_timeout, unix_audit_log
+            ).await;
+        });
+    }
     tokio::spawn(async move {
-        listen_for_requests(analysis_sender).await;
+        let (keep_alive, limits) = (config.keep_alive(), config.limits());
+        let stream_handler = match config.tls() {
+            Some(tls) => TcpStreamHandler::with_tls(config.address, config.port, keep_alive, limits, &tls).await.unwrap(),
+            None => TcpStreamHandler::with_limits(config.address, config.port, keep_alive, limits).await,
+        };
+        listen_for_requests(
+            analysis_sender, Listener::Tcp(stream_handler), authenticator, config.request_timeout, audit_log
+        ).await;
     });
     let mut count = 0;
     async {
@@ -231,5 +359,12 @@ async fn serve() {
 
 #[tokio::main]
 async fn main() {
-    tokio::join!(serve());
+    let config = ServerConfig::load();
+    match config.backend {
+        StorageBackend::HashMap => {
+            let storage = config.new_hashmap_storage();
+            serve(config, storage).await
+        },
+        StorageBackend::BTreeMap => serve(config, BTreeMapStorage::new()).await,
+    }
 }
@@ -0,0 +1,313 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::analysis::{InterpreterResponse, Statement};
+use crate::error::ServerError;
+
+
+/// Which requests are written to the audit log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditFilter {
+    /// Every request
+    All,
+    /// Requests that change the storage, the users or the server
+    Mutating,
+    /// Requests with a statement only admins can run
+    Admin,
+}
+
+impl AuditFilter {
+    /// Get a filter from its name: all, mutating or admin
+    pub fn from_name(name: &str) -> Result<AuditFilter, ServerError> {
+        match name.to_lowercase().as_str() {
+            "all" => Ok(AuditFilter::All),
+            "mutating" => Ok(AuditFilter::Mutating),
+            "admin" => Ok(AuditFilter::Admin),
+            _ => Err(ServerError::ConfigError(format!("Unknown audit filter {}.", name))),
+        }
+    }
+
+    /// Check if a request with these statements should be written to the log
+    pub fn accepts(&self, statements: &[Statement]) -> bool {
+        match self {
+            AuditFilter::All => true,
+            AuditFilter::Mutating => statements.iter().any(|statement| {
+                statement.is_write() || (statement.is_admin() && !statement.is_read())
+            }),
+            AuditFilter::Admin => statements.iter().any(Statement::is_admin),
+        }
+    }
+}
+
+
+/// Settings for the audit log
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditConfig {
+    /// Where the log is written
+    pub path: PathBuf,
+    /// Which requests are written
+    pub filter: AuditFilter,
+    /// How big the log can get in bytes before it is rotated
+    pub max_bytes: u64,
+    /// How many rotated logs are kept, as `<path>.1` for the newest up to `<path>.<max_files>`
+    pub max_files: usize,
+}
+
+impl AuditConfig {
+    /// Settings to write every request to a path, rotating at 10mb and keeping 5 old logs
+    pub fn new<P: Into<PathBuf>>(path: P) -> AuditConfig {
+        AuditConfig { path: path.into(), filter: AuditFilter::All, max_bytes: 10 << 20, max_files: 5 }
+    }
+}
+
+
+/// How a request ended
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    /// The request ran
+    Succeeded,
+    /// The client couldn't be authenticated or wasn't allowed to run the request
+    Denied,
+    /// The request was allowed but failed
+    Failed,
+}
+
+impl AuditOutcome {
+    /// Get the outcome of a response
+    pub fn of(response: &Result<InterpreterResponse, ServerError>) -> AuditOutcome {
+        match response {
+            Ok(_) => AuditOutcome::Succeeded,
+            Err(ServerError::AuthenticationError(_)) | Err(ServerError::AuthorizationError(_)) => AuditOutcome::Denied,
+            Err(_) => AuditOutcome::Failed,
+        }
+    }
+}
+
+
+/// A line of the audit log
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the epoch when the request finished
+    pub timestamp_ms: u64,
+    /// Who sent the request, if they were authenticated
+    pub user: Option<String>,
+    /// Where the request came from, if known
+    pub peer: Option<String>,
+    /// The statements in the request, with passwords hidden
+    pub statements: Vec<Statement>,
+    /// How the request ended
+    pub outcome: AuditOutcome,
+    /// The error the request ended with, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// Create a record of a request that has just finished
+    pub fn new(
+        user: Option<String>,
+        peer: Option<String>,
+        statements: &[Statement],
+        response: &Result<InterpreterResponse, ServerError>,
+    ) -> AuditRecord {
+        let timestamp_ms = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_millis() as u64,
+            Err(_) => 0,
+        };
+        AuditRecord {
+            timestamp_ms,
+            user,
+            peer,
+            statements: statements.iter().map(Statement::redacted).collect(),
+            outcome: AuditOutcome::of(response),
+            error: response.as_ref().err().map(|error| error.to_string()),
+        }
+    }
+}
+
+
+/// The audit log shared by every listener
+pub type SharedAuditLog = Arc<Mutex<AuditLog>>;
+
+
+/// A log of who ran what, written as one JSON record per line.
+///
+/// Once the file reaches its size limit it is renamed to `<path>.1`, the older logs move up by
+/// one and the oldest is removed.
+pub struct AuditLog {
+    /// Where and what to write
+    config: AuditConfig,
+    /// The open log file
+    file: File,
+    /// The length of the log in bytes
+    size: u64,
+}
+
+impl AuditLog {
+    /// Open an audit log for appending, creating it if needed
+    pub fn open(config: AuditConfig) -> Result<AuditLog, ServerError> {
+        let file = open_file(&config.path)?;
+        let size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(error) => return Err(make_audit_error(&config.path, error)),
+        };
+        Ok(AuditLog { config, file, size })
+    }
+
+    /// Share the log between listeners
+    pub fn shared(self) -> SharedAuditLog {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Write a request to the log if the filter accepts it
+    pub fn record(
+        &mut self,
+        user: Option<String>,
+        peer: Option<String>,
+        statements: &[Statement],
+        response: &Result<InterpreterResponse, ServerError>,
+    ) -> Result<(), ServerError> {
+        if !self.config.filter.accepts(statements) {
+            return Ok(());
+        }
+        self.write(&AuditRecord::new(user, peer, statements, response))
+    }
+
+    /// Write a record, rotating the log first if it would grow too big
+    pub fn write(&mut self, record: &AuditRecord) -> Result<(), ServerError> {
+        let mut line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(error) => return Err(ServerError::InternalError(format!("Could not write audit record: {}", error))),
+        };
+        line.push('\n');
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_bytes {
+            self.rotate()?;
+        }
+        if let Err(error) = self.file.write_all(line.as_bytes()) {
+            return Err(make_audit_error(&self.config.path, error));
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Move the current log out of the way and start a new one
+    fn rotate(&mut self) -> Result<(), ServerError> {
+        let path = &self.config.path;
+        let _ = fs::remove_file(rotated_path(path, self.config.max_files));
+        for index in (1..self.config.max_files).rev() {
+            let _ = fs::rename(rotated_path(path, index), rotated_path(path, index + 1));
+        }
+        let moved = match self.config.max_files {
+            0 => fs::remove_file(path),
+            _ => fs::rename(path, rotated_path(path, 1)),
+        };
+        if let Err(error) = moved {
+            return Err(make_audit_error(path, error));
+        }
+        self.file = open_file(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+
+/// Write a request to a shared audit log, if there is one, reporting rather than failing on errors
+pub fn record(
+    audit_log: &Option<SharedAuditLog>,
+    user: Option<String>,
+    peer: Option<String>,
+    statements: &[Statement],
+    response: &Result<InterpreterResponse, ServerError>,
+) {
+    if let Some(audit_log) = audit_log {
+        if let Err(error) = audit_log.lock().unwrap().record(user, peer, statements, response) {
+            println!("Error writing to the audit log: {}", error);
+        }
+    }
+}
+
+
+/// Open a log file for appending
+fn open_file(path: &Path) -> Result<File, ServerError> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Ok(file),
+        Err(error) => Err(make_audit_error(path, error)),
+    }
+}
+
+
+/// The path of an old log, counting up from 1 for the newest
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), index))
+}
+
+
+/// Create an error for an audit log that can't be written
+fn make_audit_error(path: &Path, error: std::io::Error) -> ServerError {
+    ServerError::WriteError(format!("Could not write audit log {}: {}", path.display(), error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthorizationLevel;
+    use crate::storage::StorageValue;
+
+    fn set(key: &str) -> Statement {
+        Statement::Set(key.to_string(), StorageValue::Int(1), None)
+    }
+
+    #[test]
+    fn test_filters() {
+        let get = || Statement::Get("a".to_string());
+        assert!(AuditFilter::All.accepts(&[get()]));
+        assert!(!AuditFilter::Mutating.accepts(&[get()]));
+        assert!(AuditFilter::Mutating.accepts(&[get(), set("a")]));
+        assert!(AuditFilter::Mutating.accepts(&[Statement::UserAdd("bob".to_string(), AuthorizationLevel::Read, None)]));
+        assert!(!AuditFilter::Mutating.accepts(&[Statement::UserList]));
+        assert!(!AuditFilter::Admin.accepts(&[set("a")]));
+        assert!(AuditFilter::Admin.accepts(&[Statement::UserList]));
+        assert!(AuditFilter::Admin.accepts(&[Statement::Shutdown]));
+    }
+
+    #[test]
+    fn test_records_and_rotation() {
+        let path = std::env::temp_dir().join(format!("rust_store_audit_{}.log", std::process::id()));
+        let config = AuditConfig { filter: AuditFilter::Mutating, max_bytes: 400, max_files: 2, ..AuditConfig::new(&path) };
+        let mut log = AuditLog::open(config).unwrap();
+        let denied = Err(ServerError::AuthorizationError("Not authorized to run set on key a.".to_string()));
+        let password = Statement::UserPassword("bob".to_string(), "hunter2".to_string());
+        log.record(Some("alice".to_string()), Some("127.0.0.1:5000".to_string()), &[set("a")], &denied).unwrap();
+        log.record(Some("alice".to_string()), None, &[password], &Ok(InterpreterResponse::Null)).unwrap();
+        // Reads are filtered out
+        log.record(None, None, &[Statement::Get("a".to_string())], &Ok(InterpreterResponse::Null)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["user"], "alice");
+        assert_eq!(lines[0]["peer"], "127.0.0.1:5000");
+        assert_eq!(lines[0]["outcome"], "denied");
+        assert_eq!(lines[0]["error"], "AuthorizationError: Not authorized to run set on key a.");
+        assert_eq!(lines[1]["outcome"], "succeeded");
+        assert!(!contents.contains("hunter2"));
+
+        for _ in 0..10 {
+            log.record(None, None, &[set("a")], &Ok(InterpreterResponse::Null)).unwrap();
+        }
+        assert!(fs::metadata(&path).unwrap().len() <= 400);
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        for file in [path.clone(), rotated_path(&path, 1), rotated_path(&path, 2)] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -1,13 +1,35 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 
 use crate::error::ServerError;
 
+/// Signed, short-lived tokens that stand in for a user
+pub mod capabilities;
+/// Hashing passwords and API keys
+pub mod credentials;
+/// Limiting the keys and commands each user can use
+pub mod rules;
+/// Authenticating users listed in a users file
+pub mod users;
+
+pub use capabilities::{Capability, CapabilityAuthenticator, CapabilitySigner};
+pub use rules::AccessRules;
+pub use users::{SharedUsers, User, UserAuthenticator, UserDirectory, UsersFile};
+
+
+/// The header the server puts the subject of a verified client certificate in.
+///
+/// It is dropped from what clients send, so authenticators can trust it.
+pub const CLIENT_CERTIFICATE_HEADER: &str = "Client-Certificate-Subject";
+
 
 /// The result from the authentication service.
 #[derive(Clone, PartialEq, Debug)]
 pub enum AuthenticationResult {
-    /// Authentication passed, return a user id and authorization level
-    Authenticated(String, Option<AuthorizationLevel>),
+    /// Authentication passed, return a user id, authorization level and any rules limiting the user
+    Authenticated(String, Option<AuthorizationLevel>, Option<Arc<AccessRules>>),
     /// Authentication failed - credentials rejected
     Unauthenticated,
 }
@@ -18,8 +40,15 @@ pub trait AuthenticationService {
     fn authenticate(&mut self, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError>;
 }
 
+impl<A: AuthenticationService + ?Sized> AuthenticationService for Box<A> {
+    fn authenticate(&mut self, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
+        (**self).authenticate(headers)
+    }
+}
+
 /// Defines the different kinds of authorizations a user can have.
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AuthorizationLevel {
     /// Administrator level - can run anything
     Admin,
@@ -29,6 +58,27 @@ pub enum AuthorizationLevel {
     Read,
 }
 
+impl AuthorizationLevel {
+    /// Get a level from its name: admin, write or read
+    pub fn from_name(name: &str) -> Result<AuthorizationLevel, ServerError> {
+        match name.to_lowercase().as_str() {
+            "admin" => Ok(AuthorizationLevel::Admin),
+            "write" => Ok(AuthorizationLevel::Write),
+            "read" => Ok(AuthorizationLevel::Read),
+            _ => Err(ServerError::RequestError(format!("Unknown authorization level {}.", name))),
+        }
+    }
+
+    /// The name of the level, as `from_name` reads it
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthorizationLevel::Admin => "admin",
+            AuthorizationLevel::Write => "write",
+            AuthorizationLevel::Read => "read",
+        }
+    }
+}
+
 /// A simple authenticator that just looks for a username field and authenticates based on that.
 /// 
 /// The available usenames are:
@@ -38,11 +88,21 @@ pub enum AuthorizationLevel {
 /// - "read" --> user with read-only privileges
 /// - other name --> unauthorized user
 /// - not present --> generates an internal error
+///
+/// Without a username field, the username from basic authorization is used, and without that the
+/// subject of the client certificate. Passwords are never checked.
 pub struct MockAuthenticator;
 
 impl AuthenticationService for MockAuthenticator {
     fn authenticate(&mut self, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
-        let username = match headers.get("Username") {
+        let basic_username = match users::parse_authorization(headers) {
+            Some(users::Credentials::Basic(username, _)) => Some(username),
+            _ => None,
+        };
+        let username = headers.get("Username")
+            .or(basic_username.as_ref())
+            .or_else(|| headers.get(CLIENT_CERTIFICATE_HEADER));
+        let username = match username {
             Some(username) => username,
             None => {
                 return Err(ServerError::InternalError("Authentication service error".to_string()))
@@ -50,10 +110,10 @@ impl AuthenticationService for MockAuthenticator {
         };
         match &username[..] {
             "unauthenticated" => Ok(AuthenticationResult::Unauthenticated),
-            "admin" => Ok(AuthenticationResult::Authenticated("admin".to_string(), Some(AuthorizationLevel::Admin))),
-            "write" => Ok(AuthenticationResult::Authenticated("write".to_string(), Some(AuthorizationLevel::Write))),
-            "read" => Ok(AuthenticationResult::Authenticated("read".to_string(), Some(AuthorizationLevel::Read))),
-            username => Ok(AuthenticationResult::Authenticated(username.to_string(), None)),
+            "admin" => Ok(AuthenticationResult::Authenticated("admin".to_string(), Some(AuthorizationLevel::Admin), None)),
+            "write" => Ok(AuthenticationResult::Authenticated("write".to_string(), Some(AuthorizationLevel::Write), None)),
+            "read" => Ok(AuthenticationResult::Authenticated("read".to_string(), Some(AuthorizationLevel::Read), None)),
+            username => Ok(AuthenticationResult::Authenticated(username.to_string(), None, None)),
         }
     }
 }
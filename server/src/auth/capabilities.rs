@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{AccessRules, AuthenticationResult, AuthenticationService};
+use crate::analysis::interpreter::Privileges;
+use crate::error::ServerError;
+
+
+/// The longest a capability token can last, in seconds
+pub const MAX_CAPABILITY_LIFETIME: u64 = 24 * 60 * 60;
+/// The username requests made with a capability token run as
+pub const CAPABILITY_USERNAME: &str = "capability";
+/// Shortest secret tokens can be signed with, in bytes
+pub const MIN_SECRET_LENGTH: usize = 16;
+/// Commands only admins can run. None of them is about a key, so key prefixes can't limit them.
+const ADMIN_COMMANDS: [&str; 8] = [
+    "shutdown", "save", "bgsave", "user_add", "user_del", "user_passwd", "user_list", "cap_issue",
+];
+
+
+/// What a capability token lets its holder do
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Capability {
+    /// The privileges the token gives
+    pub privileges: Privileges,
+    /// When the token stops working, in seconds since the Unix epoch
+    pub expires_at: u64,
+    /// Prefixes of the keys the token can touch. Empty allows every key.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<String>,
+}
+
+impl Capability {
+    /// The rules limiting the token to its key prefixes, if it has any.
+    ///
+    /// An admin token with prefixes can still manage every key under them, but not run the
+    /// commands that reach past them, like shutting down the server or issuing more tokens.
+    pub fn access_rules(&self) -> Option<AccessRules> {
+        if self.prefixes.is_empty() {
+            return None;
+        }
+        let patterns = self.prefixes.iter().map(|prefix| format!("{}*", escape_pattern(prefix))).collect();
+        match self.privileges {
+            Privileges::Read => Some(AccessRules { read: patterns, ..AccessRules::default() }),
+            Privileges::Admin => {
+                let deny_commands = ADMIN_COMMANDS.iter().map(|command| command.to_string()).collect();
+                Some(AccessRules { write: patterns, deny_commands, ..AccessRules::default() })
+            },
+            _ => Some(AccessRules { write: patterns, ..AccessRules::default() }),
+        }
+    }
+}
+
+
+/// Signs and checks capability tokens with a secret only the server knows.
+///
+/// A token is `<payload>.<signature>`: the capability as JSON, then an HMAC-SHA256 of it, both in
+/// URL-safe base64. Anyone can read a token but only the server can make one.
+#[derive(Clone)]
+pub struct CapabilitySigner {
+    /// The key for the HMAC
+    secret: Vec<u8>,
+}
+
+impl CapabilitySigner {
+    /// Create a signer for a secret
+    pub fn new(secret: &[u8]) -> Result<CapabilitySigner, ServerError> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(ServerError::ConfigError(
+                format!("Capability secrets need at least {} bytes.", MIN_SECRET_LENGTH)
+            ));
+        }
+        Ok(CapabilitySigner { secret: secret.to_vec() })
+    }
+
+    /// Create a token lasting for a number of seconds from now
+    pub fn issue(&self, privileges: Privileges, lifetime: u64, prefixes: Vec<String>) -> Result<String, ServerError> {
+        if lifetime == 0 || lifetime > MAX_CAPABILITY_LIFETIME {
+            return Err(ServerError::RequestError(
+                format!("Capability tokens last from 1 to {} seconds.", MAX_CAPABILITY_LIFETIME)
+            ));
+        }
+        self.sign(&Capability { privileges, expires_at: now() + lifetime, prefixes })
+    }
+
+    /// Create a token for a capability
+    pub fn sign(&self, capability: &Capability) -> Result<String, ServerError> {
+        let payload = match serde_json::to_vec(capability) {
+            Ok(payload) => payload,
+            Err(error) => return Err(ServerError::InternalError(format!("Could not write capability: {}", error))),
+        };
+        let signature = self.mac(&payload).finalize().into_bytes();
+        Ok(format!(
+            "{}.{}",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD),
+        ))
+    }
+
+    /// Check a token was signed with this secret and hasn't expired, giving back its capability
+    pub fn verify(&self, token: &str) -> Option<Capability> {
+        self.verify_at(token, now())
+    }
+
+    /// Check a token as of a time in seconds since the Unix epoch
+    fn verify_at(&self, token: &str, now: u64) -> Option<Capability> {
+        let (payload, signature) = token.trim().split_once('.')?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        // Compares in constant time
+        self.mac(&payload).verify_slice(&signature).ok()?;
+        let capability: Capability = serde_json::from_slice(&payload).ok()?;
+        match capability.expires_at > now {
+            true => Some(capability),
+            false => None,
+        }
+    }
+
+    /// Start an HMAC of some data
+    fn mac(&self, data: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(data);
+        mac
+    }
+}
+
+
+/// Accepts capability tokens sent in the `Capability` header, leaving any other request to
+/// another authenticator.
+///
+/// Requests with a token that is forged or expired are unauthenticated, even if they also carry
+/// other credentials.
+pub struct CapabilityAuthenticator<A: AuthenticationService> {
+    /// Authenticates requests without a token
+    inner: A,
+    /// Checks tokens
+    signer: CapabilitySigner,
+}
+
+impl<A: AuthenticationService> CapabilityAuthenticator<A> {
+    /// The header clients send tokens in
+    pub const HEADER: &'static str = "Capability";
+
+    /// Accept tokens from a signer on top of another authenticator
+    pub fn new(inner: A, signer: CapabilitySigner) -> CapabilityAuthenticator<A> {
+        CapabilityAuthenticator { inner, signer }
+    }
+}
+
+impl<A: AuthenticationService> AuthenticationService for CapabilityAuthenticator<A> {
+    fn authenticate(&mut self, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
+        let token = match headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(Self::HEADER)) {
+            Some((_, token)) => token,
+            None => return self.inner.authenticate(headers),
+        };
+        match self.signer.verify(token) {
+            Some(capability) => Ok(AuthenticationResult::Authenticated(
+                CAPABILITY_USERNAME.to_string(),
+                capability.privileges.level(),
+                capability.access_rules().map(Arc::new),
+            )),
+            None => Ok(AuthenticationResult::Unauthenticated),
+        }
+    }
+}
+
+
+/// Get the current time in seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+
+/// Escape the characters that mean something in a glob pattern
+fn escape_pattern(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Statement;
+    use crate::storage::StorageValue;
+    use crate::auth::{AuthorizationLevel, MockAuthenticator};
+
+    fn signer() -> CapabilitySigner {
+        CapabilitySigner::new(b"a secret of enough bytes").unwrap()
+    }
+
+    #[test]
+    fn test_tokens() {
+        let signer = signer();
+        let capability = Capability { privileges: Privileges::Read, expires_at: 1000, prefixes: vec!["a*".to_string()] };
+        let token = signer.sign(&capability).unwrap();
+        assert_eq!(signer.verify_at(&token, 999), Some(capability.clone()));
+        assert_eq!(signer.verify_at(&token, 1000), None);
+        // Another secret, or a changed payload, breaks the signature
+        let other = CapabilitySigner::new(b"another secret of enough bytes").unwrap();
+        assert_eq!(other.verify_at(&token, 0), None);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = Capability { privileges: Privileges::Admin, ..capability.clone() };
+        let forged_payload = base64::encode_config(serde_json::to_vec(&forged).unwrap(), base64::URL_SAFE_NO_PAD);
+        assert_eq!(signer.verify_at(&format!("{}.{}", forged_payload, signature), 0), None);
+        assert_eq!(signer.verify_at("nonsense", 0), None);
+
+        // Prefixes are taken literally
+        let rules = capability.access_rules().unwrap();
+        assert!(rules.check(&Statement::Get("a*b".to_string())).is_ok());
+        assert!(rules.check(&Statement::Get("ab".to_string())).is_err());
+        assert!(CapabilitySigner::new(b"short").is_err());
+        assert!(signer.issue(Privileges::Read, MAX_CAPABILITY_LIFETIME + 1, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_admin_tokens_with_prefixes() {
+        let capability = Capability { privileges: Privileges::Admin, expires_at: 1000, prefixes: vec!["a".to_string()] };
+        let rules = capability.access_rules().unwrap();
+        assert!(rules.check(&Statement::Set("ab".to_string(), StorageValue::Int(1), None)).is_ok());
+        let issue = Statement::IssueCapability(Privileges::Admin, 60, vec![]);
+        for statement in [Statement::Shutdown, Statement::Save, Statement::UserList, issue] {
+            assert!(statement.is_admin());
+            assert!(matches!(rules.check(&statement), Err(ServerError::AuthorizationError(_))));
+        }
+        // Without prefixes the token is a plain admin
+        let capability = Capability { prefixes: vec![], ..capability };
+        assert_eq!(capability.access_rules(), None);
+    }
+
+    #[test]
+    fn test_capability_authenticator() {
+        let signer = signer();
+        let mut authenticator = CapabilityAuthenticator::new(MockAuthenticator, signer.clone());
+        let token = signer.issue(Privileges::Write, 60, vec!["jobs:".to_string()]).unwrap();
+        let headers = HashMap::from([("capability".to_string(), token)]);
+        match authenticator.authenticate(&headers).unwrap() {
+            AuthenticationResult::Authenticated(username, level, Some(rules)) => {
+                assert_eq!(username, CAPABILITY_USERNAME);
+                assert_eq!(level, Some(AuthorizationLevel::Write));
+                assert_eq!(rules.write, vec!["jobs:*".to_string()]);
+            },
+            other => panic!("Expected a capability, got {:?}", other),
+        }
+        let forged = HashMap::from([("Capability".to_string(), "abc.def".to_string())]);
+        assert_eq!(authenticator.authenticate(&forged).unwrap(), AuthenticationResult::Unauthenticated);
+        // Without a token the other authenticator decides
+        let read = HashMap::from([("Username".to_string(), "read".to_string())]);
+        assert_eq!(
+            authenticator.authenticate(&read).unwrap(),
+            AuthenticationResult::Authenticated("read".to_string(), Some(AuthorizationLevel::Read), None)
+        );
+    }
+}
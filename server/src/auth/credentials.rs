@@ -0,0 +1,148 @@
+use hmac::Hmac;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::error::ServerError;
+
+
+/// The name of the hashing scheme, written at the start of every password hash
+const SCHEME: &str = "pbkdf2-sha256";
+/// How many PBKDF2 rounds new password hashes use
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+/// Length of the random salt in bytes
+const SALT_LENGTH: usize = 16;
+/// Length of a password hash in bytes
+const HASH_LENGTH: usize = 32;
+/// Length of a generated API key in bytes, before encoding
+const API_KEY_LENGTH: usize = 32;
+
+
+/// Hash a password with a random salt, in the form `pbkdf2-sha256$<iterations>$<salt>$<hash>`
+pub fn hash_password(password: &str) -> String {
+    hash_password_with_iterations(password, DEFAULT_ITERATIONS)
+}
+
+
+/// Hash a password with a random salt and a given number of rounds
+pub fn hash_password_with_iterations(password: &str, iterations: u32) -> String {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = derive(password, &salt, iterations);
+    format!("{}${}${}${}", SCHEME, iterations, base64::encode(salt), base64::encode(hash))
+}
+
+
+/// Hash a password a user chose, which can't be empty
+pub fn hash_new_password(password: &str, iterations: u32) -> Result<String, ServerError> {
+    if password.is_empty() {
+        return Err(ServerError::RequestError("Passwords can't be empty.".to_string()));
+    }
+    Ok(hash_password_with_iterations(password, iterations))
+}
+
+
+/// Check a password against a hash made by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> Result<bool, ServerError> {
+    let (iterations, salt, expected) = parse_password_hash(password_hash)?;
+    let hash = derive(password, &salt, iterations);
+    Ok(constant_time_eq(&hash, &expected))
+}
+
+
+/// Check a password hash is in the form `hash_password` writes
+pub fn check_password_hash(password_hash: &str) -> Result<(), ServerError> {
+    parse_password_hash(password_hash).map(|_| ())
+}
+
+
+/// Create a new random API key
+pub fn generate_api_key() -> String {
+    let mut key = [0u8; API_KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut key);
+    to_hex(&key)
+}
+
+
+/// Hash an API key for storing. Keys are long and random, so a single round of SHA-256 is enough.
+pub fn hash_api_key(key: &str) -> String {
+    to_hex(&Sha256::digest(key.as_bytes()))
+}
+
+
+/// Compare two byte strings in a time that doesn't depend on where they differ
+pub fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    left.iter().zip(right).fold(0, |difference, (left, right)| difference | (left ^ right)) == 0
+}
+
+
+/// Run PBKDF2 over a password
+fn derive(password: &str, salt: &[u8], iterations: u32) -> [u8; HASH_LENGTH] {
+    let mut hash = [0u8; HASH_LENGTH];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut hash);
+    hash
+}
+
+
+/// Split a password hash into its rounds, salt and hash
+fn parse_password_hash(password_hash: &str) -> Result<(u32, Vec<u8>, Vec<u8>), ServerError> {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    let (iterations, salt, hash) = match parts[..] {
+        [SCHEME, iterations, salt, hash] => (iterations, salt, hash),
+        _ => return Err(make_hash_error()),
+    };
+    let iterations = match iterations.parse() {
+        Ok(iterations) if iterations > 0 => iterations,
+        _ => return Err(make_hash_error()),
+    };
+    match (base64::decode(salt), base64::decode(hash)) {
+        (Ok(salt), Ok(hash)) if hash.len() == HASH_LENGTH => Ok((iterations, salt, hash)),
+        _ => Err(make_hash_error()),
+    }
+}
+
+
+/// Write bytes as lowercase hexadecimal
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// Create an error for password hashes that can't be read
+fn make_hash_error() -> ServerError {
+    ServerError::InternalError(format!("Password hashes must look like {}$<iterations>$<salt>$<hash>.", SCHEME))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hashes() {
+        let hash = hash_password_with_iterations("hunter2", 10);
+        assert!(hash.starts_with("pbkdf2-sha256$10$"));
+        assert!(verify_password("hunter2", &hash).unwrap());
+        assert!(!verify_password("hunter3", &hash).unwrap());
+        // Every hash gets its own salt
+        assert_ne!(hash, hash_password_with_iterations("hunter2", 10));
+        assert!(verify_password("hunter2", "md5$abc").is_err());
+        assert!(check_password_hash("pbkdf2-sha256$0$AAAA$AAAA").is_err());
+        assert!(matches!(hash_new_password("", 10), Err(ServerError::RequestError(_))));
+        assert!(check_password_hash(&hash_new_password("hunter2", 10).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let key = generate_api_key();
+        assert_eq!(key.len(), API_KEY_LENGTH * 2);
+        assert_ne!(key, generate_api_key());
+        assert_eq!(hash_api_key(&key), hash_api_key(&key));
+        assert_eq!(
+            hash_api_key("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
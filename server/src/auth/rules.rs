@@ -0,0 +1,200 @@
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::interpreter::Privileges;
+use crate::analysis::pattern::glob_match;
+use crate::analysis::statements::{KeyScope, Statement};
+use crate::error::ServerError;
+
+
+/// Limits on the keys and commands a user can use, on top of their authorization level.
+///
+/// Key patterns are globs like `metrics:*`. `read` patterns allow reading the keys they match and
+/// `write` patterns allow reading and writing them. Once there is any key pattern, keys that
+/// none match are off limits; rules without key patterns only limit commands.
+///
+/// Commands are named by their keyword, like `get` or `keys`. Denied commands always fail, and if
+/// any commands are allowed then every other command fails too.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccessRules {
+    /// Patterns of keys that can be read
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub read: Vec<String>,
+    /// Patterns of keys that can be read and written
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub write: Vec<String>,
+    /// The only commands that can be run, unless empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow_commands: Vec<String>,
+    /// Commands that can't be run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deny_commands: Vec<String>,
+}
+
+impl AccessRules {
+    /// Check the rules allow a statement, naming the command and key if they don't
+    pub fn check(&self, statement: &Statement) -> Result<(), ServerError> {
+        if let Statement::Null = statement {
+            return Ok(());
+        }
+        let command = statement.command_name();
+        if !self.allows_command(command) {
+            return Err(ServerError::AuthorizationError(
+                format!("Not authorized to run {}.", command)
+            ));
+        }
+        if self.read.is_empty() && self.write.is_empty() {
+            return Ok(());
+        }
+        let (key, allowed) = match statement.key_scope() {
+            KeyScope::None => return Ok(()),
+            KeyScope::Key(key) => {
+                let allowed = self.key_patterns(statement).any(|pattern| glob_match(pattern, key));
+                (key.to_string(), allowed)
+            },
+            KeyScope::Prefix(prefix) => {
+                let allowed = self.key_patterns(statement).any(|pattern| covers_prefix(pattern, &prefix));
+                (format!("{}*", prefix), allowed)
+            },
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ServerError::AuthorizationError(
+                format!("Not authorized to run {} on key {}.", command, key)
+            ))
+        }
+    }
+
+    /// Check that a capability token with these privileges and key prefixes gives no more than
+    /// the rules do.
+    ///
+    /// Tokens can't carry command limits, so users limited to some commands can't issue any, and
+    /// users limited to some keys have to limit the token to prefixes their patterns cover.
+    pub fn check_capability(&self, privileges: Privileges, prefixes: &[String]) -> Result<(), ServerError> {
+        if !self.allow_commands.is_empty() || !self.deny_commands.is_empty() {
+            return Err(ServerError::AuthorizationError(
+                "Users limited to some commands can't issue capability tokens.".to_string()
+            ));
+        }
+        if self.read.is_empty() && self.write.is_empty() {
+            return Ok(());
+        }
+        if prefixes.is_empty() {
+            return Err(ServerError::AuthorizationError(
+                "Users limited to some keys can only issue capability tokens with key prefixes.".to_string()
+            ));
+        }
+        for prefix in prefixes {
+            let mut patterns: Box<dyn Iterator<Item = &String>> = match privileges {
+                Privileges::Read => Box::new(self.read.iter().chain(self.write.iter())),
+                _ => Box::new(self.write.iter()),
+            };
+            if !patterns.any(|pattern| covers_prefix(pattern, prefix)) {
+                return Err(ServerError::AuthorizationError(
+                    format!("Not authorized to issue a capability token for keys {}*.", prefix)
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a command is allowed
+    fn allows_command(&self, command: &str) -> bool {
+        let denied = self.deny_commands.iter().any(|denied| denied.eq_ignore_ascii_case(command));
+        let allowed = self.allow_commands.is_empty() ||
+            self.allow_commands.iter().any(|allowed| allowed.eq_ignore_ascii_case(command));
+        allowed && !denied
+    }
+
+    /// The key patterns that allow a statement: write patterns for writes, any pattern for the rest
+    fn key_patterns<'a>(&'a self, statement: &Statement) -> Box<dyn Iterator<Item = &'a String> + 'a> {
+        match statement.is_write() {
+            true => Box::new(self.write.iter()),
+            false => Box::new(self.read.iter().chain(self.write.iter())),
+        }
+    }
+}
+
+
+/// Check if a pattern matches every key starting with a prefix.
+///
+/// That holds when it matches the prefix itself and ends with a star, which then also swallows
+/// whatever follows the prefix.
+fn covers_prefix(pattern: &str, prefix: &str) -> bool {
+    pattern.ends_with('*') && !pattern.ends_with("\\*") && glob_match(pattern, prefix)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageValue;
+
+    fn rules() -> AccessRules {
+        AccessRules {
+            read: vec!["metrics:*".to_string()],
+            write: vec!["team_a:*".to_string()],
+            allow_commands: vec![],
+            deny_commands: vec!["keys".to_string()],
+        }
+    }
+
+    fn set(key: &str) -> Statement {
+        Statement::Set(key.to_string(), StorageValue::Int(1), None)
+    }
+
+    #[test]
+    fn test_key_rules() {
+        let rules = rules();
+        assert!(rules.check(&Statement::Get("metrics:cpu".to_string())).is_ok());
+        assert!(rules.check(&Statement::Get("team_a:x".to_string())).is_ok());
+        assert!(rules.check(&set("team_a:x")).is_ok());
+        assert!(matches!(
+            rules.check(&set("metrics:cpu")),
+            Err(ServerError::AuthorizationError(message)) if message == "Not authorized to run set on key metrics:cpu."
+        ));
+        assert!(rules.check(&Statement::Get("team_b:x".to_string())).is_err());
+        assert!(rules.check(&Statement::Begin).is_ok());
+    }
+
+    #[test]
+    fn test_listing_rules() {
+        let rules = rules();
+        assert!(rules.check(&Statement::Prefix("metrics:".to_string(), None)).is_ok());
+        assert!(rules.check(&Statement::Prefix("metrics:cpu".to_string(), None)).is_ok());
+        assert!(rules.check(&Statement::Prefix("metrics".to_string(), None)).is_err());
+        assert!(rules.check(&Statement::Scan(0, Some("team_a:*".to_string()), None)).is_ok());
+        assert!(rules.check(&Statement::Scan(0, None, None)).is_err());
+        assert!(rules.check(&Statement::Range("team_a:a".to_string(), "team_a:z".to_string(), None)).is_ok());
+        assert!(rules.check(&Statement::Range("team_a:a".to_string(), "team_b".to_string(), None)).is_err());
+        assert!(matches!(
+            rules.check(&Statement::Keys("metrics:*".to_string())),
+            Err(ServerError::AuthorizationError(message)) if message == "Not authorized to run keys."
+        ));
+    }
+
+    #[test]
+    fn test_capability_rules() {
+        let key_rules = AccessRules { deny_commands: vec![], ..rules() };
+        let prefixes = |prefixes: &[&str]| prefixes.iter().map(|prefix| prefix.to_string()).collect::<Vec<_>>();
+        assert!(key_rules.check_capability(Privileges::Write, &prefixes(&["team_a:jobs:"])).is_ok());
+        assert!(key_rules.check_capability(Privileges::Read, &prefixes(&["metrics:", "team_a:"])).is_ok());
+        assert!(key_rules.check_capability(Privileges::Write, &prefixes(&["team_a:", "metrics:"])).is_err());
+        assert!(key_rules.check_capability(Privileges::Admin, &prefixes(&["team_a"])).is_err());
+        assert!(matches!(
+            key_rules.check_capability(Privileges::Admin, &[]), Err(ServerError::AuthorizationError(_))
+        ));
+        assert!(AccessRules::default().check_capability(Privileges::Admin, &[]).is_ok());
+        assert!(rules().check_capability(Privileges::Read, &prefixes(&["metrics:"])).is_err());
+    }
+
+    #[test]
+    fn test_command_rules() {
+        let rules = AccessRules { allow_commands: vec!["get".to_string(), "SET".to_string()], ..AccessRules::default() };
+        assert!(rules.check(&Statement::Get("anything".to_string())).is_ok());
+        assert!(rules.check(&set("anything")).is_ok());
+        assert!(rules.check(&Statement::Delete("anything".to_string())).is_err());
+        assert!(rules.check(&Statement::Null).is_ok());
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{AccessRules, AuthenticationResult, AuthenticationService, AuthorizationLevel, CLIENT_CERTIFICATE_HEADER};
+use super::credentials::{check_password_hash, constant_time_eq, hash_api_key, verify_password};
+use crate::error::ServerError;
+
+
+/// A user that can connect to the server
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct User {
+    /// Name the user logs in with
+    pub username: String,
+    /// What the user is allowed to do
+    pub level: AuthorizationLevel,
+    /// Salted hash of the password, see `credentials::hash_password`. Users without one can only use API keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    /// Hashes of the API keys the user can log in with, see `credentials::hash_api_key`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub api_key_hashes: Vec<String>,
+    /// Limits on the keys and commands the user can use, beyond their level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<AccessRules>,
+}
+
+
+/// The contents of a users file.
+///
+/// The file is JSON, like
+/// `{"users": [{"username": "alice", "level": "admin", "password_hash": "pbkdf2-sha256$..."}]}`.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct UsersFile {
+    /// Everyone who can connect
+    pub users: Vec<User>,
+}
+
+impl UsersFile {
+    /// Read a users file
+    pub fn load(path: &Path) -> Result<UsersFile, ServerError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => return Err(make_users_file_error(path, error.to_string())),
+        };
+        match serde_json::from_str(&contents) {
+            Ok(users) => Ok(users),
+            Err(error) => Err(make_users_file_error(path, error.to_string())),
+        }
+    }
+
+    /// Write a users file, replacing it as a whole so readers never see half of it
+    pub fn save(&self, path: &Path) -> Result<(), ServerError> {
+        let contents = match serde_json::to_string_pretty(self) {
+            Ok(contents) => contents,
+            Err(error) => return Err(make_users_file_error(path, error.to_string())),
+        };
+        let temporary = path.with_extension("tmp");
+        if let Err(error) = std::fs::write(&temporary, contents).and_then(|_| std::fs::rename(&temporary, path)) {
+            return Err(make_users_file_error(path, error.to_string()));
+        }
+        Ok(())
+    }
+}
+
+
+/// Users shared between the authenticator and the interpreters that manage them
+pub type SharedUsers = Arc<RwLock<UserDirectory>>;
+
+
+/// Everyone who can connect, which can change while the server runs.
+///
+/// Changes are written straight back to the users file, if there is one, so they survive
+/// restarts without passwords ever reaching the command log.
+pub struct UserDirectory {
+    /// Users by name
+    users: HashMap<String, User>,
+    /// Usernames by the hash of each of their API keys
+    api_keys: HashMap<String, String>,
+    /// Where changes are saved, if anywhere
+    path: Option<PathBuf>,
+}
+
+impl UserDirectory {
+    /// Create a directory for a list of users, kept in memory only
+    pub fn new(users: Vec<User>) -> Result<UserDirectory, ServerError> {
+        let mut directory = UserDirectory {
+            users: HashMap::new(),
+            api_keys: HashMap::new(),
+            path: None,
+        };
+        let mut by_name = HashMap::new();
+        for user in users {
+            if by_name.contains_key(&user.username) {
+                return Err(ServerError::InternalError(format!("User {} is listed more than once.", user.username)));
+            }
+            if let Some(password_hash) = &user.password_hash {
+                check_password_hash(password_hash)?;
+            }
+            by_name.insert(user.username.clone(), user);
+        }
+        directory.replace(by_name);
+        Ok(directory)
+    }
+
+    /// Create a directory for the users in a users file, saving any changes back to it
+    pub fn from_file(path: &Path) -> Result<UserDirectory, ServerError> {
+        let mut directory = UserDirectory::new(UsersFile::load(path)?.users)?;
+        directory.path = Some(path.to_path_buf());
+        Ok(directory)
+    }
+
+    /// Share the directory between threads
+    pub fn shared(self) -> SharedUsers {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Get a user by name
+    pub fn get(&self, username: &str) -> Option<&User> {
+        self.users.get(username)
+    }
+
+    /// Get the user an API key belongs to
+    pub fn find_by_api_key(&self, key: &str) -> Option<&User> {
+        let username = self.api_keys.get(&hash_api_key(key))?;
+        self.users.get(username)
+    }
+
+    /// Every user, sorted by name
+    pub fn list(&self) -> Vec<&User> {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by(|left, right| left.username.cmp(&right.username));
+        users
+    }
+
+    /// Add a new user, with the hash of a password if they are to log in with one.
+    ///
+    /// Passwords are hashed before they get here, see `credentials::hash_new_password`, since
+    /// hashing is slow and the directory is locked while it changes.
+    pub fn add(
+        &mut self, username: &str, level: AuthorizationLevel, password_hash: Option<&str>
+    ) -> Result<(), ServerError> {
+        check_username(username)?;
+        if self.users.contains_key(username) {
+            return Err(ServerError::ConflictError(format!("User {} already exists.", username)));
+        }
+        if let Some(password_hash) = password_hash {
+            check_password_hash(password_hash)?;
+        }
+        let mut users = self.users.clone();
+        users.insert(
+            username.to_string(),
+            User {
+                username: username.to_string(),
+                level,
+                password_hash: password_hash.map(str::to_string),
+                api_key_hashes: vec![],
+                rules: None,
+            },
+        );
+        self.save_and_replace(users)
+    }
+
+    /// Remove a user, keeping at least one admin
+    pub fn delete(&mut self, username: &str) -> Result<(), ServerError> {
+        let user = self.get_existing(username)?;
+        let admins = self.users.values().filter(|user| user.level == AuthorizationLevel::Admin).count();
+        if user.level == AuthorizationLevel::Admin && admins == 1 {
+            return Err(ServerError::ConflictError(format!("User {} is the last admin.", username)));
+        }
+        let mut users = self.users.clone();
+        users.remove(username);
+        self.save_and_replace(users)
+    }
+
+    /// Change a user's password to one already hashed
+    pub fn set_password(&mut self, username: &str, password_hash: &str) -> Result<(), ServerError> {
+        self.get_existing(username)?;
+        check_password_hash(password_hash)?;
+        let mut users = self.users.clone();
+        if let Some(user) = users.get_mut(username) {
+            user.password_hash = Some(password_hash.to_string());
+        }
+        self.save_and_replace(users)
+    }
+
+    /// Get a user that has to exist
+    fn get_existing(&self, username: &str) -> Result<&User, ServerError> {
+        match self.users.get(username) {
+            Some(user) => Ok(user),
+            None => Err(ServerError::KeyError(format!("No user {}.", username))),
+        }
+    }
+
+    /// Write the changed users to the users file, then start using them.
+    ///
+    /// If the file can't be written nothing changes, so memory and disk never disagree.
+    fn save_and_replace(&mut self, users: HashMap<String, User>) -> Result<(), ServerError> {
+        if let Some(path) = &self.path {
+            let mut list: Vec<User> = users.values().cloned().collect();
+            list.sort_by(|left, right| left.username.cmp(&right.username));
+            UsersFile { users: list }.save(path)?;
+        }
+        self.replace(users);
+        Ok(())
+    }
+
+    /// Start using a new set of users
+    fn replace(&mut self, users: HashMap<String, User>) {
+        self.api_keys = users.values()
+            .flat_map(|user| user.api_key_hashes.iter().map(|key_hash| (key_hash.clone(), user.username.clone())))
+            .collect();
+        self.users = users;
+    }
+}
+
+
+/// Authenticates users from a user directory, usually read from a users file.
+///
+/// Clients send either `Authorization: Basic <base64 of username:password>` or
+/// `Authorization: Bearer <api key>`. Requests without valid credentials are unauthenticated.
+/// Clients that connected with a verified certificate and sent no credentials are the user
+/// named by the certificate's subject.
+/// Changes to the directory apply to the next request.
+pub struct UserAuthenticator {
+    /// The users that can log in
+    users: SharedUsers,
+    /// Digests of passwords that have already been checked, so PBKDF2 only runs once per user
+    verified: HashMap<String, [u8; 32]>,
+    /// Random secret mixed into the digests above, so they are useless outside this process
+    cache_secret: [u8; 32],
+}
+
+impl UserAuthenticator {
+    /// Create an authenticator for the users in a directory
+    pub fn new(users: SharedUsers) -> UserAuthenticator {
+        let mut cache_secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut cache_secret);
+        UserAuthenticator { users, verified: HashMap::new(), cache_secret }
+    }
+
+    /// Create an authenticator for the users in a users file
+    pub fn from_file(path: &Path) -> Result<UserAuthenticator, ServerError> {
+        Ok(UserAuthenticator::new(UserDirectory::from_file(path)?.shared()))
+    }
+
+    /// Check a username and password, giving back who logged in
+    fn authenticate_password(&mut self, username: &str, password: &str) -> Result<Option<AuthenticationResult>, ServerError> {
+        let users = Arc::clone(&self.users);
+        let users = users.read().unwrap();
+        let (user, password_hash) = match users.get(username) {
+            Some(user @ User { password_hash: Some(password_hash), .. }) => (user, password_hash),
+            _ => return Ok(None),
+        };
+        // The hash is part of the digest, so changing the password forgets the old one
+        let digest = self.cache_digest(username, password, password_hash);
+        let verified = match self.verified.get(username) {
+            Some(known) => constant_time_eq(known, &digest),
+            None => false,
+        };
+        if !verified {
+            if !verify_password(password, password_hash)? {
+                return Ok(None);
+            }
+            self.verified.insert(username.to_string(), digest);
+        }
+        Ok(Some(authenticated(user)))
+    }
+
+    /// Check an API key, giving back who it belongs to
+    fn authenticate_api_key(&self, key: &str) -> Option<AuthenticationResult> {
+        let users = self.users.read().unwrap();
+        users.find_by_api_key(key).map(authenticated)
+    }
+
+    /// A quick digest of a password that has been checked
+    fn cache_digest(&self, username: &str, password: &str, password_hash: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.cache_secret);
+        for part in [username, password, password_hash] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.finalize().into()
+    }
+}
+
+impl AuthenticationService for UserAuthenticator {
+    fn authenticate(&mut self, headers: &HashMap<String, String>) -> Result<AuthenticationResult, ServerError> {
+        let authentication = match parse_authorization(headers) {
+            Some(Credentials::Basic(username, password)) => self.authenticate_password(&username, &password)?,
+            Some(Credentials::Bearer(key)) => self.authenticate_api_key(&key),
+            None => match headers.get(CLIENT_CERTIFICATE_HEADER) {
+                Some(subject) => self.users.read().unwrap().get(subject).map(authenticated),
+                None => None,
+            },
+        };
+        Ok(authentication.unwrap_or(AuthenticationResult::Unauthenticated))
+    }
+}
+
+
+/// The result for a user who logged in
+fn authenticated(user: &User) -> AuthenticationResult {
+    AuthenticationResult::Authenticated(
+        user.username.clone(), Some(user.level), user.rules.clone().map(Arc::new)
+    )
+}
+
+
+/// Check a username can be used, which rules out `:` since it separates the password in basic auth
+fn check_username(username: &str) -> Result<(), ServerError> {
+    if username.is_empty() || username.contains(':') {
+        return Err(ServerError::RequestError(format!("Invalid username '{}'.", username)));
+    }
+    Ok(())
+}
+
+
+/// Credentials sent in an `Authorization` header
+pub(crate) enum Credentials {
+    /// A username and password
+    Basic(String, String),
+    /// An API key
+    Bearer(String),
+}
+
+
+/// Read the `Authorization` header, whatever case its name was sent in
+pub(crate) fn parse_authorization(headers: &HashMap<String, String>) -> Option<Credentials> {
+    let value = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))?.1.trim();
+    let (scheme, credentials) = value.split_once(' ')?;
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        Some(Credentials::Basic(username.to_string(), password.to_string()))
+    } else if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(Credentials::Bearer(credentials.to_string()))
+    } else {
+        None
+    }
+}
+
+
+/// Create an error for a users file that can't be read or written
+fn make_users_file_error(path: &Path, message: String) -> ServerError {
+    ServerError::InternalError(format!("Users file {}: {}", path.display(), message))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::credentials::{generate_api_key, hash_password_with_iterations};
+
+    fn headers(authorization: &str) -> HashMap<String, String> {
+        HashMap::from([("authorization".to_string(), authorization.to_string())])
+    }
+
+    fn basic(username: &str, password: &str) -> HashMap<String, String> {
+        headers(&format!("Basic {}", base64::encode(format!("{}:{}", username, password))))
+    }
+
+    #[test]
+    fn test_user_authenticator() {
+        let key = generate_api_key();
+        let users = vec![
+            User {
+                username: "alice".to_string(),
+                level: AuthorizationLevel::Admin,
+                password_hash: Some(hash_password_with_iterations("secret", 10)),
+                api_key_hashes: vec![],
+                rules: None,
+            },
+            User {
+                username: "reporting".to_string(),
+                level: AuthorizationLevel::Read,
+                password_hash: None,
+                api_key_hashes: vec![hash_api_key(&key)],
+                rules: None,
+            },
+        ];
+        let mut authenticator = UserAuthenticator::new(UserDirectory::new(users).unwrap().shared());
+        let alice = AuthenticationResult::Authenticated("alice".to_string(), Some(AuthorizationLevel::Admin), None);
+        assert_eq!(authenticator.authenticate(&basic("alice", "secret")).unwrap(), alice);
+        // The second time comes from the cache
+        assert_eq!(authenticator.authenticate(&basic("alice", "secret")).unwrap(), alice);
+        assert_eq!(authenticator.authenticate(&basic("alice", "wrong")).unwrap(), AuthenticationResult::Unauthenticated);
+        assert_eq!(authenticator.authenticate(&basic("bob", "secret")).unwrap(), AuthenticationResult::Unauthenticated);
+        assert_eq!(
+            authenticator.authenticate(&headers(&format!("bearer {}", key))).unwrap(),
+            AuthenticationResult::Authenticated("reporting".to_string(), Some(AuthorizationLevel::Read), None)
+        );
+        assert_eq!(authenticator.authenticate(&headers("Bearer nope")).unwrap(), AuthenticationResult::Unauthenticated);
+        // Users without a password can't log in with one
+        assert_eq!(authenticator.authenticate(&basic("reporting", "")).unwrap(), AuthenticationResult::Unauthenticated);
+        assert_eq!(authenticator.authenticate(&HashMap::new()).unwrap(), AuthenticationResult::Unauthenticated);
+        assert_eq!(authenticator.authenticate(&headers("Basic !!!")).unwrap(), AuthenticationResult::Unauthenticated);
+        // A verified certificate stands in for credentials
+        let certificate = |subject: &str| HashMap::from([(CLIENT_CERTIFICATE_HEADER.to_string(), subject.to_string())]);
+        assert_eq!(authenticator.authenticate(&certificate("alice")).unwrap(), alice);
+        assert_eq!(authenticator.authenticate(&certificate("mallory")).unwrap(), AuthenticationResult::Unauthenticated);
+    }
+
+    #[test]
+    fn test_users_file() {
+        let path = std::env::temp_dir().join(format!("rust_store_users_{}.json", std::process::id()));
+        std::fs::write(
+            &path, r#"{"users": [{"username": "ops", "level": "write", "api_key_hashes": ["abc"]}]}"#
+        ).unwrap();
+        let users = UsersFile::load(&path).unwrap();
+        assert_eq!(users.users[0].level, AuthorizationLevel::Write);
+        users.save(&path).unwrap();
+        assert_eq!(UsersFile::load(&path).unwrap(), users);
+
+        std::fs::write(&path, r#"{"users": [{"username": "ops", "level": "root"}]}"#).unwrap();
+        assert!(UserAuthenticator::from_file(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_user_changes_apply_and_persist() {
+        let path = std::env::temp_dir().join(format!("rust_store_user_changes_{}.json", std::process::id()));
+        UsersFile::default().save(&path).unwrap();
+        let users = UserDirectory::from_file(&path).unwrap().shared();
+        let mut authenticator = UserAuthenticator::new(Arc::clone(&users));
+        let first = hash_password_with_iterations("first", 10);
+        users.write().unwrap().add("alice", AuthorizationLevel::Admin, Some(&first)).unwrap();
+        users.write().unwrap().add("bob", AuthorizationLevel::Write, None).unwrap();
+        assert!(matches!(
+            users.write().unwrap().add("bob", AuthorizationLevel::Read, None),
+            Err(ServerError::ConflictError(_))
+        ));
+        assert!(users.write().unwrap().add("a:b", AuthorizationLevel::Read, None).is_err());
+
+        let alice = AuthenticationResult::Authenticated("alice".to_string(), Some(AuthorizationLevel::Admin), None);
+        assert_eq!(authenticator.authenticate(&basic("alice", "first")).unwrap(), alice);
+        assert!(users.write().unwrap().set_password("alice", "second").is_err());
+        users.write().unwrap().set_password("alice", &hash_password_with_iterations("second", 10)).unwrap();
+        assert_eq!(authenticator.authenticate(&basic("alice", "first")).unwrap(), AuthenticationResult::Unauthenticated);
+        assert_eq!(authenticator.authenticate(&basic("alice", "second")).unwrap(), alice);
+
+        assert!(matches!(users.write().unwrap().delete("alice"), Err(ServerError::ConflictError(_))));
+        users.write().unwrap().delete("bob").unwrap();
+        assert!(matches!(users.write().unwrap().delete("bob"), Err(ServerError::KeyError(_))));
+
+        let saved = UsersFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.users.len(), 1);
+        assert_eq!(saved.users[0].username, "alice");
+        assert!(verify_password("second", saved.users[0].password_hash.as_ref().unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,744 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::audit::{AuditConfig, AuditFilter};
+use crate::auth::{
+    AuthenticationService, CapabilityAuthenticator, CapabilitySigner, MockAuthenticator, SharedUsers, UserAuthenticator,
+    UserDirectory,
+};
+use crate::error::ServerError;
+use crate::io::http::{KeepAliveConfig, RequestLimits};
+use crate::io::tls::TlsConfig;
+use crate::io::unix::UnixSocketConfig;
+use crate::persistence::{FsyncPolicy, PersistenceConfig};
+use crate::storage::{EvictionPolicy, StorageBackend};
+use crate::storage::hashmap_storage::HashMapStorage;
+
+
+/// The environment variable pointing at a config file
+pub const CONFIG_ENV_VAR: &str = "RUST_STORE_CONFIG";
+
+
+/// A setting that can be given in the config file, in the environment or on the command line.
+///
+/// In the config file settings are grouped into sections, so `network.port` is written as
+/// `port = 7878` under `[network]`. On the command line the dots and underscores become
+/// dashes, so it is `--network-port 7878`.
+struct Setting {
+    /// The name in the config file, as `section.name`
+    key: &'static str,
+    /// The environment variable that overrides the config file
+    env_var: &'static str,
+    /// What the setting does, for the help message
+    description: &'static str,
+}
+
+
+/// Every setting the servers understand
+const SETTINGS: &[Setting] = &[
+    Setting { key: "network.address", env_var: "RUST_STORE_ADDRESS", description: "IP address to accept HTTP clients on" },
+    Setting { key: "network.port", env_var: "RUST_STORE_PORT", description: "Port to accept HTTP clients on" },
+    Setting { key: "network.resp_port", env_var: "RUST_STORE_RESP_PORT", description: "Port to accept Redis protocol clients on, if any (multi_server)" },
+    Setting { key: "network.unix_socket", env_var: "RUST_STORE_UNIX_SOCKET", description: "Path of a Unix socket to accept HTTP clients on, if any" },
+    Setting { key: "network.unix_socket_mode", env_var: "RUST_STORE_UNIX_SOCKET_MODE", description: "Permissions of the Unix socket in octal, like 660" },
+    Setting { key: "tls.cert_path", env_var: "RUST_STORE_TLS_CERT", description: "PEM certificate chain to serve HTTP over TLS with, or none for plain HTTP" },
+    Setting { key: "tls.key_path", env_var: "RUST_STORE_TLS_KEY", description: "PEM private key for the TLS certificate" },
+    Setting { key: "tls.client_ca_path", env_var: "RUST_STORE_TLS_CLIENT_CA", description: "PEM certificates client certificates must be signed by, or none to not ask for them" },
+    Setting { key: "workers.listeners", env_var: "RUST_STORE_LISTENERS", description: "Listener threads for each way clients connect (multi_server)" },
+    Setting { key: "workers.analyzers", env_var: "RUST_STORE_ANALYZERS", description: "Threads turning queries into statements (multi_server)" },
+    Setting { key: "workers.readers", env_var: "RUST_STORE_READERS", description: "Threads running read only requests (multi_server)" },
+    Setting { key: "workers.shards", env_var: "RUST_STORE_SHARDS", description: "How many shards to split the keys between (multi_server)" },
+    Setting { key: "workers.queue_size", env_var: "RUST_STORE_QUEUE_SIZE", description: "How many requests can wait between tasks (async_server)" },
+    Setting { key: "expiration.interval_ms", env_var: "RUST_STORE_EXPIRATION_INTERVAL_MS", description: "Milliseconds between rounds of removing expired keys" },
+    Setting { key: "expiration.calls", env_var: "RUST_STORE_EXPIRATION_CALLS", description: "How many times expired keys are looked for each round" },
+    Setting { key: "timeouts.request_ms", env_var: "RUST_STORE_REQUEST_TIMEOUT_MS", description: "Milliseconds to wait for a request to be run before giving up" },
+    Setting { key: "timeouts.keep_alive_idle_ms", env_var: "RUST_STORE_KEEP_ALIVE_IDLE_MS", description: "Milliseconds a connection can sit idle between requests" },
+    Setting { key: "timeouts.keep_alive_max_requests", env_var: "RUST_STORE_KEEP_ALIVE_MAX_REQUESTS", description: "How many requests a connection can carry" },
+    Setting { key: "timeouts.read_ms", env_var: "RUST_STORE_READ_TIMEOUT_MS", description: "Milliseconds a client has to send the rest of a request once it starts" },
+    Setting { key: "timeouts.write_ms", env_var: "RUST_STORE_WRITE_TIMEOUT_MS", description: "Milliseconds a client has to take a response" },
+    Setting { key: "limits.max_header_bytes", env_var: "RUST_STORE_MAX_HEADER_BYTES", description: "Size the request line and headers can take up, like 8kb" },
+    Setting { key: "limits.max_body_bytes", env_var: "RUST_STORE_MAX_BODY_BYTES", description: "Size a request body can take up, like 1mb" },
+    Setting { key: "limits.max_connections", env_var: "RUST_STORE_MAX_CONNECTIONS", description: "How many connections each listener keeps open at once" },
+    Setting { key: "storage.backend", env_var: StorageBackend::ENV_VAR, description: "Storage to keep the keys in, hashmap or btreemap" },
+    Setting { key: "storage.max_memory", env_var: "RUST_STORE_MAX_MEMORY", description: "Memory limit for each storage, like 512mb (hashmap only)" },
+    Setting { key: "storage.eviction_policy", env_var: "RUST_STORE_EVICTION_POLICY", description: "Which keys to evict at the memory limit: noeviction, allkeys-lru, allkeys-lfu or volatile-ttl" },
+    Setting { key: "auth.authenticator", env_var: "RUST_STORE_AUTHENTICATOR", description: "How clients are authenticated: mock or users" },
+    Setting { key: "auth.users_file", env_var: "RUST_STORE_USERS_FILE", description: "JSON file listing the users, for the users authenticator" },
+    Setting { key: "auth.capability_secret", env_var: "RUST_STORE_CAPABILITY_SECRET", description: "Secret that signs capability tokens, or none to turn them off" },
+    Setting { key: "persistence.enabled", env_var: "RUST_STORE_PERSISTENCE", description: "Whether to save the data to disk at all" },
+    Setting { key: "persistence.snapshot_path", env_var: "RUST_STORE_SNAPSHOT_PATH", description: "Where snapshots are saved, or none" },
+    Setting { key: "persistence.command_log_path", env_var: "RUST_STORE_COMMAND_LOG_PATH", description: "Where the command log is kept, or none" },
+    Setting { key: "persistence.fsync", env_var: "RUST_STORE_FSYNC", description: "How often the command log is synced: always, everysec or never" },
+    Setting { key: "audit.path", env_var: "RUST_STORE_AUDIT_PATH", description: "Where the audit log of who ran what is written, or none (multi_server and async_server)" },
+    Setting { key: "audit.filter", env_var: "RUST_STORE_AUDIT_FILTER", description: "Which requests are audited: all, mutating or admin" },
+    Setting { key: "audit.max_bytes", env_var: "RUST_STORE_AUDIT_MAX_BYTES", description: "Size the audit log is rotated at, like 10mb" },
+    Setting { key: "audit.max_files", env_var: "RUST_STORE_AUDIT_MAX_FILES", description: "How many rotated audit logs are kept" },
+];
+
+
+/// The ways clients can be authenticated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthenticatorKind {
+    /// Trust the username header, see `MockAuthenticator`
+    Mock,
+    /// Check passwords and API keys against a users file, see `UserAuthenticator`
+    Users,
+}
+
+impl AuthenticatorKind {
+    /// Get an authenticator from its name
+    pub fn from_name(name: &str) -> Result<AuthenticatorKind, ServerError> {
+        match name.to_lowercase().as_str() {
+            "mock" => Ok(AuthenticatorKind::Mock),
+            "users" => Ok(AuthenticatorKind::Users),
+            _ => Err(ServerError::ConfigError(format!("Unknown authenticator {}.", name))),
+        }
+    }
+}
+
+
+/// Settings for the server binaries.
+///
+/// Settings are read from the defaults, then a TOML config file, then environment variables and
+/// then command-line flags, with each overriding the ones before it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    /// IP address to accept HTTP clients on
+    pub address: IpAddr,
+    /// Port to accept HTTP clients on
+    pub port: usize,
+    /// Port to accept Redis protocol clients on
+    pub resp_port: Option<usize>,
+    /// Path of a Unix socket to accept HTTP clients on
+    pub unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix socket
+    pub unix_socket_mode: Option<u32>,
+    /// Certificate chain to serve HTTP over TLS with
+    pub tls_cert_path: Option<PathBuf>,
+    /// Private key for the TLS certificate
+    pub tls_key_path: Option<PathBuf>,
+    /// Certificates client certificates must be signed by
+    pub tls_client_ca_path: Option<PathBuf>,
+    /// Listener threads for each way clients connect
+    pub listeners: usize,
+    /// Threads turning queries into statements
+    pub analyzers: usize,
+    /// Threads running read only requests
+    pub readers: usize,
+    /// How many shards to split the keys between
+    pub shards: usize,
+    /// How many requests can wait between tasks in the async server
+    pub queue_size: usize,
+    /// Time between rounds of removing expired keys
+    pub expiration_interval: Duration,
+    /// How many times expired keys are looked for each round
+    pub expiration_calls: usize,
+    /// How long to wait for a request to be run
+    pub request_timeout: Duration,
+    /// How long a connection can sit idle between requests
+    pub keep_alive_idle: Duration,
+    /// How many requests a connection can carry
+    pub keep_alive_max_requests: usize,
+    /// How long a client has to send the rest of a request once it starts
+    pub read_timeout: Duration,
+    /// How long a client has to take a response
+    pub write_timeout: Duration,
+    /// Size in bytes the request line and headers can take up
+    pub max_header_bytes: usize,
+    /// Size in bytes a request body can take up
+    pub max_body_bytes: usize,
+    /// How many connections each listener keeps open at once
+    pub max_connections: usize,
+    /// Storage to keep the keys in
+    pub backend: StorageBackend,
+    /// Memory limit in bytes for each storage
+    pub max_memory: Option<usize>,
+    /// Which keys to evict at the memory limit
+    pub eviction_policy: EvictionPolicy,
+    /// How clients are authenticated
+    pub authenticator: AuthenticatorKind,
+    /// JSON file listing the users
+    pub users_file: Option<PathBuf>,
+    /// Secret that signs capability tokens
+    pub capability_secret: Option<String>,
+    /// Whether to save the data to disk at all
+    pub persistence_enabled: bool,
+    /// Where snapshots are saved
+    pub snapshot_path: Option<PathBuf>,
+    /// Where the command log is kept
+    pub command_log_path: Option<PathBuf>,
+    /// How often the command log is synced
+    pub fsync_policy: FsyncPolicy,
+    /// Where the audit log is written
+    pub audit_path: Option<PathBuf>,
+    /// Which requests are audited
+    pub audit_filter: AuditFilter,
+    /// Size in bytes the audit log is rotated at
+    pub audit_max_bytes: usize,
+    /// How many rotated audit logs are kept
+    pub audit_max_files: usize,
+}
+
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        let keep_alive = KeepAliveConfig::default();
+        let limits = RequestLimits::default();
+        let persistence = PersistenceConfig::default();
+        let audit = AuditConfig::new("audit.log");
+        ServerConfig {
+            address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port: 7878,
+            resp_port: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            tls_client_ca_path: None,
+            listeners: 3,
+            analyzers: 3,
+            readers: 3,
+            shards: 1,
+            queue_size: 128,
+            expiration_interval: Duration::from_secs(5),
+            expiration_calls: 5,
+            request_timeout: Duration::from_secs(1),
+            keep_alive_idle: keep_alive.idle_timeout,
+            keep_alive_max_requests: keep_alive.max_requests,
+            read_timeout: limits.read_timeout,
+            write_timeout: limits.write_timeout,
+            max_header_bytes: limits.max_header_bytes,
+            max_body_bytes: limits.max_body_bytes,
+            max_connections: limits.max_connections,
+            backend: StorageBackend::HashMap,
+            max_memory: None,
+            eviction_policy: EvictionPolicy::NoEviction,
+            authenticator: AuthenticatorKind::Mock,
+            users_file: None,
+            capability_secret: None,
+            persistence_enabled: true,
+            snapshot_path: persistence.snapshot_path,
+            command_log_path: persistence.command_log_path,
+            fsync_policy: persistence.fsync_policy,
+            audit_path: None,
+            audit_filter: audit.filter,
+            audit_max_bytes: audit.max_bytes as usize,
+            audit_max_files: audit.max_files,
+        }
+    }
+}
+
+
+impl ServerConfig {
+    /// Read the settings for a server binary from its arguments, the environment and any config file.
+    ///
+    /// Prints the help message and exits for `--help`, and prints the problem and exits for bad settings.
+    pub fn load() -> ServerConfig {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+            println!("{}", usage());
+            process::exit(0);
+        }
+        match ServerConfig::from_sources(&args, |name| std::env::var(name).ok()) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(2);
+            },
+        }
+    }
+
+    /// Build the settings from command-line arguments and a way to look up environment variables
+    pub fn from_sources<E: Fn(&str) -> Option<String>>(args: &[String], env: E) -> Result<ServerConfig, ServerError> {
+        let mut config_path = env(CONFIG_ENV_VAR).map(PathBuf::from);
+        let mut flags = vec![];
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) => flag,
+                None => return Err(make_config_error(format!("Unexpected argument {}. Run with --help to see the options.", arg))),
+            };
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag, value.clone()),
+                    None => return Err(make_config_error(format!("Missing a value for --{}.", flag))),
+                },
+            };
+            if flag == "config" {
+                config_path = Some(PathBuf::from(value));
+                continue;
+            }
+            match SETTINGS.iter().find(|setting| flag_name(setting.key) == flag) {
+                Some(setting) => flags.push((setting.key, value)),
+                None => return Err(make_config_error(format!("Unknown option --{}. Run with --help to see the options.", flag))),
+            }
+        }
+
+        let mut config = ServerConfig::default();
+        if let Some(path) = config_path {
+            config.apply_file(&path)?;
+        }
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env_var) {
+                config.apply(setting.key, &value, &format!("environment variable {}", setting.env_var))?;
+            }
+        }
+        for (key, value) in flags {
+            config.apply(key, &value, &format!("option --{}", flag_name(key)))?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read settings from a TOML file
+    fn apply_file(&mut self, path: &Path) -> Result<(), ServerError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => return Err(make_config_error(format!("Could not read config file {}: {}", path.display(), error))),
+        };
+        let sections: toml::value::Table = match toml::from_str(&contents) {
+            Ok(sections) => sections,
+            Err(error) => return Err(make_config_error(format!("Could not parse config file {}: {}", path.display(), error))),
+        };
+        let source = format!("config file {}", path.display());
+        for (section, settings) in sections {
+            let settings = match settings {
+                toml::Value::Table(settings) => settings,
+                _ => return Err(make_config_error(format!("Expected a [section] for {} in {}.", section, source))),
+            };
+            for (name, value) in settings {
+                let key = format!("{}.{}", section, name);
+                let value = match value {
+                    toml::Value::String(value) => value,
+                    toml::Value::Integer(value) => value.to_string(),
+                    toml::Value::Float(value) => value.to_string(),
+                    toml::Value::Boolean(value) => value.to_string(),
+                    _ => return Err(make_config_error(format!("Expected a single value for {} in {}.", key, source))),
+                };
+                self.apply(&key, &value, &source)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Change one setting, explaining where the value came from if it's bad
+    fn apply(&mut self, key: &str, value: &str, source: &str) -> Result<(), ServerError> {
+        match self.set(key, value) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(make_config_error(format!("Unknown setting {} in {}.", key, source))),
+            Err(expected) => Err(make_config_error(
+                format!("Invalid value '{}' for {} in {}, expected {}.", value, key, source, expected)
+            )),
+        }
+    }
+
+    /// Change one setting. Gives false for unknown settings and what was expected for bad values.
+    fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let value = value.trim();
+        match key {
+            "network.address" => self.address = parse(value, "an IP address")?,
+            "network.port" => self.port = parse(value, "a port number")?,
+            "network.resp_port" => self.resp_port = parse_optional(value, "a port number or none")?,
+            "network.unix_socket" => self.unix_socket = parse_optional(value, "a path or none")?,
+            "network.unix_socket_mode" => self.unix_socket_mode = match parse_optional::<String>(value, "")? {
+                Some(mode) => Some(u32::from_str_radix(&mode, 8).map_err(|_| "octal digits like 660".to_string())?),
+                None => None,
+            },
+            "tls.cert_path" => self.tls_cert_path = parse_optional(value, "a path or none")?,
+            "tls.key_path" => self.tls_key_path = parse_optional(value, "a path or none")?,
+            "tls.client_ca_path" => self.tls_client_ca_path = parse_optional(value, "a path or none")?,
+            "workers.listeners" => self.listeners = parse(value, "a number")?,
+            "workers.analyzers" => self.analyzers = parse(value, "a number")?,
+            "workers.readers" => self.readers = parse(value, "a number")?,
+            "workers.shards" => self.shards = parse(value, "a number")?,
+            "workers.queue_size" => self.queue_size = parse(value, "a number")?,
+            "expiration.interval_ms" => self.expiration_interval = Duration::from_millis(parse(value, "milliseconds")?),
+            "expiration.calls" => self.expiration_calls = parse(value, "a number")?,
+            "timeouts.request_ms" => self.request_timeout = Duration::from_millis(parse(value, "milliseconds")?),
+            "timeouts.keep_alive_idle_ms" => self.keep_alive_idle = Duration::from_millis(parse(value, "milliseconds")?),
+            "timeouts.keep_alive_max_requests" => self.keep_alive_max_requests = parse(value, "a number")?,
+            "timeouts.read_ms" => self.read_timeout = Duration::from_millis(parse(value, "milliseconds")?),
+            "timeouts.write_ms" => self.write_timeout = Duration::from_millis(parse(value, "milliseconds")?),
+            "limits.max_header_bytes" => self.max_header_bytes = parse_size(value)?,
+            "limits.max_body_bytes" => self.max_body_bytes = parse_size(value)?,
+            "limits.max_connections" => self.max_connections = parse(value, "a number")?,
+            "storage.backend" => {
+                self.backend = StorageBackend::from_name(value).map_err(|_| "hashmap or btreemap".to_string())?
+            },
+            "storage.max_memory" => self.max_memory = match parse_optional::<String>(value, "")? {
+                Some(size) => Some(parse_size(&size)?),
+                None => None,
+            },
+            "storage.eviction_policy" => {
+                self.eviction_policy = EvictionPolicy::from_name(value)
+                    .map_err(|_| "noeviction, allkeys-lru, allkeys-lfu or volatile-ttl".to_string())?
+            },
+            "auth.authenticator" => {
+                self.authenticator = AuthenticatorKind::from_name(value).map_err(|_| "mock or users".to_string())?
+            },
+            "auth.users_file" => self.users_file = parse_optional(value, "a path or none")?,
+            "auth.capability_secret" => self.capability_secret = parse_optional(value, "a secret or none")?,
+            "persistence.enabled" => self.persistence_enabled = parse_bool(value)?,
+            "persistence.snapshot_path" => self.snapshot_path = parse_optional(value, "a path or none")?,
+            "persistence.command_log_path" => self.command_log_path = parse_optional(value, "a path or none")?,
+            "persistence.fsync" => {
+                self.fsync_policy = FsyncPolicy::from_name(value).map_err(|_| "always, everysec or never".to_string())?
+            },
+            "audit.path" => self.audit_path = parse_optional(value, "a path or none")?,
+            "audit.filter" => {
+                self.audit_filter = AuditFilter::from_name(value).map_err(|_| "all, mutating or admin".to_string())?
+            },
+            "audit.max_bytes" => self.audit_max_bytes = parse_size(value)?,
+            "audit.max_files" => self.audit_max_files = parse(value, "a number")?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Check the settings work together
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let counts = [
+            ("workers.listeners", self.listeners),
+            ("workers.analyzers", self.analyzers),
+            ("workers.readers", self.readers),
+            ("workers.shards", self.shards),
+            ("workers.queue_size", self.queue_size),
+            ("expiration.calls", self.expiration_calls),
+            ("timeouts.keep_alive_max_requests", self.keep_alive_max_requests),
+            ("limits.max_header_bytes", self.max_header_bytes),
+            ("limits.max_body_bytes", self.max_body_bytes),
+            ("limits.max_connections", self.max_connections),
+            ("audit.max_bytes", self.audit_max_bytes),
+            ("audit.max_files", self.audit_max_files),
+        ];
+        for (key, count) in counts {
+            if count == 0 {
+                return Err(make_config_error(format!("{} must be at least 1.", key)));
+            }
+        }
+        let durations = [
+            ("expiration.interval_ms", self.expiration_interval),
+            ("timeouts.request_ms", self.request_timeout),
+            ("timeouts.keep_alive_idle_ms", self.keep_alive_idle),
+            ("timeouts.read_ms", self.read_timeout),
+            ("timeouts.write_ms", self.write_timeout),
+        ];
+        for (key, duration) in durations {
+            if duration.is_zero() {
+                return Err(make_config_error(format!("{} must be more than 0.", key)));
+            }
+        }
+        for (key, port) in [("network.port", Some(self.port)), ("network.resp_port", self.resp_port)] {
+            if port.is_some_and(|port| port > u16::MAX as usize) {
+                return Err(make_config_error(format!("{} must be at most {}.", key, u16::MAX)));
+            }
+        }
+        if self.port != 0 && self.resp_port == Some(self.port) {
+            return Err(make_config_error("network.port and network.resp_port must be different.".to_string()));
+        }
+        if self.unix_socket_mode.is_some() && self.unix_socket.is_none() {
+            return Err(make_config_error("network.unix_socket_mode needs network.unix_socket to be set.".to_string()));
+        }
+        if self.unix_socket_mode.is_some_and(|mode| mode > 0o777) {
+            return Err(make_config_error("network.unix_socket_mode must be at most 777.".to_string()));
+        }
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => return Err(make_config_error("tls.cert_path needs tls.key_path to be set.".to_string())),
+            (None, Some(_)) => return Err(make_config_error("tls.key_path needs tls.cert_path to be set.".to_string())),
+            (None, None) if self.tls_client_ca_path.is_some() => {
+                return Err(make_config_error("tls.client_ca_path needs tls.cert_path to be set.".to_string()));
+            },
+            _ => (),
+        }
+        match self.max_memory {
+            Some(0) => return Err(make_config_error("storage.max_memory must be more than 0.".to_string())),
+            Some(_) if self.backend != StorageBackend::HashMap => {
+                return Err(make_config_error("storage.max_memory is only supported by the hashmap backend.".to_string()));
+            },
+            None if self.eviction_policy != EvictionPolicy::NoEviction => {
+                return Err(make_config_error("storage.eviction_policy needs storage.max_memory to be set.".to_string()));
+            },
+            _ => (),
+        }
+        if self.authenticator == AuthenticatorKind::Users && self.users_file.is_none() {
+            return Err(make_config_error("auth.authenticator users needs auth.users_file to be set.".to_string()));
+        }
+        if let Err(ServerError::ConfigError(message)) = self.capability_signer() {
+            return Err(make_config_error(format!("auth.capability_secret: {}", message)));
+        }
+        Ok(())
+    }
+
+    /// The settings for keeping connections open
+    pub fn keep_alive(&self) -> KeepAliveConfig {
+        KeepAliveConfig { idle_timeout: self.keep_alive_idle, max_requests: self.keep_alive_max_requests }
+    }
+
+    /// The limits on what each client can ask of the server
+    pub fn limits(&self) -> RequestLimits {
+        RequestLimits {
+            max_header_bytes: self.max_header_bytes,
+            max_body_bytes: self.max_body_bytes,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            max_connections: self.max_connections,
+        }
+    }
+
+    /// The settings for the Unix socket, if there is one
+    pub fn unix_socket(&self) -> Option<UnixSocketConfig> {
+        let path = self.unix_socket.clone()?;
+        Some(UnixSocketConfig {
+            permissions: self.unix_socket_mode,
+            keep_alive: self.keep_alive(),
+            limits: self.limits(),
+            ..UnixSocketConfig::new(path)
+        })
+    }
+
+    /// The settings for serving HTTP over TLS, if it is turned on
+    pub fn tls(&self) -> Option<TlsConfig> {
+        let (cert_path, key_path) = (self.tls_cert_path.clone()?, self.tls_key_path.clone()?);
+        Some(TlsConfig { client_ca_path: self.tls_client_ca_path.clone(), ..TlsConfig::new(cert_path, key_path) })
+    }
+
+    /// The settings for saving the data to disk
+    pub fn persistence(&self) -> PersistenceConfig {
+        if !self.persistence_enabled {
+            return PersistenceConfig::disabled();
+        }
+        PersistenceConfig {
+            snapshot_path: self.snapshot_path.clone(),
+            command_log_path: self.command_log_path.clone(),
+            fsync_policy: self.fsync_policy,
+        }
+    }
+
+    /// The settings for the audit log, if there is one
+    pub fn audit(&self) -> Option<AuditConfig> {
+        let path = self.audit_path.clone()?;
+        Some(AuditConfig {
+            filter: self.audit_filter,
+            max_bytes: self.audit_max_bytes as u64,
+            max_files: self.audit_max_files,
+            ..AuditConfig::new(path)
+        })
+    }
+
+    /// Read the users file, if clients are authenticated against one
+    pub fn load_users(&self) -> Result<Option<SharedUsers>, ServerError> {
+        match (self.authenticator, &self.users_file) {
+            (AuthenticatorKind::Mock, _) => Ok(None),
+            (AuthenticatorKind::Users, Some(users_file)) => Ok(Some(UserDirectory::from_file(users_file)?.shared())),
+            (AuthenticatorKind::Users, None) => {
+                Err(make_config_error("auth.authenticator users needs auth.users_file to be set.".to_string()))
+            },
+        }
+    }
+
+    /// Create the authenticator the settings ask for, checking clients against the loaded users.
+    ///
+    /// Capability tokens are accepted on top of it when there is a capability secret.
+    pub fn new_authenticator(&self, users: Option<SharedUsers>) -> Result<Box<dyn AuthenticationService + Send>, ServerError> {
+        let authenticator: Box<dyn AuthenticationService + Send> = match (self.authenticator, users) {
+            (AuthenticatorKind::Mock, _) => Box::new(MockAuthenticator),
+            (AuthenticatorKind::Users, Some(users)) => Box::new(UserAuthenticator::new(users)),
+            (AuthenticatorKind::Users, None) => {
+                return Err(make_config_error("The users authenticator needs the users to be loaded first.".to_string()))
+            },
+        };
+        match self.capability_signer()? {
+            Some(signer) => Ok(Box::new(CapabilityAuthenticator::new(authenticator, signer))),
+            None => Ok(authenticator),
+        }
+    }
+
+    /// Create the signer for capability tokens, if there is a secret for it
+    pub fn capability_signer(&self) -> Result<Option<CapabilitySigner>, ServerError> {
+        match &self.capability_secret {
+            Some(secret) => CapabilitySigner::new(secret.as_bytes()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Create an empty hashmap storage with the memory limit
+    pub fn new_hashmap_storage(&self) -> HashMapStorage {
+        match self.max_memory {
+            Some(max_memory) => HashMapStorage::with_memory_limit(max_memory, self.eviction_policy),
+            None => HashMapStorage::new(),
+        }
+    }
+}
+
+
+/// The help message listing every setting
+pub fn usage() -> String {
+    let mut usage = format!(
+        "Options:\n  --config <path>\n      TOML file to read settings from [env: {}]\n", CONFIG_ENV_VAR
+    );
+    for setting in SETTINGS {
+        usage.push_str(&format!(
+            "  --{} <value>\n      {} [file: {}] [env: {}]\n",
+            flag_name(setting.key), setting.description, setting.key, setting.env_var
+        ));
+    }
+    usage
+}
+
+
+/// The command-line flag for a setting, without the leading dashes
+fn flag_name(key: &str) -> String {
+    key.replace(['.', '_'], "-")
+}
+
+
+/// Create an error for bad settings
+fn make_config_error(message: String) -> ServerError {
+    ServerError::ConfigError(message)
+}
+
+
+/// Parse a value, giving what was expected if it doesn't fit
+fn parse<T: FromStr>(value: &str, expected: &str) -> Result<T, String> {
+    value.parse().map_err(|_| expected.to_string())
+}
+
+
+/// Parse a value that can be left out with `none` or an empty string
+fn parse_optional<T: FromStr>(value: &str, expected: &str) -> Result<Option<T>, String> {
+    match value.to_lowercase().as_str() {
+        "" | "none" => Ok(None),
+        _ => parse(value, expected).map(Some),
+    }
+}
+
+
+/// Parse a yes or no value
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err("true or false".to_string()),
+    }
+}
+
+
+/// Parse a number of bytes, which can end in kb, mb or gb
+fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.to_lowercase();
+    let (number, multiplier) = match value.len().checked_sub(2).map(|split| value.split_at(split)) {
+        Some((number, "kb")) => (number, 1 << 10),
+        Some((number, "mb")) => (number, 1 << 20),
+        Some((number, "gb")) => (number, 1 << 30),
+        _ => (value.as_str(), 1),
+    };
+    let expected = "a number of bytes, like 1024, 64kb, 512mb or 2gb";
+    let number: usize = parse(number.trim(), expected)?;
+    number.checked_mul(multiplier).ok_or_else(|| expected.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sources_override_each_other() {
+        let path = std::env::temp_dir().join(format!("rust_store_config_{}.toml", std::process::id()));
+        std::fs::write(&path, "
+            [network]
+            port = 9000
+            unix_socket = \"/tmp/store.sock\"
+            unix_socket_mode = 660
+
+            [workers]
+            listeners = 8
+            shards = 4
+
+            [storage]
+            max_memory = \"64mb\"
+            eviction_policy = \"allkeys-lru\"
+
+            [persistence]
+            enabled = false
+
+            [audit]
+            path = \"/tmp/audit.log\"
+            filter = \"mutating\"
+
+            [limits]
+            max_body_bytes = \"64kb\"
+            max_connections = 100
+        ").unwrap();
+        let env = HashMap::from([
+            (CONFIG_ENV_VAR, path.to_str().unwrap().to_string()),
+            ("RUST_STORE_LISTENERS", "6".to_string()),
+            ("RUST_STORE_SHARDS", "2".to_string()),
+        ]);
+        let config = ServerConfig::from_sources(
+            &args(&["--workers-shards", "3", "--timeouts-request-ms=250", "--timeouts-read-ms", "500"]),
+            |name| env.get(name).cloned(),
+        ).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.listeners, 6);
+        assert_eq!(config.shards, 3);
+        assert_eq!(config.analyzers, 3);
+        assert_eq!(config.request_timeout, Duration::from_millis(250));
+        assert_eq!(config.max_memory, Some(64 << 20));
+        assert_eq!(config.eviction_policy, EvictionPolicy::AllKeysLru);
+        assert_eq!(config.unix_socket().unwrap().permissions, Some(0o660));
+        assert_eq!(config.persistence(), PersistenceConfig::disabled());
+        let audit = config.audit().unwrap();
+        assert_eq!((audit.filter, audit.max_bytes, audit.max_files), (AuditFilter::Mutating, 10 << 20, 5));
+        let limits = config.limits();
+        assert_eq!((limits.max_header_bytes, limits.max_body_bytes, limits.max_connections), (8 << 10, 64 << 10, 100));
+        assert_eq!(limits.read_timeout, Duration::from_millis(500));
+        assert_eq!(config.unix_socket().unwrap().limits.max_connections, 100);
+    }
+
+    #[test]
+    fn test_bad_settings_are_explained() {
+        let error = |arguments: &[&str]| match ServerConfig::from_sources(&args(arguments), |_| None) {
+            Err(ServerError::ConfigError(message)) => message,
+            other => panic!("Expected a config error, got {:?}", other),
+        };
+        assert_eq!(
+            error(&["--workers-listeners", "many"]),
+            "Invalid value 'many' for workers.listeners in option --workers-listeners, expected a number."
+        );
+        assert_eq!(error(&["--workers-readers", "0"]), "workers.readers must be at least 1.");
+        assert_eq!(error(&["--timeouts-write-ms", "0"]), "timeouts.write_ms must be more than 0.");
+        assert_eq!(error(&["--port", "1"]), "Unknown option --port. Run with --help to see the options.");
+        assert_eq!(error(&["--network-port"]), "Missing a value for --network-port.");
+        assert_eq!(
+            error(&["--storage-eviction-policy", "allkeys-lru"]),
+            "storage.eviction_policy needs storage.max_memory to be set."
+        );
+        assert_eq!(
+            error(&["--storage-backend", "btree", "--storage-max-memory", "1gb"]),
+            "storage.max_memory is only supported by the hashmap backend."
+        );
+        assert!(error(&["--config", "/nonexistent/store.toml"]).starts_with("Could not read config file"));
+        assert_eq!(
+            error(&["--auth-authenticator", "users"]),
+            "auth.authenticator users needs auth.users_file to be set."
+        );
+        assert_eq!(error(&["--tls-cert-path", "cert.pem"]), "tls.cert_path needs tls.key_path to be set.");
+        assert_eq!(
+            error(&["--auth-capability-secret", "short"]),
+            "auth.capability_secret: Capability secrets need at least 16 bytes."
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::analysis::{Interpreter, InterpreterRequest, InterpreterResponse, ResponseMode, Statement};
+use crate::auth::{AccessRules, AuthenticationResult, AuthenticationService, AuthorizationLevel};
+use crate::auth::credentials::DEFAULT_ITERATIONS;
+use crate::error::ServerError;
+use crate::io::stream::StreamQuery;
+use crate::multithreaded::shards::ShardSet;
+use crate::storage::Storage;
+use crate::storage::hashmap_storage::HashMapStorage;
+
+
+/// A store used as a library, running requests in the calling thread without any networking.
+///
+/// The store can be shared between threads, and requests from different threads run the same
+/// way they do in the multithreaded server: reads share the interpreter while writes take it
+/// for themselves. Requests run as an administrator unless an authenticator is added, in which
+/// case they are authenticated from the headers passed along with them.
+pub struct EmbeddedStore<S: Storage + Send + Sync = HashMapStorage> {
+    /// The interpreters the keys are split between
+    shards: ShardSet<S>,
+    /// Checks the headers of each request, if authentication is turned on
+    authenticator: Option<Mutex<Box<dyn AuthenticationService + Send>>>,
+    /// How many PBKDF2 rounds new passwords are hashed with
+    hash_iterations: u32,
+}
+
+
+impl EmbeddedStore {
+    /// Create a store keeping everything in memory
+    pub fn new() -> EmbeddedStore {
+        EmbeddedStore::with_interpreter(Interpreter::new(HashMapStorage::new()))
+    }
+}
+
+
+impl Default for EmbeddedStore {
+    fn default() -> EmbeddedStore {
+        EmbeddedStore::new()
+    }
+}
+
+
+impl<S: Storage + Send + Sync> EmbeddedStore<S> {
+    /// Create a store from an existing interpreter, like one restored from disk
+    pub fn with_interpreter(interpreter: Interpreter<S>) -> EmbeddedStore<S> {
+        EmbeddedStore::with_shards(vec![interpreter])
+    }
+
+    /// Create a store with the keys split between one interpreter per shard
+    pub fn with_shards(interpreters: Vec<Interpreter<S>>) -> EmbeddedStore<S> {
+        EmbeddedStore { shards: ShardSet::new(interpreters), authenticator: None, hash_iterations: DEFAULT_ITERATIONS }
+    }
+
+    /// Authenticate every request from its headers
+    pub fn with_authenticator<A: AuthenticationService + Send + 'static>(mut self, authenticator: A) -> EmbeddedStore<S> {
+        self.authenticator = Some(Mutex::new(Box::new(authenticator)));
+        self
+    }
+
+    /// Set how many PBKDF2 rounds new passwords are hashed with
+    pub fn with_hash_iterations(mut self, hash_iterations: u32) -> EmbeddedStore<S> {
+        self.hash_iterations = hash_iterations;
+        self
+    }
+
+    /// Run a query written in the query language
+    pub fn query(&self, query: &str) -> Result<InterpreterResponse, ServerError> {
+        self.request(StreamQuery::Text(query.to_string()), &HashMap::new())
+    }
+
+    /// Run statements that are already parsed
+    pub fn execute(&self, statements: Vec<Statement>) -> Result<InterpreterResponse, ServerError> {
+        self.request(StreamQuery::Statements(statements), &HashMap::new())
+    }
+
+    /// Run a query with headers, which hold the credentials and the `Response-Mode`
+    pub fn query_with_headers(
+        &self, query: &str, headers: &HashMap<String, String>
+    ) -> Result<InterpreterResponse, ServerError> {
+        self.request(StreamQuery::Text(query.to_string()), headers)
+    }
+
+    /// Run statements with headers, which hold the credentials and the `Response-Mode`
+    pub fn execute_with_headers(
+        &self, statements: Vec<Statement>, headers: &HashMap<String, String>
+    ) -> Result<InterpreterResponse, ServerError> {
+        self.request(StreamQuery::Statements(statements), headers)
+    }
+
+    /// Authenticate and run a request
+    fn request(&self, query: StreamQuery, headers: &HashMap<String, String>) -> Result<InterpreterResponse, ServerError> {
+        let (authorization, access_rules) = self.authorize(headers)?;
+        let response_mode = ResponseMode::from_headers(headers)?;
+        let statements = query.into_runnable_statements(self.hash_iterations)?;
+        self.shards.interpret(InterpreterRequest { statements, authorization, access_rules, response_mode })
+    }
+
+    /// Find what the sender of a request is allowed to do, and any rules limiting it
+    fn authorize(
+        &self, headers: &HashMap<String, String>
+    ) -> Result<(AuthorizationLevel, Option<Arc<AccessRules>>), ServerError> {
+        let authenticator = match &self.authenticator {
+            Some(authenticator) => authenticator,
+            None => return Ok((AuthorizationLevel::Admin, None)),
+        };
+        let authentication = authenticator.lock().unwrap().authenticate(headers)?;
+        match authentication {
+            AuthenticationResult::Authenticated(_, Some(level), access_rules) => Ok((level, access_rules)),
+            AuthenticationResult::Authenticated(username, None, _) => Err(ServerError::AuthorizationError(
+                format!("User {} not authorized to access this resource.", username)
+            )),
+            AuthenticationResult::Unauthenticated => {
+                Err(ServerError::AuthenticationError("Authentication failed.".to_string()))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::auth::{CapabilityAuthenticator, CapabilitySigner, MockAuthenticator, User, UserAuthenticator, UserDirectory};
+    use crate::auth::credentials::{generate_api_key, hash_api_key, hash_password_with_iterations};
+    use crate::storage::StorageValue;
+
+    #[test]
+    fn test_shared_between_threads() {
+        let store = Arc::new(EmbeddedStore::new());
+        let handles: Vec<_> = (0..4).map(|thread_index| {
+            let store = Arc::clone(&store);
+            thread::spawn(move || {
+                for index in 0..25 {
+                    store.query(&format!("set key_{}_{} {};", thread_index, index, index)).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let response = store.query("keys \"key_*\";").unwrap();
+        assert!(matches!(response, InterpreterResponse::Keys(keys) if keys.len() == 100));
+        let response = store.execute(vec![Statement::Get("key_3_7".to_string())]).unwrap();
+        assert!(matches!(response, InterpreterResponse::Value(StorageValue::Int(7))));
+    }
+
+    #[test]
+    fn test_optional_authentication() {
+        let store = EmbeddedStore::new().with_authenticator(MockAuthenticator);
+        let headers = |username: &str| HashMap::from([("Username".to_string(), username.to_string())]);
+        assert!(store.query("set a 1;").is_err());
+        assert!(matches!(
+            store.query_with_headers("set a 1;", &headers("read")), Err(ServerError::AuthorizationError(_))
+        ));
+        store.query_with_headers("set a 1;", &headers("write")).unwrap();
+        let mut all = headers("read");
+        all.insert(ResponseMode::HEADER.to_string(), "all".to_string());
+        let response = store.query_with_headers("get a; ex b;", &all).unwrap();
+        assert!(matches!(response, InterpreterResponse::Results(results) if results.len() == 2));
+    }
+
+    #[test]
+    fn test_user_management() {
+        let users = UserDirectory::new(vec![]).unwrap().shared();
+        let root_hash = hash_password_with_iterations("Root Pass", 10);
+        users.write().unwrap().add("root", AuthorizationLevel::Admin, Some(&root_hash)).unwrap();
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        interpreter.set_users(Arc::clone(&users));
+        let store = EmbeddedStore::with_interpreter(interpreter)
+            .with_authenticator(UserAuthenticator::new(Arc::clone(&users)))
+            .with_hash_iterations(10);
+        let login = |username: &str, password: &str| HashMap::from([(
+            "Authorization".to_string(),
+            format!("Basic {}", base64::encode(format!("{}:{}", username, password))),
+        )]);
+        let root = login("root", "Root Pass");
+
+        store.query_with_headers("user_add \"Alice\" write \"S3cret\";", &root).unwrap();
+        store.query_with_headers("set a 1;", &login("Alice", "S3cret")).unwrap();
+        // The password was hashed before the request reached the interpreter, with the store's rounds
+        let alice = users.read().unwrap().get("Alice").unwrap().password_hash.clone().unwrap();
+        assert!(alice.starts_with("pbkdf2-sha256$10$"));
+        assert!(matches!(
+            store.query_with_headers("user_passwd \"Alice\" \"\";", &root), Err(ServerError::RequestError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("user_list;", &login("Alice", "S3cret")), Err(ServerError::AuthorizationError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("begin; user_del \"Alice\"; commit;", &root), Err(ServerError::RequestError(_))
+        ));
+
+        store.query_with_headers("user_passwd \"Alice\" \"changed\";", &root).unwrap();
+        assert!(matches!(
+            store.query_with_headers("get a;", &login("Alice", "S3cret")), Err(ServerError::AuthenticationError(_))
+        ));
+        match store.query_with_headers("user_list;", &root).unwrap() {
+            InterpreterResponse::Value(StorageValue::Map(map)) => {
+                assert_eq!(map.get(&StorageValue::from("Alice")).unwrap(), &StorageValue::from("write"));
+                assert_eq!(map.len(), 2);
+            },
+            other => panic!("Expected a map of users, got {:?}", other),
+        }
+
+        store.query_with_headers("user_del \"Alice\";", &root).unwrap();
+        assert!(matches!(
+            store.query_with_headers("get a;", &login("Alice", "changed")), Err(ServerError::AuthenticationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_access_rules() {
+        let key = generate_api_key();
+        let user = User {
+            username: "team_a".to_string(),
+            level: AuthorizationLevel::Write,
+            password_hash: None,
+            api_key_hashes: vec![hash_api_key(&key)],
+            rules: Some(AccessRules {
+                read: vec!["metrics_*".to_string()],
+                write: vec!["team_a_*".to_string()],
+                deny_commands: vec!["del".to_string()],
+                ..AccessRules::default()
+            }),
+        };
+        let users = UserDirectory::new(vec![user]).unwrap().shared();
+        let store = EmbeddedStore::new().with_authenticator(UserAuthenticator::new(users));
+        let team_a = HashMap::from([("Authorization".to_string(), format!("Bearer {}", key))]);
+
+        store.query_with_headers("set team_a_x 1;", &team_a).unwrap();
+        store.query_with_headers("keys \"team_a_*\";", &team_a).unwrap();
+        assert!(matches!(
+            store.query_with_headers("set team_a_y 1; set team_b_y 1;", &team_a),
+            Err(ServerError::AuthorizationError(message)) if message == "Not authorized to run set on key team_b_y."
+        ));
+        assert!(matches!(
+            store.query_with_headers("get team_a_y;", &team_a), Err(ServerError::KeyError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("set metrics_cpu 1;", &team_a), Err(ServerError::AuthorizationError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("del team_a_x;", &team_a),
+            Err(ServerError::AuthorizationError(message)) if message == "Not authorized to run del."
+        ));
+        assert!(matches!(
+            store.query_with_headers("keys \"*\";", &team_a), Err(ServerError::AuthorizationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let signer = CapabilitySigner::new(b"a secret of enough bytes").unwrap();
+        let mut interpreter = Interpreter::new(HashMapStorage::new());
+        interpreter.set_capability_signer(signer.clone());
+        let store = EmbeddedStore::with_interpreter(interpreter)
+            .with_authenticator(CapabilityAuthenticator::new(MockAuthenticator, signer));
+        let headers = |username: &str| HashMap::from([("Username".to_string(), username.to_string())]);
+        store.query_with_headers("set jobs_a 1; set other 2;", &headers("admin")).unwrap();
+        assert!(matches!(
+            store.query_with_headers("cap_issue read 60;", &headers("write")), Err(ServerError::AuthorizationError(_))
+        ));
+        let token = match store.query_with_headers("cap_issue read 60 \"jobs_\";", &headers("admin")).unwrap() {
+            InterpreterResponse::Value(StorageValue::String(token)) => token,
+            other => panic!("Expected a token, got {:?}", other),
+        };
+        let capability = HashMap::from([("Capability".to_string(), token)]);
+        store.query_with_headers("get jobs_a;", &capability).unwrap();
+        assert!(matches!(
+            store.query_with_headers("get other;", &capability), Err(ServerError::AuthorizationError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("set jobs_a 2;", &capability), Err(ServerError::AuthorizationError(_))
+        ));
+        assert!(matches!(
+            store.query_with_headers("cap_issue admin 0;", &headers("admin")), Err(ServerError::RequestError(_))
+        ));
+    }
+}
@@ -1,8 +1,10 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter, Result};
 
+use serde::{Deserialize, Serialize};
+
 /// Defines the basic error types that can be encountered.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum ServerError {
     /// An error around key access - missing key or filled key
     KeyError(String),
@@ -26,6 +28,16 @@ pub enum ServerError {
     AuthenticationError(String),
     /// Error 
     RequestError(String),
+    /// A key changed since the client last saw it
+    ConflictError(String),
+    /// The server was started with settings that don't work
+    ConfigError(String),
+    /// A request is bigger than the server accepts
+    TooLargeError(String),
+    /// A client took too long to send a request
+    TimeoutError(String),
+    /// The server is too busy to take the request
+    UnavailableError(String),
 }
 
 /// Get the error codes associated with each internal error type.
@@ -42,6 +54,11 @@ pub fn get_error_code(error: &ServerError) -> String {
         ServerError::AuthorizationError(_) => "401 Unauthorized",
         ServerError::AuthenticationError(_) => "403 Forbidden",
         ServerError::RequestError(_) => "400 Bad Request",
+        ServerError::ConflictError(_) => "409 Conflict",
+        ServerError::ConfigError(_) => "500 Internal Service Error",
+        ServerError::TooLargeError(_) => "413 Payload Too Large",
+        ServerError::TimeoutError(_) => "408 Request Timeout",
+        ServerError::UnavailableError(_) => "503 Service Unavailable",
     };
     err_string.to_string()
 }
@@ -60,6 +77,11 @@ impl Display for ServerError {
             ServerError::AuthorizationError(msg) => ("AuthorizationError", msg),
             ServerError::AuthenticationError(msg) => ("AuthenticationError", msg),
             ServerError::RequestError(msg) => ("RequestError", msg),
+            ServerError::ConflictError(msg) => ("ConflictError", msg),
+            ServerError::ConfigError(msg) => ("ConfigError", msg),
+            ServerError::TooLargeError(msg) => ("TooLargeError", msg),
+            ServerError::TimeoutError(msg) => ("TimeoutError", msg),
+            ServerError::UnavailableError(msg) => ("UnavailableError", msg),
         };
         write!(f, "{}: {}", err, msg)
     }
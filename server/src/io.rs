@@ -1,6 +1,18 @@
 /// Handles stream objects for IO operations
 pub mod stream;
+/// Reading HTTP requests and writing responses for the TCP and Unix socket streams
+pub mod http;
+/// Routes for the resource style HTTP API
+pub mod rest;
 /// Stream implementation using a TCP stream
 pub mod tcp;
+/// Encrypting TCP streams with TLS
+pub mod tls;
 /// Stream implementation using async TCP streams
-pub mod tcp_async;
\ No newline at end of file
+pub mod tcp_async;
+/// Stream implementation using a Unix domain socket
+pub mod unix;
+/// Stream implementation using async Unix domain sockets
+pub mod unix_async;
+/// Stream implementation speaking the Redis protocol
+pub mod resp;
\ No newline at end of file
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use httparse::{self, Request, Status};
+use serde_json::{self, Value};
+
+use crate::analysis::{InterpreterResponse, Statement};
+use crate::auth::CLIENT_CERTIFICATE_HEADER;
+use crate::error::{self, ServerError};
+use crate::io::rest;
+use crate::io::stream::{StreamQuery, response_to_json};
+
+
+/// How much to read from a connection at a time
+pub const MAX_BUFFER_SIZE: usize = 1024;
+const MAX_NUMBER_OF_HEADERS: usize = 32;
+/// How long a connection can sit idle between requests when not told otherwise
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many requests a connection can carry when not told otherwise
+const DEFAULT_MAX_REQUESTS: usize = 100;
+/// How long clients get to send the rest of a request once it starts, and to take a response
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Settings for keeping connections open between requests.
+#[derive(Clone, Copy, Debug)]
+pub struct KeepAliveConfig {
+    /// How long to wait for the next request before closing the connection
+    pub idle_timeout: Duration,
+    /// How many requests a connection can carry before it is closed
+    pub max_requests: usize,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> KeepAliveConfig {
+        KeepAliveConfig { idle_timeout: DEFAULT_IDLE_TIMEOUT, max_requests: DEFAULT_MAX_REQUESTS }
+    }
+}
+
+impl KeepAliveConfig {
+    /// Close every connection after its first request
+    pub fn disabled() -> KeepAliveConfig {
+        KeepAliveConfig { max_requests: 1, ..KeepAliveConfig::default() }
+    }
+
+    /// Decide what happens to a connection once it has served some requests
+    pub fn connection_after(&self, keep_alive_requested: bool, served: usize) -> Connection {
+        if keep_alive_requested && served < self.max_requests {
+            Connection::KeepAlive { timeout: self.idle_timeout, remaining: self.max_requests - served }
+        } else {
+            Connection::Close
+        }
+    }
+}
+
+
+/// Limits on how much one client can ask of the server.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+    /// The most bytes the request line and headers can take up
+    pub max_header_bytes: usize,
+    /// The most bytes a request body can take up
+    pub max_body_bytes: usize,
+    /// How long a client has to send the rest of a request once it has started
+    pub read_timeout: Duration,
+    /// How long a client has to take a response
+    pub write_timeout: Duration,
+    /// How many connections a listener keeps open at once
+    pub max_connections: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_header_bytes: 8 << 10,
+            max_body_bytes: 1 << 20,
+            read_timeout: DEFAULT_IO_TIMEOUT,
+            write_timeout: DEFAULT_IO_TIMEOUT,
+            max_connections: 1024,
+        }
+    }
+}
+
+
+/// Counts the connections open on a listener, so clients past the limit can be turned away.
+#[derive(Clone, Debug)]
+pub struct ConnectionCounter {
+    /// How many connections are open
+    open: Arc<AtomicUsize>,
+    /// How many connections can be open at once
+    max: usize,
+}
+
+impl ConnectionCounter {
+    /// Count connections up to a limit
+    pub fn new(max: usize) -> ConnectionCounter {
+        ConnectionCounter { open: Arc::new(AtomicUsize::new(0)), max }
+    }
+
+    /// Count a new connection, if there is room for it
+    pub fn try_open(&self) -> Option<ConnectionSlot> {
+        let opened = self.open.fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+            match open < self.max {
+                true => Some(open + 1),
+                false => None,
+            }
+        });
+        match opened {
+            Ok(_) => Some(ConnectionSlot { open: Arc::clone(&self.open) }),
+            Err(_) => None,
+        }
+    }
+}
+
+
+/// A connection counted against the limit, until it is dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    /// The count to take the connection off
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+
+/// The error for a client that was turned away because too many others are connected
+pub fn make_busy_error() -> ServerError {
+    ServerError::UnavailableError("Too many connections, try again later.".to_string())
+}
+
+
+/// The error for a client that took too long to send a request
+pub fn make_timeout_error() -> ServerError {
+    ServerError::TimeoutError("Took too long to send the request.".to_string())
+}
+
+
+/// What happens to a connection after a response is sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connection {
+    /// The connection stays open for more requests
+    KeepAlive {
+        /// How long the connection can go idle before it is closed
+        timeout: Duration,
+        /// How many more requests the connection can carry
+        remaining: usize,
+    },
+    /// The connection is closed
+    Close,
+}
+
+
+/// A request read off a connection
+pub struct HttpRequest {
+    /// The query sent in the body, or the statement for a resource route
+    pub query: Result<StreamQuery, ServerError>,
+    /// The html headers for this request
+    pub headers: HashMap<String, String>,
+    /// Whether the client wants to keep the connection open afterwards
+    pub keep_alive: bool,
+}
+
+
+/// Create a properly formatted HTTP response
+fn make_response(code: &str, json_payload: &str, connection: Connection) -> String {
+    let connection = match connection {
+        Connection::KeepAlive { timeout, remaining } => format!(
+            "Connection: keep-alive\r\nKeep-Alive: timeout={}, max={}\r\n", timeout.as_secs(), remaining
+        ),
+        Connection::Close => "Connection: close\r\n".to_string(),
+    };
+    format!("HTTP/1.1 {}\r\n\
+    {}\
+    Content-Type: application/json\r\n\
+    Content-Length: {}\r\n\
+    \r\n\
+    {}", code, connection, json_payload.len(), json_payload)
+}
+
+
+/// Turn the result of a request into the HTTP response for it
+pub fn format_response(response: Result<InterpreterResponse, ServerError>, connection: Connection) -> String {
+    let (code, json_payload) = match response {
+        Ok(response) => {
+            let code = "200 Ok".to_string();
+            let payload = response_to_json(&response);
+            (code, payload)
+        },
+        Err(error) => {
+            let code = error::get_error_code(&error);
+            (code, format!("{}", error))
+        }
+    };
+    make_response(&code, &json_payload, connection)
+}
+
+
+/// Extract the length of the query body from an HTTP request.
+fn extract_body_length_from_request(request: &Request) -> Result<Option<usize>, ServerError> {
+    let mut length: Option<usize> = None;
+    for header in request.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Content-Length") {
+            let length_str = match String::from_utf8(header.value.to_vec()) {
+                Ok(utf_str) => utf_str,
+                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+            };
+            length = match length_str.trim().parse() {
+                Ok(value) => Some(value),
+                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+            };
+            break;
+        }
+    }
+    Ok(length)
+}
+
+
+/// Extract the query from the body in the POST request.
+///
+/// The body holds either a `query` string in the query language or a list of `statements`
+/// already serialized, which keeps the case of keys and strings that parsing would lose.
+fn extract_request_from_body(body: &str) -> Result<StreamQuery, ServerError> {
+    let json_value: Result<Value, _> = serde_json::from_str(body);
+    let mut map = match json_value {
+        Ok(Value::Object(map)) => map,
+        _ => return Err(ServerError::RequestError("Malformed request.".to_string())),
+    };
+    match (map.remove("query"), map.remove("statements")) {
+        (Some(Value::String(query)), None) => Ok(StreamQuery::Text(query)),
+        (None, Some(statements)) => {
+            let statements: Vec<Statement> = match serde_json::from_value(statements) {
+                Ok(statements) => statements,
+                Err(_) => return Err(ServerError::RequestError("Malformed statements.".to_string())),
+            };
+            for statement in statements.iter() {
+                check_sent_statement(statement)?;
+            }
+            Ok(StreamQuery::Statements(statements))
+        },
+        _ => Err(ServerError::RequestError("Malformed request.".to_string())),
+    }
+}
+
+
+/// Check a statement sent already serialized is one a query could have given.
+///
+/// The parser never makes the statements the server only runs for itself, and only builds
+/// collections whose contents match their types, so neither can come in this way.
+fn check_sent_statement(statement: &Statement) -> Result<(), ServerError> {
+    match statement {
+        Statement::ExpireKeys => Err(ServerError::RequestError("Statement can't be sent by clients.".to_string())),
+        Statement::Scan(_, _, Some(0)) => Err(ServerError::RequestError("Expected a count above 0.".to_string())),
+        Statement::Set(_, value, _) | Statement::Update(_, value, _) | Statement::SetIfNotExists(_, value, _) |
+        Statement::CompareAndSet(_, _, value, _) | Statement::VectorSet(_, _, value) |
+        Statement::VectorAppend(_, value) | Statement::VectorPrepend(_, value) |
+        Statement::MapSet(_, _, value) => value.validate(),
+        _ => Ok(()),
+    }
+}
+
+
+/// Convert the headers of an HTTP request into a hashmap.
+fn convert_headers_to_map(request: &Request) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    for header in request.headers.iter() {
+        // Only the server can vouch for a client certificate
+        if header.name.eq_ignore_ascii_case(CLIENT_CERTIFICATE_HEADER) {
+            continue;
+        }
+        if let Ok(value) = String::from_utf8(header.value.to_vec()) {
+            map.insert(header.name.to_string(), value);
+        }
+    }
+    map
+}
+
+
+/// Check whether the client wants the connection kept open.
+///
+/// HTTP/1.1 connections stay open unless the client says otherwise, HTTP/1.0 ones only if asked.
+fn wants_keep_alive(request: &Request) -> bool {
+    let connection = request.headers.iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Connection"))
+        .map(|header| String::from_utf8_lossy(header.value).to_lowercase());
+    match connection {
+        Some(value) if value.split(',').any(|option| option.trim() == "close") => false,
+        Some(value) if value.split(',').any(|option| option.trim() == "keep-alive") => true,
+        _ => request.version == Some(1),
+    }
+}
+
+
+/// Parse the request at the start of the buffer.
+///
+/// Gives None until the whole request has arrived, then the request and how many bytes it took
+/// up, so anything after it can be kept for the next request on the connection.
+pub fn parse_request(buffer: &[u8], limits: &RequestLimits) -> Result<Option<(HttpRequest, usize)>, ServerError> {
+    let mut headers_list = [httparse::EMPTY_HEADER; MAX_NUMBER_OF_HEADERS];
+    let mut request = Request::new(&mut headers_list);
+    let headers_too_large = || ServerError::TooLargeError(
+        format!("Request headers are bigger than {} bytes.", limits.max_header_bytes)
+    );
+    let body_start = match request.parse(buffer) {
+        Ok(Status::Complete(size)) if size > limits.max_header_bytes => return Err(headers_too_large()),
+        Ok(Status::Complete(size)) => size,
+        Ok(Status::Partial) if buffer.len() > limits.max_header_bytes => return Err(headers_too_large()),
+        Ok(Status::Partial) => return Ok(None),
+        Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+    };
+    let body_length = extract_body_length_from_request(&request)?.unwrap_or(0);
+    // Checked before the body arrives, so it is never held in memory
+    if body_length > limits.max_body_bytes {
+        return Err(ServerError::TooLargeError(
+            format!("Request body is bigger than {} bytes.", limits.max_body_bytes)
+        ));
+    }
+    let request_length = match body_start.checked_add(body_length) {
+        Some(length) => length,
+        None => return Err(ServerError::RequestError("Malformed request.".to_string())),
+    };
+    if buffer.len() < request_length {
+        return Ok(None);
+    }
+    let body = String::from_utf8_lossy(&buffer[body_start..request_length]);
+    let path = request.path.unwrap_or("/");
+    let query = match request.method {
+        Some(method) if rest::is_route(path) => {
+            rest::route_request(method, path, &body).map(|statement| StreamQuery::Statements(vec![statement]))
+        },
+        Some("POST") => extract_request_from_body(&body),
+        _ => Err(ServerError::RequestError("Malformed request.".to_string())),
+    };
+    let request = HttpRequest {
+        query,
+        headers: convert_headers_to_map(&request),
+        keep_alive: wants_keep_alive(&request),
+    };
+    Ok(Some((request, request_length)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Statement;
+
+    #[test]
+    fn test_parse_pipelined_requests() {
+        let limits = RequestLimits::default();
+        let buffer = b"POST / HTTP/1.1\r\nContent-Length: 19\r\nUsername: admin\r\n\
+            client-certificate-subject: admin\r\n\r\n{\"query\": \"get a;\"}\
+            POST / HTTP/1.0\r\nContent-Length: 2\r\n\r\n{}GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let (request, used) = parse_request(buffer, &limits).unwrap().unwrap();
+        assert!(matches!(request.query, Ok(StreamQuery::Text(query)) if query == "get a;"));
+        assert_eq!(request.headers.get("Username").unwrap(), "admin");
+        assert!(!request.headers.contains_key("client-certificate-subject"));
+        assert!(request.keep_alive);
+
+        let (request, next_used) = parse_request(&buffer[used..], &limits).unwrap().unwrap();
+        assert!(matches!(request.query, Err(ServerError::RequestError(_))));
+        assert!(!request.keep_alive);
+
+        let rest = &buffer[used + next_used..];
+        assert!(parse_request(&rest[..10], &limits).unwrap().is_none());
+        let (request, last_used) = parse_request(rest, &limits).unwrap().unwrap();
+        assert!(request.query.is_err());
+        assert!(!request.keep_alive);
+        assert_eq!(last_used, rest.len());
+        assert!(parse_request(b"NOT HTTP\r\n\r\n", &limits).is_err());
+    }
+
+    #[test]
+    fn test_connection_after_requests() {
+        let config = KeepAliveConfig { idle_timeout: Duration::from_secs(3), max_requests: 2 };
+        let connection = config.connection_after(true, 1);
+        assert_eq!(connection, Connection::KeepAlive { timeout: Duration::from_secs(3), remaining: 1 });
+        assert_eq!(config.connection_after(true, 2), Connection::Close);
+        assert_eq!(config.connection_after(false, 1), Connection::Close);
+        let response = format_response(Ok(InterpreterResponse::Null), connection);
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\nConnection: keep-alive\r\nKeep-Alive: timeout=3, max=1\r\n"));
+        assert!(response.ends_with("\r\n\r\n\"Null\""));
+    }
+
+    #[test]
+    fn test_parse_statements() {
+        let body = "{\"statements\": [{\"Get\": \"MixedCase\"}, \"Null\"]}";
+        let buffer = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let (request, _) = parse_request(buffer.as_bytes(), &RequestLimits::default()).unwrap().unwrap();
+        let statements = vec![Statement::Get("MixedCase".to_string()), Statement::Null];
+        assert!(matches!(request.query, Ok(StreamQuery::Statements(found)) if found == statements));
+        let parse_body = |body: &str| {
+            let buffer = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+            parse_request(buffer.as_bytes(), &RequestLimits::default()).unwrap().unwrap().0.query
+        };
+        assert!(matches!(parse_body("{\"statements\": [{\"Nope\": 1}]}"), Err(ServerError::RequestError(_))));
+        assert!(matches!(parse_body("{\"statements\": [\"ExpireKeys\"]}"), Err(ServerError::RequestError(_))));
+        let mistyped = "{\"Vector\": {\"vector\": [{\"String\": \"x\"}], \"collection_type\": \"Int\"}}";
+        let body = format!("{{\"statements\": [{{\"Set\": [\"v\", {}, null]}}]}}", mistyped);
+        assert!(matches!(parse_body(&body), Err(ServerError::TypeError(_))));
+    }
+
+    #[test]
+    fn test_request_limits() {
+        let limits = RequestLimits { max_header_bytes: 64, max_body_bytes: 16, ..RequestLimits::default() };
+        // Too big a body is turned down as soon as the headers say so
+        let buffer = b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n";
+        assert!(matches!(parse_request(buffer, &limits), Err(ServerError::TooLargeError(_))));
+        let buffer = b"POST / HTTP/1.1\r\nContent-Length: 16\r\n\r\n";
+        assert!(parse_request(buffer, &limits).unwrap().is_none());
+        // Headers that never end are cut off at the limit
+        let buffer = format!("POST / HTTP/1.1\r\nUsername: {}", "a".repeat(64));
+        assert!(matches!(parse_request(buffer.as_bytes(), &limits), Err(ServerError::TooLargeError(_))));
+        assert!(parse_request(&buffer.as_bytes()[..40], &limits).unwrap().is_none());
+
+        let counter = ConnectionCounter::new(1);
+        let slot = counter.try_open();
+        assert!(slot.is_some());
+        assert!(counter.try_open().is_none());
+        drop(slot);
+        assert!(counter.try_open().is_some());
+        assert_eq!(error::get_error_code(&make_busy_error()), "503 Service Unavailable");
+    }
+}
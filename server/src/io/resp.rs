@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+use crate::analysis::{InterpreterResponse, ResponseMode};
+use crate::error::ServerError;
+use crate::io::http::{ConnectionCounter, ConnectionSlot, RequestLimits};
+use crate::io::stream::{StreamHandler, StreamQuery, StreamRequest, StreamSender};
+
+use self::commands::{Reply, error_to_resp, make_arity_error, make_reply, translate_command};
+use self::protocol::{RespValue, RespVersion, read_command};
+
+/// Reading commands and writing values in the protocol
+pub mod protocol;
+/// Translating commands into statements and results into replies
+pub mod commands;
+
+
+/// Write a value to the client
+fn write_value(stream: &mut TcpStream, value: &RespValue, version: RespVersion) -> Result<(), ServerError> {
+    let mut bytes = vec![];
+    value.encode(version, &mut bytes);
+    if stream.write_all(&bytes).is_err() {
+        return Err(ServerError::NetworkError("Error writing to stream.".to_string()));
+    }
+    if stream.flush().is_err() {
+        return Err(ServerError::NetworkError("Error flushing write buffer for stream.".to_string()));
+    }
+    Ok(())
+}
+
+
+/// Sends the reply to one command back to a client.
+pub struct RespStreamSender {
+    /// The connection to the client
+    stream: TcpStream,
+    /// The version of the protocol the client speaks
+    version: RespVersion,
+    /// How to turn the results into a reply
+    reply: Reply,
+    /// Lets the connection know it can read the next command
+    finished: Sender<()>,
+}
+
+impl StreamSender for RespStreamSender {
+    fn send(&mut self, response: Result<InterpreterResponse, ServerError>) -> Result<(), ServerError> {
+        let value = match response {
+            Ok(InterpreterResponse::Results(results)) => make_reply(self.reply, &results),
+            Ok(response) => make_reply(self.reply, &[Ok(response)]),
+            Err(error) => error_to_resp(&error),
+        };
+        let result = write_value(&mut self.stream, &value, self.version);
+        let _ = self.finished.send(());
+        result
+    }
+}
+
+
+/// Passes the response to a request back to the connection that sent it.
+struct ChannelSender {
+    /// Where the response goes
+    response: Sender<Result<InterpreterResponse, ServerError>>,
+}
+
+impl StreamSender for ChannelSender {
+    fn send(&mut self, response: Result<InterpreterResponse, ServerError>) -> Result<(), ServerError> {
+        let _ = self.response.send(response);
+        Ok(())
+    }
+}
+
+
+/// The reading side of a connection, which gives up on a command the client is too slow to send.
+struct TimedStream {
+    /// The connection to the client
+    stream: TcpStream,
+    /// When the command being read has to be finished by, or None while waiting for one
+    deadline: Option<Instant>,
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Some(left),
+                _ => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+            },
+            None => None,
+        };
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.read(buffer)
+    }
+}
+
+
+/// A client connection, which sends commands one at a time.
+///
+/// The next command isn't read until the reply to the last one has been written, so replies
+/// always come back in the order the commands were sent.
+struct RespConnection {
+    /// Where commands are read from
+    reader: BufReader<TimedStream>,
+    /// Where replies are written to
+    stream: TcpStream,
+    /// The version of the protocol the client asked for
+    version: RespVersion,
+    /// The headers sent along with every request, holding the credentials from AUTH
+    headers: HashMap<String, String>,
+    /// How big commands can be and how long clients get to send them
+    limits: RequestLimits,
+    /// The connection's place under the connection limit, or None if there was no room for it
+    slot: Option<ConnectionSlot>,
+    /// Where requests are handed over to the server
+    requests: Sender<StreamRequest>,
+}
+
+impl RespConnection {
+    /// Start handling a new client
+    fn new(
+        stream: TcpStream, limits: RequestLimits, slot: Option<ConnectionSlot>, requests: Sender<StreamRequest>
+    ) -> Result<RespConnection, ServerError> {
+        let reader = match stream.try_clone() {
+            Ok(reader) => BufReader::new(TimedStream { stream: reader, deadline: None }),
+            Err(_) => return Err(ServerError::NetworkError("Could not read TCP connection.".to_string())),
+        };
+        let mut headers = HashMap::new();
+        headers.insert(ResponseMode::HEADER.to_string(), "all".to_string());
+        Ok(RespConnection { reader, stream, version: RespVersion::Resp2, headers, limits, slot, requests })
+    }
+
+    /// Handle commands until the client leaves
+    fn run(&mut self) {
+        if self.stream.set_write_timeout(Some(self.limits.write_timeout)).is_err() {
+            return;
+        }
+        if self.slot.is_none() {
+            let error = RespValue::error("ERR", "max number of clients reached");
+            let _ = write_value(&mut self.stream, &error, self.version);
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return;
+        }
+        loop {
+            // Clients can stay idle between commands, but once one starts the rest has to follow in time
+            self.reader.get_mut().deadline = None;
+            match self.reader.fill_buf() {
+                Ok(buffer) if !buffer.is_empty() => (),
+                _ => break,
+            }
+            self.reader.get_mut().deadline = Some(Instant::now() + self.limits.read_timeout);
+            let arguments = match read_command(&mut self.reader, self.limits.max_body_bytes) {
+                Ok(Some(arguments)) if arguments.is_empty() => continue,
+                Ok(Some(arguments)) => arguments,
+                Ok(None) => break,
+                Err(ServerError::NetworkError(_)) => break,
+                Err(error) => {
+                    // The rest of the stream can't be trusted after a protocol error
+                    let _ = write_value(&mut self.stream, &error_to_resp(&error), self.version);
+                    break;
+                },
+            };
+            let name = String::from_utf8_lossy(&arguments[0]).to_uppercase();
+            if name == "QUIT" {
+                let _ = write_value(&mut self.stream, &RespValue::SimpleString("OK".to_string()), self.version);
+                break;
+            }
+            let result = match self.run_connection_command(&name, &arguments) {
+                Some(reply) => write_value(&mut self.stream, &reply, self.version),
+                None => self.run_command(&arguments),
+            };
+            if let Err(error) = result {
+                println!("{:?}", error);
+                break;
+            }
+        }
+        // Make room for another client before this one sees the connection close
+        self.slot = None;
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Run a command that only changes the connection, if it is one
+    fn run_connection_command(&mut self, name: &str, arguments: &[Vec<u8>]) -> Option<RespValue> {
+        let text: Vec<String> = arguments.iter().map(|argument| String::from_utf8_lossy(argument).to_string()).collect();
+        let reply = match name {
+            "PING" => match text.len() {
+                1 => RespValue::SimpleString("PONG".to_string()),
+                2 => RespValue::BulkString(arguments[1].clone()),
+                _ => make_arity_error(name),
+            },
+            "ECHO" => match text.len() {
+                2 => RespValue::BulkString(arguments[1].clone()),
+                _ => make_arity_error(name),
+            },
+            "AUTH" => match text.len() {
+                2 => self.authenticate("default", &text[1]),
+                3 => self.authenticate(&text[1], &text[2]),
+                _ => make_arity_error(name),
+            },
+            "HELLO" => self.hello(&text[1..]),
+            "SELECT" => match text.get(1).map(|index| &index[..]) {
+                Some("0") => RespValue::SimpleString("OK".to_string()),
+                Some(_) => RespValue::error("ERR", "DB index is out of range"),
+                None => make_arity_error(name),
+            },
+            // Clients send these when connecting, but there's nothing to set up
+            "CLIENT" => RespValue::SimpleString("OK".to_string()),
+            "COMMAND" => RespValue::Array(vec![]),
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    /// Check the credentials with the server and, if they are accepted, keep them to send along
+    /// with every later command
+    fn authenticate(&mut self, username: &str, password: &str) -> RespValue {
+        let mut headers = self.headers.clone();
+        let credentials = base64::encode(format!("{}:{}", username, password));
+        headers.insert("Authorization".to_string(), format!("Basic {}", credentials));
+        // A request with no statements runs nothing, but the server still authenticates it
+        let (response, response_receiver) = mpsc::channel();
+        let request = StreamRequest {
+            request: Ok(StreamQuery::Statements(vec![])),
+            headers: headers.clone(),
+            peer: self.stream.peer_addr().ok().map(|address| address.to_string()),
+            sender: Some(Box::new(ChannelSender { response })),
+        };
+        if self.requests.send(request).is_err() {
+            return RespValue::error("ERR", "the server has stopped");
+        }
+        match response_receiver.recv() {
+            // A user without access to anything is still who they say they are
+            Ok(Ok(_)) | Ok(Err(ServerError::AuthorizationError(_))) => {
+                self.headers = headers;
+                RespValue::SimpleString("OK".to_string())
+            },
+            Ok(Err(ServerError::AuthenticationError(_))) => {
+                RespValue::error("WRONGPASS", "invalid username-password pair")
+            },
+            Ok(Err(error)) => error_to_resp(&error),
+            Err(_) => RespValue::error("ERR", "no response from the server"),
+        }
+    }
+
+    /// Handle `HELLO [protover [AUTH username password] [SETNAME clientname]]`
+    fn hello(&mut self, arguments: &[String]) -> RespValue {
+        let version = match arguments.first().map(|version| &version[..]) {
+            None => self.version,
+            Some("2") => RespVersion::Resp2,
+            Some("3") => RespVersion::Resp3,
+            Some(_) => return RespValue::error("NOPROTO", "unsupported protocol version"),
+        };
+        let mut index = 1;
+        while index < arguments.len() {
+            match &arguments[index].to_uppercase()[..] {
+                "AUTH" if index + 2 < arguments.len() => {
+                    let reply = self.authenticate(&arguments[index + 1], &arguments[index + 2]);
+                    if let RespValue::Error(_) = reply {
+                        return reply;
+                    }
+                    index += 3;
+                },
+                "SETNAME" if index + 1 < arguments.len() => index += 2,
+                _ => return RespValue::error("ERR", "syntax error in HELLO"),
+            }
+        }
+        self.version = version;
+        let proto = match version {
+            RespVersion::Resp2 => 2,
+            RespVersion::Resp3 => 3,
+        };
+        RespValue::Map(vec![
+            (RespValue::bulk("server"), RespValue::bulk("rust-store")),
+            (RespValue::bulk("version"), RespValue::bulk(env!("CARGO_PKG_VERSION"))),
+            (RespValue::bulk("proto"), RespValue::Integer(proto)),
+            (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+            (RespValue::bulk("role"), RespValue::bulk("master")),
+            (RespValue::bulk("modules"), RespValue::Array(vec![])),
+        ])
+    }
+
+    /// Send a command to the server and wait for the reply to be written
+    fn run_command(&mut self, arguments: &[Vec<u8>]) -> Result<(), ServerError> {
+        let command = match translate_command(arguments) {
+            Ok(command) => command,
+            Err(error) => return write_value(&mut self.stream, &error, self.version),
+        };
+        let stream = match self.stream.try_clone() {
+            Ok(stream) => stream,
+            Err(_) => return Err(ServerError::NetworkError("Could not write to TCP connection.".to_string())),
+        };
+        let (finished, finished_receiver) = mpsc::channel();
+        let sender = RespStreamSender { stream, version: self.version, reply: command.reply, finished };
+        let request = StreamRequest {
+            request: Ok(StreamQuery::Statements(command.statements)),
+            headers: self.headers.clone(),
+            peer: self.stream.peer_addr().ok().map(|address| address.to_string()),
+            sender: Some(Box::new(sender)),
+        };
+        if self.requests.send(request).is_err() {
+            return Err(ServerError::NetworkError("The server has stopped.".to_string()));
+        }
+        if finished_receiver.recv().is_err() {
+            // The request was dropped without a reply
+            let error = RespValue::error("ERR", "no response from the server");
+            return write_value(&mut self.stream, &error, self.version);
+        }
+        Ok(())
+    }
+}
+
+
+/// Accept connections and handle each client on its own thread, turning away clients past the limit
+fn accept_connections(listener: TcpListener, requests: Sender<StreamRequest>, limits: RequestLimits) {
+    let connections = ConnectionCounter::new(limits.max_connections);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                println!("Could not accept RESP connection: {:?}", error);
+                continue;
+            },
+        };
+        match RespConnection::new(stream, limits, connections.try_open(), requests.clone()) {
+            Ok(mut connection) => {
+                thread::spawn(move || connection.run());
+            },
+            Err(error) => println!("{:?}", error),
+        }
+    }
+}
+
+
+/// Handles connections from clients speaking the Redis protocol (RESP2 or RESP3).
+///
+/// Each connection is read on its own thread and its commands are translated into statements,
+/// which are handed out one at a time by `receive_request`. Credentials given with AUTH or
+/// HELLO are checked by sending the server an empty request, and once accepted are sent along
+/// as basic `Authorization`. The request limits cap how many clients can
+/// connect, how big a command can be and how long a client has to finish sending one.
+pub struct RespStreamHandler {
+    /// Requests from every connection
+    requests: Receiver<StreamRequest>,
+    /// The address the handler is listening on
+    address: SocketAddr,
+}
+
+impl RespStreamHandler {
+    /// Start listening for connections on an IP address and a port.
+    pub fn new(ip_address: IpAddr, port: usize) -> RespStreamHandler {
+        RespStreamHandler::with_limits(ip_address, port, RequestLimits::default())
+    }
+
+    /// Start listening for connections, with limits on what clients can ask of the server.
+    pub fn with_limits(ip_address: IpAddr, port: usize, limits: RequestLimits) -> RespStreamHandler {
+        let listener = TcpListener::bind(format!("{}:{}", ip_address, port)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || accept_connections(listener, sender, limits));
+        RespStreamHandler { requests, address }
+    }
+
+    /// The address the handler is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl StreamHandler for RespStreamHandler {
+    fn receive_request(&mut self) -> Option<StreamRequest> {
+        self.requests.recv().ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Read};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::auth::MockAuthenticator;
+    use crate::analysis::Interpreter;
+    use crate::single_threaded::SingleThreadedServer;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    fn send(stream: &mut TcpStream, command: &str) -> String {
+        let arguments: Vec<&str> = command.split(' ').collect();
+        let mut request = format!("*{}\r\n", arguments.len());
+        for argument in arguments {
+            request.push_str(&format!("${}\r\n{}\r\n", argument.len(), argument));
+        }
+        stream.write_all(request.as_bytes()).unwrap();
+        // Every reply in this test fits on one line, apart from arrays and maps
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        let mut rest = vec![0; reader.buffer().len()];
+        reader.read_exact(&mut rest).unwrap();
+        reply + &String::from_utf8(rest).unwrap()
+    }
+
+    #[test]
+    fn test_commands_over_a_connection() {
+        let handler = RespStreamHandler::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let address = handler.local_addr();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        assert_eq!(send(&mut stream, "PING"), "+PONG\r\n");
+        assert!(send(&mut stream, "GET a").starts_with("-ERR"));
+        assert_eq!(send(&mut stream, "AUTH unauthenticated secret"), "-WRONGPASS invalid username-password pair\r\n");
+        assert!(send(&mut stream, "HELLO 3 AUTH unauthenticated secret").starts_with("-WRONGPASS"));
+        assert!(send(&mut stream, "GET a").starts_with("-ERR"));
+        assert_eq!(send(&mut stream, "AUTH admin secret"), "+OK\r\n");
+        assert_eq!(send(&mut stream, "SET user:1 Alice"), "+OK\r\n");
+        assert_eq!(send(&mut stream, "GET user:1"), "$5\r\nAlice\r\n");
+        assert_eq!(send(&mut stream, "GET missing"), "$-1\r\n");
+        assert_eq!(send(&mut stream, "HSET h f1 a f2 b"), ":2\r\n");
+        assert_eq!(send(&mut stream, "HSET h f1 c"), ":0\r\n");
+        assert!(send(&mut stream, "RPUSH h x").starts_with("-WRONGTYPE"));
+        assert_eq!(send(&mut stream, "LPUSH l b a"), ":2\r\n");
+        assert_eq!(send(&mut stream, "RPUSH l c"), ":3\r\n");
+        assert_eq!(send(&mut stream, "LINDEX l 0"), "$1\r\na\r\n");
+        assert_eq!(send(&mut stream, "DEL user:1 missing"), ":1\r\n");
+        assert_eq!(send(&mut stream, "TTL user:1"), ":-2\r\n");
+        assert!(send(&mut stream, "HELLO 3").starts_with("%6\r\n"));
+        assert_eq!(send(&mut stream, "GET missing"), "_\r\n");
+        assert!(send(&mut stream, "NOPE").starts_with("-ERR unknown command"));
+        assert_eq!(send(&mut stream, "SHUTDOWN"), "+OK\r\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = RequestLimits {
+            max_body_bytes: 64, read_timeout: Duration::from_millis(200), max_connections: 1, ..RequestLimits::default()
+        };
+        let handler = RespStreamHandler::with_limits(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, limits);
+        let mut first = TcpStream::connect(handler.local_addr()).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(send(&mut first, "PING"), "+PONG\r\n");
+
+        let mut second = TcpStream::connect(handler.local_addr()).unwrap();
+        second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "-ERR max number of clients reached\r\n");
+
+        assert!(send(&mut first, &format!("ECHO {}", "x".repeat(100))).starts_with("-ERR"));
+        // The connection closes after a bad command, which makes room for the next client
+        first.read_to_end(&mut vec![]).unwrap();
+        let mut first = TcpStream::connect(handler.local_addr()).unwrap();
+        first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        first.write_all(b"*1\r\n$4\r\nPI").unwrap();
+        let mut reply = vec![];
+        first.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[test]
+    fn test_auth_keeps_no_password() {
+        let mut handler = RespStreamHandler::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let mut stream = TcpStream::connect(handler.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let client = thread::spawn(move || {
+            assert_eq!(send(&mut stream, "AUTH admin secret"), "+OK\r\n");
+            send(&mut stream, "GET a")
+        });
+        for _ in 0..2 {
+            let request = handler.receive_request().unwrap();
+            assert_eq!(request.headers.get("Authorization").unwrap(), "Basic YWRtaW46c2VjcmV0");
+            assert!(!request.headers.contains_key("Password") && !request.headers.contains_key("Username"));
+            request.sender.unwrap().send(Ok(InterpreterResponse::Null)).unwrap();
+        }
+        assert_eq!(client.join().unwrap(), "$-1\r\n");
+    }
+}
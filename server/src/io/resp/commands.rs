@@ -0,0 +1,526 @@
+use crate::analysis::{InterpreterResponse, Statement, ValueType};
+use crate::error::ServerError;
+use crate::io::resp::protocol::RespValue;
+use crate::storage::{CollectionType, KeyType, StorageMap, StorageValue, StorageVector};
+
+
+/// How the results of a command's statements are turned into a reply.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reply {
+    /// The last result as it is, with missing keys as nulls
+    Value,
+    /// `OK` once the command succeeds
+    Ok,
+    /// `OK` if the last statement changed something, otherwise null
+    OkOrNull,
+    /// 1 if the last statement changed something or 0 if it didn't or the key was missing
+    Flag,
+    /// The size from the last result, or 0 if the key was missing
+    Length,
+    /// The time to live in seconds, -1 for keys that don't expire and -2 for missing keys
+    Ttl,
+    /// The type of the value, or `none` for missing keys
+    Type,
+    /// The value from each statement, with missing keys as nulls
+    Array,
+    /// The entries of a map, with a missing key as an empty map
+    Hash,
+    /// How many statements from `start`, taking every `step`, gave back `expected`
+    Count {
+        /// The first statement to count
+        start: usize,
+        /// The distance between statements to count
+        step: usize,
+        /// The boolean result being counted
+        expected: bool,
+    },
+}
+
+
+/// The statements a command translates into and how to reply once they've run
+#[derive(Clone, Debug, PartialEq)]
+pub struct RespCommand {
+    /// The statements to run
+    pub statements: Vec<Statement>,
+    /// How to turn the results into a reply
+    pub reply: Reply,
+}
+
+impl RespCommand {
+    fn new(statements: Vec<Statement>, reply: Reply) -> RespCommand {
+        RespCommand { statements, reply }
+    }
+}
+
+
+/// Create the error for a command given the wrong number of arguments
+pub fn make_arity_error(name: &str) -> RespValue {
+    RespValue::error("ERR", &format!("wrong number of arguments for '{}' command", name.to_lowercase()))
+}
+
+
+/// Create the error for a command that can't be parsed
+fn make_syntax_error() -> RespValue {
+    RespValue::error("ERR", "syntax error")
+}
+
+
+/// Read an argument as text
+fn text(argument: &[u8]) -> String {
+    String::from_utf8_lossy(argument).to_string()
+}
+
+
+/// Read an argument as a string value
+fn string_value(argument: &[u8]) -> StorageValue {
+    StorageValue::String(text(argument))
+}
+
+
+/// Read an argument as a non negative number
+fn number(argument: &[u8]) -> Result<u64, RespValue> {
+    match text(argument).parse() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(RespValue::error("ERR", "value is not an integer or out of range")),
+    }
+}
+
+
+/// Read an argument as an index into a list.
+///
+/// Vectors can only be indexed from the front, so the negative indices Redis uses to count from
+/// the end are turned down.
+fn list_index(argument: &[u8]) -> Result<usize, RespValue> {
+    match text(argument).parse::<i64>() {
+        Ok(value) if value < 0 => Err(RespValue::error("ERR", "negative list indices are not supported")),
+        Ok(value) => Ok(value as usize),
+        Err(_) => Err(RespValue::error("ERR", "value is not an integer or out of range")),
+    }
+}
+
+
+/// Check that a command got a number of arguments in the range, counting the command name
+fn check_arity(arguments: &[Vec<u8>], min: usize, max: Option<usize>) -> Result<(), RespValue> {
+    let too_many = match max {
+        Some(max) => arguments.len() > max,
+        None => false,
+    };
+    if arguments.len() < min || too_many {
+        return Err(make_arity_error(&text(&arguments[0])));
+    }
+    Ok(())
+}
+
+
+/// Translate a command into the statements that carry it out.
+///
+/// Values are always stored as strings, like Redis does. Lists are vectors of strings and
+/// hashes are maps from strings to strings, which are created by the first write.
+pub fn translate_command(arguments: &[Vec<u8>]) -> Result<RespCommand, RespValue> {
+    let name = text(&arguments[0]).to_uppercase();
+    let keys: Vec<String> = arguments[1..].iter().map(|argument| text(argument)).collect();
+    let command = match &name[..] {
+        "GET" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::GetIfExists(keys[0].clone())], Reply::Value)
+        },
+        "SET" => {
+            check_arity(arguments, 3, None)?;
+            translate_set(arguments)?
+        },
+        "SETNX" => {
+            check_arity(arguments, 3, Some(3))?;
+            let statement = Statement::SetIfNotExists(keys[0].clone(), string_value(&arguments[2]), None);
+            RespCommand::new(vec![statement], Reply::Flag)
+        },
+        "SETEX" => {
+            check_arity(arguments, 4, Some(4))?;
+            let lifetime = number(&arguments[2])?;
+            let statement = Statement::Set(keys[0].clone(), string_value(&arguments[3]), Some(lifetime));
+            RespCommand::new(vec![statement], Reply::Ok)
+        },
+        "MGET" => {
+            check_arity(arguments, 2, None)?;
+            let statements = keys.into_iter().map(Statement::GetIfExists).collect();
+            RespCommand::new(statements, Reply::Array)
+        },
+        "MSET" => {
+            if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+                return Err(make_arity_error(&name));
+            }
+            let statements = arguments[1..].chunks(2)
+                .map(|pair| Statement::Set(text(&pair[0]), string_value(&pair[1]), None))
+                .collect();
+            RespCommand::new(statements, Reply::Ok)
+        },
+        "DEL" | "UNLINK" => {
+            check_arity(arguments, 2, None)?;
+            let statements = keys.into_iter().map(Statement::Delete).collect();
+            RespCommand::new(statements, Reply::Count { start: 0, step: 1, expected: true })
+        },
+        "EXISTS" => {
+            check_arity(arguments, 2, None)?;
+            let statements = keys.into_iter().map(Statement::Exists).collect();
+            RespCommand::new(statements, Reply::Count { start: 0, step: 1, expected: true })
+        },
+        "EXPIRE" => {
+            check_arity(arguments, 3, Some(3))?;
+            let lifetime = number(&arguments[2])?;
+            RespCommand::new(vec![Statement::UpdateLifetime(keys[0].clone(), Some(lifetime))], Reply::Flag)
+        },
+        "PERSIST" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::UpdateLifetime(keys[0].clone(), None)], Reply::Flag)
+        },
+        "TTL" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::GetLifetime(keys[0].clone())], Reply::Ttl)
+        },
+        "TYPE" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::ValueType(keys[0].clone())], Reply::Type)
+        },
+        "KEYS" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::Keys(keys[0].clone())], Reply::Value)
+        },
+        "SCAN" => {
+            check_arity(arguments, 2, None)?;
+            translate_scan(arguments)?
+        },
+        "RPUSH" | "LPUSH" => {
+            check_arity(arguments, 3, None)?;
+            let key = keys[0].clone();
+            let empty = StorageValue::Vector(StorageVector::new(CollectionType::String));
+            let mut statements = vec![Statement::SetIfNotExists(key.clone(), empty, None)];
+            for value in &arguments[2..] {
+                let statement = match &name[..] {
+                    "RPUSH" => Statement::VectorAppend(key.clone(), string_value(value)),
+                    _ => Statement::VectorPrepend(key.clone(), string_value(value)),
+                };
+                statements.push(statement);
+            }
+            statements.push(Statement::VectorLength(key));
+            RespCommand::new(statements, Reply::Length)
+        },
+        "RPOP" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::VectorPop(keys[0].clone())], Reply::Value)
+        },
+        "LLEN" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::VectorLength(keys[0].clone())], Reply::Length)
+        },
+        "LINDEX" => {
+            check_arity(arguments, 3, Some(3))?;
+            let index = list_index(&arguments[2])?;
+            RespCommand::new(vec![Statement::VectorGet(keys[0].clone(), index)], Reply::Value)
+        },
+        "LSET" => {
+            check_arity(arguments, 4, Some(4))?;
+            let index = list_index(&arguments[2])?;
+            let statement = Statement::VectorSet(keys[0].clone(), index, string_value(&arguments[3]));
+            RespCommand::new(vec![statement], Reply::Ok)
+        },
+        "HSET" => {
+            if arguments.len() < 4 || !arguments.len().is_multiple_of(2) {
+                return Err(make_arity_error(&name));
+            }
+            let key = keys[0].clone();
+            let empty = StorageValue::Map(StorageMap::new(KeyType::String, CollectionType::String));
+            let mut statements = vec![Statement::SetIfNotExists(key.clone(), empty, None)];
+            for pair in arguments[2..].chunks(2) {
+                statements.push(Statement::MapExists(key.clone(), string_value(&pair[0])));
+                statements.push(Statement::MapSet(key.clone(), string_value(&pair[0]), string_value(&pair[1])));
+            }
+            RespCommand::new(statements, Reply::Count { start: 1, step: 2, expected: false })
+        },
+        "HGET" => {
+            check_arity(arguments, 3, Some(3))?;
+            RespCommand::new(vec![Statement::MapGet(keys[0].clone(), string_value(&arguments[2]))], Reply::Value)
+        },
+        "HDEL" => {
+            check_arity(arguments, 3, None)?;
+            let statements = arguments[2..].iter()
+                .map(|field| Statement::MapDelete(keys[0].clone(), string_value(field)))
+                .collect();
+            RespCommand::new(statements, Reply::Count { start: 0, step: 1, expected: true })
+        },
+        "HEXISTS" => {
+            check_arity(arguments, 3, Some(3))?;
+            RespCommand::new(vec![Statement::MapExists(keys[0].clone(), string_value(&arguments[2]))], Reply::Flag)
+        },
+        "HLEN" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::MapLength(keys[0].clone())], Reply::Length)
+        },
+        "HGETALL" => {
+            check_arity(arguments, 2, Some(2))?;
+            RespCommand::new(vec![Statement::GetIfExists(keys[0].clone())], Reply::Hash)
+        },
+        "SAVE" => {
+            check_arity(arguments, 1, Some(1))?;
+            RespCommand::new(vec![Statement::Save], Reply::Ok)
+        },
+        "BGSAVE" => {
+            check_arity(arguments, 1, Some(1))?;
+            RespCommand::new(vec![Statement::BackgroundSave], Reply::Value)
+        },
+        "SHUTDOWN" => {
+            check_arity(arguments, 1, Some(1))?;
+            RespCommand::new(vec![Statement::Shutdown], Reply::Ok)
+        },
+        _ => return Err(RespValue::error("ERR", &format!("unknown command '{}'", text(&arguments[0])))),
+    };
+    Ok(command)
+}
+
+
+/// Translate `SET key value [EX seconds | PX milliseconds] [NX | XX]`
+fn translate_set(arguments: &[Vec<u8>]) -> Result<RespCommand, RespValue> {
+    let key = text(&arguments[1]);
+    let value = string_value(&arguments[2]);
+    let mut lifetime = None;
+    let mut condition = None;
+    let mut index = 3;
+    while index < arguments.len() {
+        let option = text(&arguments[index]).to_uppercase();
+        match &option[..] {
+            "EX" | "PX" if lifetime.is_none() && index + 1 < arguments.len() => {
+                let amount = number(&arguments[index + 1])?;
+                // Lifetimes are kept in whole seconds, so milliseconds are rounded up
+                lifetime = Some(if option == "EX" { amount } else { amount.div_ceil(1000) });
+                index += 2;
+            },
+            "NX" | "XX" if condition.is_none() => {
+                condition = Some(option);
+                index += 1;
+            },
+            _ => return Err(make_syntax_error()),
+        }
+    }
+    let command = match condition.as_deref() {
+        Some("NX") => RespCommand::new(vec![Statement::SetIfNotExists(key, value, lifetime)], Reply::OkOrNull),
+        Some(_) => RespCommand::new(vec![Statement::Update(key, value, lifetime)], Reply::OkOrNull),
+        None => RespCommand::new(vec![Statement::Set(key, value, lifetime)], Reply::Ok),
+    };
+    Ok(command)
+}
+
+
+/// Translate `SCAN cursor [MATCH pattern] [COUNT count]`
+fn translate_scan(arguments: &[Vec<u8>]) -> Result<RespCommand, RespValue> {
+    let cursor = number(&arguments[1])?;
+    let mut pattern = None;
+    let mut count = None;
+    let mut index = 2;
+    while index + 1 < arguments.len() {
+        match &text(&arguments[index]).to_uppercase()[..] {
+            "MATCH" => pattern = Some(text(&arguments[index + 1])),
+            "COUNT" => match number(&arguments[index + 1])? {
+                0 => return Err(make_syntax_error()),
+                value => count = Some(value as usize),
+            },
+            _ => return Err(make_syntax_error()),
+        }
+        index += 2;
+    }
+    if index != arguments.len() {
+        return Err(make_syntax_error());
+    }
+    Ok(RespCommand::new(vec![Statement::Scan(cursor, pattern, count)], Reply::Value))
+}
+
+
+/// Check if an error only means that the key or entry asked for isn't there
+fn is_missing(error: &ServerError) -> bool {
+    matches!(error, ServerError::KeyError(_) | ServerError::IndexError(_))
+}
+
+
+/// Convert an error into a RESP error
+pub fn error_to_resp(error: &ServerError) -> RespValue {
+    let kind = match error {
+        ServerError::TypeError(_) => "WRONGTYPE",
+        ServerError::AuthenticationError(_) => "NOAUTH",
+        ServerError::AuthorizationError(_) => "NOPERM",
+        _ => "ERR",
+    };
+    RespValue::error(kind, &error.to_string())
+}
+
+
+/// Convert a stored value into a RESP value
+pub fn value_to_resp(value: &StorageValue) -> RespValue {
+    match value {
+        StorageValue::Null => RespValue::Null,
+        StorageValue::Bool(value) => RespValue::Boolean(*value),
+        StorageValue::String(value) => RespValue::bulk(value),
+        StorageValue::Int(value) => RespValue::Integer(*value),
+        StorageValue::Float(value) => RespValue::Double(*value as f64),
+        StorageValue::Vector(vector) => RespValue::Array(vector.iter().map(value_to_resp).collect()),
+        StorageValue::Map(map) => RespValue::Map(
+            map.iter().map(|(key, value)| (value_to_resp(key), value_to_resp(value))).collect()
+        ),
+    }
+}
+
+
+/// Get the name Redis uses for the type of a value
+fn type_name(value_type: &ValueType) -> &'static str {
+    match value_type {
+        ValueType::Vector(_) => "list",
+        ValueType::Map(..) => "hash",
+        ValueType::Null => "none",
+        ValueType::Bool | ValueType::Int | ValueType::Float | ValueType::String => "string",
+    }
+}
+
+
+/// Convert a response from the interpreter into a RESP value
+pub fn response_to_resp(response: &InterpreterResponse) -> RespValue {
+    match response {
+        InterpreterResponse::Value(value) => value_to_resp(value),
+        InterpreterResponse::Message(message) if message == "Ok" => RespValue::SimpleString("OK".to_string()),
+        InterpreterResponse::Message(message) => RespValue::SimpleString(message.clone()),
+        InterpreterResponse::Size(size) => RespValue::Integer(*size as i64),
+        InterpreterResponse::Expiration(Some(lifetime)) => RespValue::Integer(*lifetime as i64),
+        InterpreterResponse::Expiration(None) => RespValue::Null,
+        InterpreterResponse::Key(key) => RespValue::bulk(key),
+        InterpreterResponse::Keys(keys) => RespValue::Array(keys.iter().map(|key| RespValue::bulk(key)).collect()),
+        InterpreterResponse::Scan(cursor, keys) => RespValue::Array(vec![
+            RespValue::bulk(&cursor.to_string()),
+            RespValue::Array(keys.iter().map(|key| RespValue::bulk(key)).collect()),
+        ]),
+        InterpreterResponse::Version(version) => RespValue::Integer(*version as i64),
+        InterpreterResponse::Bool(value) => RespValue::Boolean(*value),
+        InterpreterResponse::ValueType(value_type) => RespValue::SimpleString(type_name(value_type).to_string()),
+        InterpreterResponse::Results(results) => RespValue::Array(
+            results.iter().map(|result| match result {
+                Ok(response) => response_to_resp(response),
+                Err(error) => error_to_resp(error),
+            }).collect()
+        ),
+        InterpreterResponse::ShuttingDown => RespValue::SimpleString("OK".to_string()),
+        InterpreterResponse::Null => RespValue::Null,
+    }
+}
+
+
+/// Convert the result of one statement, treating missing keys as nulls
+fn result_to_resp(result: &Result<InterpreterResponse, ServerError>) -> RespValue {
+    match result {
+        Ok(response) => response_to_resp(response),
+        Err(error) if is_missing(error) => RespValue::Null,
+        Err(error) => error_to_resp(error),
+    }
+}
+
+
+/// Build the reply to a command from the results of each of its statements.
+pub fn make_reply(reply: Reply, results: &[Result<InterpreterResponse, ServerError>]) -> RespValue {
+    // Anything worse than a missing key fails the whole command
+    if let Some(Err(error)) = results.iter().find(|result| matches!(result, Err(error) if !is_missing(error))) {
+        return error_to_resp(error);
+    }
+    let last = match results.last() {
+        Some(last) => last,
+        None => return RespValue::Null,
+    };
+    match reply {
+        Reply::Value => result_to_resp(last),
+        Reply::Ok => RespValue::SimpleString("OK".to_string()),
+        Reply::OkOrNull => match last {
+            Ok(InterpreterResponse::Bool(false)) | Err(_) => RespValue::Null,
+            Ok(_) => RespValue::SimpleString("OK".to_string()),
+        },
+        Reply::Flag => match last {
+            Ok(InterpreterResponse::Bool(value)) => RespValue::Integer(*value as i64),
+            Ok(_) => RespValue::Integer(1),
+            Err(_) => RespValue::Integer(0),
+        },
+        Reply::Length => match last {
+            Ok(InterpreterResponse::Size(size)) => RespValue::Integer(*size as i64),
+            _ => RespValue::Integer(0),
+        },
+        Reply::Ttl => match last {
+            Ok(InterpreterResponse::Expiration(Some(lifetime))) => RespValue::Integer(*lifetime as i64),
+            Ok(_) => RespValue::Integer(-1),
+            Err(_) => RespValue::Integer(-2),
+        },
+        Reply::Type => match last {
+            Ok(InterpreterResponse::ValueType(value_type)) => {
+                RespValue::SimpleString(type_name(value_type).to_string())
+            },
+            _ => RespValue::SimpleString("none".to_string()),
+        },
+        Reply::Array => RespValue::Array(results.iter().map(result_to_resp).collect()),
+        Reply::Hash => match last {
+            Ok(InterpreterResponse::Value(StorageValue::Map(map))) => {
+                value_to_resp(&StorageValue::Map(map.clone()))
+            },
+            Ok(InterpreterResponse::Value(StorageValue::Null)) => RespValue::Map(vec![]),
+            _ => RespValue::error("WRONGTYPE", "Operation against a key holding the wrong kind of value"),
+        },
+        Reply::Count { start, step, expected } => {
+            let count = results.iter()
+                .skip(start)
+                .step_by(step)
+                .filter(|result| matches!(result, Ok(InterpreterResponse::Bool(value)) if *value == expected))
+                .count();
+            RespValue::Integer(count as i64)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(text: &str) -> Vec<Vec<u8>> {
+        text.split(' ').map(|argument| argument.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_translate_commands() {
+        let translated = translate_command(&command("set user:1 Alice EX 10 NX")).unwrap();
+        assert_eq!(translated.statements, vec![
+            Statement::SetIfNotExists("user:1".to_string(), StorageValue::String("Alice".to_string()), Some(10))
+        ]);
+        assert_eq!(translated.reply, Reply::OkOrNull);
+        let translated = translate_command(&command("HSET h a 1 b 2")).unwrap();
+        assert_eq!(translated.statements.len(), 5);
+        assert!(matches!(translated.statements[3], Statement::MapExists(..)));
+        let translated = translate_command(&command("SCAN 4 COUNT 2")).unwrap();
+        assert_eq!(translated.statements, vec![Statement::Scan(4, None, Some(2))]);
+        assert!(matches!(translate_command(&command("SCAN 4 COUNT 0")), Err(RespValue::Error(_))));
+
+        assert!(matches!(translate_command(&command("GET")), Err(RespValue::Error(_))));
+        assert!(matches!(translate_command(&command("SET a b EX")), Err(RespValue::Error(_))));
+        assert!(matches!(translate_command(&command("FLUSHALL")), Err(RespValue::Error(_))));
+        assert!(matches!(
+            translate_command(&command("LINDEX l -1")), Err(RespValue::Error(error)) if error.contains("negative")
+        ));
+        assert!(matches!(translate_command(&command("LSET l -1 x")), Err(RespValue::Error(_))));
+        assert!(matches!(translate_command(&command("HSET h a 1 b")), Err(RespValue::Error(_))));
+        assert!(matches!(translate_command(&command("SHUTDOWN NOW")), Err(RespValue::Error(_))));
+    }
+
+    #[test]
+    fn test_make_reply() {
+        let missing = Err(ServerError::KeyError("missing".to_string()));
+        assert_eq!(make_reply(Reply::Ttl, std::slice::from_ref(&missing)), RespValue::Integer(-2));
+        assert_eq!(make_reply(Reply::Flag, std::slice::from_ref(&missing)), RespValue::Integer(0));
+        let results = vec![
+            Ok(InterpreterResponse::Bool(true)), Ok(InterpreterResponse::Bool(false)),
+            Ok(InterpreterResponse::Message("Ok".to_string())), Ok(InterpreterResponse::Bool(false)),
+            Ok(InterpreterResponse::Message("Ok".to_string())),
+        ];
+        assert_eq!(make_reply(Reply::Count { start: 1, step: 2, expected: false }, &results), RespValue::Integer(2));
+        let results = vec![Ok(InterpreterResponse::Value(StorageValue::Int(1))), missing];
+        assert_eq!(make_reply(Reply::Array, &results), RespValue::Array(vec![RespValue::Integer(1), RespValue::Null]));
+        let results = vec![Ok(InterpreterResponse::Bool(true)), Err(ServerError::TypeError("bad".to_string()))];
+        assert!(matches!(make_reply(Reply::Length, &results), RespValue::Error(error) if error.starts_with("WRONGTYPE")));
+    }
+}
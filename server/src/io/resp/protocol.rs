@@ -0,0 +1,262 @@
+use std::io::{BufRead, Read};
+
+use crate::error::ServerError;
+
+
+/// The longest bulk string a client can send
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+/// The most arguments a single command can have
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+/// The most arguments room is made for before any of them have arrived
+const PREALLOCATED_ARGUMENTS: usize = 64;
+
+
+/// The versions of the protocol a connection can speak
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RespVersion {
+    /// RESP2, which every client supports
+    Resp2,
+    /// RESP3, which adds nulls, booleans, doubles and maps
+    Resp3,
+}
+
+
+/// A value that can be sent back to a client
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    /// A short status message
+    SimpleString(String),
+    /// An error, starting with its kind like `ERR` or `WRONGTYPE`
+    Error(String),
+    /// An integer
+    Integer(i64),
+    /// Binary safe string
+    BulkString(Vec<u8>),
+    /// A list of values
+    Array(Vec<RespValue>),
+    /// Nothing - a null bulk string in RESP2
+    Null,
+    /// A boolean - an integer in RESP2
+    Boolean(bool),
+    /// A floating point number - a bulk string in RESP2
+    Double(f64),
+    /// Key value pairs - a flat array in RESP2
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    /// Create a bulk string from some text
+    pub fn bulk(text: &str) -> RespValue {
+        RespValue::BulkString(text.as_bytes().to_vec())
+    }
+
+    /// Create an error of the given kind
+    pub fn error(kind: &str, message: &str) -> RespValue {
+        // The message has to stay on one line
+        let message = message.replace(['\r', '\n'], " ");
+        RespValue::Error(format!("{} {}", kind, message))
+    }
+
+    /// Write the value out in the given version of the protocol
+    pub fn encode(&self, version: RespVersion, out: &mut Vec<u8>) {
+        match self {
+            RespValue::SimpleString(text) => push_line(out, '+', text),
+            RespValue::Error(text) => push_line(out, '-', text),
+            RespValue::Integer(value) => push_line(out, ':', &value.to_string()),
+            RespValue::BulkString(bytes) => {
+                push_line(out, '$', &bytes.len().to_string());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            },
+            RespValue::Array(values) => {
+                push_line(out, '*', &values.len().to_string());
+                for value in values {
+                    value.encode(version, out);
+                }
+            },
+            RespValue::Null => match version {
+                RespVersion::Resp2 => out.extend_from_slice(b"$-1\r\n"),
+                RespVersion::Resp3 => out.extend_from_slice(b"_\r\n"),
+            },
+            RespValue::Boolean(value) => match version {
+                RespVersion::Resp2 => RespValue::Integer(*value as i64).encode(version, out),
+                RespVersion::Resp3 => push_line(out, '#', if *value { "t" } else { "f" }),
+            },
+            RespValue::Double(value) => match version {
+                RespVersion::Resp2 => RespValue::bulk(&value.to_string()).encode(version, out),
+                RespVersion::Resp3 => push_line(out, ',', &format_double(*value)),
+            },
+            RespValue::Map(entries) => {
+                match version {
+                    RespVersion::Resp2 => push_line(out, '*', &(2 * entries.len()).to_string()),
+                    RespVersion::Resp3 => push_line(out, '%', &entries.len().to_string()),
+                }
+                for (key, value) in entries {
+                    key.encode(version, out);
+                    value.encode(version, out);
+                }
+            },
+        }
+    }
+}
+
+
+/// Write a type marker and a line of text
+fn push_line(out: &mut Vec<u8>, marker: char, text: &str) {
+    out.push(marker as u8);
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+
+/// Format a double the way RESP3 expects, including infinities
+fn format_double(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "inf".to_string() } else { "-inf".to_string() }
+    } else if value.is_nan() {
+        "nan".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+
+/// Create an error for a request that doesn't follow the protocol
+fn make_protocol_error(message: &str) -> ServerError {
+    ServerError::RequestError(format!("Protocol error: {}", message))
+}
+
+
+/// Read one line of at most `limit` bytes, without the line ending. Gives None at the end of
+/// the stream.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<Vec<u8>>, ServerError> {
+    let mut line = vec![];
+    match reader.by_ref().take(limit as u64 + 2).read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => (),
+        Err(_) => return Err(ServerError::NetworkError("Problem reading request.".to_string())),
+    }
+    if line.last() != Some(&b'\n') && line.len() > limit {
+        return Err(make_protocol_error("line is too long"));
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(ServerError::NetworkError("Connection closed in the middle of a request.".to_string()));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+
+/// Parse the length that follows a type marker
+fn parse_length(line: &[u8], marker: u8, limit: usize) -> Result<usize, ServerError> {
+    if line.first() != Some(&marker) {
+        return Err(make_protocol_error(&format!("expected '{}'", marker as char)));
+    }
+    let length = std::str::from_utf8(&line[1..]).ok()
+        .and_then(|length| length.parse::<usize>().ok());
+    match length {
+        Some(length) if length <= limit => Ok(length),
+        _ => Err(make_protocol_error("invalid length")),
+    }
+}
+
+
+/// Read the next command a client sent, as a list of arguments taking up at most `max_bytes`.
+///
+/// Commands are normally arrays of bulk strings, but inline commands separated by spaces are
+/// accepted as well so the server can be used from telnet. Gives None once the client is gone.
+///
+/// Buffers grow as the bytes arrive rather than trusting the lengths the client announces, so a
+/// client can't make the server set aside memory it never sends.
+pub fn read_command<R: BufRead>(reader: &mut R, max_bytes: usize) -> Result<Option<Vec<Vec<u8>>>, ServerError> {
+    let line = loop {
+        match read_line(reader, max_bytes)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+    if line[0] != b'*' {
+        let arguments = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|argument| !argument.is_empty())
+            .map(|argument| argument.to_vec())
+            .collect();
+        return Ok(Some(arguments));
+    }
+    let count = parse_length(&line, b'*', MAX_ARGUMENTS)?;
+    let mut arguments = Vec::with_capacity(count.min(PREALLOCATED_ARGUMENTS));
+    let mut remaining = max_bytes;
+    for _ in 0..count {
+        let line = match read_line(reader, max_bytes)? {
+            Some(line) => line,
+            None => return Err(make_protocol_error("missing arguments")),
+        };
+        let length = parse_length(&line, b'$', MAX_BULK_LENGTH)?;
+        remaining = match remaining.checked_sub(length) {
+            Some(remaining) => remaining,
+            None => return Err(make_protocol_error("command is too large")),
+        };
+        let mut argument = vec![];
+        match reader.by_ref().take(length as u64 + 2).read_to_end(&mut argument) {
+            Ok(read) if read == length + 2 => (),
+            _ => return Err(ServerError::NetworkError("Problem reading request.".to_string())),
+        }
+        if !argument.ends_with(b"\r\n") {
+            return Err(make_protocol_error("expected a line ending after a bulk string"));
+        }
+        argument.truncate(length);
+        arguments.push(argument);
+    }
+    Ok(Some(arguments))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: RespValue, version: RespVersion) -> String {
+        let mut out = vec![];
+        value.encode(version, &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_read_commands() {
+        let mut input: &[u8] = b"*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$5\r\na\r\nb!\r\nPING  hello\r\n\r\n";
+        let command = read_command(&mut input, 1024).unwrap().unwrap();
+        assert_eq!(command, vec![b"SET".to_vec(), b"key1".to_vec(), b"a\r\nb!".to_vec()]);
+        let command = read_command(&mut input, 1024).unwrap().unwrap();
+        assert_eq!(command, vec![b"PING".to_vec(), b"hello".to_vec()]);
+        assert!(read_command(&mut input, 1024).unwrap().is_none());
+
+        let mut input: &[u8] = b"*2\r\n$3\r\nGET\r\n$10\r\nkey\r\n";
+        assert!(read_command(&mut input, 1024).is_err());
+        let mut input: &[u8] = b"*x\r\n";
+        assert!(matches!(read_command(&mut input, 1024), Err(ServerError::RequestError(_))));
+        let mut input: &[u8] = b"*1048576\r\n$536870912\r\nabc";
+        assert!(matches!(read_command(&mut input, 1 << 30), Err(ServerError::NetworkError(_))));
+
+        let mut input: &[u8] = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+        assert!(matches!(read_command(&mut input, 5), Err(ServerError::RequestError(_))));
+        let mut input: &[u8] = b"PING hello world\r\n";
+        assert!(matches!(read_command(&mut input, 5), Err(ServerError::RequestError(_))));
+    }
+
+    #[test]
+    fn test_encode_versions() {
+        let value = RespValue::Map(vec![
+            (RespValue::bulk("a"), RespValue::Boolean(true)),
+            (RespValue::bulk("b"), RespValue::Null),
+        ]);
+        assert_eq!(encode(value.clone(), RespVersion::Resp2), "*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n");
+        assert_eq!(encode(value, RespVersion::Resp3), "%2\r\n$1\r\na\r\n#t\r\n$1\r\nb\r\n_\r\n");
+        assert_eq!(encode(RespValue::Double(1.5), RespVersion::Resp2), "$3\r\n1.5\r\n");
+        assert_eq!(encode(RespValue::Double(1.5), RespVersion::Resp3), ",1.5\r\n");
+        assert_eq!(encode(RespValue::error("ERR", "bad\r\nline"), RespVersion::Resp2), "-ERR bad  line\r\n");
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use crate::analysis::Statement;
+use crate::error::ServerError;
+use crate::storage::{StorageKey, StorageValue};
+
+
+/// The path every resource lives under
+pub const KEYS_PATH: &str = "/keys";
+
+
+/// Create an error for a request that doesn't match any route
+fn make_route_error(method: &str, path: &str) -> ServerError {
+    ServerError::RequestError(format!("No route for {} {}.", method, path))
+}
+
+
+/// Decode the percent escapes and plus signs in part of a URL
+fn decode(text: &str) -> Result<String, ServerError> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let byte = text.get(index + 1..index + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => decoded.push(byte),
+                    None => return Err(ServerError::RequestError("Malformed escape in URL.".to_string())),
+                }
+                index += 3;
+            },
+            b'+' => {
+                decoded.push(b' ');
+                index += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            },
+        }
+    }
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Ok(decoded),
+        Err(_) => Err(ServerError::RequestError("URL is not valid UTF-8.".to_string())),
+    }
+}
+
+
+/// Split the query string of a URL into its parameters
+fn parse_parameters(query: &str) -> Result<HashMap<String, String>, ServerError> {
+    let mut parameters = HashMap::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        parameters.insert(decode(name)?, decode(value)?);
+    }
+    Ok(parameters)
+}
+
+
+/// Read a value from the body, written the way responses show them, like `{"Int": 5}`
+fn parse_value(body: &str) -> Result<StorageValue, ServerError> {
+    let value: StorageValue = match serde_json::from_str(body) {
+        Ok(value) => value,
+        Err(_) => return Err(ServerError::RequestError("Body should be a JSON storage value.".to_string())),
+    };
+    value.validate()?;
+    Ok(value)
+}
+
+
+/// Read a lifetime in seconds from the `ttl` parameter
+fn parse_ttl(parameters: &HashMap<String, String>) -> Result<Option<u64>, ServerError> {
+    match parameters.get("ttl") {
+        None => Ok(None),
+        Some(ttl) => match ttl.parse() {
+            Ok(ttl) => Ok(Some(ttl)),
+            Err(_) => Err(ServerError::RequestError("ttl should be a number of seconds.".to_string())),
+        },
+    }
+}
+
+
+/// Read an index into a vector from the path
+fn parse_index(index: &str) -> Result<usize, ServerError> {
+    match index.parse() {
+        Ok(index) => Ok(index),
+        Err(_) => Err(ServerError::RequestError(format!("{} is not a valid index.", index))),
+    }
+}
+
+
+/// Read a map key from the path. Keys are strings unless `key_type=int` is given.
+fn parse_map_key(field: String, parameters: &HashMap<String, String>) -> Result<StorageValue, ServerError> {
+    match parameters.get("key_type").map(|key_type| &key_type[..]) {
+        None | Some("string") => Ok(StorageValue::String(field)),
+        Some("int") => match field.parse() {
+            Ok(field) => Ok(StorageValue::Int(field)),
+            Err(_) => Err(ServerError::RequestError(format!("{} is not a valid integer key.", field))),
+        },
+        Some(key_type) => Err(ServerError::RequestError(format!("Unknown key type {}.", key_type))),
+    }
+}
+
+
+/// Check if a request is for the resource style API rather than a query
+pub fn is_route(path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or("");
+    path == KEYS_PATH || path.starts_with("/keys/")
+}
+
+
+/// Turn a request for a resource into the statement it stands for.
+///
+/// | Route | Statement |
+/// | --- | --- |
+/// | `GET /keys?pattern=` | `Keys`, every key when no pattern is given |
+/// | `GET /keys/{key}` | `Get` |
+/// | `PUT /keys/{key}?ttl=` | `Set` with the value in the body |
+/// | `DELETE /keys/{key}` | `Delete` |
+/// | `GET /keys/{key}/ttl` | `GetLifetime` |
+/// | `PUT /keys/{key}/ttl?ttl=` | `UpdateLifetime`, removing the lifetime when no ttl is given |
+/// | `GET /keys/{key}/type` | `ValueType` |
+/// | `GET /keys/{key}/items` | `VectorLength` |
+/// | `POST /keys/{key}/items` | `VectorAppend` with the value in the body |
+/// | `DELETE /keys/{key}/items` | `VectorPop` |
+/// | `GET /keys/{key}/items/{index}` | `VectorGet` |
+/// | `PUT /keys/{key}/items/{index}` | `VectorSet` with the value in the body |
+/// | `GET /keys/{key}/entries` | `MapLength` |
+/// | `GET /keys/{key}/entries/{field}?key_type=` | `MapGet` |
+/// | `PUT /keys/{key}/entries/{field}?key_type=` | `MapSet` with the value in the body |
+/// | `DELETE /keys/{key}/entries/{field}?key_type=` | `MapDelete` |
+pub fn route_request(method: &str, path: &str, body: &str) -> Result<Statement, ServerError> {
+    let (resource, query) = path.split_once('?').unwrap_or((path, ""));
+    let parameters = parse_parameters(query)?;
+    let segments = match resource.strip_prefix(KEYS_PATH) {
+        Some("") | Some("/") => vec![],
+        Some(rest) if rest.starts_with('/') => rest[1..]
+            .split('/')
+            .map(decode)
+            .collect::<Result<Vec<String>, ServerError>>()?,
+        _ => return Err(make_route_error(method, resource)),
+    };
+    let mut segments = segments.into_iter();
+    let key: Option<StorageKey> = segments.next();
+    let collection = segments.next();
+    let item = segments.next();
+    if segments.next().is_some() || matches!(&key, Some(key) if key.is_empty()) {
+        return Err(make_route_error(method, resource));
+    }
+
+    let key = match key {
+        Some(key) => key,
+        None => return match method {
+            "GET" => {
+                let pattern = parameters.get("pattern").cloned().unwrap_or_else(|| "*".to_string());
+                Ok(Statement::Keys(pattern))
+            },
+            _ => Err(make_route_error(method, resource)),
+        },
+    };
+    let statement = match (method, collection.as_deref(), item) {
+        ("GET", None, None) => Statement::Get(key),
+        ("PUT", None, None) => Statement::Set(key, parse_value(body)?, parse_ttl(&parameters)?),
+        ("DELETE", None, None) => Statement::Delete(key),
+        ("GET", Some("ttl"), None) => Statement::GetLifetime(key),
+        ("PUT", Some("ttl"), None) => Statement::UpdateLifetime(key, parse_ttl(&parameters)?),
+        ("GET", Some("type"), None) => Statement::ValueType(key),
+        ("GET", Some("items"), None) => Statement::VectorLength(key),
+        ("POST", Some("items"), None) => Statement::VectorAppend(key, parse_value(body)?),
+        ("DELETE", Some("items"), None) => Statement::VectorPop(key),
+        ("GET", Some("items"), Some(index)) => Statement::VectorGet(key, parse_index(&index)?),
+        ("PUT", Some("items"), Some(index)) => {
+            Statement::VectorSet(key, parse_index(&index)?, parse_value(body)?)
+        },
+        ("GET", Some("entries"), None) => Statement::MapLength(key),
+        ("GET", Some("entries"), Some(field)) => Statement::MapGet(key, parse_map_key(field, &parameters)?),
+        ("PUT", Some("entries"), Some(field)) => {
+            Statement::MapSet(key, parse_map_key(field, &parameters)?, parse_value(body)?)
+        },
+        ("DELETE", Some("entries"), Some(field)) => {
+            Statement::MapDelete(key, parse_map_key(field, &parameters)?)
+        },
+        _ => return Err(make_route_error(method, resource)),
+    };
+    Ok(statement)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        assert_eq!(route_request("GET", "/keys/user%3A1", "").unwrap(), Statement::Get("user:1".to_string()));
+        assert_eq!(
+            route_request("PUT", "/keys/a?ttl=30", "{\"Int\": 5}").unwrap(),
+            Statement::Set("a".to_string(), StorageValue::Int(5), Some(30))
+        );
+        assert_eq!(route_request("PUT", "/keys/a/ttl", "").unwrap(), Statement::UpdateLifetime("a".to_string(), None));
+        assert_eq!(route_request("GET", "/keys/v/items/3", "").unwrap(), Statement::VectorGet("v".to_string(), 3));
+        assert_eq!(
+            route_request("DELETE", "/keys/m/entries/7?key_type=int", "").unwrap(),
+            Statement::MapDelete("m".to_string(), StorageValue::Int(7))
+        );
+        assert_eq!(
+            route_request("PUT", "/keys/m/entries/first+name", "{\"String\": \"Ada\"}").unwrap(),
+            Statement::MapSet(
+                "m".to_string(), StorageValue::String("first name".to_string()), StorageValue::String("Ada".to_string())
+            )
+        );
+        assert_eq!(route_request("GET", "/keys?pattern=user*", "").unwrap(), Statement::Keys("user*".to_string()));
+
+        assert!(is_route("/keys/a?ttl=3") && is_route("/keys") && !is_route("/keysx") && !is_route("/"));
+        assert!(matches!(route_request("PUT", "/keys/a", "5"), Err(ServerError::RequestError(_))));
+        assert!(matches!(route_request("GET", "/keys/v/items/x", ""), Err(ServerError::RequestError(_))));
+        assert!(matches!(route_request("PATCH", "/keys/a", ""), Err(ServerError::RequestError(_))));
+        assert!(matches!(route_request("GET", "/keys/a/b/c/d", ""), Err(ServerError::RequestError(_))));
+    }
+
+    #[test]
+    fn test_mistyped_collections() {
+        let vector = "{\"Vector\": {\"vector\": [{\"String\": \"x\"}], \"collection_type\": \"Int\"}}";
+        assert!(matches!(route_request("PUT", "/keys/v", vector), Err(ServerError::TypeError(_))));
+        let map = "{\"Map\": {\"map\": [[{\"Int\": 1}, {\"Int\": 2}]], \"key_type\": \"String\", \"collection_type\": \"Int\"}}";
+        assert!(matches!(route_request("PUT", "/keys/m", map), Err(ServerError::TypeError(_))));
+        assert!(matches!(route_request("POST", "/keys/v/items", vector), Err(ServerError::TypeError(_))));
+        let vector = "{\"Vector\": {\"vector\": [{\"Int\": 1}], \"collection_type\": \"Int\"}}";
+        assert!(matches!(route_request("PUT", "/keys/v", vector), Ok(Statement::Set(..))));
+    }
+}
@@ -1,19 +1,61 @@
 use std::collections::HashMap;
 
-use crate::analysis::InterpreterResponse;
+use crate::analysis::{InterpreterResponse, Parser, Statement, Tokenizer};
 use crate::error::ServerError;
 
 
+/// The query sent by a client
+#[derive(Clone, Debug)]
+pub enum StreamQuery {
+    /// Text in the query language that still has to be parsed
+    Text(String),
+    /// Statements the stream handler already translated the request into
+    Statements(Vec<Statement>),
+}
+
+impl StreamQuery {
+    /// Get the statements to run, parsing the text if needed
+    pub fn into_statements(self) -> Result<Vec<Statement>, ServerError> {
+        match self {
+            StreamQuery::Text(text) => {
+                let mut tokenizer = Tokenizer::new(&text);
+                let tokens = tokenizer.tokenize()?;
+                Parser::new(tokens).parse()
+            },
+            StreamQuery::Statements(statements) => Ok(statements),
+        }
+    }
+
+    /// Get the statements to hand to an interpreter, with any passwords already hashed
+    pub fn into_runnable_statements(self, hash_iterations: u32) -> Result<Vec<Statement>, ServerError> {
+        self.into_statements()?.into_iter().map(|statement| statement.hash_password(hash_iterations)).collect()
+    }
+}
+
+
 /// A raw request to send to the analysis worker
 pub struct StreamRequest {
-    /// The string of the actual command to be run
-    pub request: Result<String, ServerError>,
+    /// The query to be run
+    pub request: Result<StreamQuery, ServerError>,
     /// The html headers for this request
     pub headers: HashMap<String, String>,
+    /// The address of the client, if it has one
+    pub peer: Option<String>,
     /// The handler to send a response back
     pub sender: Option<Box<dyn StreamSender + Send>>,
 }
 
+/// Convert a response into the JSON sent back to clients.
+///
+/// The results of a request run in `All` mode are sent as an array with one entry per statement.
+pub fn response_to_json(response: &InterpreterResponse) -> String {
+    match response {
+        InterpreterResponse::Results(results) => serde_json::json!(results).to_string(),
+        response => serde_json::json!(response).to_string(),
+    }
+}
+
+
 /// An object to hold stream information to return responses
 pub trait StreamSender {
     /// Send a response back out
@@ -27,3 +69,10 @@ pub trait StreamHandler {
     /// Receive a request
     fn receive_request(&mut self) -> Option<StreamRequest>;
 }
+
+
+impl<H: StreamHandler + ?Sized> StreamHandler for Box<H> {
+    fn receive_request(&mut self) -> Option<StreamRequest> {
+        (**self).receive_request()
+    }
+}
@@ -1,214 +1,420 @@
-use std::net::{IpAddr, TcpStream, TcpListener};
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::iter::Extend;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, TcpListener};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use httparse::{self, Request, Status};
-use serde_json::{self, Value};
+use rustls::ServerConfig;
 
 use crate::analysis::InterpreterResponse;
-use crate::error::{self, ServerError};
+use crate::auth::CLIENT_CERTIFICATE_HEADER;
+use crate::error::ServerError;
+use crate::io::http::{
+    self, Connection, ConnectionCounter, ConnectionSlot, HttpRequest, KeepAliveConfig, MAX_BUFFER_SIZE, RequestLimits,
+};
 use crate::io::stream::{StreamHandler, StreamRequest, StreamSender};
+use crate::io::tls::{TlsConfig, TlsStream};
 
 
-const MAX_BUFFER_SIZE: usize = 1024;
-const MAX_NUMBER_OF_HEADERS: usize = 32;
+/// A connection that HTTP requests can be read from and responses written to.
+pub trait HttpStream: Read + Write + Send + Sized + 'static {
+    /// Get another handle to the same connection
+    fn try_clone(&self) -> io::Result<Self>;
 
+    /// Set how long reads can wait before giving up
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
 
-/// Object to send responses back through a TCP stream object.
-pub struct TcpStreamSender {
-    stream: TcpStream
+    /// Set how long writes can wait before giving up
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Close the connection
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// The address of the other end of the connection, if it has one
+    fn peer_address(&self) -> Option<String>;
+
+    /// The subject of the certificate the client identified itself with, if it sent one
+    fn client_subject(&self) -> Option<String>;
 }
 
 
-/// Create a properly formatted HTTP response
-fn make_response(code: &str, json_payload: &str) -> String {
-    format!("HTTP/1.1 {}\n\
-    Connection: Closed\n\
-    Content-Type: application/json\n\
-    Content-Length: {}\n\
-     \n\
-    {}\n", code, json_payload.len(), json_payload)
+impl HttpStream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_address(&self) -> Option<String> {
+        self.peer_addr().ok().map(|address| address.to_string())
+    }
+
+    fn client_subject(&self) -> Option<String> {
+        None
+    }
 }
 
 
-impl StreamSender for TcpStreamSender {
-    fn send(&mut self, response: Result<InterpreterResponse, ServerError>) -> Result<(), ServerError> {
-        let (code, json_payload) = match response {
-            Ok(response) => {
-                let code = "200 Ok".to_string();
-                let payload = serde_json::json!(response).to_string();
-                (code, payload)
-            },
-            Err(error) => {
-                let code = error::get_error_code(&error);
-                (code, format!("{}", error))
+/// Object to send responses back through a TCP stream object.
+pub struct TcpStreamSender {
+    stream: Box<dyn Write + Send>,
+    /// Whether the connection stays open after this response
+    connection: Connection,
+    /// Lets the connection know it can read the next request
+    finished: Option<Sender<()>>,
+}
 
-            }
-        };
 
-        let http_response = make_response(&code, &json_payload);
+impl StreamSender for TcpStreamSender {
+    fn send(&mut self, response: Result<InterpreterResponse, ServerError>) -> Result<(), ServerError> {
+        let http_response = http::format_response(response, self.connection);
         let http_bytes = http_response.as_bytes();
 
-        if let Err(_) = self.stream.write(http_bytes) {
-            return Err(ServerError::NetworkError("Error writing to stream.".to_string()));
+        let result = if self.stream.write_all(http_bytes).is_err() {
+            Err(ServerError::NetworkError("Error writing to stream.".to_string()))
+        } else if self.stream.flush().is_err() {
+            Err(ServerError::NetworkError("Error flushing write buffer for stream.".to_string()))
+        } else {
+            Ok(())
         };
-        if let Err(_) = self.stream.flush() {
-            return Err(ServerError::NetworkError("Error flushing write buffer for stream.".to_string()));
+        if let Some(finished) = self.finished.take() {
+            let _ = finished.send(());
         }
-        Ok(())
-    } 
+        result
+    }
+}
+
+
+/// A client connection, which can carry many requests one after another.
+///
+/// The next request isn't read until the response to the last one has been written, so
+/// responses always come back in the order the requests were sent.
+struct HttpConnection<S: HttpStream> {
+    stream: S,
+    /// Bytes read but not yet used by a request
+    buffer: Vec<u8>,
+    /// When to close the connection
+    keep_alive: KeepAliveConfig,
+    /// How big requests can be and how long clients get to send them
+    limits: RequestLimits,
+    /// The connection's place under the connection limit, or None if there was no room for it
+    slot: Option<ConnectionSlot>,
+    /// Where requests are handed over to the server
+    requests: Sender<StreamRequest>,
 }
 
 
-/// Extract the length of the query body from an HTTP request.
-fn extract_body_length_from_request(request: &Request) -> Result<Option<usize>, ServerError> {
-    let mut length: Option<usize> = None;
-    for header in request.headers.iter() {
-        if header.name == "Content-Length" {
-            let length_str = match String::from_utf8(header.value.clone().to_vec()) {
-                Ok(utf_str) => utf_str,
-                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+impl<S: HttpStream> HttpConnection<S> {
+    /// Handle requests until the connection is closed
+    fn run(&mut self) {
+        if self.stream.set_write_timeout(Some(self.limits.write_timeout)).is_err() {
+            return;
+        }
+        if self.slot.is_none() {
+            let http_response = http::format_response(Err(http::make_busy_error()), Connection::Close);
+            let _ = self.stream.write_all(http_response.as_bytes());
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return;
+        }
+        let mut served = 0;
+        loop {
+            let request = match self.read_request() {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(error) => {
+                    // The rest of the stream can't be trusted after a bad request
+                    let http_response = http::format_response(Err(error), Connection::Close);
+                    let _ = self.stream.write_all(http_response.as_bytes());
+                    break;
+                },
             };
-            length = match length_str.parse() {
-                Ok(value) => Some(value),
-                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+            served += 1;
+            let connection = self.keep_alive.connection_after(request.keep_alive, served);
+            let stream = match self.stream.try_clone() {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            let (finished, finished_receiver) = mpsc::channel();
+            let sender = TcpStreamSender { stream: Box::new(stream), connection, finished: Some(finished) };
+            let HttpRequest { query, mut headers, .. } = request;
+            if let Some(subject) = self.stream.client_subject() {
+                headers.insert(CLIENT_CERTIFICATE_HEADER.to_string(), subject);
+            }
+            let request = StreamRequest {
+                request: query,
+                headers,
+                peer: self.stream.peer_address(),
+                sender: Some(Box::new(sender)),
             };
-            break;
+            if self.requests.send(request).is_err() {
+                break;
+            }
+            if finished_receiver.recv().is_err() || connection == Connection::Close {
+                break;
+            }
         }
+        // Make room for another client before this one sees the connection close
+        self.slot = None;
+        let _ = self.stream.shutdown(Shutdown::Both);
     }
-    Ok(length)
-}
 
-/// Extract the actual request/query string from the body in the POST request.
-fn extract_request_from_body(body: &str) -> Result<String, ServerError> {
-    let json_value: Result<Value, _> = serde_json::from_str(&body);
-    let map = match json_value {
-        Ok(Value::Object(map)) => map,
-        _ => return Err(ServerError::RequestError("Malformed request.".to_string())),
-    };
-    let query = match map.get("query") {
-        Some(Value::String(query)) => query,
-        _ => return Err(ServerError::RequestError("Malformed request.".to_string())),
-    };
-    Ok(query.clone())
+    /// Read the next request. Gives None if the client closes the connection or goes idle.
+    ///
+    /// Once part of a request has arrived, the rest has to follow within the read timeout.
+    fn read_request(&mut self) -> Result<Option<HttpRequest>, ServerError> {
+        let mut deadline = None;
+        loop {
+            if let Some((request, used)) = http::parse_request(&self.buffer, &self.limits)? {
+                self.buffer.drain(..used);
+                return Ok(Some(request));
+            }
+            let timeout = match self.buffer.is_empty() {
+                true => self.keep_alive.idle_timeout,
+                false => {
+                    let deadline = *deadline.get_or_insert_with(|| Instant::now() + self.limits.read_timeout);
+                    deadline.saturating_duration_since(Instant::now())
+                },
+            };
+            if timeout.is_zero() {
+                return Err(http::make_timeout_error());
+            }
+            if self.stream.set_read_timeout(Some(timeout)).is_err() {
+                return Err(ServerError::NetworkError("Problem reading request.".to_string()));
+            }
+            let mut temp_buffer = [0; MAX_BUFFER_SIZE];
+            match self.stream.read(&mut temp_buffer) {
+                Ok(0) if self.buffer.is_empty() => return Ok(None),
+                Ok(0) => return Err(ServerError::NetworkError("Connection closed in the middle of a request.".to_string())),
+                Ok(read) => self.buffer.extend(&temp_buffer[..read]),
+                Err(_) if self.buffer.is_empty() => return Ok(None),
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(http::make_timeout_error());
+                },
+                Err(_) => return Err(ServerError::NetworkError("Problem reading request.".to_string())),
+            }
+        }
+    }
 }
 
 
-/// Convert the headers of an HTTP request into a hashmap.
-fn convert_headers_to_map(request: &Request) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    for header in request.headers.iter() {
-        if let Ok(value) = String::from_utf8(header.value.to_vec()) {
-            map.insert(header.name.to_string(), value);
-        }
-    }
-    map
+/// Handle the HTTP requests on a connection on their own thread, sending them to `requests`.
+///
+/// Without a slot under the connection limit the client is told the server is busy.
+pub fn serve_connection<S: HttpStream>(
+    stream: S,
+    keep_alive: KeepAliveConfig,
+    limits: RequestLimits,
+    slot: Option<ConnectionSlot>,
+    requests: Sender<StreamRequest>,
+) {
+    let mut connection = HttpConnection { stream, buffer: vec![], keep_alive, limits, slot, requests };
+    thread::spawn(move || connection.run());
 }
 
 
-/// Convert the stream input into a request object
-fn convert_stream_to_request(mut stream: TcpStream) -> StreamRequest {
-    let mut buffer = vec![];
-    let mut headers = HashMap::new();
-    let (headers, body) = loop {
-        let mut temp_buffer = [0; MAX_BUFFER_SIZE];
-        let mut headers_list = [httparse::EMPTY_HEADER; MAX_NUMBER_OF_HEADERS];
-        let mut request = Request::new(&mut headers_list);
-        let size_read = match stream.read(&mut temp_buffer) {
-            Ok(read) => read,
-            Err(_) => {
-                let sender: Option<Box<dyn StreamSender + Send>> = Some(Box::new(TcpStreamSender{stream}));
-                return StreamRequest {
-                    request: Err(ServerError::NetworkError("Problem reading request.".to_string())),
-                    headers,
-                    sender,
-                };
-            },
-        };
-        buffer.extend(&temp_buffer[..size_read]);
-        let body_start = match request.parse(&buffer) {
-            Ok(Status::Complete(size)) => size,
-            _ => return StreamRequest {
-                request: Err(ServerError::NetworkError("Problem reading request.".to_string())),
-                headers,
-                sender: Some(Box::new(TcpStreamSender{stream})),
+/// Accept connections and handle each client on its own thread, over TLS if there is a config for it
+fn accept_connections(
+    listener: TcpListener,
+    requests: Sender<StreamRequest>,
+    keep_alive: KeepAliveConfig,
+    limits: RequestLimits,
+    tls: Option<Arc<ServerConfig>>,
+) {
+    let connections = ConnectionCounter::new(limits.max_connections);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                println!("Could not read TCP connection: {:?}", error);
+                continue;
             },
         };
-        let body_length = match extract_body_length_from_request(&request) {
-            Ok(length) => length,
-            Err(err) => {
-                return StreamRequest {
-                    request: Err(err),
-                    headers,
-                    sender: Some(Box::new(TcpStreamSender{stream})),
-                };
+        let slot = connections.try_open();
+        match &tls {
+            Some(tls) => match TlsStream::new(Arc::clone(tls), stream) {
+                Ok(stream) => serve_connection(stream, keep_alive, limits, slot, requests.clone()),
+                Err(error) => println!("{:?}", error),
             },
-        };
-        let body_length = match body_length {
-            None => continue,
-            Some(body_length) => body_length,
-        };
-        if (body_start as u64 + body_length as u64) as usize <= buffer.len() {
-            match request.method {
-                Some("POST") => (),
-                _ => return StreamRequest {
-                    request: Err(ServerError::RequestError("Malformed request.".to_string())),
-                    headers,
-                    sender: Some(Box::new(TcpStreamSender{stream})),
-                }
-            }
-            headers = convert_headers_to_map(&request);
-            let body = String::from_utf8_lossy(&buffer[body_start..(body_start + body_length)]);
-
-            break (headers, body);
-        }
-    };
-
-    let query = match extract_request_from_body(&body) {
-        Ok(query) => query,
-        Err(err) => {
-            return StreamRequest {
-                request: Err(err),
-                headers,
-                sender: Some(Box::new(TcpStreamSender{stream})),
-            };
+            None => serve_connection(stream, keep_alive, limits, slot, requests.clone()),
         }
-    };
-    let request = Ok(query);
-    StreamRequest { request, headers, sender: Some(Box::new(TcpStreamSender{stream}))}
+    }
 }
 
 
 /// Handles connections from a TCP listener.
+///
+/// Connections are kept open between requests as HTTP/1.1 expects, until they go idle, the
+/// client asks to close them or they reach the request limit.
 pub struct TcpStreamHandler {
-    listener: TcpListener,
+    /// Requests from every connection
+    requests: Receiver<StreamRequest>,
+    /// The address the handler is listening on
+    address: SocketAddr,
 }
 
 
 impl TcpStreamHandler {
     /// Create a new TCP connection bound to an IP address and a port.
     pub fn new(ip_address: IpAddr, port: usize) -> TcpStreamHandler {
-        let listener = TcpListener::bind(format!("{}:{}", ip_address.to_string(), port)).unwrap();
-        //let incoming = listener.incoming();
-        TcpStreamHandler{listener}
+        TcpStreamHandler::with_keep_alive(ip_address, port, KeepAliveConfig::default())
+    }
+
+    /// Create a new TCP connection with settings for keeping connections open.
+    pub fn with_keep_alive(ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig) -> TcpStreamHandler {
+        TcpStreamHandler::with_limits(ip_address, port, keep_alive, RequestLimits::default())
+    }
+
+    /// Create a new TCP connection with settings for keeping connections open and limits on clients.
+    pub fn with_limits(ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits) -> TcpStreamHandler {
+        TcpStreamHandler::listen(ip_address, port, keep_alive, limits, None)
+    }
+
+    /// Create a new TCP connection that only accepts clients speaking TLS.
+    pub fn with_tls(
+        ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits, tls: &TlsConfig
+    ) -> Result<TcpStreamHandler, ServerError> {
+        let tls = tls.server_config()?;
+        Ok(TcpStreamHandler::listen(ip_address, port, keep_alive, limits, Some(tls)))
+    }
+
+    /// Start accepting connections
+    fn listen(
+        ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits, tls: Option<Arc<ServerConfig>>
+    ) -> TcpStreamHandler {
+        let listener = TcpListener::bind(format!("{}:{}", ip_address, port)).unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || accept_connections(listener, sender, keep_alive, limits, tls));
+        TcpStreamHandler{requests, address}
+    }
+
+    /// The address the handler is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
     }
 }
 
 
 impl StreamHandler for TcpStreamHandler {
     fn receive_request(&mut self) -> Option<StreamRequest> {
-        let stream = self.listener.accept();
-        let stream = match stream {
-            Err(_) => return Some(
-                StreamRequest {
-                    request: Err(ServerError::NetworkError("Could not read TCP connection.".to_string())),
-                    headers: HashMap::new(),
-                    sender: None,
-                }
-            ),
-            Ok((stream, _)) => stream,
+        self.requests.recv().ok()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::analysis::Interpreter;
+    use crate::auth::MockAuthenticator;
+    use crate::single_threaded::SingleThreadedServer;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    fn send(stream: &mut TcpStream, method: &str, path: &str, body: &str, connection: &str) -> (String, String) {
+        let request = format!(
+            "{} {} HTTP/1.1\r\nUsername: admin\r\nConnection: {}\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, connection, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut headers = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            headers.push_str(&line);
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        (headers, String::from_utf8(body).unwrap())
+    }
+
+    fn post(stream: &mut TcpStream, query: &str, connection: &str) -> (String, String) {
+        send(stream, "POST", "/", &format!("{{\"query\": \"{}\"}}", query), connection)
+    }
+
+    #[test]
+    fn test_requests_share_a_connection() {
+        let keep_alive = KeepAliveConfig { idle_timeout: Duration::from_secs(5), max_requests: 4 };
+        let handler = TcpStreamHandler::with_keep_alive(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, keep_alive);
+        let address = handler.local_addr();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let (headers, _) = post(&mut stream, "set a 1;", "keep-alive");
+        assert!(headers.contains("Keep-Alive: timeout=5, max=3"));
+        let (_, body) = post(&mut stream, "get a;", "keep-alive");
+        assert_eq!(body, "{\"Value\":{\"Int\":1}}");
+        let (_, body) = send(&mut stream, "PUT", "/keys/b?ttl=60", "{\"String\": \"hi\"}", "keep-alive");
+        assert_eq!(body, "{\"Version\":2}");
+        let (headers, body) = send(&mut stream, "GET", "/keys/b", "", "keep-alive");
+        assert_eq!(body, "{\"Value\":{\"String\":\"hi\"}}");
+        assert!(headers.contains("Connection: close"));
+        let mut rest = vec![];
+        assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let (headers, _) = post(&mut stream, "shutdown;", "close");
+        assert!(headers.contains("Connection: close"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_limits_on_clients() {
+        let limits = RequestLimits {
+            max_body_bytes: 64, read_timeout: Duration::from_millis(200), max_connections: 1, ..RequestLimits::default()
+        };
+        let handler = TcpStreamHandler::with_limits(IpAddr::V4(Ipv4Addr::LOCALHOST), 0, KeepAliveConfig::default(), limits);
+        let address = handler.local_addr();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+        let read_response = |stream: &mut TcpStream| {
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
         };
-        Some(convert_stream_to_request(stream))
+
+        // A client that stops halfway through a request holds the only connection until it times out
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\n{").unwrap();
+        let mut turned_away = TcpStream::connect(address).unwrap();
+        assert!(read_response(&mut turned_away).starts_with("HTTP/1.1 503"));
+        assert!(read_response(&mut slow).starts_with("HTTP/1.1 408"));
+
+        let mut large = TcpStream::connect(address).unwrap();
+        large.write_all(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\n").unwrap();
+        assert!(read_response(&mut large).starts_with("HTTP/1.1 413"));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        let (_, body) = post(&mut stream, "shutdown;", "close");
+        assert_eq!(body, "\"ShuttingDown\"");
+        server.join().unwrap();
     }
 }
@@ -1,26 +1,35 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, AsyncReadExt};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{self, Instant};
+use tokio_rustls::TlsAcceptor;
 
 use std::collections::HashMap;
-use std::iter::Extend;
-
-use httparse::{self, Request, Status};
-use serde_json::{self, Value};
 
 use crate::analysis::InterpreterResponse;
-use crate::error::{self, ServerError};
+use crate::auth::CLIENT_CERTIFICATE_HEADER;
+use crate::error::ServerError;
+use crate::io::http::{
+    self, Connection, ConnectionCounter, ConnectionSlot, HttpRequest, KeepAliveConfig, MAX_BUFFER_SIZE, RequestLimits,
+};
+use crate::io::stream::StreamQuery;
+use crate::io::tls::{self, TlsConfig};
 
-const MAX_BUFFER_SIZE: usize = 1024;
-const MAX_NUMBER_OF_HEADERS: usize = 32;
+/// How many requests can wait to be received at once
+pub const CHANNEL_QUEUE_SIZE: usize = 128;
 
 
 /// Async version of the stream request
 pub struct StreamRequest {
-    /// The string of the actual command to be run
-    pub request: Result<String, ServerError>,
+    /// The query to be run
+    pub request: Result<StreamQuery, ServerError>,
     /// The html headers for this request
     pub headers: HashMap<String, String>,
+    /// The address of the client, if it has one
+    pub peer: Option<String>,
     /// The handler to send a response back
     pub sender: Option<TcpStreamSender>,
 }
@@ -28,181 +37,290 @@ pub struct StreamRequest {
 
 /// Object to send responses back through a TCP stream object.
 pub struct TcpStreamSender {
-    stream: TcpStream
-}
-
-
-/// Create a properly formatted HTTP response
-fn make_response(code: &str, json_payload: &str) -> String {
-    format!("HTTP/1.1 {}\n\
-    Connection: Closed\n\
-    Content-Type: application/json\n\
-    Content-Length: {}\n\
-     \n\
-    {}\n", code, json_payload.len(), json_payload)
+    stream: Arc<Mutex<ResponseWriter>>,
+    /// Whether the connection stays open after this response
+    connection: Connection,
+    /// How long writing the response can take
+    write_timeout: Duration,
+    /// Lets the connection know it can read the next request
+    finished: Option<oneshot::Sender<()>>,
 }
 
 
 impl TcpStreamSender {
     /// Send a response
     pub async fn send(&mut self, response: Result<InterpreterResponse, ServerError>) -> Result<(), ServerError> {
-        let (code, json_payload) = match response {
-            Ok(response) => {
-                let code = "200 Ok".to_string();
-                let payload = serde_json::json!(response).to_string();
-                (code, payload)
-            },
-            Err(error) => {
-                let code = error::get_error_code(&error);
-                (code, format!("{}", error))
-
-            }
-        };
-
-        let http_response = make_response(&code, &json_payload);
+        let http_response = http::format_response(response, self.connection);
         let http_bytes = http_response.as_bytes();
-        if let Err(_) = self.stream.write(http_bytes).await {
-            return Err(ServerError::NetworkError("Error writing to stream.".to_string()));
+        let result = {
+            let mut stream = self.stream.lock().await;
+            if !matches!(time::timeout(self.write_timeout, stream.write_all(http_bytes)).await, Ok(Ok(()))) {
+                Err(ServerError::NetworkError("Error writing to stream.".to_string()))
+            } else if !matches!(time::timeout(self.write_timeout, stream.flush()).await, Ok(Ok(()))) {
+                Err(ServerError::NetworkError("Error flushing write buffer for stream.".to_string()))
+            } else {
+                Ok(())
+            }
         };
-        if let Err(_) = self.stream.flush().await {
-            return Err(ServerError::NetworkError("Error flushing write buffer for stream.".to_string()));
+        if let Some(finished) = self.finished.take() {
+            let _ = finished.send(());
         }
-        Ok(())
-    } 
+        result
+    }
 }
 
 
-/// Extract the length of the query body from an HTTP request.
-fn extract_body_length_from_request(request: &Request) -> Result<Option<usize>, ServerError> {
-    let mut length: Option<usize> = None;
-    for header in request.headers.iter() {
-        if header.name == "Content-Length" {
-            let length_str = match String::from_utf8(header.value.clone().to_vec()) {
-                Ok(utf_str) => utf_str,
-                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+/// The half of a connection responses are written to
+type ResponseWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+
+/// A client connection, which can carry many requests one after another.
+struct HttpConnection<R: AsyncRead + Unpin + Send + 'static> {
+    reader: R,
+    writer: Arc<Mutex<ResponseWriter>>,
+    /// Bytes read but not yet used by a request
+    buffer: Vec<u8>,
+    /// When to close the connection
+    keep_alive: KeepAliveConfig,
+    /// How big requests can be and how long clients get to send them
+    limits: RequestLimits,
+    /// The connection's place under the connection limit, or None if there was no room for it
+    slot: Option<ConnectionSlot>,
+    /// Where requests are handed over to the server
+    requests: mpsc::Sender<StreamRequest>,
+    /// The address of the client, if it has one
+    peer: Option<String>,
+    /// The subject of the certificate the client identified itself with, if it sent one
+    client_subject: Option<String>,
+}
+
+
+impl<R: AsyncRead + Unpin + Send + 'static> HttpConnection<R> {
+    /// Handle requests until the connection is closed
+    async fn run(mut self) {
+        if self.slot.is_none() {
+            self.write_error(http::make_busy_error()).await;
+            let _ = self.writer.lock().await.shutdown().await;
+            return;
+        }
+        let mut served = 0;
+        loop {
+            let request = match self.read_request().await {
+                Ok(Some(request)) => request,
+                Ok(None) => break,
+                Err(error) => {
+                    // The rest of the stream can't be trusted after a bad request
+                    self.write_error(error).await;
+                    break;
+                },
             };
-            length = match length_str.parse() {
-                Ok(value) => Some(value),
-                Err(_) => return Err(ServerError::RequestError("Malformed request.".to_string())),
+            served += 1;
+            let connection = self.keep_alive.connection_after(request.keep_alive, served);
+            let (finished, finished_receiver) = oneshot::channel();
+            let sender = TcpStreamSender {
+                stream: Arc::clone(&self.writer),
+                connection,
+                write_timeout: self.limits.write_timeout,
+                finished: Some(finished),
             };
-            break;
+            let HttpRequest { query, mut headers, .. } = request;
+            if let Some(subject) = &self.client_subject {
+                headers.insert(CLIENT_CERTIFICATE_HEADER.to_string(), subject.clone());
+            }
+            let request = StreamRequest { request: query, headers, peer: self.peer.clone(), sender: Some(sender) };
+            if self.requests.send(request).await.is_err() {
+                break;
+            }
+            if finished_receiver.await.is_err() || connection == Connection::Close {
+                break;
+            }
+        }
+        // Make room for another client before this one sees the connection close
+        self.slot = None;
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+
+    /// Send an error to the client before the connection is closed
+    async fn write_error(&mut self, error: ServerError) {
+        let http_response = http::format_response(Err(error), Connection::Close);
+        let mut writer = self.writer.lock().await;
+        let _ = time::timeout(self.limits.write_timeout, writer.write_all(http_response.as_bytes())).await;
+    }
+
+    /// Read the next request. Gives None if the client closes the connection or goes idle.
+    ///
+    /// Once part of a request has arrived, the rest has to follow within the read timeout.
+    async fn read_request(&mut self) -> Result<Option<HttpRequest>, ServerError> {
+        let mut deadline = None;
+        loop {
+            if let Some((request, used)) = http::parse_request(&self.buffer, &self.limits)? {
+                self.buffer.drain(..used);
+                return Ok(Some(request));
+            }
+            let timeout = match self.buffer.is_empty() {
+                true => self.keep_alive.idle_timeout,
+                false => {
+                    let deadline = *deadline.get_or_insert_with(|| Instant::now() + self.limits.read_timeout);
+                    deadline.saturating_duration_since(Instant::now())
+                },
+            };
+            let mut temp_buffer = [0; MAX_BUFFER_SIZE];
+            let read = time::timeout(timeout, self.reader.read(&mut temp_buffer)).await;
+            match read {
+                Ok(Ok(0)) if self.buffer.is_empty() => return Ok(None),
+                Ok(Ok(0)) => return Err(ServerError::NetworkError("Connection closed in the middle of a request.".to_string())),
+                Ok(Ok(read)) => self.buffer.extend(&temp_buffer[..read]),
+                _ if self.buffer.is_empty() => return Ok(None),
+                Err(_) => return Err(http::make_timeout_error()),
+                Ok(Err(_)) => return Err(ServerError::NetworkError("Problem reading request.".to_string())),
+            }
         }
     }
-    Ok(length)
 }
 
-/// Extract the actual request/query string from the body in the POST request.
-fn extract_request_from_body(body: &str) -> Result<String, ServerError> {
-    let json_value: Result<Value, _> = serde_json::from_str(&body);
-    let map = match json_value {
-        Ok(Value::Object(map)) => map,
-        _ => return Err(ServerError::RequestError("Malformed request.".to_string())),
-    };
-    let query = match map.get("query") {
-        Some(Value::String(query)) => query,
-        _ => return Err(ServerError::RequestError("Malformed request.".to_string())),
-    };
-    Ok(query.clone())
+
+/// The client at the other end of a connection
+#[derive(Clone, Debug, Default)]
+pub struct Client {
+    /// The address of the client, if it has one
+    pub peer: Option<String>,
+    /// The subject of the certificate the client identified itself with, if it sent one
+    pub subject: Option<String>,
 }
 
 
-/// Convert the headers of an HTTP request into a hashmap.
-fn convert_headers_to_map(request: &Request) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    for header in request.headers.iter() {
-        if let Ok(value) = String::from_utf8(header.value.to_vec()) {
-            map.insert(header.name.to_string(), value);
-        }
-    }
-    map
+/// Handle the HTTP requests on the two halves of a connection from `client` in their own task, sending them to `requests`.
+///
+/// Without a slot under the connection limit the client is told the server is busy.
+pub fn serve_connection<R, W>(
+    reader: R,
+    writer: W,
+    client: Client,
+    keep_alive: KeepAliveConfig,
+    limits: RequestLimits,
+    slot: Option<ConnectionSlot>,
+    requests: mpsc::Sender<StreamRequest>,
+)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+{
+    let writer: ResponseWriter = Box::new(writer);
+    let connection = HttpConnection {
+        reader,
+        writer: Arc::new(Mutex::new(writer)),
+        buffer: vec![],
+        keep_alive,
+        limits,
+        slot,
+        requests,
+        peer: client.peer,
+        client_subject: client.subject,
+    };
+    tokio::spawn(connection.run());
 }
 
 
-/// Convert the stream input into a request object
-async fn convert_stream_to_request(mut stream: TcpStream) -> StreamRequest {
-    let mut buffer = vec![];
-    let mut headers = HashMap::new();
-    let (headers, body) = loop {
-        let mut temp_buffer = [0; MAX_BUFFER_SIZE];
-        let mut headers_list = [httparse::EMPTY_HEADER; MAX_NUMBER_OF_HEADERS];
-        let mut request = Request::new(&mut headers_list);
-        let size_read = match stream.read(&mut temp_buffer).await {
-            Ok(read) => read,
-            Err(_) => {
-                let sender = Some(TcpStreamSender{stream});
-                return StreamRequest {
-                    request: Err(ServerError::NetworkError("Problem reading request.".to_string())),
-                    headers,
-                    sender,
-                };
-            },
-        };
-        buffer.extend(&temp_buffer[..size_read]);
-        let body_start = match request.parse(&buffer) {
-            Ok(Status::Complete(size)) => size,
-            _ => return StreamRequest {
-                request: Err(ServerError::NetworkError("Problem reading request.".to_string())),
-                headers,
-                sender: Some(TcpStreamSender{stream}),
+/// Accept connections and handle each client in its own task, over TLS if there is an acceptor for it
+async fn accept_connections(
+    listener: TcpListener,
+    requests: mpsc::Sender<StreamRequest>,
+    keep_alive: KeepAliveConfig,
+    limits: RequestLimits,
+    tls: Option<TlsAcceptor>,
+) {
+    let connections = ConnectionCounter::new(limits.max_connections);
+    loop {
+        let (stream, address): (TcpStream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                println!("Could not read TCP connection: {:?}", error);
+                continue;
             },
         };
-        let body_length = match extract_body_length_from_request(&request) {
-            Ok(length) => length,
-            Err(err) => {
-                return StreamRequest {
-                    request: Err(err),
-                    headers,
-                    sender: Some(TcpStreamSender{stream}),
-                };
+        let peer = Some(address.to_string());
+        let slot = connections.try_open();
+        let acceptor = match &tls {
+            Some(acceptor) => acceptor.clone(),
+            None => {
+                let (reader, writer) = stream.into_split();
+                serve_connection(reader, writer, Client { peer, subject: None }, keep_alive, limits, slot, requests.clone());
+                continue;
             },
         };
-        let body_length = match body_length {
-            None => continue,
-            Some(body_length) => body_length,
-        };
-        if (body_start as u64 + body_length as u64) as usize <= buffer.len() {
-            match request.method {
-                Some("POST") => (),
-                _ => return StreamRequest {
-                    request: Err(ServerError::RequestError("Malformed request.".to_string())),
-                    headers,
-                    sender: Some(TcpStreamSender{stream}),
-                }
-            }
-            headers = convert_headers_to_map(&request);
-            let body = String::from_utf8_lossy(&buffer[body_start..(body_start + body_length)]);
-
-            break (headers, body);
-        }
-    };
-
-    let query = match extract_request_from_body(&body) {
-        Ok(query) => query,
-        Err(err) => {
-            return StreamRequest {
-                request: Err(err),
-                headers,
-                sender: Some(TcpStreamSender{stream}),
+        let requests = requests.clone();
+        // Handshake in its own task so a slow client doesn't hold up the others
+        tokio::spawn(async move {
+            let stream = match time::timeout(keep_alive.idle_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(error)) => {
+                    println!("TLS handshake failed: {:?}", error);
+                    return;
+                },
+                Err(_) => {
+                    println!("TLS handshake timed out.");
+                    return;
+                },
             };
-        }
-    };
-    let request = Ok(query);
-    StreamRequest { request, headers, sender: Some(TcpStreamSender{stream})}
+            let subject = tls::client_subject(stream.get_ref().1);
+            let (reader, writer) = tokio::io::split(stream);
+            serve_connection(reader, writer, Client { peer, subject }, keep_alive, limits, slot, requests);
+        });
+    }
 }
 
 
 /// Handles connections from a TCP listener.
+///
+/// Connections are kept open between requests as HTTP/1.1 expects, until they go idle, the
+/// client asks to close them or they reach the request limit.
 pub struct TcpStreamHandler {
-    listener: TcpListener,
+    requests: mpsc::Receiver<StreamRequest>,
+    /// The address the handler is listening on
+    address: SocketAddr,
 }
 
 
 impl TcpStreamHandler {
     /// Create a new TCP connection bound to an IP address and a port.
     pub async fn new(ip_address: IpAddr, port: usize) -> TcpStreamHandler {
-        let listener = TcpListener::bind(format!("{}:{}", ip_address.to_string(), port)).await.unwrap();
-        //let incoming = listener.incoming();
-        TcpStreamHandler{listener}
+        TcpStreamHandler::with_keep_alive(ip_address, port, KeepAliveConfig::default()).await
+    }
+
+    /// Create a new TCP connection with settings for keeping connections open.
+    pub async fn with_keep_alive(ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig) -> TcpStreamHandler {
+        TcpStreamHandler::with_limits(ip_address, port, keep_alive, RequestLimits::default()).await
+    }
+
+    /// Create a new TCP connection with settings for keeping connections open and limits on clients.
+    pub async fn with_limits(
+        ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits
+    ) -> TcpStreamHandler {
+        TcpStreamHandler::listen(ip_address, port, keep_alive, limits, None).await
+    }
+
+    /// Create a new TCP connection that only accepts clients speaking TLS.
+    pub async fn with_tls(
+        ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits, tls: &TlsConfig
+    ) -> Result<TcpStreamHandler, ServerError> {
+        let acceptor = TlsAcceptor::from(tls.server_config()?);
+        Ok(TcpStreamHandler::listen(ip_address, port, keep_alive, limits, Some(acceptor)).await)
+    }
+
+    /// Start accepting connections
+    async fn listen(
+        ip_address: IpAddr, port: usize, keep_alive: KeepAliveConfig, limits: RequestLimits, tls: Option<TlsAcceptor>
+    ) -> TcpStreamHandler {
+        let listener = TcpListener::bind(format!("{}:{}", ip_address, port)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel(CHANNEL_QUEUE_SIZE);
+        tokio::spawn(accept_connections(listener, sender, keep_alive, limits, tls));
+        TcpStreamHandler{requests, address}
+    }
+
+    /// The address the handler is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
     }
 }
 
@@ -210,15 +328,14 @@ impl TcpStreamHandler {
 impl TcpStreamHandler {
     /// Receive a request
     pub async fn receive_request(&mut self) -> StreamRequest {
-        let stream = self.listener.accept().await;
-        let stream = match stream {
-            Err(_) => return StreamRequest {
+        match self.requests.recv().await {
+            Some(request) => request,
+            None => StreamRequest {
                 request: Err(ServerError::NetworkError("Could not read TCP connection.".to_string())),
                 headers: HashMap::new(),
+                peer: None,
                 sender: None,
             },
-            Ok((stream, _)) => stream,
-        };
-        convert_stream_to_request(stream).await
+        }
     }
 }
@@ -0,0 +1,343 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::error::ServerError;
+use crate::io::tcp::HttpStream;
+
+
+/// Settings for serving HTTP over TLS.
+///
+/// With a client CA, clients must present a certificate it signed, and the subject of that
+/// certificate is handed to the authenticator in the `Client-Certificate-Subject` header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// PEM file with the server's certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file with the server's private key
+    pub key_path: PathBuf,
+    /// PEM file with the certificates client certificates must be signed by, for mutual TLS
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Settings to serve with a certificate and key, without client certificates
+    pub fn new<P: Into<PathBuf>>(cert_path: P, key_path: P) -> TlsConfig {
+        TlsConfig { cert_path: cert_path.into(), key_path: key_path.into(), client_ca_path: None }
+    }
+
+    /// Read the certificates and key into a config for accepting connections
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, ServerError> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = match ServerConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions() {
+            Ok(builder) => builder,
+            Err(error) => return Err(make_tls_error(&self.cert_path, error)),
+        };
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => builder.with_client_cert_verifier(client_verifier(client_ca_path, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+        let certificates = read_certificates(&self.cert_path)?;
+        let key = read_private_key(&self.key_path)?;
+        match builder.with_single_cert(certificates, key) {
+            Ok(config) => Ok(Arc::new(config)),
+            Err(error) => Err(make_tls_error(&self.key_path, error)),
+        }
+    }
+}
+
+
+/// Get the subject a client identified itself with: the common name of its certificate, or the
+/// whole subject if it has no common name
+pub fn client_subject(connection: &ServerConnection) -> Option<String> {
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, certificate) = X509Certificate::from_der(certificate).ok()?;
+    let subject = certificate.subject();
+    let common_name = subject.iter_common_name().next().and_then(|name| name.as_str().ok()).map(str::to_string);
+    Some(common_name.unwrap_or_else(|| subject.to_string()))
+}
+
+
+/// A TLS connection to a client.
+///
+/// Clones share the same session, so one can read requests while another writes the response.
+pub struct TlsStream {
+    /// The session and the connection it runs over
+    stream: Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>,
+    /// Another handle to the connection, for timeouts and shutting it down without the lock
+    tcp: TcpStream,
+}
+
+impl TlsStream {
+    /// Start a TLS session on a new connection. The handshake happens on the first read.
+    pub fn new(config: Arc<ServerConfig>, stream: TcpStream) -> Result<TlsStream, ServerError> {
+        let connection = match ServerConnection::new(config) {
+            Ok(connection) => connection,
+            Err(error) => return Err(ServerError::NetworkError(format!("Could not start TLS session: {}", error))),
+        };
+        let tcp = match stream.try_clone() {
+            Ok(tcp) => tcp,
+            Err(error) => return Err(ServerError::NetworkError(format!("Could not start TLS session: {}", error))),
+        };
+        Ok(TlsStream { stream: Arc::new(Mutex::new(StreamOwned::new(connection, stream))), tcp })
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.stream.lock().unwrap().read(buffer)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.stream.lock().unwrap().write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.lock().unwrap().flush()
+    }
+}
+
+impl HttpStream for TlsStream {
+    fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream { stream: Arc::clone(&self.stream), tcp: self.tcp.try_clone()? })
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if let Ok(mut stream) = self.stream.try_lock() {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+        self.tcp.shutdown(how)
+    }
+
+    fn peer_address(&self) -> Option<String> {
+        self.tcp.peer_addr().ok().map(|address| address.to_string())
+    }
+
+    fn client_subject(&self) -> Option<String> {
+        client_subject(&self.stream.lock().unwrap().conn)
+    }
+}
+
+
+/// Build the check that client certificates were signed by a CA
+fn client_verifier(
+    path: &Path, provider: Arc<CryptoProvider>
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, ServerError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in read_certificates(path)? {
+        if let Err(error) = roots.add(certificate) {
+            return Err(make_tls_error(path, error));
+        }
+    }
+    match WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build() {
+        Ok(verifier) => Ok(verifier),
+        Err(error) => Err(make_tls_error(path, error)),
+    }
+}
+
+
+/// Read every certificate in a PEM file
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, ServerError> {
+    let mut reader = open_pem(path)?;
+    let certificates = match rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>() {
+        Ok(certificates) => certificates,
+        Err(error) => return Err(make_tls_error(path, error)),
+    };
+    if certificates.is_empty() {
+        return Err(make_tls_error(path, "no certificates found"));
+    }
+    Ok(certificates)
+}
+
+
+/// Read the first private key in a PEM file
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, ServerError> {
+    let mut reader = open_pem(path)?;
+    match rustls_pemfile::private_key(&mut reader) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(make_tls_error(path, "no private key found")),
+        Err(error) => Err(make_tls_error(path, error)),
+    }
+}
+
+
+/// Open a PEM file for reading
+fn open_pem(path: &Path) -> Result<BufReader<File>, ServerError> {
+    match File::open(path) {
+        Ok(file) => Ok(BufReader::new(file)),
+        Err(error) => Err(make_tls_error(path, error)),
+    }
+}
+
+
+/// Create an error for certificates or keys that can't be used
+fn make_tls_error<E: std::fmt::Display>(path: &Path, error: E) -> ServerError {
+    ServerError::ConfigError(format!("Could not use TLS file {}: {}", path.display(), error))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, DnType, IsCa, KeyPair};
+    use rustls::{ClientConfig, ClientConnection};
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+
+    use super::*;
+    use crate::analysis::{Interpreter, InterpreterResponse};
+    use crate::auth::{CLIENT_CERTIFICATE_HEADER, MockAuthenticator};
+    use crate::io::http::{KeepAliveConfig, RequestLimits};
+    use crate::io::{tcp, tcp_async};
+    use crate::single_threaded::SingleThreadedServer;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    /// A CA, a server certificate and a client certificate for `admin`, written to a temporary directory
+    struct Certificates {
+        directory: PathBuf,
+        ca: CertifiedKey,
+        client: CertifiedKey,
+    }
+
+    impl Certificates {
+        fn generate(name: &str) -> Certificates {
+            let directory = std::env::temp_dir().join(format!("rust_store_tls_{}_{}", name, std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, "Test CA");
+            let key_pair = KeyPair::generate().unwrap();
+            let ca = CertifiedKey { cert: params.self_signed(&key_pair).unwrap(), key_pair };
+            let server = Certificates::sign(&ca, CertificateParams::new(vec!["localhost".to_string()]).unwrap());
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, "admin");
+            let client = Certificates::sign(&ca, params);
+            std::fs::write(directory.join("ca.pem"), ca.cert.pem()).unwrap();
+            std::fs::write(directory.join("server.pem"), server.cert.pem()).unwrap();
+            std::fs::write(directory.join("server.key"), server.key_pair.serialize_pem()).unwrap();
+            Certificates { directory, ca, client }
+        }
+
+        fn sign(ca: &CertifiedKey, params: CertificateParams) -> CertifiedKey {
+            let key_pair = KeyPair::generate().unwrap();
+            CertifiedKey { cert: params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap(), key_pair }
+        }
+
+        fn config(&self, mutual: bool) -> TlsConfig {
+            let config = TlsConfig::new(self.directory.join("server.pem"), self.directory.join("server.key"));
+            match mutual {
+                true => TlsConfig { client_ca_path: Some(self.directory.join("ca.pem")), ..config },
+                false => config,
+            }
+        }
+
+        /// Send a request over TLS, with the client certificate if asked, and read back the response
+        fn request(&self, address: std::net::SocketAddr, with_certificate: bool, headers: &str, query: &str) -> io::Result<String> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.ca.cert.der().clone()).unwrap();
+            let provider = Arc::new(crypto::ring::default_provider());
+            let builder = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions().unwrap()
+                .with_root_certificates(roots);
+            let config = match with_certificate {
+                true => {
+                    let key = PrivatePkcs8KeyDer::from(self.client.key_pair.serialize_der());
+                    builder.with_client_auth_cert(vec![self.client.cert.der().clone()], key.into()).unwrap()
+                },
+                false => builder.with_no_client_auth(),
+            };
+            let name = ServerName::try_from("localhost").unwrap();
+            let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+            let mut stream = StreamOwned::new(connection, TcpStream::connect(address)?);
+            let body = format!("{{\"query\": \"{}\"}}", query);
+            write!(
+                stream, "POST / HTTP/1.1\r\n{}Connection: close\r\nContent-Length: {}\r\n\r\n{}", headers, body.len(), body
+            )?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        }
+    }
+
+    impl Drop for Certificates {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let certificates = Certificates::generate("mutual");
+        let handler = tcp::TcpStreamHandler::with_tls(
+            IpAddr::V4(Ipv4Addr::LOCALHOST), 0, KeepAliveConfig::default(), RequestLimits::default(), &certificates.config(true)
+        ).unwrap();
+        let address = handler.local_addr();
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+
+        // The certificate says who the client is, and can't be claimed with a header
+        let response = certificates.request(address, true, "Client-Certificate-Subject: read\r\n", "set a 1;").unwrap();
+        assert!(response.ends_with("{\"Version\":1}"), "{}", response);
+        let response = certificates.request(address, true, "", "get a;").unwrap();
+        assert!(response.ends_with("{\"Value\":{\"Int\":1}}"), "{}", response);
+        // Clients without a certificate don't get through the handshake
+        assert!(certificates.request(address, false, "Username: admin\r\n", "get a;").is_err());
+
+        let response = certificates.request(address, true, "", "shutdown;").unwrap();
+        assert!(response.ends_with("\"ShuttingDown\""), "{}", response);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_async_tls() {
+        let certificates = Certificates::generate("async");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut handler = tcp_async::TcpStreamHandler::with_tls(
+                IpAddr::V4(Ipv4Addr::LOCALHOST), 0, KeepAliveConfig::default(), RequestLimits::default(), &certificates.config(false)
+            ).await.unwrap();
+            let address = handler.local_addr();
+            let client = thread::spawn(move || {
+                let response = certificates.request(address, false, "Username: admin\r\n", "get a;");
+                // Plain HTTP gets nowhere
+                let mut plain = TcpStream::connect(address).unwrap();
+                let _ = plain.write_all(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}");
+                let mut rest = vec![];
+                let _ = plain.read_to_end(&mut rest);
+                (response, rest)
+            });
+
+            let request = handler.receive_request().await;
+            assert_eq!(request.headers.get("Username").map(String::as_str), Some("admin"));
+            assert!(!request.headers.contains_key(CLIENT_CERTIFICATE_HEADER));
+            assert!(request.peer.is_some());
+            request.sender.unwrap().send(Ok(InterpreterResponse::Message("Ok".to_string()))).await.unwrap();
+            let (response, rest) = tokio::task::spawn_blocking(move || client.join().unwrap()).await.unwrap();
+            assert!(response.unwrap().ends_with("{\"Message\":\"Ok\"}"));
+            assert!(!String::from_utf8_lossy(&rest).contains("HTTP/1.1 200"));
+        });
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::ServerError;
+use crate::io::http::{ConnectionCounter, KeepAliveConfig, RequestLimits};
+use crate::io::stream::{StreamHandler, StreamRequest};
+use crate::io::tcp::{self, HttpStream};
+
+
+/// Settings for listening on a Unix domain socket.
+#[derive(Clone, Debug)]
+pub struct UnixSocketConfig {
+    /// Where to create the socket
+    pub path: PathBuf,
+    /// The permissions to give the socket file, like `0o660`, or None to leave them to the umask
+    pub permissions: Option<u32>,
+    /// When to close connections
+    pub keep_alive: KeepAliveConfig,
+    /// How big requests can be and how long clients get to send them
+    pub limits: RequestLimits,
+}
+
+impl UnixSocketConfig {
+    /// Listen at a path with the default settings
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixSocketConfig {
+        UnixSocketConfig {
+            path: path.into(),
+            permissions: None,
+            keep_alive: KeepAliveConfig::default(),
+            limits: RequestLimits::default(),
+        }
+    }
+
+    /// Clear out a socket left behind by an earlier run so the path can be bound again.
+    ///
+    /// Anything at the path that isn't a socket is left alone and reported as an error.
+    pub fn prepare_path(&self) -> Result<(), ServerError> {
+        match fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.file_type().is_socket() => match fs::remove_file(&self.path) {
+                Ok(_) => Ok(()),
+                Err(error) => Err(make_socket_error(&self.path, error)),
+            },
+            Ok(_) => Err(ServerError::NetworkError(
+                format!("{} already exists and is not a socket.", self.path.display())
+            )),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Bind the socket at the path with the configured permissions.
+    ///
+    /// Changing the mode after binding would leave a moment where the socket has the umask's
+    /// permissions, so with permissions set it is bound inside a new directory only the server
+    /// can get into, given its mode there and then moved into place.
+    pub fn bind<L, F: FnOnce(&Path) -> io::Result<L>>(&self, bind: F) -> Result<L, ServerError> {
+        self.prepare_path()?;
+        let mode = match self.permissions {
+            Some(mode) => mode,
+            None => return bind(&self.path).map_err(|error| make_socket_error(&self.path, error)),
+        };
+        let directory = self.private_directory();
+        if let Err(error) = DirBuilder::new().mode(0o700).create(&directory) {
+            return Err(make_socket_error(&directory, error));
+        }
+        let bound_path = directory.join("socket");
+        let result = bind(&bound_path)
+            .and_then(|listener| fs::set_permissions(&bound_path, Permissions::from_mode(mode)).map(|_| listener))
+            .and_then(|listener| fs::rename(&bound_path, &self.path).map(|_| listener));
+        let _ = fs::remove_dir_all(&directory);
+        result.map_err(|error| make_socket_error(&self.path, error))
+    }
+
+    /// A directory next to the socket to bind it in, so it can be renamed into place
+    fn private_directory(&self) -> PathBuf {
+        let name = match self.path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => "socket".to_string(),
+        };
+        self.path.with_file_name(format!(".{}.{}.bind", name, std::process::id()))
+    }
+}
+
+
+/// Create an error for a problem with the socket file
+pub fn make_socket_error(path: &Path, error: io::Error) -> ServerError {
+    ServerError::NetworkError(format!("Problem with socket {}: {}", path.display(), error))
+}
+
+
+impl HttpStream for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn peer_address(&self) -> Option<String> {
+        None
+    }
+
+    fn client_subject(&self) -> Option<String> {
+        None
+    }
+}
+
+
+/// Accept connections and handle each client on its own thread
+fn accept_connections(
+    listener: UnixListener, requests: Sender<StreamRequest>, keep_alive: KeepAliveConfig, limits: RequestLimits
+) {
+    let connections = ConnectionCounter::new(limits.max_connections);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => tcp::serve_connection(stream, keep_alive, limits, connections.try_open(), requests.clone()),
+            Err(error) => println!("Could not read Unix socket connection: {:?}", error),
+        }
+    }
+}
+
+
+/// Handles HTTP connections from a Unix domain socket, the same way `TcpStreamHandler` does
+/// for TCP. The socket file is removed when the handler is dropped.
+pub struct UnixStreamHandler {
+    /// Requests from every connection
+    requests: Receiver<StreamRequest>,
+    /// Where the socket was created
+    path: PathBuf,
+}
+
+
+impl UnixStreamHandler {
+    /// Listen on a socket at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<UnixStreamHandler, ServerError> {
+        UnixStreamHandler::with_config(UnixSocketConfig::new(path))
+    }
+
+    /// Listen on a socket with the given settings.
+    pub fn with_config(config: UnixSocketConfig) -> Result<UnixStreamHandler, ServerError> {
+        let listener = config.bind(|path| UnixListener::bind(path))?;
+        let (sender, requests) = mpsc::channel();
+        let (keep_alive, limits) = (config.keep_alive, config.limits);
+        thread::spawn(move || accept_connections(listener, sender, keep_alive, limits));
+        Ok(UnixStreamHandler { requests, path: config.path })
+    }
+}
+
+
+impl StreamHandler for UnixStreamHandler {
+    fn receive_request(&mut self) -> Option<StreamRequest> {
+        self.requests.recv().ok()
+    }
+}
+
+
+impl Drop for UnixStreamHandler {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+    use crate::analysis::Interpreter;
+    use crate::auth::MockAuthenticator;
+    use crate::single_threaded::SingleThreadedServer;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    #[test]
+    fn test_requests_over_a_socket() {
+        let path = std::env::temp_dir().join(format!("rust-store-test-{}.sock", std::process::id()));
+        fs::write(&path, "not a socket").unwrap();
+        assert!(UnixStreamHandler::new(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let config = UnixSocketConfig { permissions: Some(0o600), ..UnixSocketConfig::new(&path) };
+        let handler = UnixStreamHandler::with_config(config).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // The directory it was bound in is gone
+        let parent = fs::read_dir(path.parent().unwrap()).unwrap();
+        assert!(!parent.flatten().any(|entry| entry.file_name().to_string_lossy().ends_with(".bind")));
+        let server = thread::spawn(move || {
+            let interpreter = Interpreter::new(HashMapStorage::new());
+            SingleThreadedServer::with_interpreter(MockAuthenticator, interpreter).serve(handler);
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let body = "{\"query\": \"set a 1; shutdown;\"}";
+        let request = format!(
+            "POST / HTTP/1.1\r\nUsername: admin\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", body.len(), body
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 Ok\r\n"));
+        server.join().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+
+use crate::error::ServerError;
+use crate::io::http::{ConnectionCounter, KeepAliveConfig, RequestLimits};
+use crate::io::tcp_async::{self, CHANNEL_QUEUE_SIZE, Client, StreamRequest};
+use crate::io::unix::UnixSocketConfig;
+
+
+/// Accept connections and handle each client in its own task
+async fn accept_connections(
+    listener: UnixListener, requests: mpsc::Sender<StreamRequest>, keep_alive: KeepAliveConfig, limits: RequestLimits
+) {
+    let connections = ConnectionCounter::new(limits.max_connections);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                println!("Could not read Unix socket connection: {:?}", error);
+                continue;
+            },
+        };
+        let (reader, writer) = stream.into_split();
+        let slot = connections.try_open();
+        tcp_async::serve_connection(reader, writer, Client::default(), keep_alive, limits, slot, requests.clone());
+    }
+}
+
+
+/// Handles HTTP connections from a Unix domain socket, the same way the async
+/// `TcpStreamHandler` does for TCP. The socket file is removed when the handler is dropped.
+pub struct UnixStreamHandler {
+    /// Requests from every connection
+    requests: mpsc::Receiver<StreamRequest>,
+    /// Where the socket was created
+    path: PathBuf,
+}
+
+
+impl UnixStreamHandler {
+    /// Listen on a socket at the given path.
+    pub async fn new<P: Into<PathBuf>>(path: P) -> Result<UnixStreamHandler, ServerError> {
+        UnixStreamHandler::with_config(UnixSocketConfig::new(path)).await
+    }
+
+    /// Listen on a socket with the given settings.
+    pub async fn with_config(config: UnixSocketConfig) -> Result<UnixStreamHandler, ServerError> {
+        let listener = config.bind(|path| UnixListener::bind(path))?;
+        let (sender, requests) = mpsc::channel(CHANNEL_QUEUE_SIZE);
+        tokio::spawn(accept_connections(listener, sender, config.keep_alive, config.limits));
+        Ok(UnixStreamHandler { requests, path: config.path })
+    }
+
+    /// Receive a request
+    pub async fn receive_request(&mut self) -> StreamRequest {
+        match self.requests.recv().await {
+            Some(request) => request,
+            None => StreamRequest {
+                request: Err(ServerError::NetworkError("Could not read Unix socket connection.".to_string())),
+                headers: HashMap::new(),
+                peer: None,
+                sender: None,
+            },
+        }
+    }
+}
+
+
+impl Drop for UnixStreamHandler {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
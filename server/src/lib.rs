@@ -17,5 +17,13 @@ pub mod io;
 pub mod error;
 /// Single threaded API
 pub mod single_threaded;
+/// Using the store as a library without any networking
+pub mod embedded;
 /// Authorization & Authentication
 pub mod auth;
+/// Saving the database to disk and restoring it
+pub mod persistence;
+/// Server settings from a config file, the command line and the environment
+pub mod config;
+/// Recording who ran what against the server
+pub mod audit;
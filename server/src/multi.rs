@@ -1,11 +1,44 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use server::analysis::Interpreter;
+use server::config::ServerConfig;
 use server::multithreaded::Coordinator;
+use server::persistence;
+use server::storage::{Storage, StorageBackend};
+use server::storage::btree_storage::BTreeMapStorage;
 
-fn main() {
-    let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-    let port = 7878;
-    let mut coordinator = Coordinator::new(3, 3, ip, port);
+/// Restore one interpreter per shard, each with its own files when there is more than one
+fn restore_shards<S: Storage + Send, F: Fn() -> S>(config: &ServerConfig, new_storage: F) -> Vec<Interpreter<S>> {
+    let persistence = config.persistence();
+    if config.shards == 1 {
+        return vec![persistence::restore_interpreter(new_storage(), &persistence).unwrap()];
+    }
+    (0..config.shards)
+        .map(|shard| persistence::restore_interpreter(new_storage(), &persistence.for_shard(shard)).unwrap())
+        .collect()
+}
 
+fn serve<S: Storage + Send + Sync + 'static>(config: &ServerConfig, mut interpreters: Vec<Interpreter<S>>) {
+    let users = config.load_users().unwrap();
+    if let Some(users) = &users {
+        for interpreter in interpreters.iter_mut() {
+            interpreter.set_users(Arc::clone(users));
+        }
+    }
+    if let Some(signer) = config.capability_signer().unwrap() {
+        for interpreter in interpreters.iter_mut() {
+            interpreter.set_capability_signer(signer.clone());
+        }
+    }
+    let authenticator = config.new_authenticator(users).unwrap();
+    let mut coordinator = Coordinator::with_config(config, interpreters, authenticator).unwrap();
     coordinator.serve();
+}
 
-}
\ No newline at end of file
+fn main() {
+    let config = ServerConfig::load();
+    match config.backend {
+        StorageBackend::HashMap => serve(&config, restore_shards(&config, || config.new_hashmap_storage())),
+        StorageBackend::BTreeMap => serve(&config, restore_shards(&config, BTreeMapStorage::new)),
+    }
+}
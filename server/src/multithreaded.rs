@@ -8,5 +8,7 @@ pub mod expiration;
 pub mod coordinator;
 /// Listen for requests and send responses
 pub mod listener;
+/// Splits the keys between several interpreters
+pub mod shards;
 
 pub use coordinator::Coordinator;
@@ -4,17 +4,24 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::thread::{self, JoinHandle};
 
-use crate::analysis::{InterpreterRequest, Parser, Tokenizer, Statement};
-use crate::auth::AuthorizationLevel;
+use crate::analysis::{InterpreterRequest, ResponseMode, Statement};
+use crate::auth::{AccessRules, AuthorizationLevel};
+use crate::auth::credentials::DEFAULT_ITERATIONS;
 use crate::error::ServerError;
+use crate::io::stream::StreamQuery;
 use crate::multithreaded::executor::{ExecutorRequest, ExecutorResponse};
+use crate::multithreaded::shards::ShardRouter;
 
 /// Request for an analyzer
 pub struct AnalysisRequest {
-    /// The request string
-    pub request: String,
+    /// The query to run
+    pub request: StreamQuery,
     /// The authorization level for this request
     pub authorization: AuthorizationLevel,
+    /// Rules limiting the keys and commands this request can use, if any
+    pub access_rules: Option<Arc<AccessRules>>,
+    /// Which responses to send back
+    pub response_mode: ResponseMode,
     /// A sender back to the listener node for responding
     pub sender: Option<Sender<ExecutorResponse>>,
 }
@@ -24,8 +31,12 @@ pub struct AnalysisRequest {
 pub struct AnalysisWorker {
     /// The channel to receive requests
     receive_channel: Arc<Mutex<Receiver<AnalysisRequest>>>,
-    /// The channel to send requests to the executor
-    send_channel: Sender<ExecutorRequest>,
+    /// The channels to send requests to the executor of each shard
+    send_channels: Vec<Sender<ExecutorRequest>>,
+    /// The channel to send read only requests to the readers
+    read_channel: Sender<ExecutorRequest>,
+    /// Picks the shard to send each request to
+    router: ShardRouter,
     /// Flag to manage shutdowns
     shutdown_signal: Arc<AtomicBool>,
     /// Length to wait for receiving before stopping and checking for shutdown
@@ -36,8 +47,19 @@ pub struct AnalysisWorker {
 
 
 impl AnalysisWorker {
+    /// Send a request to the readers if it only reads, otherwise to the executor of the shard
+    /// that owns it.
+    /// 
+    /// Requests needing several shards lock them all, and are owned by the lowest one.
     fn send_response(&mut self, response: ExecutorRequest) {
-        let send_result = self.send_channel.send(response);
+        if response.request.statements.iter().all(Statement::is_read) {
+            if let Err(error) = self.read_channel.send(response) {
+                println!("{:?}", error);
+            }
+            return;
+        }
+        let shard = self.router.owning_shard(&response.request.statements);
+        let send_result = self.send_channels[shard].send(response);
         if let Err(error) = send_result {
             println!("{:?}", error);
         }
@@ -51,19 +73,12 @@ impl AnalysisWorker {
         }
     }
 
-    fn process_request(&mut self, request: &str) -> Result<Vec<Statement>, ServerError> {
-        let mut tokenizer = Tokenizer::new(&request);
-        let tokens = tokenizer.tokenize()?;
-        let mut parser = Parser::new(tokens);
-        parser.parse()        
-    }
-
     fn analyze_request(&mut self, request: AnalysisRequest) {
-        let AnalysisRequest{request, authorization, sender} = request;
-        let statements = self.process_request(&request);
+        let AnalysisRequest{request, authorization, access_rules, response_mode, sender} = request;
+        let statements = request.into_runnable_statements(DEFAULT_ITERATIONS);
         match statements {
             Ok(statements) => {
-                let interpreter_request = InterpreterRequest{statements, authorization};
+                let interpreter_request = InterpreterRequest{statements, authorization, access_rules, response_mode};
                 let exec_request = ExecutorRequest{request: interpreter_request, sender};
                 self.send_response(exec_request);
             },
@@ -114,7 +129,9 @@ impl AnalysisWorker {
         println!("Starting analysis worker.");
         let mut temp_worker = AnalysisWorker {
             receive_channel: Arc::clone(&self.receive_channel),
-            send_channel: self.send_channel.clone(),
+            send_channels: self.send_channels.clone(),
+            read_channel: self.read_channel.clone(),
+            router: self.router,
             shutdown_signal: Arc::clone(&self.shutdown_signal),
             receive_deadline: self.receive_deadline.clone(),
             thread: None,
@@ -147,7 +164,9 @@ impl AnalysisPool {
     /// Create a new pool
     pub fn new(
         workers: usize,
-        send_channel: Sender<ExecutorRequest>,
+        send_channels: Vec<Sender<ExecutorRequest>>,
+        read_channel: Sender<ExecutorRequest>,
+        router: ShardRouter,
         receive_channel: Arc<Mutex<Receiver<AnalysisRequest>>>
     ) -> AnalysisPool {
         let mut pool = AnalysisPool { workers: vec![], shutdown_signal: Arc::new(AtomicBool::new(false)) };
@@ -156,7 +175,9 @@ impl AnalysisPool {
             pool.workers.push(
                 AnalysisWorker {
                     receive_channel: receive_channel.clone(),
-                    send_channel: send_channel.clone(),
+                    send_channels: send_channels.clone(),
+                    read_channel: read_channel.clone(),
+                    router,
                     shutdown_signal: pool.shutdown_signal.clone(),
                     receive_deadline,
                     thread: None,
@@ -7,20 +7,46 @@ use std::net::IpAddr;
 
 use super::executor::Executor;
 use super::expiration::ExpirationWorker;
-use crate::auth::MockAuthenticator;
+use super::shards::ShardSet;
+use crate::analysis::Interpreter;
+use crate::audit::{AuditLog, SharedAuditLog};
+use crate::auth::{AuthenticationService, MockAuthenticator};
+use crate::config::ServerConfig;
+use crate::error::ServerError;
+use crate::io::http::RequestLimits;
+use crate::io::resp::RespStreamHandler;
+use crate::io::stream::StreamHandler;
 use crate::io::tcp::TcpStreamHandler;
+use crate::io::unix::{UnixSocketConfig, UnixStreamHandler};
+use crate::storage::Storage;
+use crate::storage::hashmap_storage::HashMapStorage;
 use super::listener::ListenerPool;
-use super::analysis::AnalysisPool;
+use super::analysis::{AnalysisPool, AnalysisRequest};
 
 
 /// Higher level struct to run a multithreaded server.
-pub struct Coordinator {
+pub struct Coordinator<
+    S: Storage + Send + Sync + 'static = HashMapStorage,
+    A: AuthenticationService + Send + 'static = MockAuthenticator,
+> {
     /// Pool of listeners
-    listener_pool: ListenerPool<TcpStreamHandler, MockAuthenticator>,
+    listener_pool: ListenerPool<TcpStreamHandler, A>,
+    /// Pools of listeners for any other ways clients can connect
+    extra_listener_pools: Vec<ListenerPool<Box<dyn StreamHandler + Send>, A>>,
+    /// Where listeners send requests to be analyzed
+    analysis_send_channel: mpsc::Sender<AnalysisRequest>,
+    /// Authenticator shared by all listeners
+    authenticator: Arc<Mutex<A>>,
+    /// How long listeners wait for a request to be run
+    request_timeout: Duration,
+    /// Where every listener records who ran what, if anywhere
+    audit_log: Option<SharedAuditLog>,
     /// Pool of analyzers
     analysis_pool: AnalysisPool,
-    /// Executor worker
-    executor: Executor,
+    /// Executor workers, one for each shard
+    executors: Vec<Executor<S>>,
+    /// Executor workers sharing the read only requests
+    readers: Vec<Executor<S>>,
     /// Old key expiration worker
     expiration: ExpirationWorker,
     /// Flag to kick off shutdown process
@@ -32,41 +58,174 @@ impl Coordinator
 {
     /// Create a new Coordinator
     pub fn new(listeners: usize, analyzers: usize, ip_addr: IpAddr, port: usize) -> Coordinator {
-        let handler = TcpStreamHandler::new(ip_addr, port);
+        let interpreter = Interpreter::new(HashMapStorage::new());
+        Coordinator::with_interpreter(listeners, analyzers, ip_addr, port, interpreter)
+    }
+}
+
+
+impl<S: Storage + Send + Sync + 'static> Coordinator<S>
+{
+    /// Create a new Coordinator serving from an existing interpreter
+    pub fn with_interpreter(
+        listeners: usize,
+        analyzers: usize,
+        ip_addr: IpAddr,
+        port: usize,
+        interpreter: Interpreter<S>,
+    ) -> Coordinator<S> {
+        let readers = ServerConfig::default().readers;
+        Coordinator::with_shards(listeners, analyzers, readers, ip_addr, port, vec![interpreter])
+    }
+
+    /// Create a new Coordinator with the keys split between one interpreter per shard
+    /// 
+    /// Each shard gets its own executor, so requests on different shards run in parallel.
+    /// Requests that only read go to a separate pool of readers and run alongside the writes.
+    pub fn with_shards(
+        listeners: usize,
+        analyzers: usize,
+        readers: usize,
+        ip_addr: IpAddr,
+        port: usize,
+        interpreters: Vec<Interpreter<S>>,
+    ) -> Coordinator<S> {
+        let config = ServerConfig { listeners, analyzers, readers, address: ip_addr, port, ..ServerConfig::default() };
+        let handler = TcpStreamHandler::with_keep_alive(config.address, config.port, config.keep_alive());
+        Coordinator::with_workers(&config, handler, interpreters, MockAuthenticator)
+    }
+}
+
+
+impl<S: Storage + Send + Sync + 'static, A: AuthenticationService + Send + 'static> Coordinator<S, A>
+{
+    /// Create a new Coordinator with everything taken from the server settings
+    /// 
+    /// This includes TLS and the Redis protocol and Unix socket listeners, if they are set.
+    pub fn with_config(
+        config: &ServerConfig, interpreters: Vec<Interpreter<S>>, authenticator: A
+    ) -> Result<Coordinator<S, A>, ServerError> {
+        let (keep_alive, limits) = (config.keep_alive(), config.limits());
+        let handler = match config.tls() {
+            Some(tls) => TcpStreamHandler::with_tls(config.address, config.port, keep_alive, limits, &tls)?,
+            None => TcpStreamHandler::with_limits(config.address, config.port, keep_alive, limits),
+        };
+        let mut coordinator = Coordinator::with_workers(config, handler, interpreters, authenticator);
+        if let Some(audit) = config.audit() {
+            coordinator.set_audit_log(AuditLog::open(audit)?.shared());
+        }
+        if let Some(resp_port) = config.resp_port {
+            coordinator.listen_resp(config.listeners, config.address, resp_port, limits);
+        }
+        if let Some(unix_socket) = config.unix_socket() {
+            coordinator.listen_unix(config.listeners, unix_socket)?;
+        }
+        Ok(coordinator)
+    }
+
+    /// Create the workers to serve the requests from a TCP listener
+    fn with_workers(
+        config: &ServerConfig, handler: TcpStreamHandler, interpreters: Vec<Interpreter<S>>, authenticator: A
+    ) -> Coordinator<S, A> {
         let handler = Arc::new(Mutex::new(handler));
-        let authenticator = Arc::new(Mutex::new(MockAuthenticator));
+        let authenticator = Arc::new(Mutex::new(authenticator));
         let (analysis_send_channel, analysis_receive_channel) = mpsc::channel();
         let analysis_receive_channel = Arc::new(Mutex::new(analysis_receive_channel));
-        let (executor_send_channel, executor_receive_channel) = mpsc::channel();
+        let shards = Arc::new(ShardSet::new(interpreters));
+        let start_shutdown = Arc::new(AtomicBool::new(false));
+        let mut executor_send_channels = vec![];
+        let mut executors = vec![];
+        for shard in 0..shards.router().shard_count() {
+            let (executor_send_channel, executor_receive_channel) = mpsc::channel();
+            executor_send_channels.push(executor_send_channel);
+            executors.push(
+                Executor::for_shard(executor_receive_channel, Arc::clone(&start_shutdown), Arc::clone(&shards), shard)
+            );
+        }
+        let (read_send_channel, read_receive_channel) = mpsc::channel();
+        let read_receive_channel = Arc::new(Mutex::new(read_receive_channel));
+        let readers = (0..config.readers)
+            .map(|_| Executor::with_shared_channel(
+                Arc::clone(&read_receive_channel), Arc::clone(&start_shutdown), Arc::clone(&shards)
+            ))
+            .collect();
 
-        let listener_pool = ListenerPool::new(
-            listeners, analysis_send_channel, handler, authenticator
+        let listener_pool = ListenerPool::with_receive_timeout(
+            config.listeners, analysis_send_channel.clone(), handler, Arc::clone(&authenticator), config.request_timeout
         );
         let analysis_pool = AnalysisPool::new(
-            analyzers,
-            executor_send_channel.clone(),
+            config.analyzers,
+            executor_send_channels.clone(),
+            read_send_channel,
+            shards.router(),
             analysis_receive_channel,
         );
 
-        let start_shutdown = Arc::new(AtomicBool::new(false));
-        let executor = Executor::new(executor_receive_channel, Arc::clone(&start_shutdown));
-
-        let expiration = ExpirationWorker::new(executor_send_channel.clone());
+        let expiration = ExpirationWorker::with_schedule(
+            executor_send_channels[0].clone(), config.expiration_calls, config.expiration_interval
+        );
     
         Coordinator {
             listener_pool,
+            extra_listener_pools: vec![],
+            analysis_send_channel,
+            authenticator,
+            request_timeout: config.request_timeout,
+            audit_log: None,
             analysis_pool,
-            executor,
+            executors,
+            readers,
             expiration,
             start_shutdown
         }
     }
 
+    /// Also take requests from another stream handler
+    pub fn listen_on<H: StreamHandler + Send + 'static>(&mut self, listeners: usize, handler: H) {
+        let handler: Box<dyn StreamHandler + Send> = Box::new(handler);
+        let mut listener_pool = ListenerPool::with_receive_timeout(
+            listeners,
+            self.analysis_send_channel.clone(),
+            Arc::new(Mutex::new(handler)),
+            Arc::clone(&self.authenticator),
+            self.request_timeout,
+        );
+        if let Some(audit_log) = &self.audit_log {
+            listener_pool.set_audit_log(Arc::clone(audit_log));
+        }
+        self.extra_listener_pools.push(listener_pool);
+    }
+
+    /// Record who ran what on every listener in an audit log
+    pub fn set_audit_log(&mut self, audit_log: SharedAuditLog) {
+        self.listener_pool.set_audit_log(Arc::clone(&audit_log));
+        for listener_pool in self.extra_listener_pools.iter_mut() {
+            listener_pool.set_audit_log(Arc::clone(&audit_log));
+        }
+        self.audit_log = Some(audit_log);
+    }
+
+    /// Also accept clients speaking the Redis protocol on another port
+    pub fn listen_resp(&mut self, listeners: usize, ip_addr: IpAddr, port: usize, limits: RequestLimits) {
+        self.listen_on(listeners, RespStreamHandler::with_limits(ip_addr, port, limits));
+    }
+
+    /// Also accept HTTP clients on a Unix domain socket
+    pub fn listen_unix(&mut self, listeners: usize, config: UnixSocketConfig) -> Result<(), ServerError> {
+        self.listen_on(listeners, UnixStreamHandler::with_config(config)?);
+        Ok(())
+    }
+
     /// Start the server
     pub fn serve(&mut self) {
-        self.executor.start();
+        for executor in self.executors.iter_mut().chain(self.readers.iter_mut()) {
+            executor.start();
+        }
         self.analysis_pool.start();
         self.listener_pool.start();
+        for listener_pool in self.extra_listener_pools.iter_mut() {
+            listener_pool.start();
+        }
         self.expiration.start();
         println!("Ready for requests.");
         loop {
@@ -83,9 +242,14 @@ impl Coordinator
     fn stop(&mut self) {
         println!("Stopping the service.");
         self.listener_pool.stop();
+        for listener_pool in self.extra_listener_pools.iter_mut() {
+            listener_pool.stop();
+        }
         self.analysis_pool.stop();
         self.expiration.stop();
-        self.executor.stop();
+        for executor in self.executors.iter_mut().chain(self.readers.iter_mut()) {
+            executor.stop();
+        }
         println!("Finished shutting down all workers.");
     }
 
@@ -6,6 +6,8 @@ use std::time::Duration;
 
 use crate::analysis::{Interpreter, InterpreterRequest, InterpreterResponse};
 use crate::error::ServerError;
+use crate::multithreaded::shards::ShardSet;
+use crate::storage::Storage;
 use crate::storage::hashmap_storage::HashMapStorage;
 
 
@@ -24,9 +26,14 @@ pub struct ExecutorResponse {
 }
 
 /// An executor sends requests to the interpreter from an open channel and returns responses.
-pub struct Executor{
-    /// The interpreter backed by some storage object.
-    interpreter: Arc<Mutex<Interpreter<HashMapStorage>>>,
+///
+/// A sharded server has one executor for each shard, which runs the writes that shard owns,
+/// and a pool of readers sharing the read only requests.
+pub struct Executor<S: Storage + Send + Sync + 'static = HashMapStorage> {
+    /// The interpreters backed by some storage objects, shared with the other executors.
+    shards: Arc<ShardSet<S>>,
+    /// The shard whose writes this executor runs, or None to run anything it is sent
+    shard: Option<usize>,
     /// The channel handling all requests - many sender/single receiver
     request_channel: Arc<Mutex<Receiver<ExecutorRequest>>>,
     /// A flag to set to shut down all workers prior to shutting down the executor
@@ -42,9 +49,58 @@ pub struct Executor{
 impl Executor {
     /// Create a new executor
     pub fn new(request_channel: Receiver<ExecutorRequest>, start_shutdown_flag: Arc<AtomicBool>) -> Executor {
+        Executor::with_interpreter(
+            request_channel, start_shutdown_flag, Interpreter::new(HashMapStorage::new())
+        )
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> Executor<S> {
+    /// Create a new executor running an existing interpreter
+    pub fn with_interpreter(
+        request_channel: Receiver<ExecutorRequest>,
+        start_shutdown_flag: Arc<AtomicBool>,
+        interpreter: Interpreter<S>,
+    ) -> Executor<S> {
+        Executor::with_shards(
+            request_channel, start_shutdown_flag, Arc::new(ShardSet::new(vec![interpreter]))
+        )
+    }
+
+    /// Create a new executor running requests on a set of shards shared with other executors
+    pub fn with_shards(
+        request_channel: Receiver<ExecutorRequest>,
+        start_shutdown_flag: Arc<AtomicBool>,
+        shards: Arc<ShardSet<S>>,
+    ) -> Executor<S> {
+        Executor::with_shared_channel(Arc::new(Mutex::new(request_channel)), start_shutdown_flag, shards)
+    }
+
+    /// Create a new executor running the writes owned by one of a set of shards
+    pub fn for_shard(
+        request_channel: Receiver<ExecutorRequest>,
+        start_shutdown_flag: Arc<AtomicBool>,
+        shards: Arc<ShardSet<S>>,
+        shard: usize,
+    ) -> Executor<S> {
+        Executor {
+            shard: Some(shard),
+            ..Executor::with_shards(request_channel, start_shutdown_flag, shards)
+        }
+    }
+
+    /// Create a new executor taking requests from a channel shared with other executors
+    /// 
+    /// This lets a pool of executors work through read only requests at the same time.
+    pub fn with_shared_channel(
+        request_channel: Arc<Mutex<Receiver<ExecutorRequest>>>,
+        start_shutdown_flag: Arc<AtomicBool>,
+        shards: Arc<ShardSet<S>>,
+    ) -> Executor<S> {
         Executor {
-            interpreter: Arc::new(Mutex::new(Interpreter::new(HashMapStorage::new()))),
-            request_channel: Arc::new(Mutex::new(request_channel)),
+            shards,
+            shard: None,
+            request_channel,
             start_shutdown_flag,
             shutdown_flag: Arc::new(AtomicBool::new(false)),
             timeout: Duration::from_secs(1),
@@ -56,10 +112,15 @@ impl Executor {
     /// Execute a request
     fn execute(&mut self, request: ExecutorRequest) -> bool {
         let ExecutorRequest{request, sender} = request;
-        let interpreter_response = self.interpreter.try_lock().unwrap().interpret(request);
-        let keep_going = match interpreter_response {
-            Ok(InterpreterResponse::ShuttingDown) => false,
-            _ => true,
+        let interpreter_response = match self.shard {
+            Some(shard) if self.shards.router().owning_shard(&request.statements) != shard => Err(
+                ServerError::InternalError(format!("Request sent to the executor of shard {} belongs to another shard.", shard))
+            ),
+            _ => self.shards.interpret(request),
+        };
+        let keep_going = match &interpreter_response {
+            Ok(response) => !response.is_shutting_down(),
+            Err(_) => true,
         };
         let executor_response = ExecutorResponse{response: interpreter_response};
         if let Some(sender) = sender {
@@ -78,7 +139,7 @@ impl Executor {
                 println!("Shutting down the executor.");
                 break;
             }
-            let request = self.request_channel.try_lock().unwrap().recv_timeout(self.timeout);
+            let request = self.request_channel.lock().unwrap().recv_timeout(self.timeout);
             let request = match request {
                 Ok(request) => request,
                 Err(_) => {
@@ -97,7 +158,8 @@ impl Executor {
     pub fn start(&mut self) {
         println!("Starting executor.");
         let mut temp_worker = Executor{
-            interpreter: Arc::clone(&self.interpreter),
+            shards: Arc::clone(&self.shards),
+            shard: self.shard,
             request_channel: Arc::clone(&self.request_channel),
             start_shutdown_flag: Arc::clone(&self.start_shutdown_flag),
             shutdown_flag: Arc::clone(&self.shutdown_flag ),
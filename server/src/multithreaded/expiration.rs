@@ -5,7 +5,7 @@ use std::time::Duration;
 use std::thread::{self, JoinHandle};
 
 use crate::multithreaded::executor::ExecutorRequest;
-use crate::analysis::{InterpreterRequest, Statement};
+use crate::analysis::{InterpreterRequest, ResponseMode, Statement};
 use crate::auth::AuthorizationLevel;
 
 
@@ -34,10 +34,15 @@ pub struct ExpirationWorker {
 impl ExpirationWorker {
     /// Create a new worker to expire old keys
     pub fn new(channel: Sender<ExecutorRequest>) -> ExpirationWorker {
+        ExpirationWorker::with_schedule(channel, 5, Duration::from_secs(5))
+    }
+
+    /// Create a new worker that sends `ncalls` expiration requests every `interval`
+    pub fn with_schedule(channel: Sender<ExecutorRequest>, ncalls: usize, interval: Duration) -> ExpirationWorker {
         ExpirationWorker {
             channel,
-            ncalls: 5,
-            interval: Duration::from_secs(5),
+            ncalls,
+            interval,
             shutdown_signal: Arc::new(AtomicBool::new(false)),
             thread: None,
         }
@@ -47,7 +52,10 @@ impl ExpirationWorker {
         for _ in 0..self.ncalls {
             let request = ExecutorRequest {
                 request: InterpreterRequest {
-                    statements: vec![Statement::ExpireKeys], authorization: AuthorizationLevel::Admin
+                    statements: vec![Statement::ExpireKeys],
+                    authorization: AuthorizationLevel::Admin,
+                    access_rules: None,
+                    response_mode: ResponseMode::Last,
                 },
                 sender: None,
             };
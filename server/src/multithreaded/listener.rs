@@ -5,13 +5,18 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::thread::{self, JoinHandle};
 
-use crate::auth::{AuthenticationService, AuthenticationResult};
+use crate::audit::{self, SharedAuditLog};
+use crate::auth::{AccessRules, AuthenticationService, AuthenticationResult, AuthorizationLevel};
 use crate::error::ServerError;
 use crate::io::stream::{StreamHandler, StreamSender};
-use crate::analysis::InterpreterResponse;
+use crate::analysis::{InterpreterResponse, ResponseMode, Statement};
 use crate::multithreaded::executor::ExecutorResponse;
 use crate::multithreaded::analysis::AnalysisRequest;
-use crate::io::stream::StreamRequest;
+use crate::io::stream::{StreamQuery, StreamRequest};
+
+
+/// Who sent a request: their username, authorization level and any rules limiting them
+type Authentication = (String, Option<AuthorizationLevel>, Option<Arc<AccessRules>>);
 
 
 /// A worker to listen for TCP connections and send off requests to the analyzer.
@@ -22,6 +27,7 @@ pub struct ListenerWorker<T: StreamHandler + Send + 'static, A: AuthenticationSe
     shutdown_signal: Arc<AtomicBool>,
     thread: Option<JoinHandle<()>>,
     authenticator: Arc<Mutex<A>>,
+    audit_log: Option<SharedAuditLog>,
 }
 
 
@@ -78,19 +84,42 @@ impl<T: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
                 Some(request) => request,
                 None => continue,
             };
-            let request_string = match request.request {
+            let query = match request.request {
                 Ok(req) => req,
                 Err(err) => {
                     send_response(Err(err), request.sender);
                     continue;
                 },
             };
+            let peer = request.peer;
+            // The audit log needs the statements, so parse them here rather than in the analyzer
+            let (query, statements) = match self.audit_log {
+                Some(_) => match query.into_statements() {
+                    Ok(statements) => (Ok(StreamQuery::Statements(statements.clone())), statements),
+                    Err(err) => (Err(err), vec![]),
+                },
+                None => (Ok(query), vec![]),
+            };
+            let (username, authorization, access_rules) = match self.authenticate(&request.headers) {
+                Ok(authentication) => authentication,
+                Err(err) => {
+                    self.finish(Err(err), None, peer, &statements, request.sender);
+                    continue;
+                }
+            };
+            let query = match query {
+                Ok(query) => query,
+                Err(err) => {
+                    self.finish(Err(err), Some(username), peer, &statements, request.sender);
+                    continue;
+                }
+            };
             let (
                 analysis_request, response_channel
-            ) = match self.convert_to_analysis_request(&request_string, &request.headers) {
+            ) = match self.convert_to_analysis_request(query, &username, authorization, access_rules, &request.headers) {
                 Ok((analysis_request, response_channel)) => (analysis_request, response_channel),
                 Err(err) => {
-                    send_response(Err(err), request.sender);
+                    self.finish(Err(err), Some(username), peer, &statements, request.sender);
                     continue;
                 }
             };
@@ -98,7 +127,8 @@ impl<T: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
                 Ok(_) => (),
                 Err(err) => {
                     println!("Error sending analysis request: {:?}", err);
-                    send_response(Err(ServerError::InternalError("Internal error found.".to_string())), request.sender);
+                    let err = ServerError::InternalError("Internal error found.".to_string());
+                    self.finish(Err(err), Some(username), peer, &statements, request.sender);
                     continue;
                 }
             }
@@ -107,27 +137,48 @@ impl<T: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
                 Ok(resp) => resp.response,
                 Err(_) => Err(ServerError::InternalError("Command timed out.".to_string())),
             };
-            send_response(response, request.sender);
+            self.finish(response, Some(username), peer, &statements, request.sender);
         }
     }
 
-    fn convert_to_analysis_request(
-        &mut self, request: &str, headers: &HashMap<String, String>
-    ) -> Result<(AnalysisRequest, Receiver<ExecutorResponse>), ServerError> {
+    /// Record a request in the audit log, if there is one, and send back its response
+    fn finish(
+        &self,
+        response: Result<InterpreterResponse, ServerError>,
+        username: Option<String>,
+        peer: Option<String>,
+        statements: &[Statement],
+        sender: Option<Box<dyn StreamSender + Send>>,
+    ) {
+        audit::record(&self.audit_log, username, peer, statements, &response);
+        send_response(response, sender);
+    }
+
+    /// Find out who sent a request
+    fn authenticate(
+        &mut self, headers: &HashMap<String, String>
+    ) -> Result<Authentication, ServerError> {
         let authentication = {
             let mut authenticator = self.authenticator.lock().unwrap();
             authenticator.authenticate(headers)
         };
-        let (username, authorization)= match authentication {
-            Ok(AuthenticationResult::Authenticated(username, level)) => (username, level),
+        match authentication {
+            Ok(AuthenticationResult::Authenticated(username, level, access_rules)) => Ok((username, level, access_rules)),
             Ok(AuthenticationResult::Unauthenticated) => {
-                return Err(ServerError::AuthenticationError("Authentication failed.".to_string()));
-            },
-            Err(error) => {
-                return Err(error);
+                Err(ServerError::AuthenticationError("Authentication failed.".to_string()))
             },
-        };
+            Err(error) => Err(error),
+        }
+    }
 
+    fn convert_to_analysis_request(
+        &mut self,
+        request: StreamQuery,
+        username: &str,
+        authorization: Option<AuthorizationLevel>,
+        access_rules: Option<Arc<AccessRules>>,
+        headers: &HashMap<String, String>,
+    ) -> Result<(AnalysisRequest, Receiver<ExecutorResponse>), ServerError> {
         let authorization = match authorization {
             None => {
                 let error = ServerError::AuthorizationError(
@@ -137,8 +188,11 @@ impl<T: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
             },
             Some(auth) => auth,
         };
+        let response_mode = ResponseMode::from_headers(headers)?;
         let (sender, receiver) = mpsc::channel();
-        let request = AnalysisRequest{request: request.to_string(), authorization, sender: Some(sender)};
+        let request = AnalysisRequest{
+            request, authorization, access_rules, response_mode, sender: Some(sender)
+        };
         Ok((request, receiver))
     }
     
@@ -162,6 +216,7 @@ impl<T: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
             shutdown_signal: Arc::clone(&self.shutdown_signal),
             thread: None,
             authenticator: Arc::clone(&self.authenticator),
+            audit_log: self.audit_log.clone(),
         };
 
         self.thread = Some(thread::spawn(move || {
@@ -194,9 +249,21 @@ impl<H: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
         send_channel: Sender<AnalysisRequest>,
         receive_channel: Arc<Mutex<H>>,
         authentication_server: Arc<Mutex<A>>,
+    ) -> ListenerPool<H, A> {
+        ListenerPool::with_receive_timeout(
+            workers, send_channel, receive_channel, authentication_server, Duration::from_secs(1)
+        )
+    }
+
+    /// Create a new pool of Listeners that wait up to `receive_timeout` for each request to be run
+    pub fn with_receive_timeout(
+        workers: usize,
+        send_channel: Sender<AnalysisRequest>,
+        receive_channel: Arc<Mutex<H>>,
+        authentication_server: Arc<Mutex<A>>,
+        receive_timeout: Duration,
     ) -> ListenerPool<H, A> {
         let mut pool = ListenerPool { workers: vec![], shutdown_signal: Arc::new(AtomicBool::new(false)) };
-        let receive_timeout = Duration::from_secs(1);
         for _ in 0..workers {
             pool.workers.push(
                 ListenerWorker {
@@ -206,12 +273,20 @@ impl<H: StreamHandler + Send + 'static, A: AuthenticationService + Send + 'stati
                     receive_timeout,
                     thread: None,
                     authenticator: Arc::clone(&authentication_server),
+                    audit_log: None,
                 }
             );
         }
         pool
     }
 
+    /// Record the requests every listener handles in an audit log
+    pub fn set_audit_log(&mut self, audit_log: SharedAuditLog) {
+        for worker in self.workers.iter_mut() {
+            worker.audit_log = Some(Arc::clone(&audit_log));
+        }
+    }
+
     /// Start the pool
     pub fn start(&mut self) {
         println!("Starting listener pool.");
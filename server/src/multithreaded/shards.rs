@@ -0,0 +1,497 @@
+use std::collections::BTreeMap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::analysis::{
+    Interpreter,
+    InterpreterRequest,
+    InterpreterResponse,
+    ResponseCollector,
+    ResponseMode,
+    Statement,
+    validate_authorization,
+    validate_transactions,
+};
+use crate::error::ServerError;
+use crate::storage::Storage;
+
+
+/// Decides which shard each statement belongs to.
+///
+/// Keys are hashed with FNV-1a rather than the standard library hasher, since each shard keeps
+/// its own files on disk and the same key has to land on the same shard after an upgrade.
+/// For the same reason the number of shards must not change between runs.
+#[derive(Clone, Copy, Debug)]
+pub struct ShardRouter {
+    /// How many shards there are
+    shards: usize,
+}
+
+impl ShardRouter {
+    /// Create a router for the given number of shards
+    pub fn new(shards: usize) -> ShardRouter {
+        ShardRouter { shards: shards.max(1) }
+    }
+
+    /// The number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards
+    }
+
+    /// Get the shard a key lives on
+    pub fn shard_for_key(&self, key: &str) -> usize {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        (hash % self.shards as u64) as usize
+    }
+
+    /// Get the shards a statement needs, in ascending order
+    pub fn shards_for_statement(&self, statement: &Statement) -> Vec<usize> {
+        if let Some(key) = statement.key() {
+            return vec![self.shard_for_key(key)];
+        }
+        match statement {
+            Statement::Scan(cursor, ..) => vec![self.split_cursor(*cursor).0],
+            // Every shard shares the same users, so they are only changed once
+            Statement::Null | Statement::UserAdd(..) | Statement::UserDelete(..) |
+            Statement::UserPassword(..) | Statement::UserList | Statement::IssueCapability(..) => vec![0],
+            // Transactions cover whichever shards the rest of the request needs
+            Statement::Begin | Statement::Commit => vec![],
+            _ => (0..self.shards).collect(),
+        }
+    }
+
+    /// Get every shard a request needs, in ascending order
+    pub fn shards_for_statements(&self, statements: &[Statement]) -> Vec<usize> {
+        let mut shards: Vec<usize> = statements.iter()
+            .flat_map(|statement| self.shards_for_statement(statement))
+            .collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+    }
+
+    /// Get the shard whose executor runs a request that writes: the lowest shard it needs
+    pub fn owning_shard(&self, statements: &[Statement]) -> usize {
+        match self.shards_for_statements(statements).first() {
+            Some(shard) => *shard,
+            None => 0,
+        }
+    }
+
+    /// Split a scan cursor into the shard it points at and the cursor within that shard
+    fn split_cursor(&self, cursor: u64) -> (usize, u64) {
+        let shards = self.shards as u64;
+        ((cursor % shards) as usize, cursor / shards)
+    }
+
+    /// Combine a shard and a cursor within it into a scan cursor
+    ///
+    /// Only the start of the first shard gives 0, so 0 still means both start and finish.
+    fn join_cursor(&self, shard: usize, cursor: u64) -> u64 {
+        cursor * self.shards as u64 + shard as u64
+    }
+}
+
+
+/// A set of interpreters that each own part of the keys.
+///
+/// Requests on a single shard only lock that shard, so they can run in parallel with requests
+/// on other shards. Requests needing several shards lock all of them in ascending order before
+/// running anything, so they run atomically and can't deadlock with each other.
+///
+/// A transaction is started on every shard the request locked, so each keeps an undo log for
+/// its own keys, and all of them are rolled back if any statement fails. Each shard writes its
+/// part of a committed transaction to its own command log, one shard after another.
+///
+/// Each shard's writes are run by the executor that owns it, see `ShardRouter::owning_shard`.
+/// The interpreters live here behind locks rather than inside those executors because readers
+/// and requests spanning several shards have to reach other shards too; the locks make that
+/// safe without funnelling every shard's writes through one thread.
+pub struct ShardSet<S: Storage + Send + Sync> {
+    /// Routes statements to shards
+    router: ShardRouter,
+    /// The interpreter for each shard
+    shards: Vec<RwLock<Interpreter<S>>>,
+}
+
+impl<S: Storage + Send + Sync> ShardSet<S> {
+    /// Create a set of shards from one interpreter per shard
+    pub fn new(interpreters: Vec<Interpreter<S>>) -> ShardSet<S> {
+        ShardSet {
+            router: ShardRouter::new(interpreters.len()),
+            shards: interpreters.into_iter().map(RwLock::new).collect(),
+        }
+    }
+
+    /// The router used to pick shards
+    pub fn router(&self) -> ShardRouter {
+        self.router
+    }
+
+    /// Interpret a request on whichever shards it needs
+    /// 
+    /// Requests that only read take shared locks, so they can run alongside each other on the
+    /// same shard, while anything that writes takes exclusive locks.
+    pub fn interpret(&self, request: InterpreterRequest) -> Result<InterpreterResponse, ServerError> {
+        let shard_indices = self.router.shards_for_statements(&request.statements);
+        let has_scan = request.statements.iter().any(|statement| matches!(statement, Statement::Scan(..)));
+        let read_only = request.statements.iter().all(Statement::is_read);
+        if shard_indices.len() == 1 && !has_scan {
+            let shard = &self.shards[shard_indices[0]];
+            return match read_only {
+                true => shard.read().unwrap().interpret_read(request),
+                false => shard.write().unwrap().interpret(request),
+            };
+        }
+
+        let InterpreterRequest{statements, authorization, access_rules, response_mode} = request;
+        validate_authorization(&statements, authorization, access_rules.as_deref())?;
+        validate_transactions(&statements)?;
+        if read_only {
+            let mut locked: BTreeMap<usize, RwLockReadGuard<Interpreter<S>>> = BTreeMap::new();
+            for index in shard_indices {
+                locked.insert(index, self.shards[index].read().unwrap());
+            }
+            let locked_shards: Vec<usize> = locked.keys().copied().collect();
+            self.run_statements(statements, &mut |shard, statement| {
+                let request = InterpreterRequest {
+                    statements: vec![statement],
+                    authorization,
+                    access_rules: access_rules.clone(),
+                    response_mode: ResponseMode::Last,
+                };
+                match locked.get(&shard) {
+                    Some(interpreter) => interpreter.interpret_read(request),
+                    None => Err(make_unlocked_error(shard)),
+                }
+            }, &locked_shards, response_mode)
+        } else {
+            let mut locked: BTreeMap<usize, RwLockWriteGuard<Interpreter<S>>> = BTreeMap::new();
+            for index in shard_indices {
+                locked.insert(index, self.shards[index].write().unwrap());
+            }
+            let locked_shards: Vec<usize> = locked.keys().copied().collect();
+            let response = self.run_statements(statements, &mut |shard, statement| {
+                let interpreter = match locked.get_mut(&shard) {
+                    Some(interpreter) => interpreter,
+                    None => return Err(make_unlocked_error(shard)),
+                };
+                match statement {
+                    Statement::Begin => interpreter.begin_transaction(),
+                    Statement::Commit => interpreter.commit_transaction(),
+                    statement => interpreter.interpret(InterpreterRequest {
+                        statements: vec![statement],
+                        authorization,
+                        access_rules: access_rules.clone(),
+                        response_mode: ResponseMode::Last,
+                    }),
+                }
+            }, &locked_shards, response_mode);
+            if response.is_err() {
+                for interpreter in locked.values_mut() {
+                    interpreter.rollback_transaction()?;
+                }
+            }
+            response
+        }
+    }
+
+    /// Run the statements of a request in order, collecting the responses for the mode
+    fn run_statements(
+        &self,
+        statements: Vec<Statement>,
+        run_on_shard: &mut ShardRunner,
+        locked_shards: &[usize],
+        response_mode: ResponseMode,
+    ) -> Result<InterpreterResponse, ServerError> {
+        let mut responses = ResponseCollector::new(response_mode);
+        for statement in statements {
+            let response = self.run_statement(statement.clone(), run_on_shard, locked_shards);
+            if !responses.add(&statement, response) {
+                break;
+            }
+        }
+        responses.finish()
+    }
+
+    /// Run one statement of a request across shards that are already locked
+    fn run_statement(
+        &self, statement: Statement, run_on_shard: &mut ShardRunner, locked_shards: &[usize]
+    ) -> Result<InterpreterResponse, ServerError> {
+        if let Statement::Scan(cursor, pattern, count) = statement {
+            let (shard, shard_cursor) = self.router.split_cursor(cursor);
+            let response = run_on_shard(shard, Statement::Scan(shard_cursor, pattern, count))?;
+            return match response {
+                InterpreterResponse::Scan(0, keys) if shard + 1 < self.router.shard_count() => {
+                    Ok(InterpreterResponse::Scan(self.router.join_cursor(shard + 1, 0), keys))
+                },
+                InterpreterResponse::Scan(0, keys) => Ok(InterpreterResponse::Scan(0, keys)),
+                InterpreterResponse::Scan(next_cursor, keys) => {
+                    Ok(InterpreterResponse::Scan(self.router.join_cursor(shard, next_cursor), keys))
+                },
+                other => Ok(other),
+            };
+        }
+
+        let shard_indices = match statement {
+            Statement::Begin | Statement::Commit => locked_shards.to_vec(),
+            _ => self.router.shards_for_statement(&statement),
+        };
+        let mut responses = vec![];
+        for shard in shard_indices {
+            responses.push(run_on_shard(shard, statement.clone())?);
+        }
+        Ok(combine_responses(&statement, responses))
+    }
+}
+
+
+/// Runs a statement on one of the locked shards
+type ShardRunner<'a> = dyn FnMut(usize, Statement) -> Result<InterpreterResponse, ServerError> + 'a;
+
+
+/// Create an error for a statement sent to a shard that the request didn't lock
+fn make_unlocked_error(shard: usize) -> ServerError {
+    ServerError::InternalError(format!("Shard {} was not locked.", shard))
+}
+
+
+/// Merge the responses each shard gave for the same statement
+fn combine_responses(statement: &Statement, responses: Vec<InterpreterResponse>) -> InterpreterResponse {
+    let mut keys = vec![];
+    let mut size = 0;
+    let mut last_response = InterpreterResponse::Null;
+    for response in responses {
+        match response {
+            InterpreterResponse::Keys(shard_keys) => keys.extend(shard_keys),
+            InterpreterResponse::Size(shard_size) => size += shard_size,
+            other => last_response = other,
+        }
+    }
+    match statement {
+        Statement::Range(_, _, limit) | Statement::Prefix(_, limit) => {
+            keys.sort();
+            if let Some(limit) = limit {
+                keys.truncate(*limit);
+            }
+            InterpreterResponse::Keys(keys)
+        },
+        Statement::Keys(_) => InterpreterResponse::Keys(keys),
+        Statement::ExpireKeys => InterpreterResponse::Size(size),
+        _ => last_response,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, mpsc};
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::auth::AuthorizationLevel;
+    use crate::io::stream::response_to_json;
+    use crate::multithreaded::executor::{Executor, ExecutorRequest};
+    use crate::storage::StorageValue;
+    use crate::storage::btree_storage::BTreeMapStorage;
+    use crate::storage::hashmap_storage::HashMapStorage;
+
+    fn run<S: Storage + Send + Sync>(
+        shards: &ShardSet<S>, statements: Vec<Statement>
+    ) -> Result<InterpreterResponse, ServerError> {
+        shards.interpret(InterpreterRequest {
+            statements, authorization: AuthorizationLevel::Admin, access_rules: None, response_mode: ResponseMode::Last
+        })
+    }
+
+    fn set(key: &str, value: i64) -> Statement {
+        Statement::Set(key.to_string(), StorageValue::Int(value), None)
+    }
+
+    fn make_shards(count: usize) -> ShardSet<HashMapStorage> {
+        ShardSet::new((0..count).map(|_| Interpreter::new(HashMapStorage::new())).collect())
+    }
+
+    #[test]
+    fn test_keys_are_spread_across_shards() {
+        let router = ShardRouter::new(4);
+        let mut used = [false; 4];
+        for index in 0..100 {
+            let shard = router.shard_for_key(&format!("key{}", index));
+            assert_eq!(shard, router.shard_for_key(&format!("key{}", index)));
+            used[shard] = true;
+        }
+        assert!(used.iter().all(|used| *used));
+    }
+
+    #[test]
+    fn test_request_across_shards() {
+        let shards = make_shards(4);
+        let statements = (0..20).map(|index| set(&format!("key{}", index), index)).collect();
+        run(&shards, statements).unwrap();
+        for shard in &shards.shards {
+            assert!(shard.read().unwrap().storage.len().unwrap() < 20);
+        }
+        let response = run(&shards, vec![set("a", 1), Statement::Get("key7".to_string())]).unwrap();
+        assert!(matches!(response, InterpreterResponse::Value(StorageValue::Int(7))));
+        let response = run(&shards, vec![Statement::Keys("key1*".to_string())]).unwrap();
+        let mut keys = match response {
+            InterpreterResponse::Keys(keys) => keys,
+            other => panic!("Unexpected response {:?}", other),
+        };
+        keys.sort();
+        assert_eq!(keys, vec!["key1", "key10", "key11", "key12", "key13", "key14", "key15", "key16",
+            "key17", "key18", "key19"]);
+    }
+
+    #[test]
+    fn test_error_stops_request() {
+        let shards = make_shards(3);
+        let statements = vec![set("a", 1), Statement::Get("missing".to_string()), set("b", 2)];
+        assert!(matches!(run(&shards, statements), Err(ServerError::KeyError(_))));
+        assert!(matches!(run(&shards, vec![Statement::Exists("b".to_string())]),
+            Ok(InterpreterResponse::Bool(false))));
+    }
+
+    #[test]
+    fn test_scan_across_shards() {
+        let shards = make_shards(3);
+        let statements = (0..10).map(|index| set(&format!("key{}", index), index)).collect();
+        run(&shards, statements).unwrap();
+        let mut cursor = 0;
+        let mut seen = vec![];
+        loop {
+            match run(&shards, vec![Statement::Scan(cursor, None, Some(2))]).unwrap() {
+                InterpreterResponse::Scan(next_cursor, keys) => {
+                    seen.extend(keys);
+                    cursor = next_cursor;
+                },
+                other => panic!("Unexpected response {:?}", other),
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        seen.sort();
+        let mut expected: Vec<String> = (0..10).map(|index| format!("key{}", index)).collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+        assert!(matches!(
+            run(&shards, vec![Statement::Scan(cursor, None, Some(0))]), Err(ServerError::RequestError(_))
+        ));
+    }
+
+    #[test]
+    fn test_range_across_shards() {
+        let shards = ShardSet::new((0..3).map(|_| Interpreter::new(BTreeMapStorage::new())).collect());
+        let statements = ["e", "a", "d", "b", "c"].iter().map(|key| set(key, 1)).collect();
+        run(&shards, statements).unwrap();
+        let response = run(&shards, vec![Statement::Range("b".to_string(), "z".to_string(), Some(3))]);
+        assert!(matches!(response, Ok(InterpreterResponse::Keys(keys)) if keys == vec!["b", "c", "d"]));
+    }
+
+    #[test]
+    fn test_reads_share_a_shard() {
+        let shards = Arc::new(make_shards(1));
+        run(&shards, vec![set("x", 1)]).unwrap();
+        let read_guard = shards.shards[0].read().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let other_shards = Arc::clone(&shards);
+        thread::spawn(move || {
+            sender.send(run(&other_shards, vec![Statement::Get("x".to_string())])).unwrap();
+        });
+        let response = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(response, Ok(InterpreterResponse::Value(StorageValue::Int(1)))));
+        drop(read_guard);
+    }
+
+    #[test]
+    fn test_executors_run_their_own_shards() {
+        let shards = Arc::new(make_shards(2));
+        let router = shards.router();
+        let key_on = |shard| {
+            (0..).map(|index| format!("key{}", index)).find(|key| router.shard_for_key(key) == shard).unwrap()
+        };
+        let start_shutdown = Arc::new(AtomicBool::new(false));
+        let mut senders = vec![];
+        let mut executors = vec![];
+        for shard in 0..2 {
+            let (sender, receiver) = mpsc::channel();
+            let mut executor = Executor::for_shard(receiver, Arc::clone(&start_shutdown), Arc::clone(&shards), shard);
+            executor.start();
+            senders.push(sender);
+            executors.push(executor);
+        }
+        let send = |shard: usize, key: &str| {
+            let (sender, receiver) = mpsc::channel();
+            let request = InterpreterRequest {
+                statements: vec![set(key, 1)], authorization: AuthorizationLevel::Write, access_rules: None, response_mode: ResponseMode::Last
+            };
+            senders[shard].send(ExecutorRequest { request, sender: Some(sender) }).unwrap();
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap().response
+        };
+        assert_eq!(router.owning_shard(&[set(&key_on(1), 1), set(&key_on(0), 1)]), 0);
+
+        // Writes to shard 1 don't wait for shard 0, and its executor won't touch shard 0
+        let write_guard = shards.shards[0].write().unwrap();
+        assert!(matches!(send(1, &key_on(1)), Ok(InterpreterResponse::Version(1))));
+        assert!(matches!(send(1, &key_on(0)), Err(ServerError::InternalError(_))));
+        drop(write_guard);
+        assert!(matches!(send(0, &key_on(0)), Ok(InterpreterResponse::Version(1))));
+        for executor in executors.iter_mut() {
+            executor.stop();
+        }
+    }
+
+    #[test]
+    fn test_interpret_read_rejects_writes() {
+        let interpreter = Interpreter::new(HashMapStorage::new());
+        let request = InterpreterRequest {
+            statements: vec![set("x", 1)], authorization: AuthorizationLevel::Admin, access_rules: None, response_mode: ResponseMode::Last
+        };
+        assert!(matches!(interpreter.interpret_read(request), Err(ServerError::InternalError(_))));
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_every_shard() {
+        let shards = make_shards(4);
+        let keys: Vec<String> = (0..8).map(|index| format!("key{}", index)).collect();
+        run(&shards, vec![set(&keys[0], 0)]).unwrap();
+        let mut statements = vec![Statement::Begin];
+        statements.extend(keys.iter().map(|key| set(key, 1)));
+        statements.push(Statement::VectorPop(keys[0].clone()));
+        statements.push(Statement::Commit);
+        assert!(matches!(run(&shards, statements), Err(ServerError::TypeError(_))));
+        let response = run(&shards, vec![Statement::Keys("*".to_string())]).unwrap();
+        assert!(matches!(response, InterpreterResponse::Keys(found) if found == vec![keys[0].clone()]));
+        let response = run(&shards, vec![Statement::Get(keys[0].clone())]).unwrap();
+        assert!(matches!(response, InterpreterResponse::Value(StorageValue::Int(0))));
+
+        let mut statements = vec![Statement::Begin];
+        statements.extend(keys.iter().map(|key| set(key, 2)));
+        statements.push(Statement::Commit);
+        run(&shards, statements).unwrap();
+        for key in keys {
+            let response = run(&shards, vec![Statement::Get(key)]).unwrap();
+            assert!(matches!(response, InterpreterResponse::Value(StorageValue::Int(2))));
+        }
+    }
+
+    #[test]
+    fn test_all_responses_across_shards() {
+        let shards = make_shards(3);
+        let statements = vec![set("a", 1), Statement::Get("missing".to_string()), Statement::Get("a".to_string())];
+        let request = InterpreterRequest {
+            statements, authorization: AuthorizationLevel::Admin, access_rules: None, response_mode: ResponseMode::All
+        };
+        let response = shards.interpret(request).unwrap();
+        let json = response_to_json(&response);
+        assert!(json.starts_with("[{\"Ok\":{\"Version\":1}},{\"Err\":{\"KeyError\":"));
+        assert!(json.ends_with("{\"Ok\":{\"Value\":{\"Int\":1}}}]"));
+    }
+}
@@ -0,0 +1,9 @@
+/// Append-only log of every mutating statement
+pub mod command_log;
+/// Point-in-time copies of the whole storage
+pub mod snapshot;
+/// Rebuilding the storage when the server starts
+pub mod restore;
+
+pub use self::command_log::{CommandLog, FsyncPolicy};
+pub use self::restore::{PersistenceConfig, restore_interpreter};
@@ -0,0 +1,372 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::Statement;
+use crate::error::ServerError;
+
+
+/// How often the command log is forced out to the disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// Sync after every write - safest but slowest
+    Always,
+    /// Sync at most once per second
+    EverySecond,
+    /// Leave it to the operating system
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Get a policy from its name, like `everysec`
+    pub fn from_name(name: &str) -> Result<FsyncPolicy, ServerError> {
+        match name.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySecond),
+            "never" | "no" => Ok(FsyncPolicy::Never),
+            _ => Err(ServerError::ConfigError(format!("Unknown fsync policy {}.", name))),
+        }
+    }
+}
+
+
+/// A single line in the command log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct LogEntry {
+    /// Seconds since the epoch when the statement was run
+    timestamp: u64,
+    /// The statement that was run, or None for an entry that only moves the version clock on
+    statement: Option<Statement>,
+    /// The version clock once the statement had run, which is the version it gave the key it
+    /// changed. Logs written before versions were saved have 0.
+    #[serde(default)]
+    version: u64,
+}
+
+
+/// A statement read back from the command log
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedStatement {
+    /// The statement to run again
+    pub statement: Statement,
+    /// The version clock once the statement had first run, or 0 if it wasn't saved
+    pub version: u64,
+}
+
+
+/// Everything in a command log after some offset
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Replay {
+    /// The statements to run again, in order
+    pub statements: Vec<LoggedStatement>,
+    /// The highest version the log says was handed out, including by statements that are no
+    /// longer run and transactions that were rolled back
+    pub version_clock: u64,
+}
+
+
+/// An append-only log of every statement that changed the storage.
+///
+/// Each entry is written as a single line of JSON. Lifetimes are relative to when the statement
+/// was run, so the timestamp is saved with each entry and used on replay to work out how much
+/// of the lifetime is left.
+pub struct CommandLog {
+    /// The open log file
+    file: File,
+    /// How often to sync
+    policy: FsyncPolicy,
+    /// The last time the file was synced
+    last_sync: Instant,
+    /// Set if something has been written since the last sync
+    dirty: bool,
+    /// The length of the log in bytes
+    offset: u64,
+}
+
+
+impl CommandLog {
+    /// Open a command log for appending, creating it if needed.
+    ///
+    /// A partial final line left by a crash is cut off first, so new entries start on a line of
+    /// their own instead of being joined onto it.
+    pub fn open(path: &Path, policy: FsyncPolicy) -> Result<CommandLog, ServerError> {
+        let mut file = match OpenOptions::new().create(true).read(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(
+                ServerError::WriteError(
+                    format!("Could not open command log {}: {}", path.display(), err)
+                )
+            ),
+        };
+        let offset = match complete_length(&mut file) {
+            Ok(offset) => offset,
+            Err(_) => return Err(ServerError::WriteError("Could not read command log size.".to_string())),
+        };
+        let size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Err(ServerError::WriteError("Could not read command log size.".to_string())),
+        };
+        if offset < size {
+            println!("Removing incomplete entry at the end of the command log.");
+            if file.set_len(offset).is_err() {
+                return Err(ServerError::WriteError("Could not remove incomplete command log entry.".to_string()));
+            }
+        }
+        Ok(CommandLog {
+            file,
+            policy,
+            last_sync: Instant::now(),
+            dirty: false,
+            offset,
+        })
+    }
+
+    /// The number of bytes written to the log so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Add a statement to the end of the log, with the version clock once it had run.
+    pub fn append(&mut self, statement: &Statement, version: u64) -> Result<(), ServerError> {
+        self.write_entry(Some(statement.clone()), version)
+    }
+
+    /// Record that versions up to this one have been handed out, without a statement to replay.
+    pub fn append_version(&mut self, version: u64) -> Result<(), ServerError> {
+        self.write_entry(None, version)
+    }
+
+    /// Write an entry to the end of the log.
+    fn write_entry(&mut self, statement: Option<Statement>, version: u64) -> Result<(), ServerError> {
+        let entry = LogEntry { timestamp: seconds_since_epoch(SystemTime::now()), statement, version };
+        let mut line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(err) => return Err(ServerError::InternalError(format!("Could not serialize statement: {}", err))),
+        };
+        line.push('\n');
+        if self.file.write_all(line.as_bytes()).is_err() {
+            return Err(ServerError::WriteError("Error writing to the command log.".to_string()));
+        }
+        self.dirty = true;
+        self.offset += line.len() as u64;
+        if self.policy == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Sync the log if the policy says it's time to.
+    pub fn sync_if_due(&mut self) -> Result<(), ServerError> {
+        let due = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EverySecond => self.last_sync.elapsed() >= Duration::from_secs(1),
+            FsyncPolicy::Never => false,
+        };
+        if due && self.dirty {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Force everything written so far out to the disk.
+    pub fn sync(&mut self) -> Result<(), ServerError> {
+        if self.file.sync_data().is_err() {
+            return Err(ServerError::WriteError("Error syncing the command log.".to_string()));
+        }
+        self.last_sync = Instant::now();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+
+/// Find the length of a log up to the end of its last complete line
+fn complete_length(file: &mut File) -> io::Result<u64> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut chunk = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let chunk = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|byte| *byte == b'\n') {
+            return Ok(start + newline as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+
+/// Convert a time into whole seconds since the epoch
+fn seconds_since_epoch(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+
+/// Rewrite a logged statement so that its lifetime is relative to the current time.
+///
+/// Statements whose lifetime has already run out are turned into deletes, or dropped entirely
+/// if they could not have left anything behind.
+fn rebase_lifetime(statement: Statement, timestamp: u64, now: u64) -> Option<Statement> {
+    let remaining = |lifetime: u64| (timestamp + lifetime).checked_sub(now).filter(|left| *left > 0);
+    match statement {
+        Statement::Set(key, value, Some(lifetime)) => match remaining(lifetime) {
+            Some(left) => Some(Statement::Set(key, value, Some(left))),
+            None => Some(Statement::Delete(key)),
+        },
+        Statement::Update(key, value, Some(lifetime)) => match remaining(lifetime) {
+            Some(left) => Some(Statement::Update(key, value, Some(left))),
+            None => Some(Statement::Delete(key)),
+        },
+        Statement::SetIfNotExists(key, value, Some(lifetime)) => {
+            remaining(lifetime).map(|left| Statement::SetIfNotExists(key, value, Some(left)))
+        },
+        Statement::UpdateLifetime(key, Some(lifetime)) => match remaining(lifetime) {
+            Some(left) => Some(Statement::UpdateLifetime(key, Some(left))),
+            None => Some(Statement::Delete(key)),
+        },
+        other => Some(other),
+    }
+}
+
+
+/// Read back every statement in a command log after the given offset, ready to be run again.
+///
+/// A missing log is treated as empty. A partial final line (e.g. from a crash in the middle of
+/// a write) is skipped, but a bad line anywhere else is an error.
+pub fn read_statements(path: &Path, offset: u64) -> Result<Replay, ServerError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) if !path.exists() => return Ok(Replay::default()),
+        Err(err) => return Err(
+            ServerError::InternalError(format!("Could not open command log {}: {}", path.display(), err))
+        ),
+    };
+    if file.seek(SeekFrom::Start(offset)).is_err() {
+        return Err(ServerError::InternalError("Could not seek in the command log.".to_string()));
+    }
+    let lines: Vec<String> = match BufReader::new(file).lines().collect() {
+        Ok(lines) => lines,
+        Err(err) => return Err(ServerError::InternalError(format!("Could not read command log: {}", err))),
+    };
+    let now = seconds_since_epoch(SystemTime::now());
+    let mut replay = Replay::default();
+    for (index, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: LogEntry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(_) if index == lines.len() - 1 => {
+                println!("Skipping incomplete entry at the end of the command log.");
+                break;
+            },
+            Err(err) => return Err(
+                ServerError::InternalError(format!("Corrupt command log entry on line {}: {}", index + 1, err))
+            ),
+        };
+        replay.version_clock = replay.version_clock.max(entry.version);
+        let statement = entry.statement.and_then(|statement| rebase_lifetime(statement, entry.timestamp, now));
+        if let Some(statement) = statement {
+            replay.statements.push(LoggedStatement { statement, version: entry.version });
+        }
+    }
+    Ok(replay)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::storage::StorageValue;
+
+    fn temp_log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rust_store_{}_{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_rebase_lifetime() {
+        let statement = Statement::Set("x".to_string(), StorageValue::Int(1), Some(100));
+        assert_eq!(
+            rebase_lifetime(statement.clone(), 1000, 1040),
+            Some(Statement::Set("x".to_string(), StorageValue::Int(1), Some(60))),
+        );
+        assert_eq!(rebase_lifetime(statement, 1000, 1100), Some(Statement::Delete("x".to_string())));
+        let statement = Statement::SetIfNotExists("x".to_string(), StorageValue::Int(1), Some(10));
+        assert_eq!(rebase_lifetime(statement, 1000, 2000), None);
+        let statement = Statement::Set("x".to_string(), StorageValue::Int(1), None);
+        assert_eq!(rebase_lifetime(statement.clone(), 0, 2000), Some(statement));
+    }
+
+    fn statements(replay: Replay) -> Vec<Statement> {
+        replay.statements.into_iter().map(|logged| logged.statement).collect()
+    }
+
+    #[test]
+    fn test_append_and_read() {
+        let path = temp_log_path("append_and_read");
+        let mut log = CommandLog::open(&path, FsyncPolicy::Always).unwrap();
+        log.append(&Statement::Set("x".to_string(), StorageValue::Int(1), None), 1).unwrap();
+        log.append(&Statement::Delete("x".to_string()), 1).unwrap();
+        let first_offset = log.offset();
+        log.append_version(4).unwrap();
+        log.append(&Statement::Delete("y".to_string()), 4).unwrap();
+        let replay = read_statements(&path, 0).unwrap();
+        assert_eq!(replay.version_clock, 4);
+        assert_eq!(replay.statements.len(), 3);
+        assert_eq!(replay.statements[1], LoggedStatement { statement: Statement::Delete("x".to_string()), version: 1 });
+        let statements = statements(read_statements(&path, first_offset).unwrap());
+        assert_eq!(statements, vec![Statement::Delete("y".to_string())]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_skips_partial_last_line() {
+        let path = temp_log_path("partial_last_line");
+        let mut log = CommandLog::open(&path, FsyncPolicy::Never).unwrap();
+        log.append(&Statement::Delete("x".to_string()), 0).unwrap();
+        log.file.write_all(b"{\"timestamp\":1,\"statem").unwrap();
+        assert_eq!(read_statements(&path, 0).unwrap().statements.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append_after_partial_last_line() {
+        let path = temp_log_path("append_after_partial");
+        let mut log = CommandLog::open(&path, FsyncPolicy::Never).unwrap();
+        log.append(&Statement::Delete("x".to_string()), 0).unwrap();
+        log.file.write_all(b"{\"timestamp\":1,\"statem").unwrap();
+        drop(log);
+
+        // Restarting cuts off the partial entry, so the next one gets a line of its own
+        assert_eq!(read_statements(&path, 0).unwrap().statements.len(), 1);
+        let mut log = CommandLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(log.offset(), fs::metadata(&path).unwrap().len());
+        log.append(&Statement::Delete("y".to_string()), 0).unwrap();
+        drop(log);
+
+        let statements = statements(read_statements(&path, 0).unwrap());
+        assert_eq!(statements, vec![Statement::Delete("x".to_string()), Statement::Delete("y".to_string())]);
+        let log = CommandLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(log.offset(), fs::metadata(&path).unwrap().len());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_missing_log() {
+        let path = temp_log_path("missing");
+        assert_eq!(read_statements(&path, 0).unwrap(), Replay::default());
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::{Path, PathBuf};
+
+use crate::analysis::Interpreter;
+use crate::error::ServerError;
+use crate::persistence::command_log::{CommandLog, FsyncPolicy};
+use crate::persistence::snapshot;
+use crate::storage::Storage;
+
+
+/// Where and how the database is saved to disk.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersistenceConfig {
+    /// Where snapshots are saved and loaded from, if anywhere
+    pub snapshot_path: Option<PathBuf>,
+    /// Where the command log is kept, if anywhere
+    pub command_log_path: Option<PathBuf>,
+    /// How often the command log is synced to disk
+    pub fsync_policy: FsyncPolicy,
+}
+
+
+impl Default for PersistenceConfig {
+    /// Save snapshots and the command log to the working directory
+    fn default() -> PersistenceConfig {
+        PersistenceConfig {
+            snapshot_path: Some(PathBuf::from("rust_store.snapshot")),
+            command_log_path: Some(PathBuf::from("rust_store.log")),
+            fsync_policy: FsyncPolicy::EverySecond,
+        }
+    }
+}
+
+
+impl PersistenceConfig {
+    /// Keep everything in memory only
+    pub fn disabled() -> PersistenceConfig {
+        PersistenceConfig { snapshot_path: None, command_log_path: None, fsync_policy: FsyncPolicy::Never }
+    }
+
+    /// The settings for one shard of a sharded server, which keeps its own files.
+    ///
+    /// The shard number goes before the extension, so `rust_store.log` becomes `rust_store.2.log`.
+    pub fn for_shard(&self, shard: usize) -> PersistenceConfig {
+        PersistenceConfig {
+            snapshot_path: self.snapshot_path.as_deref().map(|path| shard_path(path, shard)),
+            command_log_path: self.command_log_path.as_deref().map(|path| shard_path(path, shard)),
+            fsync_policy: self.fsync_policy,
+        }
+    }
+}
+
+
+/// Add a shard number to a file name, just before the extension.
+fn shard_path(path: &Path, shard: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", shard));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+
+/// Build an interpreter from whatever has been saved to disk.
+///
+/// The snapshot is loaded first, then any part of the command log written after the snapshot
+/// was taken is replayed on top of it. The interpreter keeps writing to the same files.
+pub fn restore_interpreter<S: Storage + Send>(
+    storage: S, config: &PersistenceConfig
+) -> Result<Interpreter<S>, ServerError> {
+    let mut interpreter = Interpreter::new(storage);
+    let mut command_log_offset = 0;
+    if let Some(path) = &config.snapshot_path {
+        let position = snapshot::load_snapshot(&mut interpreter.storage, path)?;
+        command_log_offset = position.command_log_offset;
+        interpreter.advance_version_clock(position.version_clock);
+        interpreter.set_snapshot_path(path.clone());
+    }
+    if let Some(path) = &config.command_log_path {
+        let count = interpreter.replay_command_log(path, command_log_offset)?;
+        println!("Replayed {} statements from {}.", count, path.display());
+        interpreter.set_command_log(CommandLog::open(path, config.fsync_policy)?);
+    }
+    Ok(interpreter)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::analysis::{InterpreterRequest, InterpreterResponse, ResponseMode, Statement};
+    use crate::auth::AuthorizationLevel;
+    use crate::storage::hashmap_storage::HashMapStorage;
+    use crate::storage::{EvictionPolicy, StorageElement, StorageValue};
+
+    fn temp_config(name: &str) -> PersistenceConfig {
+        let base = std::env::temp_dir().join(format!("rust_store_{}_{}", name, std::process::id()));
+        let config = PersistenceConfig {
+            snapshot_path: Some(base.with_extension("snapshot")),
+            command_log_path: Some(base.with_extension("log")),
+            fsync_policy: FsyncPolicy::EverySecond,
+        };
+        remove_files(&config);
+        config
+    }
+
+    fn remove_files(config: &PersistenceConfig) {
+        for path in [&config.snapshot_path, &config.command_log_path].into_iter().flatten() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn run<S: Storage + Send>(
+        interpreter: &mut Interpreter<S>, statements: Vec<Statement>, authorization: AuthorizationLevel
+    ) -> Result<InterpreterResponse, ServerError> {
+        interpreter.interpret(InterpreterRequest { statements, authorization, access_rules: None, response_mode: ResponseMode::Last })
+    }
+
+    #[test]
+    fn test_restore_from_command_log() {
+        let config = PersistenceConfig { snapshot_path: None, ..temp_config("restore_log") };
+        let mut interpreter = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::Get("x".to_string()),
+            Statement::Set("y".to_string(), StorageValue::Int(2), Some(500)),
+            Statement::Delete("x".to_string()),
+            Statement::Set("z".to_string(), StorageValue::Int(3), None),
+        ];
+        run(&mut interpreter, statements, AuthorizationLevel::Write).unwrap();
+        drop(interpreter);
+
+        let restored = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        assert!(!restored.storage.contains_key("x").unwrap());
+        assert!(restored.storage.get("y").unwrap().expiration.is_some());
+        assert!(matches!(restored.storage.get("z").unwrap().value, StorageValue::Int(3)));
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_restore_from_snapshot_and_command_log() {
+        let config = temp_config("restore_snapshot");
+        let mut interpreter = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::Save,
+            Statement::Set("y".to_string(), StorageValue::Int(2), None),
+        ];
+        run(&mut interpreter, statements, AuthorizationLevel::Admin).unwrap();
+        drop(interpreter);
+
+        let restored = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        assert_eq!(restored.storage.len().unwrap(), 2);
+        assert!(matches!(restored.storage.get("x").unwrap().value, StorageValue::Int(1)));
+        assert!(matches!(restored.storage.get("y").unwrap().value, StorageValue::Int(2)));
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_background_save() {
+        let config = PersistenceConfig { command_log_path: None, ..temp_config("background_save") };
+        let mut interpreter = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        let statements = vec![
+            Statement::Set("x".to_string(), StorageValue::Int(1), None),
+            Statement::BackgroundSave,
+        ];
+        run(&mut interpreter, statements, AuthorizationLevel::Admin).unwrap();
+        let snapshot_path = config.snapshot_path.as_ref().unwrap();
+        for _ in 0..100 {
+            if snapshot_path.exists() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Wait for the save to finish before it can be started again
+        while run(&mut interpreter, vec![Statement::BackgroundSave], AuthorizationLevel::Admin).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(interpreter);
+
+        let restored = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        assert!(matches!(restored.storage.get("x").unwrap().value, StorageValue::Int(1)));
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_save_requires_admin() {
+        let config = temp_config("save_requires_admin");
+        let mut interpreter = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        let result = run(&mut interpreter, vec![Statement::Save], AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::AuthorizationError(_))));
+        let result = run(&mut interpreter, vec![Statement::BackgroundSave], AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::AuthorizationError(_))));
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_evicted_keys_stay_evicted() {
+        let config = PersistenceConfig { snapshot_path: None, ..temp_config("evicted_keys") };
+        let set = |key: &str| Statement::Set(key.to_string(), StorageValue::Int(1), None);
+        let mut sizing = HashMapStorage::new();
+        sizing.set("a", StorageElement { key: "a".to_string(), value: StorageValue::Int(1), expiration: None, version: 0 }).unwrap();
+        let max_memory = sizing.used_memory() * 3 / 2;
+        let storage = HashMapStorage::with_memory_limit(max_memory, EvictionPolicy::AllKeysLru);
+        let mut interpreter = restore_interpreter(storage, &config).unwrap();
+        for key in ["a", "b", "c"] {
+            run(&mut interpreter, vec![set(key)], AuthorizationLevel::Write).unwrap();
+        }
+        assert!(!interpreter.storage.contains_key("a").unwrap());
+        drop(interpreter);
+
+        let storage = HashMapStorage::with_memory_limit(max_memory, EvictionPolicy::AllKeysLru);
+        let restored = restore_interpreter(storage, &config).unwrap();
+        assert!(!restored.storage.contains_key("a").unwrap());
+        assert!(restored.storage.contains_key("b").unwrap());
+        assert!(restored.storage.contains_key("c").unwrap());
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_evictions_outlast_rollback() {
+        let config = PersistenceConfig { snapshot_path: None, ..temp_config("evictions_outlast_rollback") };
+        let set = |key: &str, value: i64| Statement::Set(key.to_string(), StorageValue::Int(value), None);
+        let mut sizing = HashMapStorage::new();
+        sizing.set("a", StorageElement { key: "a".to_string(), value: StorageValue::Int(1), expiration: None, version: 0 }).unwrap();
+        let max_memory = sizing.used_memory() * 3 / 2;
+        let storage = HashMapStorage::with_memory_limit(max_memory, EvictionPolicy::AllKeysLru);
+        let mut interpreter = restore_interpreter(storage, &config).unwrap();
+        run(&mut interpreter, vec![set("a", 1)], AuthorizationLevel::Write).unwrap();
+        let statements = vec![
+            Statement::Begin, set("a", 2), set("b", 2), set("c", 2), Statement::Get("missing".to_string()), Statement::Commit,
+        ];
+        assert!(run(&mut interpreter, statements, AuthorizationLevel::Write).is_err());
+        assert_eq!(interpreter.storage.len().unwrap(), 0);
+        drop(interpreter);
+
+        let storage = HashMapStorage::with_memory_limit(max_memory, EvictionPolicy::AllKeysLru);
+        let restored = restore_interpreter(storage, &config).unwrap();
+        assert_eq!(restored.storage.len().unwrap(), 0);
+        remove_files(&config);
+    }
+
+    #[test]
+    fn test_shard_paths() {
+        let config = PersistenceConfig::default().for_shard(2);
+        assert_eq!(config.snapshot_path, Some(PathBuf::from("rust_store.2.snapshot")));
+        assert_eq!(config.command_log_path, Some(PathBuf::from("rust_store.2.log")));
+        assert_eq!(PersistenceConfig::disabled().for_shard(1).command_log_path, None);
+    }
+
+    #[test]
+    fn test_versions_survive_restart() {
+        let config = temp_config("versions_survive_restart");
+        let mut interpreter = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        let set = |key: &str| Statement::Set(key.to_string(), StorageValue::Int(1), None);
+        run(&mut interpreter, vec![set("x"), set("y")], AuthorizationLevel::Write).unwrap();
+        let statements = vec![
+            Statement::Begin, set("x"), set("z"), Statement::Get("missing".to_string()), Statement::Commit,
+        ];
+        let result = run(&mut interpreter, statements, AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::KeyError(_))));
+        let statement = Statement::CompareAndSet("y".to_string(), 2, StorageValue::Int(2), None);
+        let result = run(&mut interpreter, vec![statement], AuthorizationLevel::Write);
+        assert!(matches!(result, Ok(InterpreterResponse::Version(5))));
+        drop(interpreter);
+
+        // The versions used by the rolled back transaction aren't handed out again
+        let mut restored = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        assert_eq!(restored.storage.get("x").unwrap().version, 1);
+        assert_eq!(restored.storage.get("y").unwrap().version, 5);
+        assert!(!restored.storage.contains_key("z").unwrap());
+        run(&mut restored, vec![set("y"), set("y"), set("y")], AuthorizationLevel::Write).unwrap();
+        assert_eq!(restored.storage.get("y").unwrap().version, 8);
+        let statement = Statement::CompareAndSet("y".to_string(), 5, StorageValue::Int(3), None);
+        let result = run(&mut restored, vec![statement], AuthorizationLevel::Write);
+        assert!(matches!(result, Err(ServerError::ConflictError(_))));
+
+        // The same holds once a snapshot has been taken
+        run(&mut restored, vec![Statement::Save], AuthorizationLevel::Admin).unwrap();
+        let statements = vec![Statement::Begin, set("x"), Statement::Get("missing".to_string()), Statement::Commit];
+        assert!(run(&mut restored, statements, AuthorizationLevel::Write).is_err());
+        drop(restored);
+        let mut restored = restore_interpreter(HashMapStorage::new(), &config).unwrap();
+        assert_eq!(restored.storage.get("y").unwrap().version, 8);
+        run(&mut restored, vec![set("w")], AuthorizationLevel::Write).unwrap();
+        assert_eq!(restored.storage.get("w").unwrap().version, 10);
+        remove_files(&config);
+    }
+}
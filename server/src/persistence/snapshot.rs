@@ -0,0 +1,185 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ServerError;
+use crate::storage::{Storage, StorageElement};
+
+
+/// The snapshot format written by this version of the server.
+const SNAPSHOT_VERSION: u32 = 1;
+
+
+/// A point-in-time copy of everything in the storage.
+///
+/// Expirations are saved as absolute times, so keys that expired while the server was down
+/// are dropped when the snapshot is loaded.
+#[derive(Debug, Deserialize, Serialize)]
+struct Snapshot {
+    /// The format version
+    version: u32,
+    /// When the snapshot was taken
+    created: SystemTime,
+    /// How far into the command log the snapshot reaches
+    command_log_offset: u64,
+    /// The version clock when the snapshot was taken. Older snapshots have 0.
+    #[serde(default)]
+    version_clock: u64,
+    /// Every element in the storage
+    elements: Vec<StorageElement>,
+}
+
+
+/// Where a loaded snapshot leaves the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SnapshotPosition {
+    /// How far into the command log the snapshot reaches
+    pub command_log_offset: u64,
+    /// The highest version the snapshot knows was handed out
+    pub version_clock: u64,
+}
+
+
+/// Write a snapshot of the given elements to disk.
+///
+/// The snapshot is written to a temporary file first and then moved into place, so a crash
+/// part way through never leaves a broken snapshot behind.
+pub fn write_snapshot(
+    path: &Path, elements: Vec<StorageElement>, command_log_offset: u64, version_clock: u64
+) -> Result<(), ServerError> {
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        created: SystemTime::now(),
+        command_log_offset,
+        version_clock,
+        elements,
+    };
+    let mut temp_path = PathBuf::from(path);
+    temp_path.set_extension("tmp");
+    let file = match File::create(&temp_path) {
+        Ok(file) => file,
+        Err(err) => return Err(
+            ServerError::WriteError(format!("Could not create snapshot {}: {}", temp_path.display(), err))
+        ),
+    };
+    let mut writer = BufWriter::new(file);
+    if let Err(err) = serde_json::to_writer(&mut writer, &snapshot) {
+        return Err(ServerError::WriteError(format!("Could not write snapshot: {}", err)));
+    }
+    let file = match writer.into_inner() {
+        Ok(file) => file,
+        Err(_) => return Err(ServerError::WriteError("Could not write snapshot.".to_string())),
+    };
+    if file.sync_all().is_err() {
+        return Err(ServerError::WriteError("Could not sync snapshot.".to_string()));
+    }
+    if let Err(err) = fs::rename(&temp_path, path) {
+        return Err(ServerError::WriteError(format!("Could not move snapshot into place: {}", err)));
+    }
+    Ok(())
+}
+
+
+/// Load a snapshot into the storage and return how far into the command log it reaches and
+/// the version clock it was taken at.
+///
+/// A missing snapshot loads nothing.
+pub fn load_snapshot<S: Storage>(storage: &mut S, path: &Path) -> Result<SnapshotPosition, ServerError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) if !path.exists() => return Ok(SnapshotPosition::default()),
+        Err(err) => return Err(
+            ServerError::InternalError(format!("Could not open snapshot {}: {}", path.display(), err))
+        ),
+    };
+    let snapshot: Snapshot = match serde_json::from_reader(BufReader::new(file)) {
+        Ok(snapshot) => snapshot,
+        Err(err) => return Err(ServerError::InternalError(format!("Corrupt snapshot: {}", err))),
+    };
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(
+            ServerError::InternalError(format!("Unsupported snapshot version {}.", snapshot.version))
+        );
+    }
+    let mut version_clock = snapshot.version_clock;
+    let mut count = 0;
+    for element in snapshot.elements {
+        version_clock = version_clock.max(element.version);
+        if element.is_expired() {
+            continue;
+        }
+        let key = element.key.clone();
+        storage.set(&key, element)?;
+        count += 1;
+    }
+    println!("Loaded {} keys from {}.", count, path.display());
+    Ok(SnapshotPosition { command_log_offset: snapshot.command_log_offset, version_clock })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::hashmap_storage::HashMapStorage;
+    use crate::storage::{CollectionType, KeyType, StorageMap, StorageValue};
+
+    fn temp_snapshot_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rust_store_{}_{}.snapshot", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let path = temp_snapshot_path("round_trip");
+        let mut map = StorageMap::new(KeyType::Int, CollectionType::String);
+        map.set(StorageValue::Int(1), StorageValue::String("one".to_string())).unwrap();
+        let elements = vec![
+            StorageElement { key: "map".to_string(), value: StorageValue::Map(map), expiration: None, version: 0 },
+            StorageElement {
+                key: "later".to_string(),
+                value: StorageValue::Int(1),
+                expiration: Some(SystemTime::now() + Duration::from_secs(500)),
+                version: 0,
+            },
+            StorageElement {
+                key: "gone".to_string(),
+                value: StorageValue::Int(2),
+                expiration: Some(SystemTime::now() - Duration::from_secs(1)),
+                version: 9,
+            },
+        ];
+        write_snapshot(&path, elements, 42, 7).unwrap();
+
+        let mut storage = HashMapStorage::new();
+        let position = load_snapshot(&mut storage, &path).unwrap();
+        assert_eq!(position, SnapshotPosition { command_log_offset: 42, version_clock: 9 });
+        assert_eq!(storage.len().unwrap(), 2);
+        assert!(storage.get("later").unwrap().expiration.is_some());
+        assert!(!storage.contains_key("gone").unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_snapshot() {
+        let path = temp_snapshot_path("missing");
+        let mut storage = HashMapStorage::new();
+        assert_eq!(load_snapshot(&mut storage, &path).unwrap(), SnapshotPosition::default());
+        assert_eq!(storage.len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_load_unknown_version() {
+        let path = temp_snapshot_path("unknown_version");
+        fs::write(&path, "{\"version\":99,\"created\":{\"secs_since_epoch\":0,\"nanos_since_epoch\":0},\
+            \"command_log_offset\":0,\"elements\":[]}").unwrap();
+        let mut storage = HashMapStorage::new();
+        assert!(matches!(load_snapshot(&mut storage, &path), Err(ServerError::InternalError(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
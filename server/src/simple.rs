@@ -1,10 +1,36 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use server::config::ServerConfig;
 use server::io::tcp::TcpStreamHandler;
+use server::persistence;
 use server::single_threaded::SingleThreadedServer;
+use server::storage::{Storage, StorageBackend};
+use server::storage::btree_storage::BTreeMapStorage;
+
+/// Run a server backed by the given storage.
+fn serve<S: Storage + Send>(config: &ServerConfig, storage: S) {
+    let mut interpreter = persistence::restore_interpreter(storage, &config.persistence()).unwrap();
+    let users = config.load_users().unwrap();
+    if let Some(users) = &users {
+        interpreter.set_users(Arc::clone(users));
+    }
+    if let Some(signer) = config.capability_signer().unwrap() {
+        interpreter.set_capability_signer(signer);
+    }
+    let (keep_alive, limits) = (config.keep_alive(), config.limits());
+    let stream_handler = match config.tls() {
+        Some(tls) => TcpStreamHandler::with_tls(config.address, config.port, keep_alive, limits, &tls).unwrap(),
+        None => TcpStreamHandler::with_limits(config.address, config.port, keep_alive, limits),
+    };
+    let authenticator = config.new_authenticator(users).unwrap();
+    SingleThreadedServer::with_interpreter(authenticator, interpreter).serve(stream_handler)
+}
 
 /// Run a server.
 fn main() {
-    let mut server = SingleThreadedServer::new();
-    let stream_handler = TcpStreamHandler::new(IpAddr::V4(Ipv4Addr::new(127,0,0,1)), 7878);
-    server.serve(stream_handler);
+    let config = ServerConfig::load();
+    match config.backend {
+        StorageBackend::HashMap => serve(&config, config.new_hashmap_storage()),
+        StorageBackend::BTreeMap => serve(&config, BTreeMapStorage::new()),
+    }
 }
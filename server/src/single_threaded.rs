@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
 use crate::auth::{AuthenticationResult, AuthenticationService, MockAuthenticator};
+use crate::auth::credentials::DEFAULT_ITERATIONS;
 use crate::error::ServerError;
-use crate::io::stream::{StreamHandler, StreamRequest};
-use crate::analysis::{Interpreter, InterpreterRequest, InterpreterResponse, Parser, Statement, Tokenizer};
+use crate::io::stream::{StreamHandler, StreamQuery, StreamRequest};
+use crate::analysis::{Interpreter, InterpreterRequest, InterpreterResponse, ResponseMode, Statement};
 use crate::storage::hashmap_storage::HashMapStorage;
 use crate::storage::Storage;
 
@@ -19,6 +20,11 @@ pub struct SingleThreadedServer<Auth, Stor>
 
 
 impl<Auth: AuthenticationService, Stor: Storage + Send> SingleThreadedServer<Auth, Stor> {
+    /// Create a server from an authenticator and an existing interpreter.
+    pub fn with_interpreter(authenticator: Auth, interpreter: Interpreter<Stor>) -> SingleThreadedServer<Auth, Stor> {
+        SingleThreadedServer{interpreter, authenticator}
+    }
+
     /// Start running the server.
     pub fn serve<H: StreamHandler>(&mut self, mut stream_handler: H) {
         loop {
@@ -29,7 +35,7 @@ impl<Auth: AuthenticationService, Stor: Storage + Send> SingleThreadedServer<Aut
                 break;
             }
             let request: StreamRequest = request.unwrap();
-            let StreamRequest{request, sender, headers} = request;
+            let StreamRequest{request, sender, headers, ..} = request;
             let (response, shut_down) = self.handle_request(request, headers);
             if let Some(mut sender) = sender{
                 let res = sender.send(response);
@@ -46,10 +52,10 @@ impl<Auth: AuthenticationService, Stor: Storage + Send> SingleThreadedServer<Aut
     }
     
     /// Handle a single stream request to the server. 
-    fn handle_request(&mut self, request: Result<String, ServerError>, headers: HashMap<String, String>) -> (Result<InterpreterResponse, ServerError>, bool) {
+    fn handle_request(&mut self, request: Result<StreamQuery, ServerError>, headers: HashMap<String, String>) -> (Result<InterpreterResponse, ServerError>, bool) {
         let authentication = self.authenticator.authenticate(&headers);
-        let (username, authorization)= match authentication {
-            Ok(AuthenticationResult::Authenticated(username, level)) => (username, level),
+        let (username, authorization, access_rules)= match authentication {
+            Ok(AuthenticationResult::Authenticated(username, level, access_rules)) => (username, level, access_rules),
             Ok(AuthenticationResult::Unauthenticated) => {
                 return (Err(ServerError::AuthenticationError("Authentication failed.".to_string())), false);
             },
@@ -70,16 +76,13 @@ impl<Auth: AuthenticationService, Stor: Storage + Send> SingleThreadedServer<Aut
         if let Err(error) = &request {
             return (Err(error.clone()), false);
         }
-        let request_string = request.unwrap();
+        let query = request.unwrap();
+        let response_mode = match ResponseMode::from_headers(&headers) {
+            Ok(response_mode) => response_mode,
+            Err(error) => return (Err(error), false),
+        };
 
-        let mut tokenizer = Tokenizer::new(&request_string);
-        let tokens = tokenizer.tokenize();
-        if let Err(error) = tokens {
-            return (Err(error), false);
-        }
-        let tokens = tokens.unwrap();
-        let mut parser = Parser::new(tokens);
-        let statements = parser.parse();
+        let statements = query.into_runnable_statements(DEFAULT_ITERATIONS);
         if let Err(error) = statements {
             return (Err(error), false)
         }
@@ -91,7 +94,7 @@ impl<Auth: AuthenticationService, Stor: Storage + Send> SingleThreadedServer<Aut
                 break;
             }
         }
-        let int_request = InterpreterRequest{statements, authorization};
+        let int_request = InterpreterRequest{statements, authorization, access_rules, response_mode};
         let result = self.interpreter.interpret(int_request);
         (result, shut_down)
     }
@@ -102,7 +105,7 @@ impl SingleThreadedServer<MockAuthenticator, HashMapStorage> {
     pub fn new() -> SingleThreadedServer<MockAuthenticator, HashMapStorage>  {
         let storage = HashMapStorage::new();
         let authenticator = MockAuthenticator;
-        let interpreter = Interpreter{storage};
+        let interpreter = Interpreter::new(storage);
         SingleThreadedServer{interpreter, authenticator}
     }
 }
@@ -3,5 +3,12 @@ pub mod types;
 
 pub use self::types::{*};
 
+/// Keeps track of keys so they can be scanned a few at a time
+pub mod scan_order;
+
+pub use self::scan_order::ScanOrder;
+
 /// Contains an implementation of storage using a HashMap
 pub mod hashmap_storage;
+/// Contains an implementation of storage using a BTreeMap, which keeps keys in order
+pub mod btree_storage;
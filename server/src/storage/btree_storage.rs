@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use std::time::SystemTime;
+
+use crate::error::ServerError;
+use crate::storage::{
+    ScanOrder,
+    Storage,
+    StorageElement,
+    StorageKey,
+    make_key_error,
+};
+
+
+/// The most expired keys removed by one call to invalidate_expired_keys
+const EXPIRATION_BATCH: usize = 100;
+
+
+/// Top level storage container backed by a BTreeMap
+/// Keys are kept in sorted order so that ranges and prefixes can be
+/// listed without scanning the whole database.
+/// Expiring keys are indexed by expiration time so the earliest ones can be
+/// removed first.
+pub struct BTreeMapStorage {
+    storage: BTreeMap<StorageKey, StorageElement>,
+    expirations: BTreeSet<(SystemTime, StorageKey)>,
+    /// The order keys are visited in by scans
+    scan_order: ScanOrder,
+}
+
+impl BTreeMapStorage {
+    /// Create a new storage container
+    pub fn new() -> BTreeMapStorage {
+        BTreeMapStorage {
+            storage: BTreeMap::new(),
+            expirations: BTreeSet::new(),
+            scan_order: ScanOrder::new(),
+        }
+    }
+
+    /// List unexpired keys from the start bound onwards while they pass the filter
+    fn collect_keys<F: Fn(&str) -> bool>(
+        &self, start: Bound<&str>, keep_going: F, limit: Option<usize>
+    ) -> Vec<StorageKey> {
+        self.storage
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| keep_going(key))
+            .filter(|(_, element)| !element.is_expired())
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+}
+
+impl Default for BTreeMapStorage {
+    fn default() -> Self {
+        BTreeMapStorage::new()
+    }
+}
+
+
+/// Implement the Storage trait for the BTreeMapStorage
+impl Storage for BTreeMapStorage {
+
+    /// Get a value if it exists.
+    fn get_if_exists(&self, key: &str) -> Result<Option<StorageElement>, ServerError> {
+        match self.storage.get(key) {
+            Some(element) if element.is_expired() => Ok(None),
+            Some(element) => Ok(Some(element.clone())),
+            None => Ok(None),
+        }
+    }
+
+    /// Get a value or else throw an error.
+    fn get(&self, key: &str) -> Result<StorageElement, ServerError> {
+        match self.get_if_exists(key) {
+            Ok(Some(element)) => Ok(element),
+            Ok(None) => Err(make_key_error(key)),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Get a value or else throw an error.
+    fn get_mut(&mut self, key: &str) -> Result<&mut StorageElement, ServerError> {
+        match self.storage.get_mut(key) {
+            Some(element) if element.is_expired() => Err(make_key_error(key)),
+            Some(element) => Ok(element),
+            None => Err(make_key_error(key)),
+        }
+    }
+
+    /// Update the expiration time of an entry.
+    fn update_expiration(
+        &mut self, key: &str, expiration: Option<SystemTime>
+    ) -> Result<(), ServerError> {
+        let element = match self.storage.get_mut(key) {
+            Some(element) if element.is_expired() => return Err(make_key_error(key)),
+            Some(element) => element,
+            None => return Err(make_key_error(key)),
+        };
+        if let Some(old_expiration) = element.expiration {
+            self.expirations.remove(&(old_expiration, key.to_string()));
+        }
+        if let Some(new_expiration) = expiration {
+            self.expirations.insert((new_expiration, key.to_string()));
+        }
+        element.expiration = expiration;
+        Ok(())
+    }
+
+    /// Remove the keys that have expired, earliest first.
+    fn invalidate_expired_keys(&mut self) -> Result<usize, ServerError> {
+        let now = SystemTime::now();
+        let expired: Vec<StorageKey> = self.expirations
+            .iter()
+            .take_while(|(expiration, _)| *expiration <= now)
+            .take(EXPIRATION_BATCH)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &expired {
+            self.delete(key)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Delete an entry from the database.
+    fn delete(&mut self, key: &str) -> Result<bool, ServerError> {
+        match self.storage.remove(key) {
+            Some(element) => {
+                if let Some(expiration) = element.expiration {
+                    self.expirations.remove(&(expiration, key.to_string()));
+                }
+                self.scan_order.remove(key);
+                Ok(!element.is_expired())
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Update an existing entry.
+    fn update(&mut self, key: &str, value: StorageElement) -> Result<(), ServerError> {
+        match self.contains_key(key) {
+            Ok(false) => Err(make_key_error(key)),
+            Ok(true) => self.set(key, value),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set a value if it doesn't already exist.
+    fn set_if_not_exists(&mut self, key: &str, value: StorageElement) -> Result<bool, ServerError> {
+        match self.contains_key(key) {
+            Ok(true) => Ok(false),
+            Ok(false) => {
+                self.set(key, value)?;
+                Ok(true)
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Set a value.
+    fn set(&mut self, key: &str, value: StorageElement) -> Result<(), ServerError> {
+        if let Some(expiration) = value.expiration {
+            self.expirations.insert((expiration, key.to_string()));
+        }
+        let old_value = self.storage.insert(StorageKey::from(key), value);
+        if old_value.is_none() {
+            self.scan_order.insert(key);
+        }
+        if let Some(StorageElement { expiration: Some(old_expiration), .. }) = old_value {
+            let new_expiration = self.storage[key].expiration;
+            if new_expiration != Some(old_expiration) {
+                self.expirations.remove(&(old_expiration, key.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a key exists in the database.
+    fn contains_key(&self, key: &str) -> Result<bool, ServerError> {
+        match self.storage.get(key) {
+            Some(element) => Ok(!element.is_expired()),
+            None => Ok(false),
+        }
+    }
+
+    /// Get the number of keys
+    fn len(&self) -> Result<usize, ServerError> {
+        Ok(self.storage.len())
+    }
+
+    /// Check if a key is expired and remove if so
+    fn check_and_expire(&mut self, key: &str) -> Result<bool, ServerError> {
+        let element = match self.storage.get(key) {
+            Some(element) => element,
+            None => return Err(ServerError::KeyError(format!("Key {} not found.", key))),
+        };
+        if element.is_expired() {
+            self.delete(key)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Get the number of expiring keys
+    fn expiring_keys_count(&self) -> Result<usize, ServerError> {
+        Ok(self.expirations.len())
+    }
+
+    /// Get a copy of every element that has not expired, in key order
+    fn elements(&self) -> Result<Vec<StorageElement>, ServerError> {
+        let elements = self.storage.values()
+            .filter(|element| !element.is_expired())
+            .cloned()
+            .collect();
+        Ok(elements)
+    }
+
+    /// There is no memory limit for this storage, so nothing is ever evicted
+    fn enforce_memory_limit(&mut self) -> Result<Vec<StorageKey>, ServerError> {
+        Ok(vec![])
+    }
+
+    /// Get keys in sorted order, from start (inclusive) up to end (exclusive)
+    fn range_keys(
+        &self, start: &str, end: &str, limit: Option<usize>
+    ) -> Result<Vec<StorageKey>, ServerError> {
+        Ok(self.collect_keys(Bound::Included(start), |key| key < end, limit))
+    }
+
+    /// Visit keys in the order they were added, skipping any that have expired
+    fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<StorageKey>), ServerError> {
+        let (next_cursor, keys) = self.scan_order.scan(cursor, count);
+        let keys = keys.into_iter()
+            .filter(|key| !self.storage[*key].is_expired())
+            .cloned()
+            .collect();
+        Ok((next_cursor, keys))
+    }
+
+    /// Get keys beginning with the prefix in sorted order
+    fn prefix_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<StorageKey>, ServerError> {
+        Ok(self.collect_keys(Bound::Included(prefix), |key| key.starts_with(prefix), limit))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::storage::StorageValue;
+
+    fn make_element(key: &str, expiration: Option<SystemTime>) -> StorageElement {
+        StorageElement { key: key.to_string(), value: StorageValue::Int(1), expiration, version: 0 }
+    }
+
+    fn make_storage(keys: &[&str]) -> BTreeMapStorage {
+        let mut storage = BTreeMapStorage::new();
+        for key in keys {
+            storage.set(key, make_element(key, None)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        let mut storage = make_storage(&["key1"]);
+        assert!(matches!(storage.get("key1").unwrap().value, StorageValue::Int(1)));
+        assert!(matches!(storage.get("key2"), Err(ServerError::KeyError(_))));
+        assert!(!storage.set_if_not_exists("key1", make_element("key1", None)).unwrap());
+        assert!(storage.delete("key1").unwrap());
+        assert!(!storage.delete("key1").unwrap());
+        assert_eq!(storage.len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_range_keys() {
+        let storage = make_storage(&["b", "a", "d", "c", "e"]);
+        assert_eq!(storage.range_keys("b", "e", None).unwrap(), vec!["b", "c", "d"]);
+        assert_eq!(storage.range_keys("b", "e", Some(2)).unwrap(), vec!["b", "c"]);
+        assert_eq!(storage.range_keys("a0", "c0", None).unwrap(), vec!["b", "c"]);
+        assert!(storage.range_keys("e", "a", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prefix_keys() {
+        let storage = make_storage(&["user:2", "order:1", "user:1", "users", "uses"]);
+        assert_eq!(storage.prefix_keys("user:", None).unwrap(), vec!["user:1", "user:2"]);
+        assert_eq!(storage.prefix_keys("user", Some(2)).unwrap(), vec!["user:1", "user:2"]);
+        assert!(storage.prefix_keys("zzz", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_range_skips_expired_keys() {
+        let mut storage = make_storage(&["a", "c"]);
+        let past = SystemTime::now() - Duration::from_secs(1);
+        storage.set("b", make_element("b", Some(past))).unwrap();
+        assert_eq!(storage.range_keys("a", "z", None).unwrap(), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_expiration() {
+        let mut storage = make_storage(&["a"]);
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let future = SystemTime::now() + Duration::from_secs(500);
+        storage.set("b", make_element("b", Some(past))).unwrap();
+        storage.set("c", make_element("c", Some(future))).unwrap();
+        storage.update_expiration("a", Some(future)).unwrap();
+        assert_eq!(storage.expiring_keys_count().unwrap(), 3);
+
+        assert_eq!(storage.invalidate_expired_keys().unwrap(), 1);
+        assert_eq!(storage.len().unwrap(), 2);
+        storage.update_expiration("a", None).unwrap();
+        storage.set("c", make_element("c", None)).unwrap();
+        assert_eq!(storage.expiring_keys_count().unwrap(), 0);
+    }
+}
@@ -1,19 +1,28 @@
 use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::vec::Vec;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rand;
 use rand::RngCore;
 
 use crate::error::ServerError;
 use crate::storage::{
+    EvictionPolicy,
+    ScanOrder,
     Storage,
     StorageElement,
     StorageKey,
     make_key_error,
+    make_unordered_error,
 };
 
 
+/// How many random keys are compared when looking for one to evict
+const EVICTION_SAMPLES: usize = 5;
+
+
 /// Container for an entry in the hash map.
 #[derive(Debug)]
 struct HashMapContainer {
@@ -21,6 +30,18 @@ struct HashMapContainer {
     element: StorageElement,
     /// The location in the key vector for O(1) time deletion
     key_index: Option<usize>,
+    /// The estimated memory used by this entry
+    size: usize,
+    /// Logical time of the last access, for LRU eviction
+    last_access: AtomicU64,
+    /// Number of accesses, for LFU eviction
+    access_count: AtomicU64,
+}
+
+
+/// Estimate the memory used by an entry, including every copy of the key.
+fn container_size(key: &str, element: &StorageElement) -> usize {
+    size_of::<HashMapContainer>() + key.len() + ScanOrder::entry_size(key) + element.estimated_size()
 }
 
 
@@ -30,12 +51,43 @@ struct HashMapContainer {
 pub struct HashMapStorage {
     storage: HashMap<StorageKey, HashMapContainer>,
     expiring_keys: Vec<StorageKey>,
+    /// The memory budget in bytes, if any
+    max_memory: Option<usize>,
+    /// How to pick keys to evict once the budget is used up
+    eviction_policy: EvictionPolicy,
+    /// The estimated memory used by all entries
+    used_memory: usize,
+    /// Logical clock used to order accesses
+    clock: AtomicU64,
+    /// A key handed out by get_mut, whose size may have changed since
+    resized_key: Option<StorageKey>,
+    /// The order keys are visited in by scans, also used to sample keys for eviction
+    scan_order: ScanOrder,
 }
 
 impl HashMapStorage {
     /// Create a new storage container
     pub fn new() -> HashMapStorage {
-        HashMapStorage { storage: HashMap::new(), expiring_keys: vec![] }
+        HashMapStorage {
+            storage: HashMap::new(),
+            expiring_keys: vec![],
+            max_memory: None,
+            eviction_policy: EvictionPolicy::NoEviction,
+            used_memory: 0,
+            clock: AtomicU64::new(0),
+            resized_key: None,
+            scan_order: ScanOrder::new(),
+        }
+    }
+
+    /// Create a new storage container limited to roughly the given number of bytes
+    pub fn with_memory_limit(max_memory: usize, eviction_policy: EvictionPolicy) -> HashMapStorage {
+        HashMapStorage { max_memory: Some(max_memory), eviction_policy, ..HashMapStorage::new() }
+    }
+
+    /// The estimated number of bytes used as of the last write
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
     }
 
     fn invalidate_key_index(&mut self, index: usize) {
@@ -53,13 +105,93 @@ impl HashMapStorage {
 
     /// Get a random key from the database.
     fn get_random_key(&self) -> Option<&StorageKey> {
-        if self.expiring_keys.len() == 0 {
-            return None
+        self.scan_order.random_key()
+    }
+
+    /// Get a random key with an expiration from the database.
+    fn get_random_expiring_key(&self) -> Option<&StorageKey> {
+        get_random_key_from(&self.expiring_keys)
+    }
+
+    /// Record an access for the eviction policies
+    fn touch(&self, container: &HashMapContainer) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        container.last_access.store(now, Ordering::Relaxed);
+        container.access_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the memory used by a key that was handed out for changes
+    fn update_resized_key(&mut self) {
+        let key = match self.resized_key.take() {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(container) = self.storage.get_mut(&key) {
+            let new_size = container_size(&key, &container.element);
+            self.used_memory = self.used_memory - container.size + new_size;
+            container.size = new_size;
+        }
+    }
+
+    /// Score a key for eviction - the lowest score is evicted first
+    fn eviction_score(&self, container: &HashMapContainer) -> u128 {
+        if container.element.is_expired() {
+            return 0;
+        }
+        let last_access = container.last_access.load(Ordering::Relaxed) as u128;
+        match self.eviction_policy {
+            EvictionPolicy::AllKeysLru => last_access,
+            EvictionPolicy::AllKeysLfu => {
+                ((container.access_count.load(Ordering::Relaxed) as u128) << 64) | last_access
+            },
+            EvictionPolicy::VolatileTtl | EvictionPolicy::NoEviction => {
+                match container.element.expiration.map(|time| time.duration_since(UNIX_EPOCH)) {
+                    Some(Ok(duration)) => duration.as_nanos(),
+                    _ => u128::MAX,
+                }
+            },
+        }
+    }
+
+    /// Pick the keys to compare when looking for one to evict
+    /// 
+    /// Small enough pools are checked in full, otherwise a few random keys are sampled.
+    fn sample_eviction_candidates(&self) -> Vec<&StorageKey> {
+        match self.eviction_policy {
+            EvictionPolicy::NoEviction => vec![],
+            EvictionPolicy::AllKeysLru | EvictionPolicy::AllKeysLfu => {
+                if self.scan_order.len() <= EVICTION_SAMPLES {
+                    return self.scan_order.keys().collect();
+                }
+                (0..EVICTION_SAMPLES).filter_map(|_| self.get_random_key()).collect()
+            },
+            EvictionPolicy::VolatileTtl => {
+                if self.expiring_keys.len() <= EVICTION_SAMPLES {
+                    return self.expiring_keys.iter().collect();
+                }
+                (0..EVICTION_SAMPLES).filter_map(|_| self.get_random_expiring_key()).collect()
+            },
         }
-        let mut rng = rand::thread_rng();
-        let index = (rng.next_u64() as usize) % self.expiring_keys.len();
-        Some(&self.expiring_keys[index])
     }
+
+    /// Pick the best key to evict, if any are allowed to be evicted
+    fn find_eviction_candidate(&self) -> Option<StorageKey> {
+        self.sample_eviction_candidates()
+            .into_iter()
+            .min_by_key(|key| self.eviction_score(&self.storage[*key]))
+            .cloned()
+    }
+}
+
+
+/// Pick a random key out of a vector of keys.
+fn get_random_key_from(keys: &[StorageKey]) -> Option<&StorageKey> {
+    if keys.is_empty() {
+        return None
+    }
+    let mut rng = rand::thread_rng();
+    let index = (rng.next_u64() as usize) % keys.len();
+    Some(&keys[index])
 }
 
 
@@ -70,7 +202,10 @@ impl Storage for HashMapStorage {
     fn get_if_exists(&self, key: &str) -> Result<Option<StorageElement>, ServerError> {
         match self.storage.get(key) {
             Some(value) if value.element.is_expired() => Ok(None),
-            Some(value) => Ok(Some(value.element.clone())),
+            Some(value) => {
+                self.touch(value);
+                Ok(Some(value.element.clone()))
+            },
             None => Ok(None),
         }
     }
@@ -85,12 +220,17 @@ impl Storage for HashMapStorage {
     }
 
     /// Get a value or else throw an error.
+    /// 
+    /// The size of the element is recalculated on the next write, since it may have changed.
     fn get_mut(&mut self, key: &str) -> Result<&mut StorageElement, ServerError> {
-        match self.storage.get_mut(key) {
-            Some(value) if value.element.is_expired() => Err(make_key_error(key)),
-            Some(value) =>Ok(&mut value.element),
-            None => Err(ServerError::KeyError(format!("No item with index {} found.", key))),
+        self.update_resized_key();
+        match self.storage.get(key) {
+            Some(value) if value.element.is_expired() => return Err(make_key_error(key)),
+            Some(value) => self.touch(value),
+            None => return Err(ServerError::KeyError(format!("No item with index {} found.", key))),
         }
+        self.resized_key = Some(key.to_string());
+        Ok(&mut self.storage.get_mut(key).unwrap().element)
     }
 
     /// Update the expiration time of an entry.
@@ -132,7 +272,7 @@ impl Storage for HashMapStorage {
 
     /// Get a random key from the database.
     fn invalidate_expired_keys(&mut self) -> Result<usize, ServerError> {
-        let key = match self.get_random_key() {
+        let key = match self.get_random_expiring_key() {
             Some(key) => key.clone(),
             None => return Ok(0),
         };
@@ -147,6 +287,7 @@ impl Storage for HashMapStorage {
     fn delete(
         &mut self, key: &str
     ) -> Result<bool, ServerError> {
+        self.update_resized_key();
         let value = self.storage.remove(key);
         println!("{:?}", value);
         let result = if let Some(container) = value {
@@ -154,6 +295,8 @@ impl Storage for HashMapStorage {
                 let index = container.key_index.unwrap();
                 self.invalidate_key_index(index);
             }
+            self.scan_order.remove(key);
+            self.used_memory -= container.size;
             if container.element.is_expired() {
                 Ok(false)
             } else {
@@ -194,6 +337,15 @@ impl Storage for HashMapStorage {
     fn set(
         &mut self, key: &str, value: StorageElement
     ) -> Result<(), ServerError> {
+        self.update_resized_key();
+        let size = container_size(key, &value);
+        let old_size = match self.storage.get(key) {
+            Some(container) => container.size,
+            None => {
+                self.scan_order.insert(key);
+                0
+            },
+        };
         let index = match self.storage.get(key) {
             None => {
                 if let None = value.expiration {
@@ -229,8 +381,12 @@ impl Storage for HashMapStorage {
             HashMapContainer {
                 element: value,
                 key_index: index,
+                size,
+                last_access: AtomicU64::new(self.clock.fetch_add(1, Ordering::Relaxed)),
+                access_count: AtomicU64::new(1),
             }
         );
+        self.used_memory = self.used_memory - old_size + size;
         Ok(())
     }
 
@@ -256,9 +412,8 @@ impl Storage for HashMapStorage {
             None => return Err(ServerError::KeyError(format!("Key {} not found.", key))),
         };
         if item.element.is_expired() {
-            match self.storage.remove(key) {
-                _ => Ok(true),
-            }
+            self.delete(key)?;
+            Ok(true)
         } else {
             Ok(false)
         }
@@ -268,6 +423,60 @@ impl Storage for HashMapStorage {
     fn expiring_keys_count(&self) -> Result<usize, ServerError> {
         Ok(self.expiring_keys.len())
     }
+
+    /// Get a copy of every element that has not expired
+    fn elements(&self) -> Result<Vec<StorageElement>, ServerError> {
+        let elements = self.storage.values()
+            .filter(|container| !container.element.is_expired())
+            .map(|container| container.element.clone())
+            .collect();
+        Ok(elements)
+    }
+
+    /// Range queries are not supported since the keys are not kept in order
+    fn range_keys(
+        &self, _start: &str, _end: &str, _limit: Option<usize>
+    ) -> Result<Vec<StorageKey>, ServerError> {
+        Err(make_unordered_error())
+    }
+
+    /// Prefix queries are not supported since the keys are not kept in order
+    fn prefix_keys(&self, _prefix: &str, _limit: Option<usize>) -> Result<Vec<StorageKey>, ServerError> {
+        Err(make_unordered_error())
+    }
+
+    /// Visit keys in the order they were added, skipping any that have expired
+    fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<StorageKey>), ServerError> {
+        let (next_cursor, keys) = self.scan_order.scan(cursor, count);
+        let keys = keys.into_iter()
+            .filter(|key| !self.storage[*key].element.is_expired())
+            .cloned()
+            .collect();
+        Ok((next_cursor, keys))
+    }
+
+    /// Evict keys until the estimated memory use is back under the limit
+    fn enforce_memory_limit(&mut self) -> Result<Vec<StorageKey>, ServerError> {
+        self.update_resized_key();
+        let max_memory = match self.max_memory {
+            Some(max_memory) => max_memory,
+            None => return Ok(vec![]),
+        };
+        let mut evicted = vec![];
+        while self.used_memory > max_memory {
+            let key = match self.find_eviction_candidate() {
+                Some(key) => key,
+                None => return Err(
+                    ServerError::WriteError(
+                        format!("Memory limit of {} bytes reached and no keys can be evicted.", max_memory)
+                    )
+                ),
+            };
+            self.delete(&key)?;
+            evicted.push(key);
+        }
+        Ok(evicted)
+    }
 }
 
 
@@ -292,6 +501,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         let mut vector = StorageVector::new(CollectionType::Bool);
         vector.push(StorageValue::Bool(true)).unwrap();
@@ -300,6 +510,7 @@ mod tests {
             key: "key2".to_string(),
             value: StorageValue::Vector(vector),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         storage.set("key2", element2).unwrap();
@@ -318,18 +529,21 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         let element2 = StorageElement {
             key: "key1".to_string(),
             value: StorageValue::Bool(false),
             expiration: None,
+            version: 0,
         };
         assert_eq!(storage.set_if_not_exists("key1", element2).unwrap(), false);
         let element3 = StorageElement {
             key: "key2".to_string(),
             value: StorageValue::Int(11),
             expiration: None,
+            version: 0,
         };
         assert_eq!(storage.set_if_not_exists("key2", element3).unwrap(), true);
         assert_eq!(storage.storage.len(), 2);
@@ -349,6 +563,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         assert!(matches!(storage.get("key1").unwrap().value, StorageValue::Int(13)));
@@ -362,6 +577,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         assert!(matches!(storage.get_if_exists("key1").unwrap().unwrap().value, StorageValue::Int(13)));
@@ -375,6 +591,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         assert_eq!(storage.contains_key("key1").unwrap(), true);
@@ -388,12 +605,14 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         let element2 = StorageElement {
             key: "key1".to_string(),
             value: StorageValue::Int(15),
             expiration: None,
+            version: 0,
         };
         storage.update("key1", element2).unwrap();
         assert!(matches!(storage.get("key1").unwrap().value, StorageValue::Int(15)));
@@ -406,12 +625,14 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         let element2 = StorageElement {
             key: "key2".to_string(),
             value: StorageValue::Int(15),
             expiration: None,
+            version: 0,
         };
         storage.set("key2", element2).unwrap();
         assert_eq!(storage.delete("key2").unwrap(), true);
@@ -427,6 +648,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         let new_expiration = SystemTime::now() + Duration::from_secs(5000);
@@ -437,6 +659,131 @@ mod tests {
         assert!(matches!(storage.update_expiration("bad_key", None), Err(ServerError::KeyError(_))));
     }
 
+    #[test]
+    fn test_elements() {
+        let mut storage = HashMapStorage::new();
+        let element1 = StorageElement {
+            key: "key1".to_string(),
+            value: StorageValue::Int(13),
+            expiration: None,
+            version: 0,
+        };
+        let element2 = StorageElement {
+            key: "key2".to_string(),
+            value: StorageValue::Int(15),
+            expiration: Some(SystemTime::now() - Duration::from_secs(1)),
+            version: 0,
+        };
+        storage.set("key1", element1).unwrap();
+        storage.set("key2", element2).unwrap();
+        let elements = storage.elements().unwrap();
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].key, "key1");
+    }
+
+    fn make_element(key: &str, value: i64, expiration: Option<SystemTime>) -> StorageElement {
+        StorageElement { key: key.to_string(), value: StorageValue::Int(value), expiration, version: 0 }
+    }
+
+    #[test]
+    fn test_used_memory() {
+        let mut storage = HashMapStorage::new();
+        storage.set("key1", make_element("key1", 1, None)).unwrap();
+        let single_size = storage.used_memory();
+        assert!(single_size > 0);
+        storage.set("key2", make_element("key2", 2, None)).unwrap();
+        assert_eq!(storage.used_memory(), 2 * single_size);
+        storage.set("key2", make_element("key2", 3, None)).unwrap();
+        assert_eq!(storage.used_memory(), 2 * single_size);
+        storage.delete("key1").unwrap();
+        assert_eq!(storage.used_memory(), single_size);
+    }
+
+    #[test]
+    fn test_used_memory_after_get_mut() {
+        let mut storage = HashMapStorage::new();
+        let element = StorageElement {
+            key: "key1".to_string(),
+            value: StorageValue::Vector(StorageVector::new(CollectionType::Int)),
+            expiration: None,
+            version: 0,
+        };
+        storage.set("key1", element).unwrap();
+        let empty_size = storage.used_memory();
+        if let StorageValue::Vector(vector) = &mut storage.get_mut("key1").unwrap().value {
+            for i in 0..10 {
+                vector.push(StorageValue::Int(i)).unwrap();
+            }
+        }
+        storage.enforce_memory_limit().unwrap();
+        assert!(storage.used_memory() > empty_size);
+    }
+
+    #[test]
+    fn test_no_eviction() {
+        let mut storage = HashMapStorage::with_memory_limit(1, EvictionPolicy::NoEviction);
+        storage.enforce_memory_limit().unwrap();
+        storage.set("key1", make_element("key1", 1, None)).unwrap();
+        assert!(matches!(storage.enforce_memory_limit(), Err(ServerError::WriteError(_))));
+        assert_eq!(storage.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut storage = HashMapStorage::new();
+        storage.set("old", make_element("old", 1, None)).unwrap();
+        storage.set("new", make_element("new", 2, None)).unwrap();
+        storage.get("old").unwrap();
+        storage.max_memory = Some(storage.used_memory() - 1);
+        storage.eviction_policy = EvictionPolicy::AllKeysLru;
+        assert_eq!(storage.enforce_memory_limit().unwrap(), vec!["new".to_string()]);
+        assert_eq!(storage.len().unwrap(), 1);
+        assert!(storage.contains_key("old").unwrap());
+    }
+
+    #[test]
+    fn test_lfu_eviction() {
+        let mut storage = HashMapStorage::new();
+        storage.set("popular", make_element("popular", 1, None)).unwrap();
+        storage.set("unpopular", make_element("unpopular", 2, None)).unwrap();
+        for _ in 0..5 {
+            storage.get("popular").unwrap();
+        }
+        storage.get("unpopular").unwrap();
+        storage.max_memory = Some(storage.used_memory() - 1);
+        storage.eviction_policy = EvictionPolicy::AllKeysLfu;
+        storage.enforce_memory_limit().unwrap();
+        assert_eq!(storage.len().unwrap(), 1);
+        assert!(storage.contains_key("popular").unwrap());
+    }
+
+    #[test]
+    fn test_volatile_ttl_eviction() {
+        let mut storage = HashMapStorage::new();
+        let soon = Some(SystemTime::now() + Duration::from_secs(10));
+        storage.set("forever", make_element("forever", 1, None)).unwrap();
+        storage.set("soon", make_element("soon", 2, soon)).unwrap();
+        storage.max_memory = Some(storage.used_memory() - 1);
+        storage.eviction_policy = EvictionPolicy::VolatileTtl;
+        storage.enforce_memory_limit().unwrap();
+        assert!(storage.contains_key("forever").unwrap());
+        assert!(!storage.contains_key("soon").unwrap());
+        // Only keys without an expiration are left, so nothing more can be evicted
+        storage.max_memory = Some(1);
+        assert!(matches!(storage.enforce_memory_limit(), Err(ServerError::WriteError(_))));
+    }
+
+    #[test]
+    fn test_check_and_expire_removes_expiring_key() {
+        let mut storage = HashMapStorage::new();
+        let expired = Some(SystemTime::now() - Duration::from_secs(1));
+        storage.set("key1", make_element("key1", 1, expired)).unwrap();
+        assert!(storage.check_and_expire("key1").unwrap());
+        assert_eq!(storage.expiring_keys_count().unwrap(), 0);
+        assert_eq!(storage.invalidate_expired_keys().unwrap(), 0);
+        assert_eq!(storage.used_memory(), 0);
+    }
+
     #[test]
     fn test_get_random_key() {
         let mut storage = HashMapStorage::new();
@@ -445,6 +792,7 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: None,
+            version: 0,
         };
         storage.set("key1", element1).unwrap();
         assert!(matches!(storage.get_random_key(), Some(_)))
@@ -460,11 +808,13 @@ mod tests {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: Some(SystemTime::now() - Duration::from_secs(1)),
+            version: 0,
         };
         let element2 = StorageElement {
             key: "key1".to_string(),
             value: StorageValue::Int(13),
             expiration: Some(SystemTime::now() + Duration::from_secs(500)),
+            version: 0,
         };
         let new_expiration = Some(SystemTime::now() + Duration::from_secs(500));
         storage.set("key1", element1).unwrap();
@@ -480,4 +830,22 @@ mod tests {
         assert_eq!(storage.delete("key1").unwrap(), false);
         assert_eq!(storage.storage.len(), 0);
     }
+
+    #[test]
+    fn test_scan_across_inserts() {
+        let mut storage = HashMapStorage::new();
+        for key in ["a", "b", "c", "d", "e"] {
+            storage.set(key, StorageElement { key: key.to_string(), value: StorageValue::Int(1), expiration: None, version: 0 }).unwrap();
+        }
+        let (cursor, mut seen) = storage.scan(0, 2).unwrap();
+        storage.set("f", StorageElement { key: "f".to_string(), value: StorageValue::Int(2), expiration: None, version: 0 }).unwrap();
+        storage.delete("e").unwrap();
+        let mut cursor = cursor;
+        while cursor != 0 {
+            let (next_cursor, keys) = storage.scan(cursor, 2).unwrap();
+            seen.extend(keys);
+            cursor = next_cursor;
+        }
+        assert_eq!(seen, vec!["a", "b", "c", "d", "f"]);
+    }
 }
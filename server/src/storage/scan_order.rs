@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+use rand::Rng;
+
+use crate::storage::StorageKey;
+
+
+/// Tracks the order in which keys were added so they can be scanned incrementally.
+///
+/// Each new key is given a larger id than any before it and the cursor is the next id to
+/// visit. Keys added during a scan land after the cursor, and removing a key never moves
+/// any other key, so every key present for a whole scan is returned exactly once.
+#[derive(Debug)]
+pub struct ScanOrder {
+    /// Keys by id, in the order they were added
+    order: BTreeMap<u64, StorageKey>,
+    /// The id of each key
+    ids: HashMap<StorageKey, u64>,
+    /// The id to give the next new key - ids start at 1 so a cursor of 0 means the start
+    next_id: u64,
+}
+
+impl ScanOrder {
+    /// Create an empty scan order
+    pub fn new() -> ScanOrder {
+        ScanOrder { order: BTreeMap::new(), ids: HashMap::new(), next_id: 1 }
+    }
+
+    /// Estimate the memory used to track one key
+    pub fn entry_size(key: &str) -> usize {
+        2 * (key.len() + size_of::<StorageKey>() + size_of::<u64>())
+    }
+
+    /// Add a key to the end of the order, if it isn't already tracked
+    pub fn insert(&mut self, key: &str) {
+        if self.ids.contains_key(key) {
+            return;
+        }
+        self.order.insert(self.next_id, key.to_string());
+        self.ids.insert(key.to_string(), self.next_id);
+        self.next_id += 1;
+    }
+
+    /// Stop tracking a key
+    pub fn remove(&mut self, key: &str) {
+        if let Some(id) = self.ids.remove(key) {
+            self.order.remove(&id);
+        }
+    }
+
+    /// The number of keys tracked
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Check if no keys are tracked
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /// Every key, in the order they were added
+    pub fn keys(&self) -> impl Iterator<Item = &StorageKey> {
+        self.order.values()
+    }
+
+    /// Pick a key at random, if there are any.
+    ///
+    /// A random id is picked and the first key from there on is taken, so keys just after ones
+    /// that were removed are a little more likely to come up. That is good enough for sampling.
+    pub fn random_key(&self) -> Option<&StorageKey> {
+        let first_id = *self.order.keys().next()?;
+        let last_id = *self.order.keys().next_back()?;
+        let id = rand::thread_rng().gen_range(first_id..=last_id);
+        self.order.range(id..).next().map(|(_, key)| key)
+    }
+
+    /// Get up to count keys from the cursor on, along with the cursor to continue from.
+    ///
+    /// The returned cursor is 0 once there is nothing left to visit.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<&StorageKey>) {
+        let mut entries = self.order.range(cursor..);
+        let keys: Vec<(&u64, &StorageKey)> = entries.by_ref().take(count).collect();
+        let next_cursor = match entries.next() {
+            Some((id, _)) => *id,
+            None => 0,
+        };
+        (next_cursor, keys.into_iter().map(|(_, key)| key).collect())
+    }
+}
+
+impl Default for ScanOrder {
+    fn default() -> Self {
+        ScanOrder::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_pages() {
+        let mut order = ScanOrder::new();
+        for key in ["a", "b", "c", "d", "e"] {
+            order.insert(key);
+        }
+        let (cursor, keys) = order.scan(0, 2);
+        assert_eq!(keys, vec!["a", "b"]);
+        let (cursor, keys) = order.scan(cursor, 2);
+        assert_eq!(keys, vec!["c", "d"]);
+        let (cursor, keys) = order.scan(cursor, 2);
+        assert_eq!(keys, vec!["e"]);
+        assert_eq!(cursor, 0);
+    }
+
+    #[test]
+    fn test_random_key() {
+        let mut order = ScanOrder::new();
+        assert_eq!(order.random_key(), None);
+        for key in ["a", "b", "c", "d"] {
+            order.insert(key);
+        }
+        order.remove("a");
+        order.remove("c");
+        for _ in 0..20 {
+            let key = order.random_key().unwrap();
+            assert!(key == "b" || key == "d");
+        }
+    }
+
+    #[test]
+    fn test_scan_with_changes() {
+        let mut order = ScanOrder::new();
+        for key in ["a", "b", "c", "d"] {
+            order.insert(key);
+        }
+        let (cursor, keys) = order.scan(0, 2);
+        assert_eq!(keys, vec!["a", "b"]);
+        order.remove("a");
+        order.remove("d");
+        order.insert("b");
+        order.insert("e");
+        let (cursor, keys) = order.scan(cursor, 10);
+        assert_eq!(keys, vec!["c", "e"]);
+        assert_eq!(cursor, 0);
+    }
+}
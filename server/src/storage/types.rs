@@ -1,6 +1,7 @@
 use std::cmp::{Eq, PartialEq};
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::mem::size_of;
 use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
@@ -110,6 +111,77 @@ impl PartialEq for StorageValue {
 impl Eq for StorageValue {}
 
 
+impl From<bool> for StorageValue {
+    fn from(value: bool) -> StorageValue {
+        StorageValue::Bool(value)
+    }
+}
+
+impl From<Int> for StorageValue {
+    fn from(value: Int) -> StorageValue {
+        StorageValue::Int(value)
+    }
+}
+
+impl From<Float> for StorageValue {
+    fn from(value: Float) -> StorageValue {
+        StorageValue::Float(value)
+    }
+}
+
+impl From<String> for StorageValue {
+    fn from(value: String) -> StorageValue {
+        StorageValue::String(value)
+    }
+}
+
+impl From<&str> for StorageValue {
+    fn from(value: &str) -> StorageValue {
+        StorageValue::String(value.to_string())
+    }
+}
+
+
+impl StorageValue {
+    /// Estimate how many bytes this value takes up in memory.
+    pub fn estimated_size(&self) -> usize {
+        let contents = match self {
+            StorageValue::String(value) => value.capacity(),
+            StorageValue::Vector(vector) => {
+                vector.vector.iter().map(|value| value.estimated_size()).sum()
+            },
+            StorageValue::Map(map) => {
+                map.map.iter().map(|(key, value)| key.estimated_size() + value.estimated_size()).sum()
+            },
+            _ => 0,
+        };
+        size_of::<StorageValue>() + contents
+    }
+
+    /// Check that the contents of a vector or map match the types it was declared with.
+    ///
+    /// Collections built up by statements always do, but ones read straight from JSON sent by a
+    /// client can claim any types.
+    pub fn validate(&self) -> Result<(), ServerError> {
+        match self {
+            StorageValue::Vector(vector) => {
+                for value in vector.vector.iter() {
+                    validate_value(value, vector.collection_type)?;
+                }
+            },
+            StorageValue::Map(map) => {
+                for (key, value) in map.map.iter() {
+                    validate_key(key, map.key_type)?;
+                    validate_value(value, map.collection_type)?;
+                }
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+
 /// Check that a storage value matches the expected type
 fn validate_value(
     value: &StorageValue, collection_type: CollectionType
@@ -188,6 +260,11 @@ impl StorageVector {
         self.vector.len()
     }
 
+    /// Iterate over the values in order
+    pub fn iter(&self) -> impl Iterator<Item=&StorageValue> {
+        self.vector.iter()
+    }
+
     /// Get the value at the given location
     pub fn get(&self, index: usize) -> Result<&StorageValue, ServerError> {
         match self.vector.get(index) {
@@ -214,6 +291,13 @@ impl StorageVector {
         Ok(())
     }
 
+    /// Push a new value to the front of the vector
+    pub fn push_front(&mut self, value: StorageValue) -> Result<(), ServerError> {
+        validate_value(&value, self.collection_type)?;
+        self.vector.insert(0, value);
+        Ok(())
+    }
+
     /// Set the value at a given index
     pub fn set(&mut self, index: usize, value: StorageValue) -> Result<(), ServerError> {
         match validate_value(&value, self.collection_type) {
@@ -242,6 +326,7 @@ impl StorageVector {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct StorageMap {
     /// The raw map to be accessed
+    #[serde(with = "map_entries")]
     map: HashMap<StorageValue, StorageValue>,
     /// The type of key to be used
     pub key_type: KeyType,
@@ -323,12 +408,43 @@ impl StorageMap {
         self.map.len()
     }
 
+    /// Iterate over the entries in no particular order
+    pub fn iter(&self) -> impl Iterator<Item=(&StorageValue, &StorageValue)> {
+        self.map.iter()
+    }
+
     
 }
 
 
+/// Serializes the inner map of a StorageMap as a list of key-value pairs.
+/// 
+/// Formats like JSON only allow string keys, which StorageValue keys are not.
+mod map_entries {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::StorageValue;
+
+    pub fn serialize<S: Serializer>(
+        map: &HashMap<StorageValue, StorageValue>, serializer: S
+    ) -> Result<S::Ok, S::Error> {
+        let entries: Vec<(&StorageValue, &StorageValue)> = map.iter().collect();
+        entries.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D
+    ) -> Result<HashMap<StorageValue, StorageValue>, D::Error> {
+        let entries: Vec<(StorageValue, StorageValue)> = Vec::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+
 /// A storage element includes the key, the value, and an optional expiration time
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct StorageElement {
     /// The key used to retrieve the value
     pub key: StorageKey,
@@ -336,10 +452,18 @@ pub struct StorageElement {
     pub expiration: Option<SystemTime>,
     /// The value pointed to by the key
     pub value: StorageValue,
+    /// Bumped every time the element changes, so clients can tell if it was modified
+    #[serde(default)]
+    pub version: u64,
 }
 
 
 impl StorageElement {
+    /// Estimate how many bytes this element takes up in memory.
+    pub fn estimated_size(&self) -> usize {
+        size_of::<StorageElement>() - size_of::<StorageValue>() + self.key.capacity() + self.value.estimated_size()
+    }
+
     /// Check if an element has expired already
     pub fn is_expired(&self) -> bool {
          match self.expiration {
@@ -350,6 +474,71 @@ impl StorageElement {
 }
 
 
+/// Which keys to throw away when the storage runs out of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum EvictionPolicy {
+    /// Never evict anything - writes fail once the limit is reached
+    NoEviction,
+    /// Evict the least recently used key
+    AllKeysLru,
+    /// Evict the least frequently used key
+    AllKeysLfu,
+    /// Evict the key closest to expiring, only considering keys with an expiration
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Get a policy from its name, like `allkeys-lru`
+    pub fn from_name(name: &str) -> Result<EvictionPolicy, ServerError> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(ServerError::ConfigError(format!("Unknown eviction policy {}.", name))),
+        }
+    }
+}
+
+
+/// The storage implementations a server can be started with.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum StorageBackend {
+    /// Unordered storage in a HashMap
+    HashMap,
+    /// Ordered storage in a BTreeMap, which supports range queries
+    BTreeMap,
+}
+
+impl StorageBackend {
+    /// The environment variable used to pick a backend
+    pub const ENV_VAR: &'static str = "RUST_STORE_STORAGE";
+
+    /// Get a backend from its name
+    pub fn from_name(name: &str) -> Result<StorageBackend, ServerError> {
+        match name.to_lowercase().as_str() {
+            "hashmap" => Ok(StorageBackend::HashMap),
+            "btreemap" | "btree" => Ok(StorageBackend::BTreeMap),
+            _ => Err(ServerError::ConfigError(format!("Unknown storage backend {}.", name))),
+        }
+    }
+
+    /// Get the backend named by the environment, defaulting to a HashMap
+    pub fn from_env() -> Result<StorageBackend, ServerError> {
+        match std::env::var(StorageBackend::ENV_VAR) {
+            Ok(name) => StorageBackend::from_name(&name),
+            Err(_) => Ok(StorageBackend::HashMap),
+        }
+    }
+}
+
+
+/// Create an error if a storage backend can't list keys in order.
+pub fn make_unordered_error() -> ServerError {
+    ServerError::RequestError("Range queries need an ordered storage backend.".to_string())
+}
+
+
 /// Create an error if a key was not found when it was expected to.
 pub fn make_key_error(key: &str) -> ServerError {
     ServerError::KeyError(format!("No entry with key '{}' exists", key))
@@ -399,6 +588,23 @@ pub trait Storage {
     fn check_and_expire(&mut self, key: &str) -> Result<bool, ServerError>;
     /// Get the number of expiring keys
     fn expiring_keys_count(&self) -> Result<usize, ServerError>;
+    /// Get a copy of every element that has not expired
+    fn elements(&self) -> Result<Vec<StorageElement>, ServerError>;
+    /// Evict keys according to the eviction policy until the storage fits in its memory limit,
+    /// giving back the keys that were evicted
+    fn enforce_memory_limit(&mut self) -> Result<Vec<StorageKey>, ServerError>;
+    /// Get keys in sorted order, from start (inclusive) up to end (exclusive)
+    fn range_keys(
+        &self, start: &str, end: &str, limit: Option<usize>
+    ) -> Result<Vec<StorageKey>, ServerError>;
+    /// Get keys beginning with the prefix in sorted order
+    fn prefix_keys(&self, prefix: &str, limit: Option<usize>) -> Result<Vec<StorageKey>, ServerError>;
+    /// Visit up to count keys from the cursor and get the cursor to continue from
+    ///
+    /// A cursor of 0 starts a new scan, and 0 is returned once every key has been visited.
+    /// Keys that exist for the whole scan are returned exactly once, even if other keys are
+    /// added or removed in between calls.
+    fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<StorageKey>), ServerError>;
 }
 
 
@@ -527,6 +733,17 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_estimated_size() {
+        let small = StorageValue::String("a".to_string());
+        let large = StorageValue::String("a".repeat(100));
+        assert!(large.estimated_size() >= small.estimated_size() + 99);
+        let mut vector = StorageVector::new(CollectionType::String);
+        vector.push(large.clone()).unwrap();
+        vector.push(large.clone()).unwrap();
+        assert!(StorageValue::Vector(vector).estimated_size() > 2 * large.estimated_size());
+    }
+
     #[test]
     fn test_vector_new() {
         let vector = StorageVector::new(CollectionType::Int);
@@ -650,6 +867,23 @@ mod test {
         assert!(matches!(map.contains_key(&StorageValue::Int(0)), Err(ServerError::TypeError(_))));
     }
 
+    #[test]
+    fn test_map_json_round_trip() {
+        let mut map = StorageMap::new(KeyType::String, CollectionType::Int);
+        map.set(StorageValue::String("key".to_string()), StorageValue::Int(3)).unwrap();
+        let json = serde_json::to_string(&StorageValue::Map(map)).unwrap();
+        let value: StorageValue = serde_json::from_str(&json).unwrap();
+        match value {
+            StorageValue::Map(map) => {
+                assert_eq!(map.len(), 1);
+                assert!(
+                    matches!(map.get(&StorageValue::String("key".to_string())), Ok(StorageValue::Int(3)))
+                );
+            },
+            _ => panic!("Expected a map."),
+        }
+    }
+
     #[test]
     fn test_map_delete() {
         let mut map = StorageMap::new(KeyType::String, CollectionType::Float);
@@ -660,5 +894,11 @@ mod test {
 
     }
 
+    #[test]
+    fn test_backend_from_name() {
+        assert_eq!(StorageBackend::from_name("BTree").unwrap(), StorageBackend::BTreeMap);
+        assert!(matches!(StorageBackend::from_name("sled"), Err(ServerError::ConfigError(_))));
+    }
+
 
 }
@@ -0,0 +1,116 @@
+use std::io::BufRead;
+use std::path::Path;
+use std::process;
+
+use server::auth::{AccessRules, AuthorizationLevel, User, UsersFile};
+use server::auth::credentials::{generate_api_key, hash_api_key, hash_password};
+use server::error::ServerError;
+
+/// How to run the tool
+const USAGE: &str = "Usage:
+  store_users add <users file> <username> <admin|write|read>
+      Add a user, or change their level and password. The password is read from standard input.
+  store_users api-key <users file> <username>
+      Give a user a new API key and print it. Only its hash is kept, so it can't be shown again.
+  store_users rule <users file> <username> <read|write|allow|deny> <pattern or command>
+      Limit a user to keys matching read or write patterns like metrics:*, or allow or deny a command.";
+
+
+/// Read the users file, starting a new one if there isn't one yet
+fn load(path: &Path) -> Result<UsersFile, ServerError> {
+    match path.exists() {
+        true => UsersFile::load(path),
+        false => Ok(UsersFile::default()),
+    }
+}
+
+
+/// Read a password from the first line of standard input
+fn read_password() -> Result<String, ServerError> {
+    let mut password = String::new();
+    if let Err(error) = std::io::stdin().lock().read_line(&mut password) {
+        return Err(ServerError::RequestError(format!("Could not read the password: {}", error)));
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(ServerError::RequestError("The password can't be empty.".to_string()));
+    }
+    Ok(password.to_string())
+}
+
+
+/// Add a user or update an existing one
+fn add_user(path: &Path, username: &str, level: &str) -> Result<(), ServerError> {
+    let level = AuthorizationLevel::from_name(level)?;
+    let password_hash = Some(hash_password(&read_password()?));
+    let mut users = load(path)?;
+    match users.users.iter_mut().find(|user| user.username == username) {
+        Some(user) => {
+            user.level = level;
+            user.password_hash = password_hash;
+        },
+        None => users.users.push(User {
+            username: username.to_string(), level, password_hash, api_key_hashes: vec![], rules: None,
+        }),
+    }
+    users.save(path)
+}
+
+
+/// Give a user a new API key, giving back the key
+fn add_api_key(path: &Path, username: &str) -> Result<String, ServerError> {
+    let mut users = load(path)?;
+    let user = match users.users.iter_mut().find(|user| user.username == username) {
+        Some(user) => user,
+        None => return Err(ServerError::KeyError(format!("No user {} in {}.", username, path.display()))),
+    };
+    let key = generate_api_key();
+    user.api_key_hashes.push(hash_api_key(&key));
+    users.save(path)?;
+    Ok(key)
+}
+
+
+/// Add a rule limiting the keys or commands a user can use
+fn add_rule(path: &Path, username: &str, kind: &str, value: &str) -> Result<(), ServerError> {
+    let mut users = load(path)?;
+    let user = match users.users.iter_mut().find(|user| user.username == username) {
+        Some(user) => user,
+        None => return Err(ServerError::KeyError(format!("No user {} in {}.", username, path.display()))),
+    };
+    let rules = user.rules.get_or_insert_with(AccessRules::default);
+    let list = match kind {
+        "read" => &mut rules.read,
+        "write" => &mut rules.write,
+        "allow" => &mut rules.allow_commands,
+        "deny" => &mut rules.deny_commands,
+        _ => return Err(ServerError::RequestError(format!("Unknown kind of rule {}.", kind))),
+    };
+    if !list.iter().any(|existing| existing == value) {
+        list.push(value.to_string());
+    }
+    users.save(path)
+}
+
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["add", path, username, level] => add_user(Path::new(path), username, level),
+        ["api-key", path, username] => add_api_key(Path::new(path), username).map(|key| println!("{}", key)),
+        ["rule", path, username, kind, value] => add_rule(Path::new(path), username, kind, value),
+        ["-h"] | ["--help"] => {
+            println!("{}", USAGE);
+            return;
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}